@@ -0,0 +1,8 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS config_secrets
+(
+    package TEXT NOT NULL,
+    path    TEXT NOT NULL,
+    value   BLOB NOT NULL,
+    PRIMARY KEY (package, path)
+);
@@ -0,0 +1,11 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS config_history
+(
+    package    TEXT NOT NULL,
+    revision   INTEGER NOT NULL,
+    created_at TEXT NOT NULL,
+    session    TEXT,
+    config     TEXT NOT NULL,
+    diff       TEXT NOT NULL,
+    PRIMARY KEY (package, revision)
+);
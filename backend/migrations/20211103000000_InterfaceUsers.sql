@@ -0,0 +1,9 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS interface_users
+(
+    package       TEXT NOT NULL,
+    interface     TEXT NOT NULL,
+    username      TEXT NOT NULL,
+    password_hash TEXT NOT NULL,
+    PRIMARY KEY (package, interface, username)
+);
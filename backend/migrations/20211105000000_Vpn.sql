@@ -0,0 +1,13 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS vpn_key
+(
+    id          INTEGER PRIMARY KEY CHECK (id = 0),
+    private_key BLOB NOT NULL CHECK (length(private_key) = 32)
+);
+CREATE TABLE IF NOT EXISTS vpn_peers
+(
+    name        TEXT PRIMARY KEY,
+    public_key  BLOB NOT NULL UNIQUE CHECK (length(public_key) = 32),
+    address     TEXT NOT NULL UNIQUE,
+    created_at  TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
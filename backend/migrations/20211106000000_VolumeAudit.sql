@@ -0,0 +1,12 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS volume_audit
+(
+    id         INTEGER PRIMARY KEY AUTOINCREMENT,
+    package    TEXT NOT NULL,
+    volume     TEXT NOT NULL,
+    path       TEXT NOT NULL,
+    size       INTEGER NOT NULL,
+    sha256     TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    session    TEXT
+);
@@ -0,0 +1,9 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS tor_client_auth_restricted
+(
+    package     TEXT NOT NULL,
+    interface   TEXT NOT NULL,
+    PRIMARY KEY (package, interface)
+);
+INSERT OR IGNORE INTO tor_client_auth_restricted (package, interface)
+SELECT DISTINCT package, interface FROM tor_client_auth;
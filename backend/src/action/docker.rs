@@ -15,6 +15,7 @@ use tracing::instrument;
 
 use crate::context::RpcContext;
 use crate::id::{Id, ImageId};
+use crate::net::firewall::{container_ip, Egress, NetworkPolicy};
 use crate::s9pk::manifest::{PackageId, SYSTEM_PACKAGE_ID};
 use crate::util::serde::{Duration as SerdeDuration, IoFormat};
 use crate::util::Version;
@@ -94,14 +95,17 @@ impl DockerAction {
         timeout: Option<Duration>,
     ) -> Result<Result<O, (i32, String)>, Error> {
         let mut cmd = tokio::process::Command::new("docker");
+        let mut container = None;
         if self.inject && allow_inject {
             cmd.arg("exec");
         } else {
             let container_name = Self::container_name(pkg_id, name);
+            container = Some(container_name.clone());
             cmd.arg("run")
                 .arg("--rm")
                 .arg("--network=start9")
                 .arg(format!("--add-host=embassy:{}", Ipv4Addr::from(HOST_IP)))
+                .arg(format!("--dns={}", Ipv4Addr::from(HOST_IP)))
                 .arg("--name")
                 .arg(&container_name)
                 .arg(format!("--hostname={}", &container_name))
@@ -143,6 +147,11 @@ impl DockerAction {
         );
         let mut handle = cmd.spawn().with_kind(crate::ErrorKind::Docker)?;
         let id = handle.id();
+        // the firewall drops everything the container sends until it is registered
+        let _firewall = match container {
+            Some(container) => Some(FirewallRegistration::new(ctx, pkg_id, container).await?),
+            None => None,
+        };
         let timeout_fut = if let Some(timeout) = timeout {
             EitherFuture::Right(async move {
                 tokio::time::sleep(timeout).await;
@@ -352,3 +361,103 @@ impl DockerAction {
         res
     }
 }
+
+/// Registers a container with the firewall once it has an address, and removes it again when
+/// dropped.
+struct FirewallRegistration {
+    ctx: RpcContext,
+    container: String,
+    registration: u64,
+    task: Option<tokio::task::JoinHandle<()>>,
+}
+impl FirewallRegistration {
+    async fn new(ctx: &RpcContext, pkg_id: &PackageId, container: String) -> Result<Self, Error> {
+        let manifest = crate::db::DatabaseModel::new()
+            .package_data()
+            .idx_model(pkg_id)
+            .map(|pde| pde.manifest())
+            .get(&mut ctx.db.handle(), false)
+            .await?
+            .into_owned();
+        let (policy, dependencies) = match manifest {
+            Some(manifest) => (
+                manifest.network,
+                manifest.dependencies.0.into_keys().collect::<Vec<_>>(),
+            ),
+            None => (NetworkPolicy::default(), Vec::new()),
+        };
+        let restricted = policy.egress != Egress::Clearnet || policy.packages.is_some();
+        let registration = ctx.net_controller.firewall.registration();
+        let task_ctx = ctx.clone();
+        let task_container = container.clone();
+        let pkg_id = pkg_id.clone();
+        let task = tokio::spawn(async move {
+            if let Err(e) = async {
+                let ip = container_ip(&task_ctx.docker, &task_container).await?;
+                task_ctx
+                    .net_controller
+                    .firewall
+                    .add(
+                        &task_container,
+                        registration,
+                        &pkg_id,
+                        ip,
+                        &policy,
+                        dependencies,
+                    )
+                    .await
+            }
+            .await
+            {
+                tracing::error!(
+                    "Could not register {} with the firewall: {}",
+                    task_container,
+                    e
+                );
+                tracing::debug!("{:?}", e);
+                if restricted {
+                    // without its rules the container must not run at all
+                    if let Err(e) = task_ctx
+                        .docker
+                        .kill_container::<String>(&task_container, None)
+                        .await
+                    {
+                        tracing::error!("Could not kill {}: {}", task_container, e);
+                        tracing::debug!("{:?}", e);
+                    }
+                }
+            }
+        });
+        Ok(FirewallRegistration {
+            ctx: ctx.clone(),
+            container,
+            registration,
+            task: Some(task),
+        })
+    }
+}
+impl Drop for FirewallRegistration {
+    fn drop(&mut self) {
+        let ctx = self.ctx.clone();
+        let container = std::mem::take(&mut self.container);
+        let registration = self.registration;
+        let task = self.task.take();
+        tokio::spawn(async move {
+            // the registration must not land after the removal
+            if let Some(task) = task {
+                task.abort();
+                task.await.ok();
+            }
+            // a container started since under the same name has a registration of its own
+            if let Err(e) = ctx
+                .net_controller
+                .firewall
+                .remove(&container, registration)
+                .await
+            {
+                tracing::error!("Could not remove {} from the firewall: {}", container, e);
+                tracing::debug!("{:?}", e);
+            }
+        });
+    }
+}
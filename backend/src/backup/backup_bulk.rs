@@ -211,6 +211,13 @@ pub async fn backup_all(
             .put(&mut db, &false)
             .await
             .expect("failed to change server status");
+        crate::db::DatabaseModel::new()
+            .server_info()
+            .status_info()
+            .backup_progress()
+            .put(&mut db, &BTreeMap::new())
+            .await
+            .expect("failed to clear backup progress");
     });
     Ok(WithRevision {
         response: (),
@@ -1,11 +1,13 @@
 use std::collections::{BTreeMap, BTreeSet};
 use std::path::Path;
+use std::time::Duration;
 
 use chrono::{DateTime, Utc};
 use color_eyre::eyre::eyre;
 use patch_db::{DbHandle, HasModel, LockType};
 use rpc_toolkit::command;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use sqlx::{Executor, Sqlite};
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
@@ -14,7 +16,9 @@ use tracing::instrument;
 use self::target::PackageBackupInfo;
 use crate::action::{ActionImplementation, NoOutput};
 use crate::context::RpcContext;
+use crate::db::model::BackupProgress;
 use crate::dependencies::reconfigure_dependents_with_live_pointers;
+use crate::disk::mount::backup::BACKUP_PROGRESS_FILE;
 use crate::id::ImageId;
 use crate::install::PKG_ARCHIVE_DIR;
 use crate::net::interface::{InterfaceId, Interfaces};
@@ -25,6 +29,9 @@ use crate::version::{Current, VersionT};
 use crate::volume::{backup_dir, Volume, VolumeId, Volumes, BACKUP_DIR};
 use crate::{Error, ResultExt};
 
+/// How often the progress a backup action reports is copied to the db.
+const BACKUP_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
 pub mod backup_bulk;
 pub mod restore;
 pub mod target;
@@ -56,6 +63,11 @@ pub fn package_backup() -> Result<(), Error> {
     Ok(())
 }
 
+/// The progress a running backup action last reported, if it reports any.
+async fn read_progress(path: &Path) -> Option<BackupProgress> {
+    serde_json::from_slice(&tokio::fs::read(path).await.ok()?).ok()
+}
+
 #[derive(Deserialize, Serialize)]
 struct BackupMetadata {
     pub timestamp: DateTime<Utc>,
@@ -94,20 +106,55 @@ impl BackupActions {
         if tokio::fs::metadata(&backup_dir).await.is_err() {
             tokio::fs::create_dir_all(&backup_dir).await?
         }
-        self.create
-            .execute::<(), NoOutput>(
-                ctx,
-                pkg_id,
-                pkg_version,
-                Some("CreateBackup"),
-                &volumes,
-                None,
-                false,
-                None,
-            )
-            .await?
+        let progress_path = backup_dir.join(BACKUP_PROGRESS_FILE);
+        let progress_model = crate::db::DatabaseModel::new()
+            .server_info()
+            .status_info()
+            .backup_progress()
+            .idx_model(pkg_id);
+        let mut db = ctx.db.handle();
+        progress_model
+            .clone()
+            .put(&mut db, &BackupProgress::default())
+            .await?;
+        let action = self.create.execute::<(), Value>(
+            ctx,
+            pkg_id,
+            pkg_version,
+            Some("CreateBackup"),
+            &volumes,
+            None,
+            false,
+            None,
+        );
+        tokio::pin!(action);
+        let res = loop {
+            tokio::select! {
+                res = &mut action => break res,
+                _ = tokio::time::sleep(BACKUP_PROGRESS_INTERVAL) => {
+                    if let Some(progress) = read_progress(&progress_path).await {
+                        progress_model.clone().put(&mut db, &progress).await?;
+                    }
+                }
+            }
+        };
+        let mut progress = read_progress(&progress_path).await.unwrap_or_default();
+        if tokio::fs::metadata(&progress_path).await.is_ok() {
+            tokio::fs::remove_file(&progress_path).await.with_ctx(|_| {
+                (
+                    crate::ErrorKind::Filesystem,
+                    progress_path.display().to_string(),
+                )
+            })?;
+        }
+        let output = res?
             .map_err(|e| eyre!("{}", e.1))
             .with_kind(crate::ErrorKind::Backup)?;
+        if !output.is_null() {
+            tracing::info!("Backup of {}: {}", pkg_id, output);
+        }
+        progress.complete = true;
+        progress_model.put(&mut db, &progress).await?;
         let tor_keys = interfaces
             .tor_keys(&mut ctx.secret_store.acquire().await?, pkg_id)
             .await?
@@ -169,6 +216,7 @@ impl BackupActions {
         volumes: &Volumes,
     ) -> Result<(), Error>
     where
+        Ex: Send,
         for<'a> &'a mut Ex: Executor<'a, Database = Sqlite>,
     {
         let mut volumes = volumes.clone();
@@ -240,7 +288,7 @@ impl BackupActions {
             .get(db, true)
             .await?;
 
-        reconfigure_dependents_with_live_pointers(ctx, db, &entry).await?;
+        reconfigure_dependents_with_live_pointers(ctx, db, secrets, &entry).await?;
 
         Ok(())
     }
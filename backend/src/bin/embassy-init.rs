@@ -8,7 +8,6 @@ use embassy::hostname::get_product_key;
 use embassy::middleware::cors::cors;
 use embassy::middleware::diagnostic::diagnostic;
 use embassy::middleware::encrypt::encrypt;
-#[cfg(feature = "avahi")]
 use embassy::net::mdns::MdnsController;
 use embassy::shutdown::Shutdown;
 use embassy::sound::CHIME;
@@ -27,7 +26,6 @@ fn status_fn(_: i32) -> StatusCode {
 #[instrument]
 async fn setup_or_init(cfg_path: Option<&str>) -> Result<(), Error> {
     if tokio::fs::metadata("/embassy-os/disk.guid").await.is_err() {
-        #[cfg(feature = "avahi")]
         let _mdns = MdnsController::init();
         tokio::fs::write(
             "/etc/nginx/sites-available/default",
@@ -116,7 +114,6 @@ async fn inner_main(cfg_path: Option<&str>) -> Result<Option<Shutdown>, Error> {
             tracing::error!("{}", e.source);
             tracing::debug!("{}", e.source);
             embassy::sound::BEETHOVEN.play().await?;
-            #[cfg(feature = "avahi")]
             let _mdns = MdnsController::init();
             tokio::fs::write(
                 "/etc/nginx/sites-available/default",
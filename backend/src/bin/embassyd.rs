@@ -5,14 +5,22 @@ use color_eyre::eyre::eyre;
 use embassy::context::{DiagnosticContext, RpcContext};
 use embassy::core::rpc_continuations::RequestGuid;
 use embassy::db::subscribe;
+use embassy::install::auto_update::{auto_update_check, AUTO_UPDATE_INTERVAL};
+use embassy::marketplace::{marketplace_refresh, CATALOGUE_MAX_AGE};
 use embassy::middleware::auth::auth;
 use embassy::middleware::cors::cors;
 use embassy::middleware::diagnostic::diagnostic;
-#[cfg(feature = "avahi")]
+use embassy::net::access::{
+    auth_check, interface_grant, interface_login, AUTH_CHECK_PATH, INTERFACE_GRANT_PATH,
+    INTERFACE_LOGIN_PATH,
+};
 use embassy::net::mdns::MdnsController;
-use embassy::net::tor::tor_health_check;
+use embassy::net::ssl::acme::{acme_renewal, ACME_RENEWAL_INTERVAL};
+use embassy::net::ssl::{certificate_expiry_check, CERTIFICATE_EXPIRY_CHECK_INTERVAL};
+use embassy::net::tor::{tor_health_check, tor_status_check, TOR_STATUS_INTERVAL};
 use embassy::shutdown::Shutdown;
 use embassy::util::{daemon, Invoke};
+use embassy::volume::{volume_usage_check, VOLUME_USAGE_INTERVAL};
 use embassy::{static_server, Error, ErrorKind, ResultExt};
 use futures::{FutureExt, TryFutureExt};
 use reqwest::{Client, Proxy};
@@ -137,6 +145,17 @@ async fn inner_main(cfg_path: Option<&str>) -> Result<Option<Shutdown>, Error> {
                                     "/ws/db" => {
                                         Ok(subscribe(ctx, req).await.unwrap_or_else(err_to_500))
                                     }
+                                    path if path.starts_with(AUTH_CHECK_PATH) => {
+                                        Ok(auth_check(ctx, req).await.unwrap_or_else(err_to_500))
+                                    }
+                                    path if path.starts_with(INTERFACE_LOGIN_PATH) => {
+                                        Ok(interface_login(ctx, req)
+                                            .await
+                                            .unwrap_or_else(err_to_500))
+                                    }
+                                    path if path.starts_with(INTERFACE_GRANT_PATH) => {
+                                        Ok(interface_grant(req).await.unwrap_or_else(err_to_500))
+                                    }
                                     path if path.starts_with("/rest/rpc/") => {
                                         match RequestGuid::from(
                                             path.strip_prefix("/rest/rpc/").unwrap(),
@@ -219,6 +238,66 @@ async fn inner_main(cfg_path: Option<&str>) -> Result<Option<Shutdown>, Error> {
             rpc_ctx.shutdown.subscribe(),
         );
 
+        let tor_status_ctx = rpc_ctx.clone();
+        let tor_status_daemon = daemon(
+            move || {
+                let ctx = tor_status_ctx.clone();
+                async move { tor_status_check(&ctx).await }
+            },
+            TOR_STATUS_INTERVAL,
+            rpc_ctx.shutdown.subscribe(),
+        );
+
+        let marketplace_ctx = rpc_ctx.clone();
+        let marketplace_daemon = daemon(
+            move || {
+                let ctx = marketplace_ctx.clone();
+                async move { marketplace_refresh(&ctx).await }
+            },
+            CATALOGUE_MAX_AGE,
+            rpc_ctx.shutdown.subscribe(),
+        );
+
+        let auto_update_ctx = rpc_ctx.clone();
+        let auto_update_daemon = daemon(
+            move || {
+                let ctx = auto_update_ctx.clone();
+                async move { auto_update_check(&ctx).await }
+            },
+            AUTO_UPDATE_INTERVAL,
+            rpc_ctx.shutdown.subscribe(),
+        );
+
+        let acme_ctx = rpc_ctx.clone();
+        let acme_daemon = daemon(
+            move || {
+                let ctx = acme_ctx.clone();
+                async move { acme_renewal(&ctx).await }
+            },
+            ACME_RENEWAL_INTERVAL,
+            rpc_ctx.shutdown.subscribe(),
+        );
+
+        let certificate_expiry_ctx = rpc_ctx.clone();
+        let certificate_expiry_daemon = daemon(
+            move || {
+                let ctx = certificate_expiry_ctx.clone();
+                async move { certificate_expiry_check(&ctx).await }
+            },
+            CERTIFICATE_EXPIRY_CHECK_INTERVAL,
+            rpc_ctx.shutdown.subscribe(),
+        );
+
+        let volume_usage_ctx = rpc_ctx.clone();
+        let volume_usage_daemon = daemon(
+            move || {
+                let ctx = volume_usage_ctx.clone();
+                async move { volume_usage_check(&ctx).await }
+            },
+            VOLUME_USAGE_INTERVAL,
+            rpc_ctx.shutdown.subscribe(),
+        );
+
         embassy::sound::CHIME.play().await?;
 
         futures::try_join!(
@@ -243,6 +322,42 @@ async fn inner_main(cfg_path: Option<&str>) -> Result<Option<Shutdown>, Error> {
                     ErrorKind::Unknown
                 ))
                 .map_ok(|_| tracing::debug!("Tor Health Daemon Shutdown")),
+            tor_status_daemon
+                .map_err(|e| Error::new(
+                    e.wrap_err("Tor Status Daemon panicked!"),
+                    ErrorKind::Unknown
+                ))
+                .map_ok(|_| tracing::debug!("Tor Status Daemon Shutdown")),
+            marketplace_daemon
+                .map_err(|e| Error::new(
+                    e.wrap_err("Marketplace Catalogue Daemon panicked!"),
+                    ErrorKind::Unknown
+                ))
+                .map_ok(|_| tracing::debug!("Marketplace Catalogue Daemon Shutdown")),
+            auto_update_daemon
+                .map_err(|e| Error::new(
+                    e.wrap_err("Auto-Update Daemon panicked!"),
+                    ErrorKind::Unknown
+                ))
+                .map_ok(|_| tracing::debug!("Auto-Update Daemon Shutdown")),
+            acme_daemon
+                .map_err(|e| Error::new(
+                    e.wrap_err("ACME Renewal Daemon panicked!"),
+                    ErrorKind::Unknown
+                ))
+                .map_ok(|_| tracing::debug!("ACME Renewal Daemon Shutdown")),
+            certificate_expiry_daemon
+                .map_err(|e| Error::new(
+                    e.wrap_err("Certificate Expiry Daemon panicked!"),
+                    ErrorKind::Unknown
+                ))
+                .map_ok(|_| tracing::debug!("Certificate Expiry Daemon Shutdown")),
+            volume_usage_daemon
+                .map_err(|e| Error::new(
+                    e.wrap_err("Volume Usage Daemon panicked!"),
+                    ErrorKind::Unknown
+                ))
+                .map_ok(|_| tracing::debug!("Volume Usage Daemon Shutdown")),
         )?;
 
         let mut shutdown = shutdown_recv
@@ -288,7 +403,6 @@ fn main() {
                         tracing::error!("{}", e.source);
                         tracing::debug!("{:?}", e.source);
                         embassy::sound::BEETHOVEN.play().await?;
-                        #[cfg(feature = "avahi")]
                         let _mdns = MdnsController::init();
                         tokio::fs::write(
                             "/etc/nginx/sites-available/default",
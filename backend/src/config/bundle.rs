@@ -0,0 +1,331 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use chrono::{DateTime, Utc};
+use clap::ArgMatches;
+use color_eyre::eyre::eyre;
+use rand::SeedableRng;
+use rpc_toolkit::command;
+use rpc_toolkit::command_helpers::prelude::RequestParts;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::instrument;
+
+use super::action::ConfigRes;
+use super::secret::SECRET_PLACEHOLDER;
+use super::spec::{ConfigSpec, ValueSpecAny};
+use super::{get_config_res, set_impl, Config, Defaultable, SetParams};
+use crate::context::RpcContext;
+use crate::db::model::PackageDataEntry;
+use crate::middleware::auth::HashSessionToken;
+use crate::s9pk::manifest::PackageId;
+use crate::util::serde::{display_serializable, parse_stdin_deserializable, IoFormat};
+use crate::util::Version;
+use crate::Error;
+
+/// Configurations of several packages, portable between nodes.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ConfigBundle {
+    pub created: DateTime<Utc>,
+    pub packages: BTreeMap<PackageId, ConfigBundleEntry>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ConfigBundleEntry {
+    pub version: Version,
+    pub config: Config,
+    /// Dotted paths of pointer values. These are exported as null and resolved again by the
+    /// importing node, since tor and lan addresses differ between nodes.
+    pub pointers: BTreeSet<String>,
+    /// Dotted paths of secrets. These are exported as a placeholder unless secrets were included.
+    pub secrets: BTreeSet<String>,
+}
+
+fn parse_comma_separated(arg: &str, _: &ArgMatches<'_>) -> Result<Vec<PackageId>, Error> {
+    arg.split(",")
+        .map(|s| s.trim().parse().map_err(Error::from))
+        .collect()
+}
+
+/// Nulls the pointers of `config`, and replaces its secrets with a placeholder unless
+/// `include_secrets` is set.
+fn bundle_entry(
+    version: Version,
+    spec: &ConfigSpec,
+    mut config: Config,
+    include_secrets: bool,
+) -> ConfigBundleEntry {
+    let mut pointers = BTreeSet::new();
+    let mut secrets = BTreeSet::new();
+    spec.visit_values(&mut config, &mut |path, spec, value| match spec {
+        ValueSpecAny::Pointer(_) => {
+            pointers.insert(path.to_owned());
+            *value = Value::Null;
+        }
+        ValueSpecAny::Secret(_) => {
+            secrets.insert(path.to_owned());
+            if !include_secrets && !value.is_null() {
+                *value = Value::String(SECRET_PLACEHOLDER.to_owned());
+            }
+        }
+        _ => (),
+    });
+    ConfigBundleEntry {
+        version,
+        config,
+        pointers,
+        secrets,
+    }
+}
+
+/// Bundles the configs of `packages`, or of every installed package if unset.
+#[command(rename = "config-export", display(display_serializable))]
+#[instrument(skip(ctx))]
+pub async fn export(
+    #[context] ctx: RpcContext,
+    #[arg(long = "packages", parse(parse_comma_separated))] packages: Option<Vec<PackageId>>,
+    #[arg(rename = "include-secrets", long = "include-secrets")] include_secrets: bool,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<ConfigBundle, Error> {
+    let installed = crate::db::DatabaseModel::new()
+        .package_data()
+        .get(&mut ctx.db.handle(), false)
+        .await?
+        .0
+        .iter()
+        .filter_map(|(id, pde)| match pde {
+            PackageDataEntry::Installed { installed, .. }
+                if installed.manifest.config.is_some() =>
+            {
+                Some((id.clone(), installed.manifest.version.clone()))
+            }
+            _ => None,
+        })
+        .collect::<BTreeMap<_, _>>();
+    let ids = match packages {
+        Some(ids) => {
+            for id in &ids {
+                if !installed.contains_key(id) {
+                    return Err(Error::new(
+                        eyre!("{} is not installed or has no config", id),
+                        crate::ErrorKind::NotFound,
+                    ));
+                }
+            }
+            ids
+        }
+        None => installed.keys().cloned().collect(),
+    };
+
+    let mut bundle = ConfigBundle {
+        created: Utc::now(),
+        packages: BTreeMap::new(),
+    };
+    for id in ids {
+        let ConfigRes { config, spec } = get_config_res(&ctx, &id).await?;
+        let config = match config {
+            Some(a) => a,
+            None => continue, // not yet configured
+        };
+        bundle.packages.insert(
+            id.clone(),
+            bundle_entry(installed[&id].clone(), &spec, config, include_secrets),
+        );
+    }
+    Ok(bundle)
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "status")]
+#[serde(rename_all = "kebab-case")]
+pub enum ImportResult {
+    Applied { warnings: Vec<String> },
+    Skipped { reason: String },
+    Failed { error: String },
+}
+
+fn display_import(res: BTreeMap<PackageId, ImportResult>, matches: &ArgMatches<'_>) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(res, matches);
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![bc => "PACKAGE", "STATUS", "DETAILS"]);
+    for (id, res) in res {
+        let (status, details) = match res {
+            ImportResult::Applied { warnings } => ("applied", warnings.join("\n")),
+            ImportResult::Skipped { reason } => ("skipped", reason),
+            ImportResult::Failed { error } => ("failed", error),
+        };
+        table.add_row(row![&id.to_string(), status, &details]);
+    }
+    table.print_tty(false);
+}
+
+/// Applies a bundle from `config-export` to the installed packages it contains, reporting what
+/// could not be applied.
+#[command(rename = "config-import", display(display_import))]
+#[instrument(skip(ctx, req, bundle))]
+pub async fn import(
+    #[context] ctx: RpcContext,
+    #[request] req: &RequestParts,
+    #[arg(stdin, parse(parse_stdin_deserializable))] bundle: ConfigBundle,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<BTreeMap<PackageId, ImportResult>, Error> {
+    let session = HashSessionToken::from_request_parts(req)
+        .ok()
+        .map(|s| s.hashed().to_owned());
+    let mut res = BTreeMap::new();
+    for (id, entry) in bundle.packages {
+        let result = match import_package(&ctx, &id, entry, session.clone()).await {
+            Ok(a) => a,
+            Err(e) => ImportResult::Failed {
+                error: e.to_string(),
+            },
+        };
+        res.insert(id, result);
+    }
+    Ok(res)
+}
+
+#[instrument(skip(ctx, entry))]
+async fn import_package(
+    ctx: &RpcContext,
+    id: &PackageId,
+    entry: ConfigBundleEntry,
+    session: Option<String>,
+) -> Result<ImportResult, Error> {
+    let version = match crate::db::DatabaseModel::new()
+        .package_data()
+        .idx_model(id)
+        .and_then(|pde| pde.installed())
+        .map(|i| i.manifest().version())
+        .get(&mut ctx.db.handle(), false)
+        .await?
+        .into_owned()
+    {
+        Some(a) => a,
+        None => {
+            return Ok(ImportResult::Skipped {
+                reason: format!("{} is not installed", id),
+            })
+        }
+    };
+    let mut warnings = Vec::new();
+    if version != entry.version {
+        warnings.push(format!(
+            "exported from {} {}, but {} is installed",
+            id, entry.version, version
+        ));
+    }
+
+    let ConfigRes {
+        config: old_config,
+        spec,
+    } = get_config_res(ctx, id).await?;
+    let mut config = entry.config;
+    for key in config.keys().cloned().collect::<Vec<_>>() {
+        if !spec.fields.contains_key(&key) {
+            warnings.push(format!("{} is not in the config spec and was dropped", key));
+            config.remove(&key);
+        }
+    }
+    let stored_secrets = super::secret::load(
+        &mut ctx.secret_store.acquire().await?,
+        &crate::hostname::get_product_key().await?,
+        id,
+    )
+    .await?;
+    super::secret::unredact(&spec, &mut config, old_config.as_ref(), &stored_secrets);
+    let mut rng = rand::rngs::StdRng::from_entropy();
+    let mut gen_errors = Vec::new();
+    spec.visit_values(&mut config, &mut |path, spec, value| {
+        if let ValueSpecAny::Secret(_) = spec {
+            if value.as_str() == Some(SECRET_PLACEHOLDER) {
+                match spec.gen(&mut rng, &None) {
+                    Ok(Value::Null) => {
+                        gen_errors.push(format!("secret {} was not exported and must be set", path))
+                    }
+                    Ok(v) => {
+                        warnings.push(format!(
+                            "secret {} was not exported and was regenerated",
+                            path
+                        ));
+                        *value = v;
+                    }
+                    Err(e) => gen_errors.push(format!("secret {}: {}", path, e)),
+                }
+            }
+        }
+    });
+    if !gen_errors.is_empty() {
+        return Ok(ImportResult::Skipped {
+            reason: gen_errors.join("\n"),
+        });
+    }
+    if let Err(e) = spec.matches(&config) {
+        return Ok(ImportResult::Skipped {
+            reason: e.to_string(),
+        });
+    }
+
+    set_impl(
+        ctx.clone(),
+        SetParams {
+            id: id.clone(),
+            config: Some(config),
+            timeout: None,
+            expire_id: None,
+            session,
+        },
+    )
+    .await?;
+    Ok(ImportResult::Applied { warnings })
+}
+
+#[test]
+fn test_bundle_entry_secrets_in_lists() {
+    let spec: ConfigSpec = serde_json::from_value(serde_json::json!({
+        "peers": {
+            "type": "list",
+            "subtype": "object",
+            "name": "Peers",
+            "range": "[0,*)",
+            "default": [],
+            "spec": {
+                "spec": {
+                    "key": {
+                        "type": "secret",
+                        "name": "Key",
+                        "nullable": true,
+                        "default": null,
+                    },
+                },
+            },
+        },
+    }))
+    .unwrap();
+    let config: Config = serde_json::from_value(serde_json::json!({
+        "peers": [{ "key": "a" }, { "key": null }],
+    }))
+    .unwrap();
+    let version: Version = "0.1.0".parse().unwrap();
+    let entry = bundle_entry(version.clone(), &spec, config.clone(), false);
+    assert_eq!(entry.config["peers"][0]["key"], SECRET_PLACEHOLDER);
+    assert_eq!(entry.config["peers"][1]["key"], Value::Null);
+    assert_eq!(
+        entry.secrets,
+        vec!["peers.0.key".to_owned(), "peers.1.key".to_owned()]
+            .into_iter()
+            .collect()
+    );
+    let entry = bundle_entry(version, &spec, config.clone(), true);
+    assert_eq!(entry.config, config);
+}
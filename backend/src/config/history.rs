@@ -0,0 +1,181 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{Executor, Row, Sqlite};
+use tracing::instrument;
+
+use super::{Config, ConfigSpec};
+use crate::s9pk::manifest::PackageId;
+use crate::{Error, ResultExt};
+
+/// Number of configurations kept per package.
+pub const CONFIG_HISTORY_RETENTION: i64 = 50;
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct ConfigChange {
+    pub path: String,
+    pub old: Value,
+    pub new: Value,
+}
+
+/// The changes from `old` to `new`, keyed by dotted path.
+pub fn diff(old: &Value, new: &Value) -> Vec<ConfigChange> {
+    let mut res = Vec::new();
+    diff_at("", old, new, &mut res);
+    res
+}
+
+fn diff_at(path: &str, old: &Value, new: &Value, res: &mut Vec<ConfigChange>) {
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            for (key, old_val) in old {
+                let path = join(path, key);
+                diff_at(&path, old_val, new.get(key).unwrap_or(&Value::Null), res);
+            }
+            for (key, new_val) in new.iter().filter(|(k, _)| !old.contains_key(*k)) {
+                diff_at(&join(path, key), &Value::Null, new_val, res);
+            }
+        }
+        (old, new) if old != new => res.push(ConfigChange {
+            path: path.to_owned(),
+            old: old.clone(),
+            new: new.clone(),
+        }),
+        _ => (),
+    }
+}
+
+fn join(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() {
+        key.to_owned()
+    } else {
+        format!("{}.{}", prefix, key)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ConfigHistoryEntry {
+    pub revision: i64,
+    pub created: DateTime<Utc>,
+    pub session: Option<String>,
+    pub config: Config,
+    pub diff: Vec<ConfigChange>,
+}
+
+/// Appends `config` to the history of `pkg_id`, pruning entries beyond
+/// [`CONFIG_HISTORY_RETENTION`]. Secrets are redacted, so reverting keeps their current values.
+#[instrument(skip(secrets, spec, old_config, config))]
+pub async fn record<Ex>(
+    secrets: &mut Ex,
+    pkg_id: &PackageId,
+    session: Option<&str>,
+    spec: &ConfigSpec,
+    old_config: Option<&Config>,
+    config: &Config,
+) -> Result<i64, Error>
+where
+    for<'a> &'a mut Ex: Executor<'a, Database = Sqlite>,
+{
+    let mut config = config.clone();
+    super::secret::redact(spec, &mut config);
+    let mut old_config = old_config.cloned().unwrap_or_default();
+    super::secret::redact(spec, &mut old_config);
+    let diff = diff(&Value::Object(old_config), &Value::Object(config.clone()));
+    let revision: i64 = sqlx::query(
+        "SELECT COALESCE(MAX(revision), 0) + 1 AS revision FROM config_history WHERE package = ?",
+    )
+    .bind(pkg_id.as_str())
+    .fetch_one(&mut *secrets)
+    .await?
+    .try_get("revision")?;
+    sqlx::query(
+        "INSERT INTO config_history (package, revision, created_at, session, config, diff) VALUES (?, ?, ?, ?, ?, ?)",
+    )
+    .bind(pkg_id.as_str())
+    .bind(revision)
+    .bind(Utc::now().to_rfc3339())
+    .bind(session)
+    .bind(serde_json::to_string(&config).with_kind(crate::ErrorKind::Serialization)?)
+    .bind(serde_json::to_string(&diff).with_kind(crate::ErrorKind::Serialization)?)
+    .execute(&mut *secrets)
+    .await?;
+    sqlx::query("DELETE FROM config_history WHERE package = ? AND revision <= ?")
+        .bind(pkg_id.as_str())
+        .bind(revision - CONFIG_HISTORY_RETENTION)
+        .execute(&mut *secrets)
+        .await?;
+    Ok(revision)
+}
+
+/// The history of `pkg_id`, newest first.
+#[instrument(skip(secrets))]
+pub async fn list<Ex>(
+    secrets: &mut Ex,
+    pkg_id: &PackageId,
+) -> Result<Vec<ConfigHistoryEntry>, Error>
+where
+    for<'a> &'a mut Ex: Executor<'a, Database = Sqlite>,
+{
+    sqlx::query(
+        "SELECT revision, created_at, session, config, diff FROM config_history WHERE package = ? ORDER BY revision DESC",
+    )
+    .bind(pkg_id.as_str())
+    .fetch_all(&mut *secrets)
+    .await?
+    .into_iter()
+    .map(|row| -> Result<_, Error> {
+        Ok(ConfigHistoryEntry {
+            revision: row.try_get("revision")?,
+            created: DateTime::parse_from_rfc3339(&row.try_get::<String, _>("created_at")?)
+                .with_kind(crate::ErrorKind::Deserialization)?
+                .with_timezone(&Utc),
+            session: row.try_get("session")?,
+            config: serde_json::from_str(&row.try_get::<String, _>("config")?)
+                .with_kind(crate::ErrorKind::Deserialization)?,
+            diff: serde_json::from_str(&row.try_get::<String, _>("diff")?)
+                .with_kind(crate::ErrorKind::Deserialization)?,
+        })
+    })
+    .collect()
+}
+
+/// Removes the history of `pkg_id`.
+#[instrument(skip(secrets))]
+pub async fn remove<Ex>(secrets: &mut Ex, pkg_id: &PackageId) -> Result<(), Error>
+where
+    for<'a> &'a mut Ex: Executor<'a, Database = Sqlite>,
+{
+    sqlx::query("DELETE FROM config_history WHERE package = ?")
+        .bind(pkg_id.as_str())
+        .execute(&mut *secrets)
+        .await?;
+    Ok(())
+}
+
+#[test]
+fn test_diff() {
+    let old = serde_json::json!({ "a": 1, "b": { "c": "x", "d": true } });
+    let new = serde_json::json!({ "a": 1, "b": { "c": "y" }, "e": [1] });
+    assert_eq!(
+        diff(&old, &new),
+        vec![
+            ConfigChange {
+                path: "b.c".to_owned(),
+                old: "x".into(),
+                new: "y".into(),
+            },
+            ConfigChange {
+                path: "b.d".to_owned(),
+                old: true.into(),
+                new: Value::Null,
+            },
+            ConfigChange {
+                path: "e".to_owned(),
+                old: Value::Null,
+                new: serde_json::json!([1]),
+            },
+        ]
+    );
+}
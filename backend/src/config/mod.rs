@@ -1,19 +1,27 @@
 use std::collections::{BTreeMap, BTreeSet};
 use std::path::PathBuf;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use clap::ArgMatches;
 use color_eyre::eyre::eyre;
 use futures::future::{BoxFuture, FutureExt};
+use futures::StreamExt;
+use http::{Request, Response, StatusCode};
+use hyper::Body;
 use indexmap::IndexSet;
 use itertools::Itertools;
 use patch_db::{DbHandle, LockType};
 use rand::SeedableRng;
 use regex::Regex;
 use rpc_toolkit::command;
+use rpc_toolkit::command_helpers::prelude::RequestParts;
 use serde_json::Value;
+use sqlx::{Executor, Sqlite};
+use tokio::io::AsyncWriteExt;
 use tracing::instrument;
 
 use crate::context::RpcContext;
+use crate::core::rpc_continuations::{RequestGuid, RpcContinuation};
 use crate::db::model::CurrentDependencyInfo;
 use crate::db::util::WithRevision;
 use crate::dependencies::{
@@ -21,12 +29,18 @@ use crate::dependencies::{
     BreakageRes, DependencyError, DependencyErrors, TaggedDependencyError,
 };
 use crate::install::cleanup::remove_from_current_dependents_lists;
+use crate::middleware::auth::HashSessionToken;
 use crate::s9pk::manifest::{Manifest, PackageId};
 use crate::util::display_none;
 use crate::util::serde::{display_serializable, parse_stdin_deserializable, IoFormat};
+use crate::volume::files::VolumeDir;
 use crate::{Error, ResultExt as _};
 
 pub mod action;
+pub mod bundle;
+pub mod history;
+pub mod rules;
+pub mod secret;
 pub mod spec;
 pub mod util;
 
@@ -34,7 +48,7 @@ pub use spec::{ConfigSpec, Defaultable};
 use util::NumRange;
 
 use self::action::ConfigRes;
-use self::spec::{PackagePointerSpec, ValueSpecPointer};
+use self::spec::{PackagePointerSpec, ValueSpecPointer, DEFAULT_MAX_FILE_SIZE};
 
 pub type Config = serde_json::Map<String, Value>;
 pub trait TypeOf {
@@ -136,6 +150,26 @@ pub enum MatchError {
     InvalidKey(String),
     #[error("Value In List Is Not Unique")]
     ListUniquenessViolation,
+    #[error("Rule Violated: {0}")]
+    RuleViolation(String),
+    #[error("Secret Does Not Match Pattern {0}")]
+    SecretPattern(Regex),
+    #[error("Invalid File Name {0:?}")]
+    InvalidFileName(String),
+    #[error("File {0:?} Does Not Have One Of The Extensions {1:?}")]
+    FileExtension(String, Vec<String>),
+    #[error("Volume {0} Is Not A Data Volume")]
+    InvalidVolume(String),
+    #[error("Invalid Duration {0:?}: {1}")]
+    InvalidDuration(String, String),
+    #[error("Duration {0:?} Is Not In Units {1:?}")]
+    DurationUnit(String, Vec<String>),
+    #[error("Duration Out Of Range: expected {1}, actual: {0}")]
+    DurationOutOfRange(String, String),
+    #[error("Invalid Datetime {0:?}: {1}")]
+    InvalidDatetime(String, String),
+    #[error("Datetime Out Of Range: expected {1}, actual: {0}")]
+    DatetimeOutOfRange(String, String),
 }
 
 #[command(rename = "config-spec", cli_only, blocking, display(display_none))]
@@ -158,24 +192,18 @@ pub fn verify_spec(#[arg] path: PathBuf) -> Result<(), Error> {
     Ok(())
 }
 
-#[command(subcommands(get, set))]
+#[command(subcommands(get, set, upload, history, revert))]
 pub fn config(#[arg] id: PackageId) -> Result<PackageId, Error> {
     Ok(id)
 }
 
-#[command(display(display_serializable))]
+/// Runs the config get action of `id`. Secrets are returned in plaintext.
 #[instrument(skip(ctx))]
-pub async fn get(
-    #[context] ctx: RpcContext,
-    #[parent_data] id: PackageId,
-    #[allow(unused_variables)]
-    #[arg(long = "format")]
-    format: Option<IoFormat>,
-) -> Result<ConfigRes, Error> {
+pub async fn get_config_res(ctx: &RpcContext, id: &PackageId) -> Result<ConfigRes, Error> {
     let mut db = ctx.db.handle();
     let pkg_model = crate::db::DatabaseModel::new()
         .package_data()
-        .idx_model(&id)
+        .idx_model(id)
         .and_then(|m| m.installed())
         .expect(&mut db)
         .await
@@ -195,15 +223,117 @@ pub async fn get(
         .get(&mut db, true)
         .await?;
     let volumes = pkg_model.manifest().volumes().get(&mut db, true).await?;
-    action.get(&ctx, &id, &*version, &*volumes).await
+    action.get(ctx, id, &*version, &*volumes).await
+}
+
+#[command(display(display_serializable))]
+#[instrument(skip(ctx))]
+pub async fn get(
+    #[context] ctx: RpcContext,
+    #[parent_data] id: PackageId,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<ConfigRes, Error> {
+    let mut res = get_config_res(&ctx, &id).await?;
+    if let Some(config) = &mut res.config {
+        secret::redact(&res.spec, config);
+    }
+    Ok(res)
+}
+
+/// Accepts a file for a `file` field of the config. The file body is streamed to the returned
+/// guid, and the response body is the value to set the field to.
+#[command(rpc_only)]
+#[instrument(skip(ctx))]
+pub async fn upload(
+    #[context] ctx: RpcContext,
+    #[parent_data] id: PackageId,
+    #[arg] field: String,
+    #[arg] filename: String,
+) -> Result<RequestGuid, Error> {
+    let ConfigRes { spec, .. } = get_config_res(&ctx, &id).await?;
+    let file_spec = spec.file_spec(&field).ok_or_else(|| {
+        Error::new(
+            eyre!("{} is not a file in the config of {}", field, id),
+            crate::ErrorKind::InvalidRequest,
+        )
+    })?;
+    file_spec
+        .check_file_name(&filename)
+        .map_err(|e| Error::new(e, crate::ErrorKind::InvalidRequest))?;
+    let root = file_spec.volume_dir(&ctx, &id);
+    let directory = file_spec.directory.clone();
+    let max_size = file_spec.max_size.unwrap_or(DEFAULT_MAX_FILE_SIZE);
+
+    let guid = RequestGuid::new();
+    let handler = Box::new(move |req: Request<Body>| {
+        async move {
+            // the volume belongs to the package, so nothing in it may redirect the write
+            let tmp = format!(".{}.{:016x}.tmp", filename, rand::random::<u64>());
+            let (dir, file) = tokio::task::spawn_blocking({
+                let tmp = tmp.clone();
+                move || {
+                    let dir = VolumeDir::open(&root, &directory)?;
+                    let file = dir.create(&tmp)?;
+                    Ok::<_, Error>((dir, file))
+                }
+            })
+            .await
+            .with_kind(crate::ErrorKind::Unknown)??;
+            let res = async {
+                let mut file = tokio::fs::File::from_std(file);
+                let mut body = req.into_body();
+                let mut size = 0;
+                while let Some(chunk) = body.next().await {
+                    let chunk = chunk.with_kind(crate::ErrorKind::Network)?;
+                    size += chunk.len() as u64;
+                    if size > max_size {
+                        return Ok(false);
+                    }
+                    file.write_all(&chunk).await?;
+                }
+                file.sync_all().await?;
+                dir.replace(&tmp, &filename)?;
+                Ok::<_, Error>(true)
+            }
+            .await;
+            match res {
+                Ok(true) => (),
+                Ok(false) => {
+                    dir.remove(&tmp);
+                    return Response::builder()
+                        .status(StatusCode::PAYLOAD_TOO_LARGE)
+                        .body(Body::from("File Too Large"))
+                        .with_kind(crate::ErrorKind::Network);
+                }
+                Err(e) => {
+                    dir.remove(&tmp);
+                    return Err(e);
+                }
+            }
+            Response::builder()
+                .status(StatusCode::OK)
+                .body(Body::from(filename))
+                .with_kind(crate::ErrorKind::Network)
+        }
+        .boxed()
+    });
+    let cont = RpcContinuation {
+        created_at: Instant::now(),
+        handler: handler,
+    };
+    ctx.add_continuation(guid.clone(), cont).await;
+    Ok(guid)
 }
 
 #[command(
     subcommands(self(set_impl(async, context(RpcContext))), set_dry),
     display(display_none)
 )]
-#[instrument]
+#[instrument(skip(req))]
 pub fn set(
+    #[request] req: &RequestParts,
     #[parent_data] id: PackageId,
     #[allow(unused_variables)]
     #[arg(long = "format")]
@@ -211,20 +341,38 @@ pub fn set(
     #[arg(long = "timeout")] timeout: Option<crate::util::serde::Duration>,
     #[arg(stdin, parse(parse_stdin_deserializable))] config: Option<Config>,
     #[arg(rename = "expire-id", long = "expire-id")] expire_id: Option<String>,
-) -> Result<(PackageId, Option<Config>, Option<Duration>, Option<String>), Error> {
-    Ok((id, config, timeout.map(|d| *d), expire_id))
+) -> Result<SetParams, Error> {
+    Ok(SetParams {
+        id,
+        config,
+        timeout: timeout.map(|d| *d),
+        expire_id,
+        session: HashSessionToken::from_request_parts(req)
+            .ok()
+            .map(|s| s.hashed().to_owned()),
+    })
+}
+
+#[derive(Clone, Debug)]
+pub struct SetParams {
+    pub id: PackageId,
+    pub config: Option<Config>,
+    pub timeout: Option<Duration>,
+    pub expire_id: Option<String>,
+    /// Hash of the session token the change was made with, recorded in the config history.
+    pub session: Option<String>,
 }
 
 #[command(rename = "dry", display(display_serializable))]
 #[instrument(skip(ctx))]
 pub async fn set_dry(
     #[context] ctx: RpcContext,
-    #[parent_data] (id, config, timeout, _): (
-        PackageId,
-        Option<Config>,
-        Option<Duration>,
-        Option<String>,
-    ),
+    #[parent_data] SetParams {
+        id,
+        config,
+        timeout,
+        ..
+    }: SetParams,
 ) -> Result<BreakageRes, Error> {
     let mut db = ctx.db.handle();
     let mut tx = db.begin().await?;
@@ -232,10 +380,12 @@ pub async fn set_dry(
     configure(
         &ctx,
         &mut tx,
+        &mut ctx.secret_store.acquire().await?,
         &id,
         config,
         &timeout,
         true,
+        None,
         &mut BTreeMap::new(),
         &mut breakages,
     )
@@ -259,40 +409,159 @@ pub async fn set_dry(
 #[instrument(skip(ctx))]
 pub async fn set_impl(
     ctx: RpcContext,
-    (id, config, timeout, expire_id): (PackageId, Option<Config>, Option<Duration>, Option<String>),
+    SetParams {
+        id,
+        config,
+        timeout,
+        expire_id,
+        session,
+    }: SetParams,
 ) -> Result<WithRevision<()>, Error> {
     let mut db = ctx.db.handle();
+    let installed = crate::db::DatabaseModel::new()
+        .package_data()
+        .idx_model(&id)
+        .and_then(|m| m.installed())
+        .expect(&mut db)
+        .await
+        .with_kind(crate::ErrorKind::NotFound)?;
+    let version = installed
+        .clone()
+        .manifest()
+        .version()
+        .get(&mut db, true)
+        .await?
+        .to_owned();
+    // the volumes of a running package may be mid-write, so only stopped packages are snapshotted
+    if installed
+        .status()
+        .main()
+        .get(&mut db, true)
+        .await?
+        .running()
+    {
+        tracing::info!("Not snapshotting {} before config set: it is running", id);
+    } else {
+        crate::snapshot::try_create(&ctx, &id, &version, "config set").await;
+    }
     let mut tx = db.begin().await?;
+    let mut sql_tx = ctx.secret_store.begin().await?;
     let mut breakages = BTreeMap::new();
     configure(
         &ctx,
         &mut tx,
+        &mut sql_tx,
         &id,
         config,
         &timeout,
         false,
+        session.as_deref(),
         &mut BTreeMap::new(),
         &mut breakages,
     )
     .await?;
+    let revision = tx.commit(expire_id).await?;
+    sql_tx.commit().await?;
     Ok(WithRevision {
         response: (),
-        revision: tx.commit(expire_id).await?,
+        revision,
     })
 }
 
-#[instrument(skip(ctx, db))]
-pub async fn configure<Db: DbHandle>(
+fn display_history(entries: Vec<history::ConfigHistoryEntry>, matches: &ArgMatches<'_>) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(entries, matches);
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![bc => "REVISION", "CREATED", "SESSION", "CHANGES"]);
+    for entry in entries {
+        table.add_row(row![
+            &format!("{}", entry.revision),
+            &format!("{}", entry.created),
+            entry.session.as_deref().unwrap_or("N/A"),
+            &entry.diff.iter().map(|c| c.path.as_str()).join(", "),
+        ]);
+    }
+    table.print_tty(false);
+}
+
+#[command(display(display_history))]
+#[instrument(skip(ctx))]
+pub async fn history(
+    #[context] ctx: RpcContext,
+    #[parent_data] id: PackageId,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<history::ConfigHistoryEntry>, Error> {
+    history::list(&mut ctx.secret_store.acquire().await?, &id).await
+}
+
+/// Reapplies a configuration from the history. Secrets keep their current values, those in lists
+/// the values of the same element, so this fails if a secret of the revision is no longer stored.
+#[command(display(display_none))]
+#[instrument(skip(ctx, req))]
+pub async fn revert(
+    #[context] ctx: RpcContext,
+    #[request] req: &RequestParts,
+    #[parent_data] id: PackageId,
+    #[arg] revision: i64,
+) -> Result<WithRevision<()>, Error> {
+    let entry = history::list(&mut ctx.secret_store.acquire().await?, &id)
+        .await?
+        .into_iter()
+        .find(|e| e.revision == revision)
+        .ok_or_else(|| {
+            Error::new(
+                eyre!(
+                    "Revision {} of the config of {} does not exist",
+                    revision,
+                    id
+                ),
+                crate::ErrorKind::NotFound,
+            )
+        })?;
+    set_impl(
+        ctx,
+        SetParams {
+            id,
+            config: Some(entry.config),
+            timeout: None,
+            expire_id: None,
+            session: HashSessionToken::from_request_parts(req)
+                .ok()
+                .map(|s| s.hashed().to_owned()),
+        },
+    )
+    .await
+}
+
+/// Writes the secrets and history of the new config through `secrets`, which should be committed
+/// along with `db`.
+#[instrument(skip(ctx, db, secrets))]
+pub async fn configure<Db: DbHandle, Ex>(
     ctx: &RpcContext,
     db: &mut Db,
+    secrets: &mut Ex,
     id: &PackageId,
     config: Option<Config>,
     timeout: &Option<Duration>,
     dry_run: bool,
+    session: Option<&str>,
     overrides: &mut BTreeMap<PackageId, Config>,
     breakages: &mut BTreeMap<PackageId, TaggedDependencyError>,
-) -> Result<(), Error> {
-    configure_rec(ctx, db, id, config, timeout, dry_run, overrides, breakages).await?;
+) -> Result<(), Error>
+where
+    Ex: Send,
+    for<'a> &'a mut Ex: Executor<'a, Database = Sqlite>,
+{
+    configure_rec(
+        ctx, db, secrets, id, config, timeout, dry_run, session, overrides, breakages,
+    )
+    .await?;
     crate::db::DatabaseModel::new()
         .package_data()
         .idx_model(&id)
@@ -308,17 +577,23 @@ pub async fn configure<Db: DbHandle>(
     Ok(())
 }
 
-#[instrument(skip(ctx, db))]
-pub fn configure_rec<'a, Db: DbHandle>(
+#[instrument(skip(ctx, db, secrets))]
+pub fn configure_rec<'a, Db: DbHandle, Ex>(
     ctx: &'a RpcContext,
     db: &'a mut Db,
+    secrets: &'a mut Ex,
     id: &'a PackageId,
     config: Option<Config>,
     timeout: &'a Option<Duration>,
     dry_run: bool,
+    session: Option<&'a str>,
     overrides: &'a mut BTreeMap<PackageId, Config>,
     breakages: &'a mut BTreeMap<PackageId, TaggedDependencyError>,
-) -> BoxFuture<'a, Result<(), Error>> {
+) -> BoxFuture<'a, Result<(), Error>>
+where
+    Ex: Send,
+    for<'b> &'b mut Ex: Executor<'b, Database = Sqlite>,
+{
     async move {
         crate::db::DatabaseModel::new()
             .package_data()
@@ -361,6 +636,15 @@ pub fn configure_rec<'a, Db: DbHandle>(
         } else {
             spec.gen(&mut rand::rngs::StdRng::from_entropy(), timeout)?
         };
+        let stored_secrets =
+            secret::load(secrets, &crate::hostname::get_product_key().await?, id).await?;
+        let missing = secret::unredact(&spec, &mut config, old_config.as_ref(), &stored_secrets);
+        crate::ensure_code!(
+            missing.is_empty(),
+            crate::ErrorKind::ConfigSpecViolation,
+            "No stored value for secrets {}: they must be set explicitly",
+            missing.join(", ")
+        );
 
         let manifest = crate::db::DatabaseModel::new()
             .package_data()
@@ -419,6 +703,15 @@ pub fn configure_rec<'a, Db: DbHandle>(
             let res = action
                 .set(ctx, id, &*version, &*dependencies, &*volumes, &config)
                 .await?;
+            secret::save(
+                secrets,
+                &crate::hostname::get_product_key().await?,
+                id,
+                &spec,
+                &config,
+            )
+            .await?;
+            history::record(secrets, id, session, &spec, old_config.as_ref(), &config).await?;
 
             // track dependencies with no pointers
             for (package_id, health_checks) in res.depends_on.into_iter() {
@@ -514,7 +807,8 @@ pub fn configure_rec<'a, Db: DbHandle>(
                     if let PackagePointerSpec::Config(cfg_ptr) = ptr {
                         if cfg_ptr.select(&next) != cfg_ptr.select(&prev) {
                             if let Err(e) = configure_rec(
-                                ctx, db, dependent, None, timeout, dry_run, overrides, breakages,
+                                ctx, db, secrets, dependent, None, timeout, dry_run, session,
+                                overrides, breakages,
                             )
                             .await
                             {
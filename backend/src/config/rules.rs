@@ -0,0 +1,565 @@
+//! Native evaluation of config rules and `visible-if` conditions.
+//!
+//! Rules are boolean expressions over the values of a config object, using a subset of the syntax
+//! of the rules of the `compat` image:
+//!
+//! - `path?` is true if the boolean at `path` is true
+//! - `#path` is the number at `path`, `'path` the string at `path`
+//! - numbers and strings can be compared with `<`, `<=`, `=`, `!=`, `>`, `>=`
+//! - numbers support `+ - * / ^`, strings support concatenation with `+`
+//! - boolean terms are combined with `AND`, `OR`, `XOR` and negated with `!( ... )`
+//!
+//! Paths are relative to the object the rule is declared on, e.g. `rpc.enable?` or
+//! `#peers.0.port`. A missing value is false as a boolean, and makes any comparison it is part of
+//! false (except `!=` between strings).
+//!
+//! Package references (`[app]`), list wildcards (`*`, `&`) and the list functions `first`, `last`,
+//! `any` and `all` are not supported. Rules using them fail to parse with an error naming them.
+use nom::branch::alt;
+use nom::bytes::complete::{escaped_transform, is_not, tag};
+use nom::character::complete::{char, digit1, satisfy, space0};
+use nom::combinator::{all_consuming, map, map_res, opt, recognize, value};
+use nom::multi::many0;
+use nom::number::complete::double;
+use nom::sequence::{delimited, pair, preceded, tuple};
+use nom::IResult;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+
+use super::{Config, MatchError, NoMatchWithPath};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PathSegment {
+    Key(String),
+    Index(usize),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ValuePath(Vec<PathSegment>);
+impl ValuePath {
+    fn resolve<'a>(&self, cfg: &'a Config) -> Option<&'a Value> {
+        let mut segments = self.0.iter();
+        let mut current = match segments.next()? {
+            PathSegment::Key(key) => cfg.get(key)?,
+            PathSegment::Index(_) => return None,
+        };
+        for segment in segments {
+            current = match (segment, current) {
+                (PathSegment::Key(key), Value::Object(o)) => o.get(key)?,
+                (PathSegment::Index(idx), Value::Array(a)) => a.get(*idx)?,
+                _ => return None,
+            };
+        }
+        Some(current)
+    }
+    fn keys(&self) -> Vec<String> {
+        self.0
+            .iter()
+            .map(|segment| match segment {
+                PathSegment::Key(key) => key.clone(),
+                PathSegment::Index(idx) => idx.to_string(),
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BoolOp {
+    And,
+    Or,
+    Xor,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CmpOp {
+    Lt,
+    Lte,
+    Eq,
+    Neq,
+    Gt,
+    Gte,
+}
+impl CmpOp {
+    fn apply<T: PartialOrd>(self, lhs: T, rhs: T) -> bool {
+        match self {
+            CmpOp::Lt => lhs < rhs,
+            CmpOp::Lte => lhs <= rhs,
+            CmpOp::Eq => lhs == rhs,
+            CmpOp::Neq => lhs != rhs,
+            CmpOp::Gt => lhs > rhs,
+            CmpOp::Gte => lhs >= rhs,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NumOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Pow,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum NumExpr {
+    Literal(f64),
+    Var(ValuePath),
+    Op(Box<NumExpr>, NumOp, Box<NumExpr>),
+}
+impl NumExpr {
+    fn eval(&self, cfg: &Config) -> Option<f64> {
+        match self {
+            NumExpr::Literal(n) => Some(*n),
+            NumExpr::Var(path) => path.resolve(cfg).and_then(|v| v.as_f64()),
+            NumExpr::Op(lhs, op, rhs) => {
+                let (lhs, rhs) = (lhs.eval(cfg)?, rhs.eval(cfg)?);
+                Some(match op {
+                    NumOp::Add => lhs + rhs,
+                    NumOp::Sub => lhs - rhs,
+                    NumOp::Mul => lhs * rhs,
+                    NumOp::Div => lhs / rhs,
+                    NumOp::Pow => lhs.powf(rhs),
+                })
+            }
+        }
+    }
+    fn paths<'a>(&'a self, res: &mut Vec<&'a ValuePath>) {
+        match self {
+            NumExpr::Literal(_) => (),
+            NumExpr::Var(path) => res.push(path),
+            NumExpr::Op(lhs, _, rhs) => {
+                lhs.paths(res);
+                rhs.paths(res);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum StrExpr {
+    Literal(String),
+    Var(ValuePath),
+    Concat(Box<StrExpr>, Box<StrExpr>),
+}
+impl StrExpr {
+    fn eval(&self, cfg: &Config) -> Option<String> {
+        match self {
+            StrExpr::Literal(s) => Some(s.clone()),
+            StrExpr::Var(path) => path
+                .resolve(cfg)
+                .and_then(|v| v.as_str())
+                .map(|s| s.to_owned()),
+            StrExpr::Concat(lhs, rhs) => Some(lhs.eval(cfg)? + &rhs.eval(cfg)?),
+        }
+    }
+    fn paths<'a>(&'a self, res: &mut Vec<&'a ValuePath>) {
+        match self {
+            StrExpr::Literal(_) => (),
+            StrExpr::Var(path) => res.push(path),
+            StrExpr::Concat(lhs, rhs) => {
+                lhs.paths(res);
+                rhs.paths(res);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum BoolExpr {
+    Var(ValuePath),
+    Not(Box<BoolExpr>),
+    Op(Box<BoolExpr>, BoolOp, Box<BoolExpr>),
+    NumCmp(NumExpr, CmpOp, NumExpr),
+    StrCmp(StrExpr, CmpOp, StrExpr),
+}
+impl BoolExpr {
+    pub fn eval(&self, cfg: &Config) -> bool {
+        match self {
+            BoolExpr::Var(path) => path.resolve(cfg).and_then(|v| v.as_bool()).unwrap_or(false),
+            BoolExpr::Not(expr) => !expr.eval(cfg),
+            BoolExpr::Op(lhs, BoolOp::And, rhs) => lhs.eval(cfg) && rhs.eval(cfg),
+            BoolExpr::Op(lhs, BoolOp::Or, rhs) => lhs.eval(cfg) || rhs.eval(cfg),
+            BoolExpr::Op(lhs, BoolOp::Xor, rhs) => lhs.eval(cfg) ^ rhs.eval(cfg),
+            BoolExpr::NumCmp(lhs, op, rhs) => match (lhs.eval(cfg), rhs.eval(cfg)) {
+                (Some(lhs), Some(rhs)) => op.apply(lhs, rhs),
+                _ => false,
+            },
+            BoolExpr::StrCmp(lhs, op, rhs) => match (lhs.eval(cfg), rhs.eval(cfg), op) {
+                (Some(lhs), Some(rhs), _) => op.apply(lhs, rhs),
+                (lhs, rhs, CmpOp::Neq) => lhs != rhs,
+                _ => false,
+            },
+        }
+    }
+    fn paths<'a>(&'a self, res: &mut Vec<&'a ValuePath>) {
+        match self {
+            BoolExpr::Var(path) => res.push(path),
+            BoolExpr::Not(expr) => expr.paths(res),
+            BoolExpr::Op(lhs, _, rhs) => {
+                lhs.paths(res);
+                rhs.paths(res);
+            }
+            BoolExpr::NumCmp(lhs, _, rhs) => {
+                lhs.paths(res);
+                rhs.paths(res);
+            }
+            BoolExpr::StrCmp(lhs, _, rhs) => {
+                lhs.paths(res);
+                rhs.paths(res);
+            }
+        }
+    }
+}
+
+fn ws<'a, O, F: FnMut(&'a str) -> IResult<&'a str, O>>(
+    f: F,
+) -> impl FnMut(&'a str) -> IResult<&'a str, O> {
+    delimited(space0, f, space0)
+}
+
+fn ident(i: &str) -> IResult<&str, PathSegment> {
+    map(
+        recognize(pair(
+            satisfy(|c| c.is_ascii_alphabetic()),
+            many0(satisfy(|c| c.is_ascii_alphanumeric() || c == '-')),
+        )),
+        |s: &str| PathSegment::Key(s.to_owned()),
+    )(i)
+}
+
+fn path(i: &str) -> IResult<&str, ValuePath> {
+    map(
+        pair(
+            ident,
+            many0(preceded(
+                char('.'),
+                alt((
+                    ident,
+                    map(map_res(digit1, |s: &str| s.parse()), PathSegment::Index),
+                )),
+            )),
+        ),
+        |(first, mut rest)| {
+            rest.insert(0, first);
+            ValuePath(rest)
+        },
+    )(i)
+}
+
+fn cmp_op(i: &str) -> IResult<&str, CmpOp> {
+    alt((
+        value(CmpOp::Lte, tag("<=")),
+        value(CmpOp::Gte, tag(">=")),
+        value(CmpOp::Neq, tag("!=")),
+        value(CmpOp::Lt, tag("<")),
+        value(CmpOp::Gt, tag(">")),
+        value(CmpOp::Eq, tag("=")),
+    ))(i)
+}
+
+fn num_atom(i: &str) -> IResult<&str, NumExpr> {
+    ws(alt((
+        map(preceded(char('#'), path), NumExpr::Var),
+        delimited(char('('), num_expr, char(')')),
+        map(double, NumExpr::Literal),
+    )))(i)
+}
+
+fn num_pow(i: &str) -> IResult<&str, NumExpr> {
+    let (i, base) = num_atom(i)?;
+    match opt(preceded(char('^'), num_pow))(i)? {
+        (i, Some(exp)) => Ok((i, NumExpr::Op(Box::new(base), NumOp::Pow, Box::new(exp)))),
+        (i, None) => Ok((i, base)),
+    }
+}
+
+fn num_product(i: &str) -> IResult<&str, NumExpr> {
+    let (i, first) = num_pow(i)?;
+    let (i, rest) = many0(pair(
+        alt((value(NumOp::Mul, char('*')), value(NumOp::Div, char('/')))),
+        num_pow,
+    ))(i)?;
+    Ok((i, fold_num(first, rest)))
+}
+
+fn num_expr(i: &str) -> IResult<&str, NumExpr> {
+    let (i, first) = num_product(i)?;
+    let (i, rest) = many0(pair(
+        alt((value(NumOp::Add, char('+')), value(NumOp::Sub, char('-')))),
+        num_product,
+    ))(i)?;
+    Ok((i, fold_num(first, rest)))
+}
+
+fn fold_num(first: NumExpr, rest: Vec<(NumOp, NumExpr)>) -> NumExpr {
+    rest.into_iter().fold(first, |lhs, (op, rhs)| {
+        NumExpr::Op(Box::new(lhs), op, Box::new(rhs))
+    })
+}
+
+fn str_literal(i: &str) -> IResult<&str, String> {
+    delimited(
+        char('"'),
+        map(
+            opt(escaped_transform(
+                is_not("\\\""),
+                '\\',
+                alt((
+                    value("\\", char('\\')),
+                    value("\"", char('"')),
+                    value("'", char('\'')),
+                    value("\n", char('n')),
+                    value("\r", char('r')),
+                    value("\t", char('t')),
+                    value("\0", char('0')),
+                )),
+            )),
+            Option::unwrap_or_default,
+        ),
+        char('"'),
+    )(i)
+}
+
+fn str_atom(i: &str) -> IResult<&str, StrExpr> {
+    ws(alt((
+        map(preceded(char('\''), path), StrExpr::Var),
+        map(str_literal, StrExpr::Literal),
+        delimited(char('('), str_expr, char(')')),
+    )))(i)
+}
+
+fn str_expr(i: &str) -> IResult<&str, StrExpr> {
+    let (i, first) = str_atom(i)?;
+    let (i, rest) = many0(preceded(char('+'), str_atom))(i)?;
+    Ok((
+        i,
+        rest.into_iter().fold(first, |lhs, rhs| {
+            StrExpr::Concat(Box::new(lhs), Box::new(rhs))
+        }),
+    ))
+}
+
+fn bool_term(i: &str) -> IResult<&str, BoolExpr> {
+    ws(alt((
+        map(delimited(tag("!("), bool_expr, char(')')), |e| {
+            BoolExpr::Not(Box::new(e))
+        }),
+        map(tuple((num_expr, cmp_op, num_expr)), |(lhs, op, rhs)| {
+            BoolExpr::NumCmp(lhs, op, rhs)
+        }),
+        map(tuple((str_expr, cmp_op, str_expr)), |(lhs, op, rhs)| {
+            BoolExpr::StrCmp(lhs, op, rhs)
+        }),
+        map(pair(path, char('?')), |(path, _)| BoolExpr::Var(path)),
+        delimited(char('('), bool_expr, char(')')),
+    )))(i)
+}
+
+fn bool_conj(i: &str) -> IResult<&str, BoolExpr> {
+    let (i, first) = bool_term(i)?;
+    let (i, rest) = many0(preceded(tag("AND"), bool_term))(i)?;
+    Ok((
+        i,
+        rest.into_iter().fold(first, |lhs, rhs| {
+            BoolExpr::Op(Box::new(lhs), BoolOp::And, Box::new(rhs))
+        }),
+    ))
+}
+
+fn bool_expr(i: &str) -> IResult<&str, BoolExpr> {
+    let (i, first) = bool_conj(i)?;
+    let (i, rest) = many0(pair(
+        alt((value(BoolOp::Or, tag("OR")), value(BoolOp::Xor, tag("XOR")))),
+        bool_conj,
+    ))(i)?;
+    Ok((
+        i,
+        rest.into_iter().fold(first, |lhs, (op, rhs)| {
+            BoolExpr::Op(Box::new(lhs), op, Box::new(rhs))
+        }),
+    ))
+}
+
+pub fn parse(src: &str) -> Result<BoolExpr, String> {
+    all_consuming(ws(bool_expr))(src)
+        .map(|(_, expr)| expr)
+        .map_err(|e| match unsupported(src) {
+            Some(construct) => format!("{} are not supported", construct),
+            None => e.to_string(),
+        })
+}
+
+/// The `compat` rule construct used in `src` that this parser does not implement, if any.
+fn unsupported(src: &str) -> Option<&'static str> {
+    let mut chars = src.chars();
+    // the path or number being read, to tell `#list.*` apart from multiplication
+    let mut word = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                word.clear();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '"' => break,
+                        _ => (),
+                    }
+                }
+            }
+            '[' => return Some("package references ([app])"),
+            '&' => return Some("list wildcards (* and &)"),
+            '*' if word.ends_with('.') && word.starts_with(|c: char| c.is_ascii_alphabetic()) => {
+                return Some("list wildcards (* and &)")
+            }
+            '(' if matches!(
+                word.rsplit('.').next(),
+                Some("first") | Some("last") | Some("any") | Some("all")
+            ) =>
+            {
+                return Some("list functions (first, last, any and all)")
+            }
+            c if c.is_ascii_alphanumeric() || c == '-' || c == '.' => word.push(c),
+            _ => word.clear(),
+        }
+    }
+    None
+}
+
+#[derive(Clone, Debug)]
+pub struct ConfigRule {
+    pub src: String,
+    pub expr: BoolExpr,
+}
+impl ConfigRule {
+    pub fn check(&self, cfg: &Config) -> bool {
+        self.expr.eval(cfg)
+    }
+}
+impl<'de> Deserialize<'de> for ConfigRule {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let src = String::deserialize(deserializer)?;
+        let expr = parse(&src).map_err(|e| {
+            serde::de::Error::custom(format!("Invalid Config Rule {:?}: {}", src, e))
+        })?;
+        Ok(ConfigRule { src, expr })
+    }
+}
+impl Serialize for ConfigRule {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.src)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ConfigRuleEntry {
+    pub rule: ConfigRule,
+    pub description: String,
+}
+impl ConfigRuleEntry {
+    /// The error for a config this rule does not hold for. It is reported at the field the rule
+    /// references, or at the deepest object containing all of them, naming each of them relative
+    /// to it.
+    pub fn violation(&self) -> NoMatchWithPath {
+        let mut paths = Vec::new();
+        self.rule.expr.paths(&mut paths);
+        let mut keys = Vec::<Vec<String>>::new();
+        for path in paths {
+            let path = path.keys();
+            if !keys.contains(&path) {
+                keys.push(path);
+            }
+        }
+        let mut prefix = keys.first().cloned().unwrap_or_default();
+        for path in keys.iter().skip(1) {
+            let common = prefix.iter().zip(path).take_while(|(a, b)| a == b).count();
+            prefix.truncate(common);
+        }
+        let mut description = self.description.clone();
+        if keys.len() > 1 {
+            let fields = keys
+                .iter()
+                .map(|path| path[prefix.len()..].join("."))
+                .collect::<Vec<_>>();
+            description += &format!(" ({})", fields.join(", "));
+        }
+        prefix.into_iter().rev().fold(
+            NoMatchWithPath::new(MatchError::RuleViolation(description)),
+            NoMatchWithPath::prepend,
+        )
+    }
+}
+
+#[test]
+fn test_rules() {
+    let cfg: Config = serde_json::from_value(serde_json::json!({
+        "rpc": { "enable": true, "port": 8332, "user": "bitcoin" },
+        "peers": [{ "port": 8333 }],
+        "pruning": { "mode": "disabled" },
+    }))
+    .unwrap();
+    for (src, expected) in [
+        ("rpc.enable?", true),
+        ("!(rpc.enable?)", false),
+        ("missing?", false),
+        ("#rpc.port = 8332", true),
+        ("#rpc.port + 1 = #peers.0.port", true),
+        ("#rpc.port > 2 ^ 16", false),
+        ("'pruning.mode = \"disabled\" OR #missing > 0", true),
+        ("'rpc.user + \"-rpc\" = \"bitcoin-rpc\"", true),
+        ("'missing != \"x\"", true),
+        (
+            "rpc.enable? AND (#rpc.port < 1024 OR 'rpc.user = \"root\")",
+            false,
+        ),
+        ("rpc.enable? XOR rpc.enable?", false),
+    ] {
+        assert_eq!(parse(src).unwrap().eval(&cfg), expected, "{}", src);
+    }
+    assert!(parse("rpc.enable? AND").is_err());
+}
+
+#[test]
+fn test_unsupported_rules() {
+    for (src, construct) in [
+        ("'[bitcoind]rpc.user = \"bitcoin\"", "package references"),
+        ("#peers.*.port > 0", "list wildcards"),
+        ("peers.&.enabled?", "list wildcards"),
+        ("#peers.first(#port > 0).port = 8333", "list functions"),
+        ("peers.any(enabled?)?", "list functions"),
+    ] {
+        let err = parse(src).unwrap_err();
+        assert!(err.starts_with(construct), "{}: {}", src, err);
+    }
+    assert!(parse("'rpc.user = \"[a]*&\"").is_ok());
+    assert!(!parse("#rpc.port * 2 >")
+        .unwrap_err()
+        .contains("not supported"));
+}
+
+#[test]
+fn test_rule_violation_path() {
+    let entry = |src: &str| ConfigRuleEntry {
+        rule: ConfigRule {
+            src: src.to_owned(),
+            expr: parse(src).unwrap(),
+        },
+        description: "Invalid".to_owned(),
+    };
+    assert_eq!(
+        entry("#rpc.port > 1024").violation().to_string(),
+        "rpc.port: Rule Violated: Invalid"
+    );
+    assert_eq!(
+        entry("#rpc.port != #rpc.peer-port").violation().to_string(),
+        "rpc: Rule Violated: Invalid (port, peer-port)"
+    );
+    assert_eq!(
+        entry("rpc.enable? OR #peers.0.port > 0")
+            .violation()
+            .to_string(),
+        ": Rule Violated: Invalid (rpc.enable, peers.0.port)"
+    );
+}
@@ -0,0 +1,241 @@
+use std::collections::BTreeMap;
+
+use serde_json::Value;
+use sqlx::{Executor, Row, Sqlite};
+use tracing::instrument;
+
+use super::{Config, ConfigSpec};
+use crate::middleware::encrypt::{decrypt_slice, encrypt_slice};
+use crate::s9pk::manifest::PackageId;
+use crate::Error;
+
+/// Returned by `config get` in place of the value of a secret. Submitting it back leaves the
+/// secret unchanged.
+pub const SECRET_PLACEHOLDER: &'static str = "********";
+
+/// Replaces every secret in `config` with [`SECRET_PLACEHOLDER`].
+pub fn redact(spec: &ConfigSpec, config: &mut Config) {
+    spec.visit_secrets(config, &mut |_, value| {
+        if !value.is_null() {
+            *value = Value::String(SECRET_PLACEHOLDER.to_owned())
+        }
+    })
+}
+
+/// Replaces placeholders in `config` with the secret they stand for, taken from `old_config` if
+/// present and from the secret store otherwise. Secrets in lists follow their element rather
+/// than its index, see [`ConfigSpec::visit_secrets_keyed`]. Returns the paths of placeholders
+/// that stand for a secret that is no longer stored; these are left in place.
+pub fn unredact(
+    spec: &ConfigSpec,
+    config: &mut Config,
+    old_config: Option<&Config>,
+    stored: &BTreeMap<String, String>,
+) -> Vec<String> {
+    let mut old = BTreeMap::new();
+    if let Some(mut old_config) = old_config.cloned() {
+        spec.visit_secrets_keyed(&mut old_config, &mut |_, key, value| {
+            if let Value::String(s) = value {
+                old.insert(key.to_owned(), s.clone());
+            }
+        });
+    }
+    let mut missing = Vec::new();
+    spec.visit_secrets_keyed(config, &mut |path, key, value| {
+        if value.as_str() == Some(SECRET_PLACEHOLDER) {
+            if let Some(secret) = old.get(key).or_else(|| stored.get(key)) {
+                *value = Value::String(secret.clone());
+            } else {
+                missing.push(path.to_owned());
+            }
+        }
+    });
+    missing
+}
+
+#[instrument(skip(secrets, product_key))]
+pub async fn load<Ex>(
+    secrets: &mut Ex,
+    product_key: &str,
+    pkg_id: &PackageId,
+) -> Result<BTreeMap<String, String>, Error>
+where
+    for<'a> &'a mut Ex: Executor<'a, Database = Sqlite>,
+{
+    let mut res = BTreeMap::new();
+    for row in sqlx::query("SELECT path, value FROM config_secrets WHERE package = ?")
+        .bind(pkg_id.as_str())
+        .fetch_all(&mut *secrets)
+        .await?
+    {
+        let path: String = row.try_get("path")?;
+        let value: Vec<u8> = row.try_get("value")?;
+        res.insert(
+            path,
+            String::from_utf8(decrypt_slice(value, product_key))
+                .map_err(|e| Error::new(e, crate::ErrorKind::Utf8))?,
+        );
+    }
+    Ok(res)
+}
+
+/// Replaces the stored secrets of `pkg_id` with those in `config`.
+#[instrument(skip(secrets, product_key, spec, config))]
+pub async fn save<Ex>(
+    secrets: &mut Ex,
+    product_key: &str,
+    pkg_id: &PackageId,
+    spec: &ConfigSpec,
+    config: &Config,
+) -> Result<(), Error>
+where
+    for<'a> &'a mut Ex: Executor<'a, Database = Sqlite>,
+{
+    let mut values = BTreeMap::new();
+    spec.visit_secrets_keyed(&mut config.clone(), &mut |_, key, value| {
+        if let Value::String(s) = value {
+            values.insert(key.to_owned(), encrypt_slice(s.as_bytes(), product_key));
+        }
+    });
+    sqlx::query("DELETE FROM config_secrets WHERE package = ?")
+        .bind(pkg_id.as_str())
+        .execute(&mut *secrets)
+        .await?;
+    for (path, value) in values {
+        sqlx::query("INSERT INTO config_secrets (package, path, value) VALUES (?, ?, ?)")
+            .bind(pkg_id.as_str())
+            .bind(path)
+            .bind(value)
+            .execute(&mut *secrets)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Removes the stored secrets of `pkg_id`.
+#[instrument(skip(secrets))]
+pub async fn remove<Ex>(secrets: &mut Ex, pkg_id: &PackageId) -> Result<(), Error>
+where
+    for<'a> &'a mut Ex: Executor<'a, Database = Sqlite>,
+{
+    sqlx::query("DELETE FROM config_secrets WHERE package = ?")
+        .bind(pkg_id.as_str())
+        .execute(&mut *secrets)
+        .await?;
+    Ok(())
+}
+
+#[test]
+fn test_redact_list_objects() {
+    let secret = serde_json::json!({
+        "type": "secret",
+        "name": "Password",
+        "nullable": false,
+        "default": null,
+    });
+    let spec: ConfigSpec = serde_json::from_value(serde_json::json!({
+        "password": secret,
+        "users": {
+            "type": "list",
+            "subtype": "object",
+            "name": "Users",
+            "range": "[0,*)",
+            "default": [],
+            "spec": { "spec": { "password": secret } },
+        },
+    }))
+    .unwrap();
+    let original: Config = serde_json::from_value(serde_json::json!({
+        "password": "hunter2",
+        "users": [{ "password": "a" }, { "password": "b" }],
+    }))
+    .unwrap();
+    let mut config = original.clone();
+    redact(&spec, &mut config);
+    assert_eq!(config["password"], SECRET_PLACEHOLDER);
+    assert_eq!(config["users"][0]["password"], SECRET_PLACEHOLDER);
+    assert_eq!(config["users"][1]["password"], SECRET_PLACEHOLDER);
+    assert!(unredact(&spec, &mut config, Some(&original), &BTreeMap::new()).is_empty());
+    assert_eq!(config, original);
+    config["users"] = serde_json::json!([
+        { "password": SECRET_PLACEHOLDER },
+        { "password": SECRET_PLACEHOLDER },
+        { "password": SECRET_PLACEHOLDER },
+    ]);
+    assert_eq!(
+        unredact(&spec, &mut config, Some(&original), &BTreeMap::new()),
+        vec!["users.2.password".to_owned()]
+    );
+}
+
+#[test]
+fn test_unredact_moved_list_elements() {
+    let secret = serde_json::json!({
+        "type": "secret",
+        "name": "Password",
+        "nullable": false,
+        "default": null,
+    });
+    let name = serde_json::json!({
+        "type": "string",
+        "name": "Name",
+        "nullable": false,
+        "default": null,
+    });
+    let spec: ConfigSpec = serde_json::from_value(serde_json::json!({
+        "users": {
+            "type": "list",
+            "subtype": "object",
+            "name": "Users",
+            "range": "[0,*)",
+            "default": [],
+            "spec": { "spec": { "name": name, "password": secret }, "unique-by": "name" },
+        },
+        "peers": {
+            "type": "list",
+            "subtype": "object",
+            "name": "Peers",
+            "range": "[0,*)",
+            "default": [],
+            "spec": { "spec": { "name": name, "password": secret } },
+        },
+    }))
+    .unwrap();
+    let original: Config = serde_json::from_value(serde_json::json!({
+        "users": [
+            { "name": "alice", "password": "a" },
+            { "name": "bob", "password": "b" },
+            { "name": "carol", "password": "c" },
+        ],
+        "peers": [
+            { "name": "x", "password": "px" },
+            { "name": "y", "password": "py" },
+        ],
+    }))
+    .unwrap();
+    let mut config = original.clone();
+    redact(&spec, &mut config);
+    // delete alice and swap the others, delete peer x
+    config["users"] = serde_json::json!([
+        { "name": "carol", "password": SECRET_PLACEHOLDER },
+        { "name": "bob", "password": SECRET_PLACEHOLDER },
+    ]);
+    config["peers"] = serde_json::json!([{ "name": "y", "password": SECRET_PLACEHOLDER }]);
+    assert!(unredact(&spec, &mut config, Some(&original), &BTreeMap::new()).is_empty());
+    assert_eq!(config["users"][0]["password"], "c");
+    assert_eq!(config["users"][1]["password"], "b");
+    assert_eq!(config["peers"][0]["password"], "py");
+    // a renamed or new element has no secret to keep
+    config["users"] = serde_json::json!([
+        { "name": "dave", "password": SECRET_PLACEHOLDER },
+        { "name": "bob", "password": SECRET_PLACEHOLDER },
+    ]);
+    config["peers"] = serde_json::json!([{ "name": "z", "password": SECRET_PLACEHOLDER }]);
+    let mut missing = unredact(&spec, &mut config, Some(&original), &BTreeMap::new());
+    missing.sort();
+    assert_eq!(
+        missing,
+        vec!["peers.0.password".to_owned(), "users.0.password".to_owned()]
+    );
+    assert_eq!(config["users"][1]["password"], "b");
+}
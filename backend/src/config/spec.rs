@@ -5,10 +5,12 @@ use std::fmt::Debug;
 use std::hash::{Hash, Hasher};
 use std::iter::FromIterator;
 use std::ops::RangeBounds;
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
 
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use indexmap::{IndexMap, IndexSet};
 use itertools::Itertools;
 use jsonpath_lib::Compiled as CompiledJsonPath;
@@ -19,12 +21,14 @@ use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_json::{Number, Value};
 use sqlx::SqlitePool;
 
+use super::rules::{ConfigRule, ConfigRuleEntry};
 use super::util::{self, CharSet, NumRange, UniqueBy, STATIC_NULL};
 use super::{Config, MatchError, NoMatchWithPath, TimeoutError, TypeOf};
 use crate::config::ConfigurationError;
 use crate::context::RpcContext;
 use crate::net::interface::InterfaceId;
 use crate::s9pk::manifest::{Manifest, PackageId};
+use crate::volume::{Volume, VolumeId};
 use crate::Error;
 
 // Config Value Specifications
@@ -261,6 +265,9 @@ pub struct WithDescription<T> {
     pub name: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub warning: Option<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub visible_if: Option<ConfigRule>,
 }
 #[async_trait]
 impl<T> ValueSpec for WithDescription<T>
@@ -341,6 +348,10 @@ pub enum ValueSpecAny {
     String(WithDescription<WithDefault<WithNullable<ValueSpecString>>>),
     Union(WithDescription<WithDefault<ValueSpecUnion>>),
     Pointer(WithDescription<ValueSpecPointer>),
+    Secret(WithDescription<WithDefault<WithNullable<ValueSpecSecret>>>),
+    File(WithDescription<WithNullable<ValueSpecFile>>),
+    Duration(WithDescription<WithDefault<WithNullable<ValueSpecDuration>>>),
+    Datetime(WithDescription<WithDefault<WithNullable<ValueSpecDatetime>>>),
 }
 impl ValueSpecAny {
     pub fn name<'a>(&'a self) -> &'a str {
@@ -359,6 +370,32 @@ impl ValueSpecAny {
             ValueSpecAny::Pointer(p) => p.name.as_str(),
             ValueSpecAny::String(s) => s.name.as_str(),
             ValueSpecAny::Union(u) => u.name.as_str(),
+            ValueSpecAny::Secret(s) => s.name.as_str(),
+            ValueSpecAny::File(f) => f.name.as_str(),
+            ValueSpecAny::Duration(d) => d.name.as_str(),
+            ValueSpecAny::Datetime(d) => d.name.as_str(),
+        }
+    }
+    pub fn visible_if<'a>(&'a self) -> Option<&'a ConfigRule> {
+        match self {
+            ValueSpecAny::Boolean(b) => b.visible_if.as_ref(),
+            ValueSpecAny::Enum(e) => e.visible_if.as_ref(),
+            ValueSpecAny::List(l) => match l {
+                ValueSpecList::Enum(e) => e.visible_if.as_ref(),
+                ValueSpecList::Number(n) => n.visible_if.as_ref(),
+                ValueSpecList::Object(o) => o.visible_if.as_ref(),
+                ValueSpecList::String(s) => s.visible_if.as_ref(),
+                ValueSpecList::Union(u) => u.visible_if.as_ref(),
+            },
+            ValueSpecAny::Number(n) => n.visible_if.as_ref(),
+            ValueSpecAny::Object(o) => o.visible_if.as_ref(),
+            ValueSpecAny::Pointer(p) => p.visible_if.as_ref(),
+            ValueSpecAny::String(s) => s.visible_if.as_ref(),
+            ValueSpecAny::Union(u) => u.visible_if.as_ref(),
+            ValueSpecAny::Secret(s) => s.visible_if.as_ref(),
+            ValueSpecAny::File(f) => f.visible_if.as_ref(),
+            ValueSpecAny::Duration(d) => d.visible_if.as_ref(),
+            ValueSpecAny::Datetime(d) => d.visible_if.as_ref(),
         }
     }
 }
@@ -374,6 +411,10 @@ impl ValueSpec for ValueSpecAny {
             ValueSpecAny::String(a) => a.matches(value),
             ValueSpecAny::Union(a) => a.matches(value),
             ValueSpecAny::Pointer(a) => a.matches(value),
+            ValueSpecAny::Secret(a) => a.matches(value),
+            ValueSpecAny::File(a) => a.matches(value),
+            ValueSpecAny::Duration(a) => a.matches(value),
+            ValueSpecAny::Datetime(a) => a.matches(value),
         }
     }
     fn validate(&self, manifest: &Manifest) -> Result<(), NoMatchWithPath> {
@@ -386,6 +427,10 @@ impl ValueSpec for ValueSpecAny {
             ValueSpecAny::String(a) => a.validate(manifest),
             ValueSpecAny::Union(a) => a.validate(manifest),
             ValueSpecAny::Pointer(a) => a.validate(manifest),
+            ValueSpecAny::Secret(a) => a.validate(manifest),
+            ValueSpecAny::File(a) => a.validate(manifest),
+            ValueSpecAny::Duration(a) => a.validate(manifest),
+            ValueSpecAny::Datetime(a) => a.validate(manifest),
         }
     }
     async fn update<Db: DbHandle>(
@@ -405,6 +450,10 @@ impl ValueSpec for ValueSpecAny {
             ValueSpecAny::String(a) => a.update(ctx, db, manifest, config_overrides, value).await,
             ValueSpecAny::Union(a) => a.update(ctx, db, manifest, config_overrides, value).await,
             ValueSpecAny::Pointer(a) => a.update(ctx, db, manifest, config_overrides, value).await,
+            ValueSpecAny::Secret(a) => a.update(ctx, db, manifest, config_overrides, value).await,
+            ValueSpecAny::File(a) => a.update(ctx, db, manifest, config_overrides, value).await,
+            ValueSpecAny::Duration(a) => a.update(ctx, db, manifest, config_overrides, value).await,
+            ValueSpecAny::Datetime(a) => a.update(ctx, db, manifest, config_overrides, value).await,
         }
     }
     fn pointers(&self, value: &Value) -> Result<BTreeSet<ValueSpecPointer>, NoMatchWithPath> {
@@ -417,6 +466,10 @@ impl ValueSpec for ValueSpecAny {
             ValueSpecAny::String(a) => a.pointers(value),
             ValueSpecAny::Union(a) => a.pointers(value),
             ValueSpecAny::Pointer(a) => a.pointers(value),
+            ValueSpecAny::Secret(a) => a.pointers(value),
+            ValueSpecAny::File(a) => a.pointers(value),
+            ValueSpecAny::Duration(a) => a.pointers(value),
+            ValueSpecAny::Datetime(a) => a.pointers(value),
         }
     }
     fn requires(&self, id: &PackageId, value: &Value) -> bool {
@@ -429,6 +482,10 @@ impl ValueSpec for ValueSpecAny {
             ValueSpecAny::String(a) => a.requires(id, value),
             ValueSpecAny::Union(a) => a.requires(id, value),
             ValueSpecAny::Pointer(a) => a.requires(id, value),
+            ValueSpecAny::Secret(a) => a.requires(id, value),
+            ValueSpecAny::File(a) => a.requires(id, value),
+            ValueSpecAny::Duration(a) => a.requires(id, value),
+            ValueSpecAny::Datetime(a) => a.requires(id, value),
         }
     }
     fn eq(&self, lhs: &Value, rhs: &Value) -> bool {
@@ -441,6 +498,10 @@ impl ValueSpec for ValueSpecAny {
             ValueSpecAny::String(a) => a.eq(lhs, rhs),
             ValueSpecAny::Union(a) => a.eq(lhs, rhs),
             ValueSpecAny::Pointer(a) => a.eq(lhs, rhs),
+            ValueSpecAny::Secret(a) => a.eq(lhs, rhs),
+            ValueSpecAny::File(a) => a.eq(lhs, rhs),
+            ValueSpecAny::Duration(a) => a.eq(lhs, rhs),
+            ValueSpecAny::Datetime(a) => a.eq(lhs, rhs),
         }
     }
 }
@@ -461,6 +522,10 @@ impl Defaultable for ValueSpecAny {
             ValueSpecAny::String(a) => a.gen(rng, timeout).map_err(ConfigurationError::from),
             ValueSpecAny::Union(a) => a.gen(rng, timeout),
             ValueSpecAny::Pointer(a) => a.gen(rng, timeout),
+            ValueSpecAny::Secret(a) => a.gen(rng, timeout).map_err(ConfigurationError::from),
+            ValueSpecAny::File(a) => a.gen(rng, timeout).map_err(crate::util::Never::absurd),
+            ValueSpecAny::Duration(a) => a.gen(rng, timeout).map_err(crate::util::Never::absurd),
+            ValueSpecAny::Datetime(a) => a.gen(rng, timeout).map_err(crate::util::Never::absurd),
         }
     }
 }
@@ -1022,10 +1087,24 @@ impl Defaultable for ValueSpecObject {
 }
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
-pub struct ConfigSpec(pub IndexMap<String, ValueSpecAny>);
+pub struct ConfigSpec {
+    #[serde(flatten)]
+    pub fields: IndexMap<String, ValueSpecAny>,
+    /// Cross-field rules checked against the object this spec describes.
+    #[serde(rename = "$rules")]
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<ConfigRuleEntry>,
+}
 impl ConfigSpec {
     pub fn matches(&self, value: &Config) -> Result<(), NoMatchWithPath> {
-        for (key, val) in self.0.iter() {
+        for (key, val) in self.fields.iter() {
+            if let Some(cond) = val.visible_if() {
+                if !cond.check(value) {
+                    // hidden fields are not validated
+                    continue;
+                }
+            }
             if let Some(v) = value.get(key) {
                 val.matches(v).map_err(|e| e.prepend(key.clone()))?;
             } else {
@@ -1033,6 +1112,11 @@ impl ConfigSpec {
                     .map_err(|e| e.prepend(key.clone()))?;
             }
         }
+        for entry in &self.rules {
+            if !entry.rule.check(value) {
+                return Err(entry.violation());
+            }
+        }
         Ok(())
     }
 
@@ -1042,14 +1126,14 @@ impl ConfigSpec {
         timeout: &Option<Duration>,
     ) -> Result<Config, ConfigurationError> {
         let mut res = Config::new();
-        for (key, val) in self.0.iter() {
+        for (key, val) in self.fields.iter() {
             res.insert(key.clone(), val.gen(rng, timeout)?);
         }
         Ok(res)
     }
 
     pub fn validate(&self, manifest: &Manifest) -> Result<(), NoMatchWithPath> {
-        for (name, val) in &self.0 {
+        for (name, val) in &self.fields {
             val.validate(manifest)
                 .map_err(|e| e.prepend(name.clone()))?;
         }
@@ -1064,7 +1148,7 @@ impl ConfigSpec {
         config_overrides: &BTreeMap<PackageId, Config>,
         cfg: &mut Config,
     ) -> Result<(), ConfigurationError> {
-        for (k, vs) in self.0.iter() {
+        for (k, vs) in self.fields.iter() {
             match cfg.get_mut(k) {
                 None => {
                     let mut v = Value::Null;
@@ -1085,7 +1169,7 @@ impl ConfigSpec {
 
     pub fn pointers(&self, cfg: &Config) -> Result<BTreeSet<ValueSpecPointer>, NoMatchWithPath> {
         cfg.iter()
-            .filter_map(|(k, v)| self.0.get(k).map(|vs| (k, vs.pointers(v))))
+            .filter_map(|(k, v)| self.fields.get(k).map(|vs| (k, vs.pointers(v))))
             .fold(Ok(BTreeSet::<ValueSpecPointer>::new()), |acc, v| {
                 match (acc, v) {
                     // propagate existing errors
@@ -1099,10 +1183,134 @@ impl ConfigSpec {
     }
 
     pub fn requires(&self, id: &PackageId, cfg: &Config) -> bool {
-        self.0
+        self.fields
             .iter()
             .any(|(k, v)| v.requires(id, cfg.get(k).unwrap_or(&STATIC_NULL)))
     }
+
+    /// Calls `f` with the dotted path and value of every secret in `cfg`. See
+    /// [`ConfigSpec::visit_values`].
+    pub fn visit_secrets<F: FnMut(&str, &mut Value)>(&self, cfg: &mut Config, f: &mut F) {
+        self.visit_values(cfg, &mut |path, spec, value| {
+            if let ValueSpecAny::Secret(_) = spec {
+                f(path, value)
+            }
+        })
+    }
+
+    /// Like [`ConfigSpec::visit_secrets`], but also passes a key for each secret that stays the
+    /// same when list elements are moved. List elements are keyed by the fields the list is
+    /// unique by, or by all their other values if it is not unique by anything, instead of their
+    /// index.
+    pub fn visit_secrets_keyed<F: FnMut(&str, &str, &mut Value)>(
+        &self,
+        cfg: &mut Config,
+        f: &mut F,
+    ) {
+        self.visit_values_at("", "", cfg, &mut |path, key, spec, value| {
+            if let ValueSpecAny::Secret(_) = spec {
+                f(path, key, value)
+            }
+        })
+    }
+
+    /// Calls `f` with the dotted path, spec and value of every field in `cfg` that is not an
+    /// object or union, descending into objects, the selected variant of unions, and each element
+    /// of lists of objects or unions, whose paths contain the index of the element. Lists of other
+    /// values are visited as a whole.
+    pub fn visit_values<F: FnMut(&str, &ValueSpecAny, &mut Value)>(
+        &self,
+        cfg: &mut Config,
+        f: &mut F,
+    ) {
+        self.visit_values_at("", "", cfg, &mut |path, _, spec, value| {
+            f(path, spec, value)
+        })
+    }
+
+    fn visit_values_at<F: FnMut(&str, &str, &ValueSpecAny, &mut Value)>(
+        &self,
+        prefix: &str,
+        key_prefix: &str,
+        cfg: &mut Config,
+        f: &mut F,
+    ) {
+        for (key, spec) in self.fields.iter() {
+            let (path, element_key) = if prefix.is_empty() {
+                (key.clone(), key.clone())
+            } else {
+                (
+                    format!("{}.{}", prefix, key),
+                    format!("{}.{}", key_prefix, key),
+                )
+            };
+            let value = match cfg.get_mut(key) {
+                Some(a) => a,
+                None => continue,
+            };
+            match (spec, value) {
+                (ValueSpecAny::Object(o), Value::Object(value)) => {
+                    o.inner.spec.visit_values_at(&path, &element_key, value, f)
+                }
+                (ValueSpecAny::Union(u), Value::Object(value)) => {
+                    u.inner.inner.visit_values_at(&path, &element_key, value, f)
+                }
+                (ValueSpecAny::List(ValueSpecList::Object(l)), Value::Array(items)) => {
+                    let spec = &l.inner.inner.spec;
+                    let keys = element_keys(
+                        items
+                            .iter()
+                            .map(|item| element_identity(&spec.unique_by, Some(&spec.spec), item))
+                            .collect(),
+                    );
+                    for (idx, (item, item_key)) in items.iter_mut().zip(keys).enumerate() {
+                        if let Value::Object(item) = item {
+                            let path = format!("{}.{}", path, idx);
+                            let item_key = format!("{}.{}", element_key, item_key);
+                            spec.spec.visit_values_at(&path, &item_key, item, f)
+                        }
+                    }
+                }
+                (ValueSpecAny::List(ValueSpecList::Union(l)), Value::Array(items)) => {
+                    let spec = &l.inner.inner.spec.inner;
+                    let keys = element_keys(
+                        items
+                            .iter()
+                            .map(|item| {
+                                let variant = item.as_object().and_then(|item| spec.variant(item));
+                                element_identity(&spec.unique_by, variant, item)
+                            })
+                            .collect(),
+                    );
+                    for (idx, (item, item_key)) in items.iter_mut().zip(keys).enumerate() {
+                        if let Value::Object(item) = item {
+                            let path = format!("{}.{}", path, idx);
+                            let item_key = format!("{}.{}", element_key, item_key);
+                            spec.visit_values_at(&path, &item_key, item, f)
+                        }
+                    }
+                }
+                (ValueSpecAny::Object(_), _)
+                | (ValueSpecAny::Union(_), _)
+                | (ValueSpecAny::List(ValueSpecList::Object(_)), _)
+                | (ValueSpecAny::List(ValueSpecList::Union(_)), _) => (),
+                (spec, value) => f(&path, &element_key, spec, value),
+            }
+        }
+    }
+
+    /// The file spec at a dotted `path` through nested objects.
+    pub fn file_spec(&self, path: &str) -> Option<&ValueSpecFile> {
+        let (key, rest) = match path.split_once('.') {
+            Some((key, rest)) => (key, Some(rest)),
+            None => (path, None),
+        };
+        match (self.fields.get(key)?, rest) {
+            (ValueSpecAny::File(f), None) => Some(&f.inner.inner),
+            (ValueSpecAny::Object(o), Some(rest)) => o.inner.spec.file_spec(rest),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -1186,29 +1394,396 @@ impl DefaultableWith for ValueSpecString {
         rng: &mut R,
         timeout: &Option<Duration>,
     ) -> Result<Value, TimeoutError> {
-        if let Some(spec) = spec {
-            let now = timeout.as_ref().map(|_| std::time::Instant::now());
-            loop {
-                let candidate = spec.gen(rng);
-                match (spec, &self.pattern) {
-                    (DefaultString::Entropy(_), Some(pattern))
-                        if !pattern.pattern.is_match(&candidate) =>
-                    {
-                        ()
-                    }
-                    _ => {
-                        return Ok(Value::String(candidate));
-                    }
+        gen_string(&self.pattern, spec, rng, timeout)
+    }
+}
+
+fn gen_string<R: Rng + CryptoRng + Sync + Send>(
+    pattern: &Option<Pattern>,
+    spec: &Option<DefaultString>,
+    rng: &mut R,
+    timeout: &Option<Duration>,
+) -> Result<Value, TimeoutError> {
+    if let Some(spec) = spec {
+        let now = timeout.as_ref().map(|_| std::time::Instant::now());
+        loop {
+            let candidate = spec.gen(rng);
+            match (spec, pattern) {
+                (DefaultString::Entropy(_), Some(pattern))
+                    if !pattern.pattern.is_match(&candidate) =>
+                {
+                    ()
                 }
-                if let (Some(now), Some(timeout)) = (now, timeout) {
-                    if &now.elapsed() > timeout {
-                        return Err(TimeoutError);
-                    }
+                _ => {
+                    return Ok(Value::String(candidate));
+                }
+            }
+            if let (Some(now), Some(timeout)) = (now, timeout) {
+                if &now.elapsed() > timeout {
+                    return Err(TimeoutError);
                 }
             }
-        } else {
-            Ok(Value::Null)
         }
+    } else {
+        Ok(Value::Null)
+    }
+}
+
+/// Like a string, but stored encrypted in the secret store and never returned in plaintext by
+/// `config get`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ValueSpecSecret {
+    #[serde(flatten)]
+    pub pattern: Option<Pattern>,
+}
+#[async_trait]
+impl ValueSpec for ValueSpecSecret {
+    fn matches(&self, value: &Value) -> Result<(), NoMatchWithPath> {
+        match value {
+            Value::String(s) => match &self.pattern {
+                Some(pattern) if !pattern.pattern.is_match(s) => Err(NoMatchWithPath::new(
+                    MatchError::SecretPattern(pattern.pattern.clone()),
+                )),
+                _ => Ok(()),
+            },
+            Value::Null => Err(NoMatchWithPath::new(MatchError::NotNullable)),
+            a => Err(NoMatchWithPath::new(MatchError::InvalidType(
+                "string",
+                a.type_of(),
+            ))),
+        }
+    }
+    fn validate(&self, _manifest: &Manifest) -> Result<(), NoMatchWithPath> {
+        Ok(())
+    }
+    async fn update<Db: DbHandle>(
+        &self,
+        _ctx: &RpcContext,
+        _db: &mut Db,
+        _manifest: &Manifest,
+        _config_overrides: &BTreeMap<PackageId, Config>,
+        _value: &mut Value,
+    ) -> Result<(), ConfigurationError> {
+        Ok(())
+    }
+    fn pointers(&self, _value: &Value) -> Result<BTreeSet<ValueSpecPointer>, NoMatchWithPath> {
+        Ok(BTreeSet::new())
+    }
+    fn requires(&self, _id: &PackageId, _value: &Value) -> bool {
+        false
+    }
+    fn eq(&self, lhs: &Value, rhs: &Value) -> bool {
+        match (lhs, rhs) {
+            (Value::String(lhs), Value::String(rhs)) => lhs == rhs,
+            _ => false,
+        }
+    }
+}
+impl DefaultableWith for ValueSpecSecret {
+    type DefaultSpec = Option<DefaultString>;
+    type Error = TimeoutError;
+
+    fn gen_with<R: Rng + CryptoRng + Sync + Send>(
+        &self,
+        spec: &Self::DefaultSpec,
+        rng: &mut R,
+        timeout: &Option<Duration>,
+    ) -> Result<Value, TimeoutError> {
+        gen_string(&self.pattern, spec, rng, timeout)
+    }
+}
+
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// A file uploaded through `package config upload`, stored in a data volume. The value is the
+/// name of the file within `directory`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ValueSpecFile {
+    pub volume_id: VolumeId,
+    #[serde(default)]
+    pub directory: PathBuf,
+    #[serde(default)]
+    pub extensions: Vec<String>,
+    /// In bytes, [`DEFAULT_MAX_FILE_SIZE`] if not set.
+    pub max_size: Option<u64>,
+}
+impl ValueSpecFile {
+    pub fn check_file_name(&self, name: &str) -> Result<(), MatchError> {
+        let path = Path::new(name);
+        if name.is_empty()
+            || path.components().count() != 1
+            || !matches!(path.components().next(), Some(Component::Normal(_)))
+        {
+            return Err(MatchError::InvalidFileName(name.to_owned()));
+        }
+        if !self.extensions.is_empty()
+            && !path
+                .extension()
+                .and_then(|e| e.to_str())
+                .map_or(false, |e| self.extensions.iter().any(|ext| ext == e))
+        {
+            return Err(MatchError::FileExtension(
+                name.to_owned(),
+                self.extensions.clone(),
+            ));
+        }
+        Ok(())
+    }
+    /// The volume the file is stored in. Its `directory` is relative to it.
+    pub fn volume_dir(&self, ctx: &RpcContext, pkg_id: &PackageId) -> PathBuf {
+        crate::volume::data_dir(&ctx.datadir, pkg_id, &self.volume_id)
+    }
+}
+#[async_trait]
+impl ValueSpec for ValueSpecFile {
+    fn matches(&self, value: &Value) -> Result<(), NoMatchWithPath> {
+        match value {
+            Value::String(s) => self.check_file_name(s).map_err(NoMatchWithPath::new),
+            Value::Null => Err(NoMatchWithPath::new(MatchError::NotNullable)),
+            a => Err(NoMatchWithPath::new(MatchError::InvalidType(
+                "string",
+                a.type_of(),
+            ))),
+        }
+    }
+    fn validate(&self, manifest: &Manifest) -> Result<(), NoMatchWithPath> {
+        match manifest.volumes.get(&self.volume_id) {
+            Some(Volume::Data { .. }) => (),
+            _ => {
+                return Err(NoMatchWithPath::new(MatchError::InvalidVolume(
+                    self.volume_id.to_string(),
+                )))
+            }
+        }
+        if self
+            .directory
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(NoMatchWithPath::new(MatchError::InvalidFileName(
+                self.directory.display().to_string(),
+            )));
+        }
+        Ok(())
+    }
+    async fn update<Db: DbHandle>(
+        &self,
+        _ctx: &RpcContext,
+        _db: &mut Db,
+        _manifest: &Manifest,
+        _config_overrides: &BTreeMap<PackageId, Config>,
+        _value: &mut Value,
+    ) -> Result<(), ConfigurationError> {
+        Ok(())
+    }
+    fn pointers(&self, _value: &Value) -> Result<BTreeSet<ValueSpecPointer>, NoMatchWithPath> {
+        Ok(BTreeSet::new())
+    }
+    fn requires(&self, _id: &PackageId, _value: &Value) -> bool {
+        false
+    }
+    fn eq(&self, lhs: &Value, rhs: &Value) -> bool {
+        match (lhs, rhs) {
+            (Value::String(lhs), Value::String(rhs)) => lhs == rhs,
+            _ => false,
+        }
+    }
+}
+impl Defaultable for ValueSpecFile {
+    type Error = crate::util::Never;
+
+    fn gen<R: Rng + CryptoRng + Sync + Send>(
+        &self,
+        _rng: &mut R,
+        _timeout: &Option<Duration>,
+    ) -> Result<Value, Self::Error> {
+        Ok(Value::Null)
+    }
+}
+
+/// A duration written with units, e.g. `30s`, `1.5h` or `7d`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ValueSpecDuration {
+    pub min: Option<crate::util::serde::Duration>,
+    pub max: Option<crate::util::serde::Duration>,
+    /// Units the value may be written in. Any unit is accepted if unset.
+    pub units: Option<BTreeSet<String>>,
+}
+impl ValueSpecDuration {
+    fn parse(&self, s: &str) -> Result<Duration, MatchError> {
+        if let Some(units) = &self.units {
+            let unit = s.trim_start_matches(|c: char| c.is_ascii_digit() || c == '.');
+            if !units.contains(unit) {
+                return Err(MatchError::DurationUnit(
+                    s.to_owned(),
+                    units.iter().cloned().collect(),
+                ));
+            }
+        }
+        s.parse::<crate::util::serde::Duration>()
+            .map(|d| *d)
+            .map_err(|e| MatchError::InvalidDuration(s.to_owned(), e.source.to_string()))
+    }
+}
+#[async_trait]
+impl ValueSpec for ValueSpecDuration {
+    fn matches(&self, value: &Value) -> Result<(), NoMatchWithPath> {
+        match value {
+            Value::String(s) => {
+                let d = self.parse(s).map_err(NoMatchWithPath::new)?;
+                if self.min.map_or(false, |min| d < *min) || self.max.map_or(false, |max| d > *max)
+                {
+                    return Err(NoMatchWithPath::new(MatchError::DurationOutOfRange(
+                        s.to_owned(),
+                        format!(
+                            "{}..{}",
+                            self.min.map(|d| d.to_string()).unwrap_or_default(),
+                            self.max.map(|d| d.to_string()).unwrap_or_default()
+                        ),
+                    )));
+                }
+                Ok(())
+            }
+            Value::Null => Err(NoMatchWithPath::new(MatchError::NotNullable)),
+            a => Err(NoMatchWithPath::new(MatchError::InvalidType(
+                "string",
+                a.type_of(),
+            ))),
+        }
+    }
+    fn validate(&self, _manifest: &Manifest) -> Result<(), NoMatchWithPath> {
+        Ok(())
+    }
+    async fn update<Db: DbHandle>(
+        &self,
+        _ctx: &RpcContext,
+        _db: &mut Db,
+        _manifest: &Manifest,
+        _config_overrides: &BTreeMap<PackageId, Config>,
+        _value: &mut Value,
+    ) -> Result<(), ConfigurationError> {
+        Ok(())
+    }
+    fn pointers(&self, _value: &Value) -> Result<BTreeSet<ValueSpecPointer>, NoMatchWithPath> {
+        Ok(BTreeSet::new())
+    }
+    fn requires(&self, _id: &PackageId, _value: &Value) -> bool {
+        false
+    }
+    fn eq(&self, lhs: &Value, rhs: &Value) -> bool {
+        match (lhs, rhs) {
+            (Value::String(lhs), Value::String(rhs)) => match (self.parse(lhs), self.parse(rhs)) {
+                (Ok(lhs), Ok(rhs)) => lhs == rhs,
+                _ => lhs == rhs,
+            },
+            _ => false,
+        }
+    }
+}
+impl DefaultableWith for ValueSpecDuration {
+    type DefaultSpec = Option<crate::util::serde::Duration>;
+    type Error = crate::util::Never;
+
+    fn gen_with<R: Rng + CryptoRng + Sync + Send>(
+        &self,
+        spec: &Self::DefaultSpec,
+        _rng: &mut R,
+        _timeout: &Option<Duration>,
+    ) -> Result<Value, Self::Error> {
+        Ok(spec
+            .map(|d| Value::String(d.to_string()))
+            .unwrap_or(Value::Null))
+    }
+}
+
+/// An RFC 3339 timestamp.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ValueSpecDatetime {
+    pub min: Option<DateTime<Utc>>,
+    pub max: Option<DateTime<Utc>>,
+}
+impl ValueSpecDatetime {
+    fn parse(s: &str) -> Result<DateTime<Utc>, MatchError> {
+        DateTime::parse_from_rfc3339(s)
+            .map(|d| d.with_timezone(&Utc))
+            .map_err(|e| MatchError::InvalidDatetime(s.to_owned(), e.to_string()))
+    }
+}
+#[async_trait]
+impl ValueSpec for ValueSpecDatetime {
+    fn matches(&self, value: &Value) -> Result<(), NoMatchWithPath> {
+        match value {
+            Value::String(s) => {
+                let d = Self::parse(s).map_err(NoMatchWithPath::new)?;
+                if self.min.map_or(false, |min| d < min) || self.max.map_or(false, |max| d > max) {
+                    return Err(NoMatchWithPath::new(MatchError::DatetimeOutOfRange(
+                        s.to_owned(),
+                        format!(
+                            "{}..{}",
+                            self.min.map(|d| d.to_rfc3339()).unwrap_or_default(),
+                            self.max.map(|d| d.to_rfc3339()).unwrap_or_default()
+                        ),
+                    )));
+                }
+                Ok(())
+            }
+            Value::Null => Err(NoMatchWithPath::new(MatchError::NotNullable)),
+            a => Err(NoMatchWithPath::new(MatchError::InvalidType(
+                "string",
+                a.type_of(),
+            ))),
+        }
+    }
+    fn validate(&self, _manifest: &Manifest) -> Result<(), NoMatchWithPath> {
+        Ok(())
+    }
+    async fn update<Db: DbHandle>(
+        &self,
+        _ctx: &RpcContext,
+        _db: &mut Db,
+        _manifest: &Manifest,
+        _config_overrides: &BTreeMap<PackageId, Config>,
+        _value: &mut Value,
+    ) -> Result<(), ConfigurationError> {
+        Ok(())
+    }
+    fn pointers(&self, _value: &Value) -> Result<BTreeSet<ValueSpecPointer>, NoMatchWithPath> {
+        Ok(BTreeSet::new())
+    }
+    fn requires(&self, _id: &PackageId, _value: &Value) -> bool {
+        false
+    }
+    fn eq(&self, lhs: &Value, rhs: &Value) -> bool {
+        match (lhs, rhs) {
+            (Value::String(lhs), Value::String(rhs)) => {
+                match (Self::parse(lhs), Self::parse(rhs)) {
+                    (Ok(lhs), Ok(rhs)) => lhs == rhs,
+                    _ => lhs == rhs,
+                }
+            }
+            _ => false,
+        }
+    }
+}
+impl DefaultableWith for ValueSpecDatetime {
+    /// A timestamp, or `now` for the time the default is generated.
+    type DefaultSpec = Option<String>;
+    type Error = crate::util::Never;
+
+    fn gen_with<R: Rng + CryptoRng + Sync + Send>(
+        &self,
+        spec: &Self::DefaultSpec,
+        _rng: &mut R,
+        _timeout: &Option<Duration>,
+    ) -> Result<Value, Self::Error> {
+        Ok(match spec.as_deref() {
+            Some("now") => Value::String(Utc::now().to_rfc3339()),
+            Some(s) => Value::String(s.to_owned()),
+            None => Value::Null,
+        })
     }
 }
 
@@ -1262,6 +1837,62 @@ pub struct ValueSpecUnion {
     pub unique_by: UniqueBy,
 }
 
+impl ValueSpecUnion {
+    /// The spec of the variant `cfg` selects.
+    fn variant(&self, cfg: &Config) -> Option<&ConfigSpec> {
+        cfg.get(&self.tag.id)
+            .and_then(|t| t.as_str())
+            .and_then(|t| self.variants.get(t))
+    }
+
+    /// See [`ConfigSpec::visit_values`]. Only the selected variant is visited.
+    fn visit_values_at<F: FnMut(&str, &str, &ValueSpecAny, &mut Value)>(
+        &self,
+        prefix: &str,
+        key_prefix: &str,
+        cfg: &mut Config,
+        f: &mut F,
+    ) {
+        if let Some(variant) = self.variant(cfg) {
+            variant.visit_values_at(prefix, key_prefix, cfg, f)
+        }
+    }
+}
+
+/// What tells a list element apart from the others: the fields the list is unique by, or else
+/// everything but its secrets, so that a secret never follows another element's position.
+fn element_identity(unique_by: &UniqueBy, spec: Option<&ConfigSpec>, item: &Value) -> Value {
+    let item = match item {
+        Value::Object(a) => a,
+        a => return a.clone(),
+    };
+    if let Some(identity) = unique_by.identity(item) {
+        return identity;
+    }
+    let mut item = item.clone();
+    if let Some(spec) = spec {
+        spec.visit_secrets(&mut item, &mut |_, value| *value = Value::Null);
+    }
+    Value::Object(item)
+}
+
+/// Path segments for list elements with the given identities. Elements that share an identity
+/// are numbered in order.
+fn element_keys(identities: Vec<Value>) -> Vec<String> {
+    identities
+        .iter()
+        .enumerate()
+        .map(|(idx, identity)| {
+            let nth = identities[..idx].iter().filter(|i| *i == identity).count();
+            if nth == 0 {
+                format!("[{}]", identity)
+            } else {
+                format!("[{}]#{}", identity, nth)
+            }
+        })
+        .collect()
+}
+
 impl<'de> serde::de::Deserialize<'de> for ValueSpecUnion {
     fn deserialize<D: serde::de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
         #[derive(Deserialize)]
@@ -1354,7 +1985,7 @@ impl ValueSpec for ValueSpecUnion {
     }
     fn validate(&self, manifest: &Manifest) -> Result<(), NoMatchWithPath> {
         for (name, variant) in &self.variants {
-            if variant.0.get(&self.tag.id).is_some() {
+            if variant.fields.get(&self.tag.id).is_some() {
                 return Err(NoMatchWithPath::new(MatchError::PropertyMatchesUnionTag(
                     self.tag.id.clone(),
                     name.clone(),
@@ -287,6 +287,20 @@ impl UniqueBy {
             UniqueBy::NotUnique => false,
         }
     }
+    /// The values of `cfg` that must differ from those of every other element of the list, or
+    /// `None` if elements need not differ at all.
+    pub fn identity(&self, cfg: &Config) -> Option<Value> {
+        match self {
+            UniqueBy::Any(any) => any.iter().find_map(|u| u.identity(cfg)),
+            UniqueBy::All(all) => all
+                .iter()
+                .map(|u| u.identity(cfg))
+                .collect::<Option<Vec<_>>>()
+                .map(Value::Array),
+            UniqueBy::Exactly(key) => Some(cfg.get(key).cloned().unwrap_or(Value::Null)),
+            UniqueBy::NotUnique => None,
+        }
+    }
 }
 impl Default for UniqueBy {
     fn default() -> Self {
@@ -8,6 +8,7 @@ use std::time::Duration;
 
 use bollard::Docker;
 use color_eyre::eyre::eyre;
+use ipnet::Ipv4Net;
 use patch_db::json_ptr::JsonPointer;
 use patch_db::{DbHandle, LockType, PatchDb, Revision};
 use reqwest::Url;
@@ -26,6 +27,7 @@ use crate::db::model::{Database, InstalledPackageDataEntry, PackageDataEntry};
 use crate::hostname::{derive_hostname, derive_id, get_product_key};
 use crate::install::cleanup::{cleanup_failed, uninstall};
 use crate::manager::ManagerMap;
+use crate::marketplace::MarketplaceCache;
 use crate::middleware::auth::HashSessionToken;
 use crate::net::tor::os_key;
 use crate::net::wifi::WpaCli;
@@ -48,6 +50,11 @@ pub struct RpcContextConfig {
     pub bind_static: Option<SocketAddr>,
     pub tor_control: Option<SocketAddr>,
     pub tor_socks: Option<SocketAddr>,
+    pub vpn_network: Option<Ipv4Net>,
+    pub vpn_port: Option<u16>,
+    /// Addresses besides the vpn and `start9` network gateways to serve dns on, like a static lan
+    /// address.
+    pub dns_listen: Option<Vec<Ipv4Addr>>,
     pub revision_cache_size: Option<usize>,
     pub datadir: Option<PathBuf>,
     pub log_server: Option<Url>,
@@ -134,6 +141,7 @@ pub struct RpcContextSeed {
     pub open_authed_websockets: Mutex<BTreeMap<HashSessionToken, Vec<oneshot::Sender<()>>>>,
     pub rpc_stream_continuations: Mutex<BTreeMap<RequestGuid, RpcContinuation>>,
     pub wifi_manager: Arc<RwLock<WpaCli>>,
+    pub marketplace_cache: MarketplaceCache,
 }
 
 #[derive(Clone)]
@@ -164,11 +172,33 @@ impl RpcContext {
             crate::net::tor::os_key(&mut secret_store.acquire().await?).await?,
             base.tor_control
                 .unwrap_or(SocketAddr::from(([127, 0, 0, 1], 9051))),
+            base.vpn_network
+                .unwrap_or_else(crate::net::vpn::default_vpn_network),
+            base.vpn_port.unwrap_or(crate::net::vpn::DEFAULT_VPN_PORT),
+            base.dns_listen.clone().unwrap_or_default(),
             secret_store.clone(),
             None,
         )
         .await?;
         tracing::info!("Initialized Net Controller");
+        let tor_settings = crate::db::DatabaseModel::new()
+            .server_info()
+            .tor()
+            .get(&mut db.handle(), false)
+            .await?
+            .into_owned();
+        let dns_settings = crate::db::DatabaseModel::new()
+            .server_info()
+            .dns()
+            .get(&mut db.handle(), false)
+            .await?;
+        net_controller.dns.configure(&dns_settings).await;
+        if tor_settings != Default::default() {
+            if let Err(e) = net_controller.tor.configure(tor_settings).await {
+                tracing::error!("Could not apply Tor settings: {}", e);
+                tracing::debug!("{:?}", e);
+            }
+        }
         let managers = ManagerMap::default();
         let metrics_cache = RwLock::new(None);
         let notification_manager = NotificationManager::new(secret_store.clone());
@@ -196,6 +226,7 @@ impl RpcContext {
             open_authed_websockets: Mutex::new(BTreeMap::new()),
             rpc_stream_continuations: Mutex::new(BTreeMap::new()),
             wifi_manager: Arc::new(RwLock::new(WpaCli::init("wlan0".to_string()))),
+            marketplace_cache: MarketplaceCache::default(),
         });
         let metrics_seed = seed.clone();
         tokio::spawn(async move {
@@ -228,6 +259,7 @@ impl RpcContext {
                 include_str!("../nginx/main-ui.conf.template"),
                 lan_hostname = info.lan_address.host_str().unwrap(),
                 tor_hostname = info.tor_address.host_str().unwrap(),
+                acme_webroot = crate::net::ssl::acme::ACME_WEBROOT,
             )
         })
         .await
@@ -237,6 +269,21 @@ impl RpcContext {
                 "/etc/nginx/sites-available/default",
             )
         })?;
+        let domains = crate::db::DatabaseModel::new()
+            .server_info()
+            .acme()
+            .get(db, true)
+            .await?
+            .domains
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        for domain in domains {
+            self.net_controller
+                .nginx
+                .add_clearnet(&self.net_controller.ssl, &domain)
+                .await?;
+        }
         Command::new("systemctl")
             .arg("reload")
             .arg("nginx")
@@ -244,6 +291,13 @@ impl RpcContext {
             .await?;
         Ok(())
     }
+    /// Registers a continuation under `guid`, dropping the ones that have gone unclaimed for more
+    /// than 30 seconds.
+    pub async fn add_continuation(&self, guid: RequestGuid, handler: RpcContinuation) {
+        let mut guard = self.rpc_stream_continuations.lock().await;
+        guard.retain(|_, v| v.created_at.elapsed() < Duration::from_secs(30));
+        guard.insert(guid, handler);
+    }
     #[instrument(skip(self))]
     pub async fn shutdown(self) -> Result<(), Error> {
         self.managers.empty().await?;
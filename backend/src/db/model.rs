@@ -12,13 +12,19 @@ use serde_json::Value;
 use torut::onion::TorSecretKeyV3;
 
 use crate::config::spec::{PackagePointerSpec, SystemPointerSpec};
+use crate::install::auto_update::AutoUpdatePolicy;
 use crate::install::progress::InstallProgress;
+use crate::net::access::AccessPolicy;
+use crate::net::dns::DnsSettings;
 use crate::net::interface::InterfaceId;
+use crate::net::ssl::acme::AcmeSettings;
+use crate::net::tor::{TorBootstrap, TorSettings};
 use crate::s9pk::manifest::{Manifest, ManifestModel, PackageId};
 use crate::status::health_check::HealthCheckId;
 use crate::status::Status;
 use crate::util::Version;
 use crate::version::{Current, VersionT};
+use crate::volume::{VolumeId, VolumeUsage};
 
 #[derive(Debug, Deserialize, Serialize, HasModel)]
 #[serde(rename_all = "kebab-case")]
@@ -54,6 +60,7 @@ impl Database {
                     backing_up: false,
                     updated: false,
                     update_progress: None,
+                    backup_progress: BTreeMap::new(),
                 },
                 wifi: WifiInfo {
                     ssids: Vec::new(),
@@ -66,6 +73,11 @@ impl Database {
                     clearnet: Vec::new(),
                 },
                 password_hash,
+                marketplace: MarketplaceInfo::default(),
+                acme: AcmeSettings::default(),
+                tor: TorSettings::default(),
+                tor_bootstrap: TorBootstrap::default(),
+                dns: DnsSettings::default(),
             },
             package_data: AllPackageData::default(),
             recovered_packages: BTreeMap::new(),
@@ -97,6 +109,29 @@ pub struct ServerInfo {
     pub unread_notification_count: u64,
     pub connection_addresses: ConnectionAddresses,
     pub password_hash: String,
+    #[serde(default)]
+    pub marketplace: MarketplaceInfo,
+    #[serde(default)]
+    pub acme: AcmeSettings,
+    #[serde(default)]
+    pub tor: TorSettings,
+    #[serde(default)]
+    pub tor_bootstrap: TorBootstrap,
+    #[serde(default)]
+    pub dns: DnsSettings,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct MarketplaceInfo {
+    pub registries: Vec<Url>,
+}
+impl Default for MarketplaceInfo {
+    fn default() -> Self {
+        MarketplaceInfo {
+            registries: vec![crate::DEFAULT_MARKETPLACE.parse().unwrap()],
+        }
+    }
 }
 
 #[derive(Debug, Default, Deserialize, Serialize, HasModel)]
@@ -106,6 +141,10 @@ pub struct ServerStatus {
     pub updated: bool,
     #[model]
     pub update_progress: Option<UpdateProgress>,
+    /// Packages the running backup has reached.
+    #[serde(default)]
+    #[model]
+    pub backup_progress: BTreeMap<PackageId, BackupProgress>,
 }
 
 #[derive(Debug, Deserialize, Serialize, HasModel)]
@@ -115,6 +154,16 @@ pub struct UpdateProgress {
     pub downloaded: u64,
 }
 
+/// Also what backup actions write to [`BACKUP_PROGRESS_FILE`](crate::disk::mount::backup::BACKUP_PROGRESS_FILE)
+/// to report their progress. The byte counts are unknown for actions that don't.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, HasModel)]
+#[serde(rename_all = "kebab-case")]
+pub struct BackupProgress {
+    pub complete: bool,
+    pub bytes_done: Option<u64>,
+    pub bytes_total: Option<u64>,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct WifiInfo {
@@ -261,6 +310,8 @@ pub struct InstalledPackageDataEntry {
     pub manifest: Manifest,
     pub last_backup: Option<DateTime<Utc>>,
     pub system_pointers: Vec<SystemPointerSpec>,
+    #[serde(default)]
+    pub auto_update: AutoUpdatePolicy,
     #[model]
     pub dependency_info: BTreeMap<PackageId, StaticDependencyInfo>,
     #[model]
@@ -269,6 +320,10 @@ pub struct InstalledPackageDataEntry {
     pub current_dependencies: BTreeMap<PackageId, CurrentDependencyInfo>,
     #[model]
     pub interface_addresses: InterfaceAddressMap,
+    #[serde(default)]
+    pub access_policies: BTreeMap<InterfaceId, AccessPolicy>,
+    #[serde(default)]
+    pub volume_usage: BTreeMap<VolumeId, VolumeUsage>,
 }
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize, HasModel)]
@@ -305,6 +360,9 @@ pub struct InterfaceAddresses {
     pub tor_address: Option<String>,
     #[model]
     pub lan_address: Option<String>,
+    /// Custom hostnames, served by nginx alongside the lan address.
+    #[serde(default)]
+    pub domains: BTreeSet<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, HasModel)]
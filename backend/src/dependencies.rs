@@ -10,6 +10,7 @@ use patch_db::{DbHandle, HasModel, LockType, Map, MapModel, PatchDbHandle};
 use rand::SeedableRng;
 use rpc_toolkit::command;
 use serde::{Deserialize, Serialize};
+use sqlx::{Executor, Sqlite};
 use tracing::instrument;
 
 use crate::action::{ActionImplementation, NoOutput};
@@ -498,17 +499,24 @@ pub async fn configure_impl(
         new_config,
         spec: _,
     } = configure_logic(ctx.clone(), &mut db, (pkg_id, dep_id.clone())).await?;
-    Ok(crate::config::configure(
+    let mut tx = db.begin().await?;
+    let mut sql_tx = ctx.secret_store.begin().await?;
+    crate::config::configure(
         &ctx,
-        &mut db,
+        &mut tx,
+        &mut sql_tx,
         &dep_id,
         Some(new_config),
         &Some(Duration::from_secs(3).into()),
         false,
+        None,
         &mut BTreeMap::new(),
         &mut BTreeMap::new(),
     )
-    .await?)
+    .await?;
+    tx.commit(None).await?;
+    sql_tx.commit().await?;
+    Ok(())
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -878,11 +886,16 @@ pub fn heal_transitive<'a, Db: DbHandle>(
     .boxed()
 }
 
-pub async fn reconfigure_dependents_with_live_pointers(
+pub async fn reconfigure_dependents_with_live_pointers<Ex>(
     ctx: &RpcContext,
     mut tx: impl DbHandle,
+    secrets: &mut Ex,
     pde: &InstalledPackageDataEntry,
-) -> Result<(), Error> {
+) -> Result<(), Error>
+where
+    Ex: Send,
+    for<'a> &'a mut Ex: Executor<'a, Database = Sqlite>,
+{
     let dependents = &pde.current_dependents;
     let me = &pde.manifest.id;
     for (dependent_id, dependency_info) in dependents {
@@ -897,10 +910,12 @@ pub async fn reconfigure_dependents_with_live_pointers(
             crate::config::configure(
                 ctx,
                 &mut tx,
+                &mut *secrets,
                 dependent_id,
                 None,
                 &None,
                 false,
+                None,
                 &mut BTreeMap::new(),
                 &mut BTreeMap::new(),
             )
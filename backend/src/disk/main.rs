@@ -6,7 +6,6 @@ use tokio::process::Command;
 use tracing::instrument;
 
 use super::util::pvscan;
-use crate::disk::mount::filesystem::block_dev::mount;
 use crate::disk::mount::util::unmount;
 use crate::util::Invoke;
 use crate::{Error, ResultExt};
@@ -122,10 +121,12 @@ pub async fn create_fs<P: AsRef<Path>>(
         .invoke(crate::ErrorKind::DiskManagement)
         .await?;
     Command::new("mkfs.ext4")
+        .arg("-O")
+        .arg("quota,project")
         .arg(Path::new("/dev/mapper").join(format!("{}_{}", guid, name)))
         .invoke(crate::ErrorKind::DiskManagement)
         .await?;
-    mount(
+    mount_with_quotas(
         Path::new("/dev/mapper").join(format!("{}_{}", guid, name)),
         datadir.as_ref().join(name),
     )
@@ -265,7 +266,7 @@ pub async fn mount_fs<P: AsRef<Path>>(
         .arg(format!("{}_{}", guid, name))
         .invoke(crate::ErrorKind::DiskManagement)
         .await?;
-    mount(
+    mount_with_quotas(
         Path::new("/dev/mapper").join(format!("{}_{}", guid, name)),
         datadir.as_ref().join(name),
     )
@@ -278,6 +279,45 @@ pub async fn mount_fs<P: AsRef<Path>>(
     Ok(())
 }
 
+/// Mounts a filesystem with project quotas enforced, which is how package volumes are limited in
+/// size. Filesystems created before that get the features turned on first.
+#[instrument]
+async fn mount_with_quotas(
+    logicalname: impl AsRef<Path> + std::fmt::Debug,
+    mountpoint: impl AsRef<Path> + std::fmt::Debug,
+) -> Result<(), Error> {
+    let info = String::from_utf8(
+        Command::new("tune2fs")
+            .arg("-l")
+            .arg(logicalname.as_ref())
+            .invoke(crate::ErrorKind::DiskManagement)
+            .await?,
+    )?;
+    let features = info
+        .lines()
+        .find_map(|l| l.strip_prefix("Filesystem features:"))
+        .unwrap_or("")
+        .split_whitespace()
+        .collect::<Vec<_>>();
+    if !features.contains(&"quota") || !features.contains(&"project") {
+        Command::new("tune2fs")
+            .arg("-O")
+            .arg("quota,project")
+            .arg(logicalname.as_ref())
+            .invoke(crate::ErrorKind::DiskManagement)
+            .await?;
+    }
+    tokio::fs::create_dir_all(mountpoint.as_ref()).await?;
+    Command::new("mount")
+        .arg("-o")
+        .arg("prjquota")
+        .arg(logicalname.as_ref())
+        .arg(mountpoint.as_ref())
+        .invoke(crate::ErrorKind::Filesystem)
+        .await?;
+    Ok(())
+}
+
 #[instrument(skip(datadir, password))]
 pub async fn mount_all_fs<P: AsRef<Path>>(
     guid: &str,
@@ -1,3 +1,4 @@
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 
 use color_eyre::eyre::eyre;
@@ -13,10 +14,20 @@ use crate::disk::util::EmbassyOsRecoveryInfo;
 use crate::middleware::encrypt::{decrypt_slice, encrypt_slice};
 use crate::s9pk::manifest::PackageId;
 use crate::util::serde::IoFormat;
-use crate::util::{AtomicFile, FileLock};
+use crate::util::{AtomicFile, FileLock, Invoke};
 use crate::volume::BACKUP_DIR;
 use crate::{Error, ResultExt};
 
+/// File in each package backup mount containing the key that package's backups are encrypted
+/// with. It is derived from the backup drive's encryption key whenever the backup is mounted, so
+/// it stays the same across password changes and when restoring on another server, and is
+/// mounted over an empty file from memory so it is never written to the backup drive.
+pub const PACKAGE_BACKUP_KEY_FILE: &'static str = ".backup-key";
+const PACKAGE_BACKUP_KEY_DIR: &'static str = "/run/embassy/backup-keys";
+/// Backup actions may keep a [`BackupProgress`](crate::db::model::BackupProgress) in this file
+/// of the package backup while they run. It is removed once they are done.
+pub const BACKUP_PROGRESS_FILE: &'static str = ".backup-progress.json";
+
 pub struct BackupMountGuard<G: GenericMountGuard> {
     backup_disk_mount_guard: Option<G>,
     encrypted_guard: Option<TmpMountGuard>,
@@ -142,6 +153,15 @@ impl<G: GenericMountGuard> BackupMountGuard<G> {
         Ok(())
     }
 
+    fn package_key(&self, id: &PackageId) -> String {
+        use sha2::{Digest, Sha256};
+
+        let mut hasher = Sha256::new();
+        hasher.update(self.enc_key.as_bytes());
+        hasher.update(id.as_bytes());
+        hex::encode(hasher.finalize())
+    }
+
     #[instrument(skip(self))]
     pub async fn mount_package_backup(
         &self,
@@ -150,8 +170,31 @@ impl<G: GenericMountGuard> BackupMountGuard<G> {
         let lock = FileLock::new(Path::new(BACKUP_DIR).join(format!("{}.lock", id)), false).await?;
         let mountpoint = Path::new(BACKUP_DIR).join(id);
         bind(self.as_ref().join(id), &mountpoint, false).await?;
+        let key_path = mountpoint.join(PACKAGE_BACKUP_KEY_FILE);
+        tokio::fs::write(&key_path, "")
+            .await
+            .with_ctx(|_| (crate::ErrorKind::Filesystem, key_path.display().to_string()))?;
+        tokio::fs::create_dir_all(PACKAGE_BACKUP_KEY_DIR).await?;
+        tokio::fs::set_permissions(
+            PACKAGE_BACKUP_KEY_DIR,
+            std::fs::Permissions::from_mode(0o700),
+        )
+        .await?;
+        let key_src = Path::new(PACKAGE_BACKUP_KEY_DIR).join(id);
+        tokio::fs::write(&key_src, self.package_key(id))
+            .await
+            .with_ctx(|_| (crate::ErrorKind::Filesystem, key_src.display().to_string()))?;
+        tokio::process::Command::new("mount")
+            .arg("--bind")
+            .arg("-o")
+            .arg("ro")
+            .arg(&key_src)
+            .arg(&key_path)
+            .invoke(crate::ErrorKind::Filesystem)
+            .await?;
         Ok(PackageBackupMountGuard {
             mountpoint: Some(mountpoint),
+            key: Some(key_src),
             lock: Some(lock),
         })
     }
@@ -217,13 +260,20 @@ impl<G: GenericMountGuard> Drop for BackupMountGuard<G> {
 
 pub struct PackageBackupMountGuard {
     mountpoint: Option<PathBuf>,
+    key: Option<PathBuf>,
     lock: Option<FileLock>,
 }
 impl PackageBackupMountGuard {
     pub async fn unmount(mut self) -> Result<(), Error> {
+        // a lazy unmount also detaches the key mounted inside
         if let Some(mountpoint) = self.mountpoint.take() {
             unmount(&mountpoint).await?;
         }
+        if let Some(key) = self.key.take() {
+            tokio::fs::remove_file(&key)
+                .await
+                .with_ctx(|_| (crate::ErrorKind::Filesystem, key.display().to_string()))?;
+        }
         if let Some(lock) = self.lock.take() {
             lock.unlock().await?;
         }
@@ -242,11 +292,15 @@ impl AsRef<Path> for PackageBackupMountGuard {
 impl Drop for PackageBackupMountGuard {
     fn drop(&mut self) {
         let mountpoint = self.mountpoint.take();
+        let key = self.key.take();
         let lock = self.lock.take();
         tokio::spawn(async move {
             if let Some(mountpoint) = mountpoint {
                 unmount(&mountpoint).await.unwrap();
             }
+            if let Some(key) = key {
+                tokio::fs::remove_file(key).await.unwrap();
+            }
             if let Some(lock) = lock {
                 lock.unlock().await.unwrap();
             }
@@ -16,7 +16,6 @@ use crate::util::Invoke;
 use crate::Error;
 
 async fn resolve_hostname(hostname: &str) -> Result<IpAddr, Error> {
-    #[cfg(feature = "avahi")]
     if hostname.ends_with(".local") {
         return Ok(crate::net::mdns::resolve_mdns(hostname).await?);
     }
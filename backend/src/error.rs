@@ -64,6 +64,9 @@ pub enum ErrorKind {
     InvalidBackupTargetId = 56,
     ProductKeyMismatch = 57,
     LanPortConflict = 58,
+    Acme = 59,
+    Vpn = 60,
+    Firewall = 61,
 }
 impl ErrorKind {
     pub fn as_str(&self) -> &'static str {
@@ -127,6 +130,9 @@ impl ErrorKind {
             InvalidBackupTargetId => "Invalid Backup Target ID",
             ProductKeyMismatch => "Incompatible Product Keys",
             LanPortConflict => "Incompatible LAN port configuration",
+            Acme => "ACME Error",
+            Vpn => "VPN Error",
+            Firewall => "Firewall Error",
         }
     }
 }
@@ -1,3 +1,5 @@
+use std::collections::BTreeMap;
+
 use tokio::process::Command;
 
 use crate::context::rpc::RpcContextConfig;
@@ -97,6 +99,7 @@ pub async fn init(cfg: &RpcContextConfig, product_key: &str) -> Result<(), Error
         backing_up: false,
         updated: false,
         update_progress: None,
+        backup_progress: BTreeMap::new(),
     };
     info.save(&mut handle).await?;
 
@@ -0,0 +1,412 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use color_eyre::eyre::eyre;
+use emver::VersionRange;
+use patch_db::LockType;
+use reqwest::{StatusCode, Url};
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use tracing::instrument;
+
+use crate::context::RpcContext;
+use crate::db::model::PackageDataEntry;
+use crate::db::util::WithRevision;
+use crate::notifications::NotificationLevel;
+use crate::s9pk::manifest::{Manifest, PackageId};
+use crate::status::health_check::HealthCheckResult;
+use crate::status::MainStatus;
+use crate::util::{display_none, Version};
+use crate::version::{Current, VersionT};
+use crate::{Error, ResultExt};
+
+pub const AUTO_UPDATE_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+/// How long an automatically updated package has to report healthy before it is rolled back.
+pub const AUTO_UPDATE_GRACE_PERIOD: Duration = Duration::from_secs(10 * 60);
+/// Upper bound on how long to wait for the download and install of an update to finish.
+const INSTALL_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AutoUpdatePolicy {
+    Off,
+    Patch,
+    Minor,
+    Any,
+}
+impl AutoUpdatePolicy {
+    /// The versions this policy allows a package at `current` to be updated to.
+    pub fn range(&self, current: &emver::Version) -> Option<VersionRange> {
+        let upper = match self {
+            AutoUpdatePolicy::Off => return None,
+            AutoUpdatePolicy::Patch => Some(emver::Version::new(
+                current.major(),
+                current.minor() + 1,
+                0,
+                0,
+            )),
+            AutoUpdatePolicy::Minor => Some(emver::Version::new(current.major() + 1, 0, 0, 0)),
+            AutoUpdatePolicy::Any => None,
+        };
+        let lower = VersionRange::Anchor(emver::GT, current.clone());
+        Some(match upper {
+            Some(upper) => VersionRange::Conj(
+                Box::new(lower),
+                Box::new(VersionRange::Anchor(emver::LT, upper)),
+            ),
+            None => lower,
+        })
+    }
+}
+impl Default for AutoUpdatePolicy {
+    fn default() -> Self {
+        AutoUpdatePolicy::Off
+    }
+}
+impl std::fmt::Display for AutoUpdatePolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AutoUpdatePolicy::Off => write!(f, "off"),
+            AutoUpdatePolicy::Patch => write!(f, "patch"),
+            AutoUpdatePolicy::Minor => write!(f, "minor"),
+            AutoUpdatePolicy::Any => write!(f, "any"),
+        }
+    }
+}
+impl FromStr for AutoUpdatePolicy {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(AutoUpdatePolicy::Off),
+            "patch" => Ok(AutoUpdatePolicy::Patch),
+            "minor" => Ok(AutoUpdatePolicy::Minor),
+            "any" => Ok(AutoUpdatePolicy::Any),
+            _ => Err(Error::new(
+                eyre!(
+                    "Invalid auto-update policy {}: expected off, patch, minor or any",
+                    s
+                ),
+                crate::ErrorKind::InvalidRequest,
+            )),
+        }
+    }
+}
+
+#[command(rename = "auto-update", display(display_none))]
+#[instrument(skip(ctx))]
+pub async fn auto_update(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[arg] policy: AutoUpdatePolicy,
+) -> Result<WithRevision<()>, Error> {
+    let mut db = ctx.db.handle();
+    let mut tx = db.begin().await?;
+    let installed = crate::db::DatabaseModel::new()
+        .package_data()
+        .idx_model(&id)
+        .and_then(|pkg| pkg.installed())
+        .expect(&mut tx)
+        .await
+        .with_ctx(|_| {
+            (
+                crate::ErrorKind::NotFound,
+                format!("{} is not installed", id),
+            )
+        })?;
+    if policy != AutoUpdatePolicy::Off
+        && installed
+            .clone()
+            .marketplace_url()
+            .get(&mut tx, true)
+            .await?
+            .is_none()
+    {
+        return Err(Error::new(
+            eyre!("{} was not installed from a marketplace", id),
+            crate::ErrorKind::InvalidRequest,
+        ));
+    }
+    installed.auto_update().put(&mut tx, &policy).await?;
+    let revision = tx.commit(None).await?;
+
+    Ok(WithRevision {
+        revision,
+        response: (),
+    })
+}
+
+/// Checks every installed package with an auto-update policy for an update and applies it.
+pub async fn auto_update_check(ctx: &RpcContext) {
+    let candidates = match async {
+        let mut db = ctx.db.handle();
+        Ok::<_, Error>(
+            crate::db::DatabaseModel::new()
+                .package_data()
+                .get(&mut db, false)
+                .await?
+                .0
+                .iter()
+                .filter_map(|(id, pde)| match pde {
+                    PackageDataEntry::Installed { installed, .. } => Some((
+                        id.clone(),
+                        installed.manifest.version.clone(),
+                        installed.auto_update,
+                        installed.marketplace_url.clone()?,
+                    )),
+                    _ => None,
+                })
+                .filter(|(_, _, policy, _)| policy != &AutoUpdatePolicy::Off)
+                .collect::<Vec<_>>(),
+        )
+    }
+    .await
+    {
+        Ok(a) => a,
+        Err(e) => {
+            tracing::error!("Failed to list packages for auto-update: {}", e);
+            tracing::debug!("{:?}", e);
+            return;
+        }
+    };
+    for (id, version, policy, marketplace_url) in candidates {
+        if let Err(e) = auto_update_package(ctx, &id, &version, policy, marketplace_url).await {
+            let err_str = format!("Automatic update of {} failed: {}", id, e);
+            tracing::error!("{}", err_str);
+            tracing::debug!("{:?}", e);
+            if let Err(e) = ctx
+                .notification_manager
+                .notify(
+                    &mut ctx.db.handle(),
+                    Some(id),
+                    NotificationLevel::Error,
+                    String::from("Automatic Update Failed"),
+                    err_str,
+                    (),
+                    None,
+                )
+                .await
+            {
+                tracing::error!("Failed to issue Notification: {}", e);
+                tracing::debug!("{:?}", e);
+            }
+        }
+    }
+}
+
+#[instrument(skip(ctx))]
+async fn auto_update_package(
+    ctx: &RpcContext,
+    id: &PackageId,
+    current: &Version,
+    policy: AutoUpdatePolicy,
+    marketplace_url: Url,
+) -> Result<(), Error> {
+    let range = match policy.range(current) {
+        Some(a) => a,
+        None => return Ok(()),
+    };
+    let res = reqwest::get(format!(
+        "{}/package/v0/manifest/{}?spec={}&eos-version-compat={}&arch={}",
+        marketplace_url,
+        id,
+        range,
+        Current::new().compat(),
+        platforms::TARGET_ARCH,
+    ))
+    .await
+    .with_kind(crate::ErrorKind::Registry)?;
+    if matches!(
+        res.status(),
+        StatusCode::BAD_REQUEST | StatusCode::NOT_FOUND
+    ) {
+        // no version satisfying the policy
+        return Ok(());
+    }
+    let man: Manifest = res
+        .error_for_status()
+        .with_kind(crate::ErrorKind::Registry)?
+        .json()
+        .await
+        .with_kind(crate::ErrorKind::Registry)?;
+    if !man.version.satisfies(&range) {
+        return Ok(());
+    }
+
+    let breakages =
+        crate::install::update::dry(ctx.clone(), id.clone(), man.version.clone()).await?;
+    if !breakages.0.is_empty() {
+        ctx.notification_manager
+            .notify(
+                &mut ctx.db.handle(),
+                Some(id.clone()),
+                NotificationLevel::Warning,
+                String::from("Automatic Update Skipped"),
+                format!(
+                    "Updating {} to {} would break: {}",
+                    id,
+                    man.version,
+                    breakages
+                        .0
+                        .keys()
+                        .map(|k| k.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+                (),
+                None,
+            )
+            .await?;
+        return Ok(());
+    }
+
+    let was_running = main_status(ctx, id).await?.map_or(false, |s| s.running());
+    tracing::info!(
+        "Automatically updating {} from {} to {}",
+        id,
+        current,
+        man.version
+    );
+    crate::install::install(
+        ctx.clone(),
+        id.to_string(),
+        Some(marketplace_url),
+        Some(format!("={}", man.version)),
+    )
+    .await?;
+    if installed_version(ctx, id).await? != Some(man.version.clone()) {
+        // the install task has already reported the failure
+        return Ok(());
+    }
+
+    if was_running && !becomes_healthy(ctx, id).await? {
+        tracing::warn!(
+            "{}@{} did not become healthy, rolling back to {}",
+            id,
+            man.version,
+            current
+        );
+        crate::install::install_from_archive(ctx, id, current).await?;
+        ctx.notification_manager
+            .notify(
+                &mut ctx.db.handle(),
+                Some(id.clone()),
+                NotificationLevel::Error,
+                String::from("Automatic Update Rolled Back"),
+                format!(
+                    "{} {} failed its health checks within {} minutes of updating and was rolled back to {}",
+                    id,
+                    man.version,
+                    AUTO_UPDATE_GRACE_PERIOD.as_secs() / 60,
+                    current
+                ),
+                (),
+                None,
+            )
+            .await?;
+    } else {
+        ctx.notification_manager
+            .notify(
+                &mut ctx.db.handle(),
+                Some(id.clone()),
+                NotificationLevel::Success,
+                String::from("Package Updated"),
+                format!("{} was automatically updated to {}", id, man.version),
+                (),
+                None,
+            )
+            .await?;
+    }
+
+    Ok(())
+}
+
+async fn main_status(ctx: &RpcContext, id: &PackageId) -> Result<Option<MainStatus>, Error> {
+    Ok(crate::db::DatabaseModel::new()
+        .package_data()
+        .idx_model(id)
+        .and_then(|pde| pde.installed())
+        .map(|i| i.status().main())
+        .get(&mut ctx.db.handle(), false)
+        .await?
+        .into_owned())
+}
+
+/// Waits for a pending install of `id` to leave its transient state and returns the installed
+/// version, if any.
+async fn installed_version(ctx: &RpcContext, id: &PackageId) -> Result<Option<Version>, Error> {
+    let started = Instant::now();
+    loop {
+        let mut db = ctx.db.handle();
+        crate::db::DatabaseModel::new()
+            .package_data()
+            .lock(&mut db, LockType::Read)
+            .await?;
+        match &*crate::db::DatabaseModel::new()
+            .package_data()
+            .idx_model(id)
+            .get(&mut db, false)
+            .await?
+        {
+            Some(PackageDataEntry::Installed { manifest, .. }) => {
+                return Ok(Some(manifest.version.clone()))
+            }
+            None => return Ok(None),
+            _ => (),
+        }
+        drop(db);
+        if started.elapsed() > INSTALL_TIMEOUT {
+            return Err(Error::new(
+                eyre!("Timed out waiting for install of {}", id),
+                crate::ErrorKind::Network,
+            ));
+        }
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+/// Returns whether `id` is running with every health check declared in its manifest passing at
+/// some point within [`AUTO_UPDATE_GRACE_PERIOD`]. Checks that have not reported yet count as
+/// failing.
+async fn becomes_healthy(ctx: &RpcContext, id: &PackageId) -> Result<bool, Error> {
+    let declared = crate::db::DatabaseModel::new()
+        .package_data()
+        .idx_model(id)
+        .and_then(|pde| pde.installed())
+        .map(|i| i.manifest().health_checks())
+        .get(&mut ctx.db.handle(), false)
+        .await?
+        .into_owned()
+        .map(|checks| checks.0.into_keys().collect::<Vec<_>>())
+        .unwrap_or_default();
+    let started = Instant::now();
+    while started.elapsed() < AUTO_UPDATE_GRACE_PERIOD {
+        if let Some(MainStatus::Running { health, .. }) = main_status(ctx, id).await? {
+            if declared.iter().all(|check| {
+                matches!(
+                    health.get(check),
+                    Some(HealthCheckResult::Success | HealthCheckResult::Disabled)
+                )
+            }) {
+                return Ok(true);
+            }
+        }
+        tokio::time::sleep(Duration::from_secs(15)).await;
+    }
+    Ok(false)
+}
+
+#[test]
+fn policy_ranges() {
+    let current = emver::Version::new(1, 2, 3, 0);
+    let patch = AutoUpdatePolicy::Patch.range(&current).unwrap();
+    assert!(emver::Version::new(1, 2, 4, 0).satisfies(&patch));
+    assert!(!emver::Version::new(1, 3, 0, 0).satisfies(&patch));
+    assert!(!current.satisfies(&patch));
+    let minor = AutoUpdatePolicy::Minor.range(&current).unwrap();
+    assert!(emver::Version::new(1, 3, 0, 0).satisfies(&minor));
+    assert!(!emver::Version::new(2, 0, 0, 0).satisfies(&minor));
+    let any = AutoUpdatePolicy::Any.range(&current).unwrap();
+    assert!(emver::Version::new(2, 0, 0, 0).satisfies(&any));
+    assert!(AutoUpdatePolicy::Off.range(&current).is_none());
+}
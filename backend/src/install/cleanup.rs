@@ -6,7 +6,7 @@ use patch_db::{DbHandle, LockType, PatchDbHandle};
 use sqlx::{Executor, Sqlite};
 use tracing::instrument;
 
-use super::{PKG_ARCHIVE_DIR, PKG_DOCKER_DIR};
+use super::{PKG_ARCHIVE_DIR, PKG_ARCHIVE_RETENTION, PKG_DOCKER_DIR};
 use crate::context::RpcContext;
 use crate::db::model::{CurrentDependencyInfo, InstalledPackageDataEntry, PackageDataEntry};
 use crate::dependencies::reconfigure_dependents_with_live_pointers;
@@ -77,6 +77,30 @@ pub async fn update_dependency_errors_of_dependents<
 
 #[instrument(skip(ctx))]
 pub async fn cleanup(ctx: &RpcContext, id: &PackageId, version: &Version) -> Result<(), Error> {
+    let mut errors = ErrorCollection::new();
+    errors.handle(cleanup_keep_archive(ctx, id, version).await);
+    let pkg_archive_dir = ctx
+        .datadir
+        .join(PKG_ARCHIVE_DIR)
+        .join(id)
+        .join(version.as_str());
+    if tokio::fs::metadata(&pkg_archive_dir).await.is_ok() {
+        tokio::fs::remove_dir_all(&pkg_archive_dir)
+            .await
+            .apply(|res| errors.handle(res));
+    }
+
+    errors.into_result()
+}
+
+/// Like [`cleanup`], but leaves the archived s9pk in place so the package can later be
+/// reinstalled at this version without downloading it again.
+#[instrument(skip(ctx))]
+pub async fn cleanup_keep_archive(
+    ctx: &RpcContext,
+    id: &PackageId,
+    version: &Version,
+) -> Result<(), Error> {
     let mut errors = ErrorCollection::new();
     ctx.managers.remove(&(id.clone(), version.clone())).await;
     // docker images start9/$APP_ID/*:$VERSION -q | xargs docker rmi
@@ -103,16 +127,6 @@ pub async fn cleanup(ctx: &RpcContext, id: &PackageId, version: &Version) -> Res
         }))
         .await,
     );
-    let pkg_archive_dir = ctx
-        .datadir
-        .join(PKG_ARCHIVE_DIR)
-        .join(id)
-        .join(version.as_str());
-    if tokio::fs::metadata(&pkg_archive_dir).await.is_ok() {
-        tokio::fs::remove_dir_all(&pkg_archive_dir)
-            .await
-            .apply(|res| errors.handle(res));
-    }
     let docker_path = ctx
         .datadir
         .join(PKG_DOCKER_DIR)
@@ -127,11 +141,63 @@ pub async fn cleanup(ctx: &RpcContext, id: &PackageId, version: &Version) -> Res
     errors.into_result()
 }
 
+/// Removes all but the newest [`PKG_ARCHIVE_RETENTION`] archived versions of `id`, never
+/// removing `current`.
+#[instrument(skip(ctx))]
+pub async fn prune_archives(
+    ctx: &RpcContext,
+    id: &PackageId,
+    current: &Version,
+) -> Result<(), Error> {
+    let archive_dir = ctx.datadir.join(PKG_ARCHIVE_DIR).join(id);
+    if tokio::fs::metadata(&archive_dir).await.is_err() {
+        return Ok(());
+    }
+    let mut versions = Vec::new();
+    let mut dir = tokio::fs::read_dir(&archive_dir).await?;
+    while let Some(entry) = dir.next_entry().await? {
+        if let Some(Ok(version)) = entry.file_name().to_str().map(|v| v.parse::<Version>()) {
+            versions.push(version);
+        }
+    }
+    versions.sort_by(|a, b| b.cmp(a));
+    let mut errors = ErrorCollection::new();
+    for version in versions
+        .into_iter()
+        .skip(PKG_ARCHIVE_RETENTION)
+        .filter(|v| v != current)
+    {
+        tracing::info!("Pruning archived {}@{}", id, version);
+        errors.handle(tokio::fs::remove_dir_all(archive_dir.join(version.as_str())).await);
+    }
+    errors.into_result()
+}
+
 #[instrument(skip(ctx, db))]
 pub async fn cleanup_failed<Db: DbHandle>(
     ctx: &RpcContext,
     db: &mut Db,
     id: &PackageId,
+) -> Result<(), Error> {
+    cleanup_failed_impl(ctx, db, id, false).await
+}
+
+/// Like [`cleanup_failed`], for installs that were read from an existing archive: the archive is
+/// kept since it was not written by the failed install.
+#[instrument(skip(ctx, db))]
+pub async fn cleanup_failed_from_archive<Db: DbHandle>(
+    ctx: &RpcContext,
+    db: &mut Db,
+    id: &PackageId,
+) -> Result<(), Error> {
+    cleanup_failed_impl(ctx, db, id, true).await
+}
+
+async fn cleanup_failed_impl<Db: DbHandle>(
+    ctx: &RpcContext,
+    db: &mut Db,
+    id: &PackageId,
+    keep_archive: bool,
 ) -> Result<(), Error> {
     crate::db::DatabaseModel::new()
         .package_data()
@@ -168,7 +234,11 @@ pub async fn cleanup_failed<Db: DbHandle>(
             None
         }
     } {
-        cleanup(ctx, id, &manifest.version).await?;
+        if keep_archive {
+            cleanup_keep_archive(ctx, id, &manifest.version).await?;
+        } else {
+            cleanup(ctx, id, &manifest.version).await?;
+        }
     }
 
     match pde {
@@ -242,6 +312,7 @@ pub async fn uninstall<Ex>(
     id: &PackageId,
 ) -> Result<(), Error>
 where
+    Ex: Send,
     for<'a> &'a mut Ex: Executor<'a, Database = Sqlite>,
 {
     let mut tx = db.begin().await?;
@@ -270,7 +341,7 @@ where
         .await?;
 
     // once we have removed the package entry, we can change all the dependent pointers to null
-    reconfigure_dependents_with_live_pointers(ctx, &mut tx, &entry).await?;
+    reconfigure_dependents_with_live_pointers(ctx, &mut tx, secrets, &entry).await?;
 
     remove_from_current_dependents_lists(
         &mut tx,
@@ -292,8 +363,16 @@ where
     if tokio::fs::metadata(&volumes).await.is_ok() {
         tokio::fs::remove_dir_all(&volumes).await?;
     }
+    let archives = ctx.datadir.join(PKG_ARCHIVE_DIR).join(&entry.manifest.id);
+    if tokio::fs::metadata(&archives).await.is_ok() {
+        tokio::fs::remove_dir_all(&archives).await?;
+    }
     tx.commit(None).await?;
     remove_tor_keys(secrets, &entry.manifest.id).await?;
+    crate::config::secret::remove(secrets, &entry.manifest.id).await?;
+    crate::config::history::remove(secrets, &entry.manifest.id).await?;
+    crate::net::access::remove_users(secrets, &entry.manifest.id).await?;
+    crate::volume::files::remove_audit(secrets, &entry.manifest.id).await?;
     Ok(())
 }
 
@@ -304,6 +383,14 @@ where
 {
     let id_str = id.as_str();
     sqlx::query!("DELETE FROM tor WHERE package = ?", id_str)
+        .execute(&mut *secrets)
+        .await?;
+    sqlx::query("DELETE FROM tor_client_auth WHERE package = ?")
+        .bind(id_str)
+        .execute(&mut *secrets)
+        .await?;
+    sqlx::query("DELETE FROM tor_client_auth_restricted WHERE package = ?")
+        .bind(id_str)
         .execute(secrets)
         .await?;
     Ok(())
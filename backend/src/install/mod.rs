@@ -5,7 +5,7 @@ use std::path::{Path, PathBuf};
 use std::process::Stdio;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::Instant;
 
 use color_eyre::eyre::eyre;
 use emver::VersionRange;
@@ -24,7 +24,11 @@ use tokio::process::Command;
 use tokio_stream::wrappers::ReadDirStream;
 use tracing::instrument;
 
-use self::cleanup::{cleanup_failed, remove_from_current_dependents_lists};
+use self::auto_update::AutoUpdatePolicy;
+use self::cleanup::{
+    cleanup_failed, cleanup_failed_from_archive, cleanup_keep_archive, prune_archives,
+    remove_from_current_dependents_lists,
+};
 use crate::context::{CliContext, RpcContext};
 use crate::core::rpc_continuations::{RequestGuid, RpcContinuation};
 use crate::db::model::{
@@ -36,8 +40,12 @@ use crate::dependencies::{
     add_dependent_to_current_dependents_lists, break_all_dependents_transitive,
     reconfigure_dependents_with_live_pointers, BreakageRes, DependencyError, DependencyErrors,
 };
-use crate::install::cleanup::{cleanup, update_dependency_errors_of_dependents};
+use crate::install::cleanup::update_dependency_errors_of_dependents;
 use crate::install::progress::{InstallProgress, InstallProgressTracker};
+use crate::net::dns::{DNS_PORT, TOR_DNS_PORT};
+use crate::net::interface::{LanPortConfig, LanProtocol};
+use crate::net::mdns::MDNS_PORT;
+use crate::net::vpn::DEFAULT_VPN_PORT;
 use crate::notifications::NotificationLevel;
 use crate::s9pk::manifest::{Manifest, PackageId};
 use crate::s9pk::reader::S9pkReader;
@@ -49,11 +57,15 @@ use crate::version::{Current, VersionT};
 use crate::volume::asset_dir;
 use crate::{Error, ErrorKind, ResultExt};
 
+pub mod auto_update;
 pub mod cleanup;
 pub mod progress;
+pub mod rollback;
 pub mod update;
 
 pub const PKG_ARCHIVE_DIR: &'static str = "package-data/archive";
+/// Number of previously installed versions of each package whose s9pk is kept for rollback.
+pub const PKG_ARCHIVE_RETENTION: usize = 3;
 pub const PKG_PUBLIC_DIR: &'static str = "package-data/public";
 pub const PKG_DOCKER_DIR: &'static str = "package-data/docker";
 pub const PKG_WASM_DIR: &'static str = "package-data/wasm";
@@ -135,32 +147,19 @@ pub async fn install(
             None => {}
         }
     }
-    // Build map of current port -> ssl mappings
-    let port_map = ssl_port_status(manifests);
+    // Build map of current port -> lan configs
+    let port_map = lan_port_status(manifests);
 
     // if any of the requested interface lan configs conflict with current state, fail the install
-    for (id, iface) in &man.interfaces.0 {
+    for (_, iface) in &man.interfaces.0 {
         if let Some(cfg) = &iface.lan_config {
             for (p, lan) in cfg {
-                if p.0 == 80 && lan.ssl || p.0 == 443 && !lan.ssl {
+                if let Some(conflict) = lan_port_conflict(&man.id, *p, lan, &port_map) {
                     return Err(Error::new(
-                        eyre!("SSL Conflict with EmbassyOS"),
+                        eyre!("{}", conflict),
                         ErrorKind::LanPortConflict,
                     ));
                 }
-                match port_map.get(&p) {
-                    Some((ssl, pkg)) => {
-                        if *ssl != lan.ssl {
-                            return Err(Error::new(
-                                eyre!("SSL Conflict with package: {}", pkg),
-                                ErrorKind::LanPortConflict,
-                            ));
-                        }
-                    }
-                    None => {
-                        continue;
-                    }
-                }
             }
         }
     }
@@ -454,19 +453,7 @@ pub async fn sideload(
         created_at: Instant::now(), // TODO
         handler: handler,
     };
-    // gc the map
-    let mut guard = ctx.rpc_stream_continuations.lock().await;
-    let garbage_collected = std::mem::take(&mut *guard)
-        .into_iter()
-        .filter(|(_, v)| v.created_at.elapsed() < Duration::from_secs(30))
-        .collect::<BTreeMap<RequestGuid, RpcContinuation>>();
-    *guard = garbage_collected;
-    drop(guard);
-    // insert the new continuation
-    ctx.rpc_stream_continuations
-        .lock()
-        .await
-        .insert(guid.clone(), cont);
+    ctx.add_continuation(guid.clone(), cont).await;
     Ok(guid)
 }
 
@@ -762,6 +749,109 @@ pub async fn download_install_s9pk(
     }
 }
 
+/// Reinstalls `pkg_id@version` from the s9pk kept in [`PKG_ARCHIVE_DIR`] by a previous install of
+/// that version. The package must currently be installed.
+#[instrument(skip(ctx))]
+pub async fn install_from_archive(
+    ctx: &RpcContext,
+    pkg_id: &PackageId,
+    version: &Version,
+) -> Result<(), Error> {
+    let pkg_archive = ctx
+        .datadir
+        .join(PKG_ARCHIVE_DIR)
+        .join(pkg_id)
+        .join(version.as_str())
+        .join(AsRef::<Path>::as_ref(pkg_id).with_extension("s9pk"));
+    if tokio::fs::metadata(&pkg_archive).await.is_err() {
+        return Err(Error::new(
+            eyre!("No archived s9pk for {}@{}", pkg_id, version),
+            crate::ErrorKind::NotFound,
+        ));
+    }
+    let manifest = S9pkReader::open(&pkg_archive, true)
+        .await?
+        .manifest()
+        .await?;
+    if &manifest.id != pkg_id || &manifest.version != version {
+        return Err(Error::new(
+            eyre!("Archived package does not match requested id and version"),
+            crate::ErrorKind::Incoherent,
+        ));
+    }
+    let size = tokio::fs::metadata(&pkg_archive).await?.len();
+    let progress = InstallProgress::new(Some(size));
+    progress.downloaded.store(size, Ordering::SeqCst);
+    progress.download_complete();
+
+    let model = crate::db::DatabaseModel::new()
+        .package_data()
+        .idx_model(pkg_id);
+    let mut handle = ctx.db.handle();
+    let mut tx = handle.begin().await?;
+    let mut pde = model.clone().get_mut(&mut tx).await?;
+    let marketplace_url = match pde.take() {
+        Some(PackageDataEntry::Installed {
+            installed,
+            static_files,
+            ..
+        }) => {
+            let marketplace_url = installed.marketplace_url.clone();
+            *pde = Some(PackageDataEntry::Updating {
+                install_progress: progress.clone(),
+                static_files,
+                installed,
+                manifest,
+            });
+            marketplace_url
+        }
+        _ => {
+            return Err(Error::new(
+                eyre!("{} is not installed", pkg_id),
+                crate::ErrorKind::InvalidRequest,
+            ))
+        }
+    };
+    pde.save(&mut tx).await?;
+    tx.commit(None).await?;
+    drop(handle);
+
+    if let Err(e) = async {
+        let progress_model = model.and_then(|pde| pde.install_progress());
+        let progress_reader =
+            InstallProgressTracker::new(File::open(&pkg_archive).await?, progress.clone());
+        let mut s9pk_reader = progress
+            .track_read_during(progress_model, &ctx.db, || {
+                S9pkReader::from_reader(progress_reader, true)
+            })
+            .await?;
+        install_s9pk(
+            ctx,
+            pkg_id,
+            version,
+            marketplace_url,
+            &mut s9pk_reader,
+            progress,
+        )
+        .await
+    }
+    .await
+    {
+        let mut handle = ctx.db.handle();
+        let mut tx = handle.begin().await?;
+
+        if let Err(e) = cleanup_failed_from_archive(ctx, &mut tx, pkg_id).await {
+            tracing::error!("Failed to clean up {}@{}: {}", pkg_id, version, e);
+            tracing::debug!("{:?}", e);
+        } else {
+            tx.commit(None).await?;
+        }
+        Err(e)
+    } else {
+        Ok(())
+    }
+}
+
 #[instrument(skip(ctx, rdr))]
 pub async fn install_s9pk<R: AsyncRead + AsyncSeek + Unpin>(
     ctx: &RpcContext,
@@ -1025,7 +1115,7 @@ pub async fn install_s9pk<R: AsyncRead + AsyncSeek + Unpin>(
     tracing::info!("Install {}@{}: Created volumes", pkg_id, version);
 
     tracing::info!("Install {}@{}: Installing interfaces", pkg_id, version);
-    let interface_addresses = manifest.interfaces.install(&mut sql_tx, pkg_id).await?;
+    let mut interface_addresses = manifest.interfaces.install(&mut sql_tx, pkg_id).await?;
     tracing::info!("Install {}@{}: Installed interfaces", pkg_id, version);
 
     tracing::info!("Install {}@{}: Creating manager", pkg_id, version);
@@ -1104,6 +1194,14 @@ pub async fn install_s9pk<R: AsyncRead + AsyncSeek + Unpin>(
         .await?
         .get_mut(&mut tx)
         .await?;
+    if let PackageDataEntry::Updating { installed, .. } = &*pde {
+        // custom domains survive updates of interfaces that still exist
+        for (id, addrs) in interface_addresses.0.iter_mut() {
+            if let Some(prev) = installed.interface_addresses.0.get(id) {
+                addrs.domains = prev.domains.clone();
+            }
+        }
+    }
     let installed = InstalledPackageDataEntry {
         status: Status {
             configured: manifest.config.is_none(),
@@ -1125,10 +1223,32 @@ pub async fn install_s9pk<R: AsyncRead + AsyncSeek + Unpin>(
             _ => None,
         },
         system_pointers: Vec::new(),
+        auto_update: match &*pde {
+            PackageDataEntry::Updating { installed, .. } => installed.auto_update,
+            _ => AutoUpdatePolicy::default(),
+        },
         dependency_info,
         current_dependents: current_dependents.clone(),
         current_dependencies: current_dependencies.clone(),
         interface_addresses,
+        access_policies: match &*pde {
+            PackageDataEntry::Updating { installed, .. } => installed
+                .access_policies
+                .iter()
+                .filter(|(id, _)| manifest.interfaces.0.contains_key(*id))
+                .map(|(id, policy)| (id.clone(), policy.clone()))
+                .collect(),
+            _ => BTreeMap::new(),
+        },
+        volume_usage: match &*pde {
+            PackageDataEntry::Updating { installed, .. } => installed
+                .volume_usage
+                .iter()
+                .filter(|(id, _)| manifest.volumes.contains_key(*id))
+                .map(|(id, usage)| (id.clone(), usage.clone()))
+                .collect(),
+            _ => BTreeMap::new(),
+        },
     };
 
     let prev = std::mem::replace(
@@ -1187,8 +1307,37 @@ pub async fn install_s9pk<R: AsyncRead + AsyncSeek + Unpin>(
             migration.or(prev_migration)
         };
 
+        let snapshot = crate::snapshot::try_create(
+            ctx,
+            pkg_id,
+            &prev_manifest.version,
+            &format!("update from {} to {}", prev_manifest.version, version),
+        )
+        .await;
         let configured = if let Some(f) = viable_migration {
-            f.await?.configured && prev_is_configured
+            match f.await {
+                Ok(res) => res.configured && prev_is_configured,
+                Err(e) => {
+                    if let Some(snapshot) = &snapshot {
+                        tracing::warn!(
+                            "Install {}@{}: Migration failed, restoring snapshot {}",
+                            pkg_id,
+                            version,
+                            snapshot.id
+                        );
+                        if let Err(e) = crate::snapshot::restore(ctx, pkg_id, &snapshot.id).await {
+                            tracing::error!(
+                                "Failed to restore snapshot {} of {}: {}",
+                                snapshot.id,
+                                pkg_id,
+                                e
+                            );
+                            tracing::debug!("{:?}", e);
+                        }
+                    }
+                    return Err(e);
+                }
+            }
         } else {
             false
         };
@@ -1196,10 +1345,12 @@ pub async fn install_s9pk<R: AsyncRead + AsyncSeek + Unpin>(
             crate::config::configure(
                 ctx,
                 &mut tx,
+                &mut sql_tx,
                 pkg_id,
                 None,
                 &None,
                 false,
+                None,
                 &mut BTreeMap::new(),
                 &mut BTreeMap::new(),
             )
@@ -1233,7 +1384,8 @@ pub async fn install_s9pk<R: AsyncRead + AsyncSeek + Unpin>(
         )
         .await?;
         if &prev.manifest.version != version {
-            cleanup(ctx, &prev.manifest.id, &prev.manifest.version).await?;
+            cleanup_keep_archive(ctx, &prev.manifest.id, &prev.manifest.version).await?;
+            prune_archives(ctx, pkg_id, version).await?;
         }
     } else if let PackageDataEntry::Restoring { .. } = prev {
         manifest
@@ -1264,7 +1416,16 @@ pub async fn install_s9pk<R: AsyncRead + AsyncSeek + Unpin>(
             .await?
             .into_owned()
     } {
-        handle_recovered_package(recovered, manifest, ctx, pkg_id, version, &mut tx).await?;
+        handle_recovered_package(
+            recovered,
+            manifest,
+            ctx,
+            pkg_id,
+            version,
+            &mut tx,
+            &mut sql_tx,
+        )
+        .await?;
         add_dependent_to_current_dependents_lists(&mut tx, pkg_id, &current_dependencies).await?;
         update_dependency_errors_of_dependents(ctx, &mut tx, pkg_id, current_dependents.keys())
             .await?;
@@ -1280,7 +1441,7 @@ pub async fn install_s9pk<R: AsyncRead + AsyncSeek + Unpin>(
         .await?;
 
     if let Some(installed) = pde.installed() {
-        reconfigure_dependents_with_live_pointers(ctx, &mut tx, installed).await?;
+        reconfigure_dependents_with_live_pointers(ctx, &mut tx, &mut sql_tx, installed).await?;
     }
 
     sql_tx.commit().await?;
@@ -1291,7 +1452,7 @@ pub async fn install_s9pk<R: AsyncRead + AsyncSeek + Unpin>(
     Ok(())
 }
 
-#[instrument(skip(ctx, tx))]
+#[instrument(skip(ctx, tx, sql_tx))]
 async fn handle_recovered_package(
     recovered: RecoveredPackageInfo,
     manifest: Manifest,
@@ -1299,6 +1460,7 @@ async fn handle_recovered_package(
     pkg_id: &PackageId,
     version: &Version,
     tx: &mut patch_db::Transaction<&mut patch_db::PatchDbHandle>,
+    sql_tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
 ) -> Result<(), Error> {
     let configured = if let Some(migration) =
         manifest
@@ -1313,10 +1475,12 @@ async fn handle_recovered_package(
         crate::config::configure(
             ctx,
             tx,
+            sql_tx,
             pkg_id,
             None,
             &None,
             false,
+            None,
             &mut BTreeMap::new(),
             &mut BTreeMap::new(),
         )
@@ -1387,15 +1551,30 @@ pub fn load_images<'a, P: AsRef<Path> + 'a + Send + Sync>(
     .boxed()
 }
 
-fn ssl_port_status(manifests: Vec<Manifest>) -> BTreeMap<Port, (bool, PackageId)> {
-    let mut ret = BTreeMap::new();
+/// Ports bound on all addresses by EmbassyOS itself, which a package may not forward.
+const RESERVED_TCP_PORTS: &[u16] = &[22, 80, 443, 5959, 5960, 9050, 9051];
+/// Http ports are served by nginx, which may share 80 and 443 with packages as long as they
+/// agree on ssl.
+const RESERVED_HTTP_PORTS: &[u16] = &[22, 5959, 5960, 9050, 9051];
+const RESERVED_UDP_PORTS: &[u16] = &[DNS_PORT, MDNS_PORT, TOR_DNS_PORT, DEFAULT_VPN_PORT];
+
+fn reserved_lan_ports(protocol: LanProtocol) -> &'static [u16] {
+    match protocol {
+        LanProtocol::Http => RESERVED_HTTP_PORTS,
+        LanProtocol::Tcp => RESERVED_TCP_PORTS,
+        LanProtocol::Udp => RESERVED_UDP_PORTS,
+    }
+}
+
+fn lan_port_status(manifests: Vec<Manifest>) -> BTreeMap<Port, Vec<(LanPortConfig, PackageId)>> {
+    let mut ret = BTreeMap::<Port, Vec<_>>::new();
     for m in manifests {
-        for (id, iface) in m.interfaces.0 {
+        for (_, iface) in m.interfaces.0 {
             match iface.lan_config {
                 None => {}
                 Some(cfg) => {
                     for (p, lan) in cfg {
-                        ret.insert(p, (lan.ssl, m.id))
+                        ret.entry(p).or_default().push((lan, m.id.clone()))
                     }
                 }
             }
@@ -1403,3 +1582,75 @@ fn ssl_port_status(manifests: Vec<Manifest>) -> BTreeMap<Port, (bool, PackageId)
     }
     ret
 }
+
+/// Why `package` cannot expose `lan` on `port`, if it cannot. Http ports may be shared between
+/// packages as long as they agree on ssl, while tcp and udp ports are owned by a single package.
+fn lan_port_conflict(
+    package: &PackageId,
+    port: Port,
+    lan: &LanPortConfig,
+    port_map: &BTreeMap<Port, Vec<(LanPortConfig, PackageId)>>,
+) -> Option<String> {
+    match lan.protocol {
+        LanProtocol::Http if port.0 == 80 && lan.ssl || port.0 == 443 && !lan.ssl => {
+            return Some("SSL Conflict with EmbassyOS".to_owned());
+        }
+        _ => (),
+    }
+    if reserved_lan_ports(lan.protocol).contains(&port.0) {
+        return Some(format!("Port {} is reserved by EmbassyOS", port.0));
+    }
+    for (other, pkg) in port_map.get(&port).into_iter().flatten() {
+        if pkg == package {
+            continue;
+        }
+        match (lan.protocol, other.protocol) {
+            (LanProtocol::Http, LanProtocol::Http) if other.ssl != lan.ssl => {
+                return Some(format!("SSL Conflict with package: {}", pkg));
+            }
+            (LanProtocol::Http, LanProtocol::Http) => (),
+            (LanProtocol::Udp, LanProtocol::Udp) => {
+                return Some(format!("UDP port {} is in use by package: {}", port.0, pkg));
+            }
+            (LanProtocol::Udp, _) | (_, LanProtocol::Udp) => (),
+            _ => {
+                return Some(format!("TCP port {} is in use by package: {}", port.0, pkg));
+            }
+        }
+    }
+    None
+}
+
+#[test]
+fn test_lan_port_conflict() {
+    let lan = |protocol, ssl| LanPortConfig {
+        ssl,
+        internal: 1,
+        protocol,
+    };
+    let a: PackageId = "a".parse().unwrap();
+    let b: PackageId = "b".parse().unwrap();
+    let mut port_map = BTreeMap::new();
+    port_map.insert(Port(8080), vec![(lan(LanProtocol::Http, false), a.clone())]);
+    port_map.insert(Port(1883), vec![(lan(LanProtocol::Tcp, false), a.clone())]);
+    let conflict = |port, protocol, ssl| {
+        lan_port_conflict(&b, Port(port), &lan(protocol, ssl), &port_map).is_some()
+    };
+    assert!(!conflict(8080, LanProtocol::Http, false));
+    assert!(conflict(8080, LanProtocol::Http, true));
+    assert!(conflict(8080, LanProtocol::Tcp, false));
+    assert!(!conflict(8080, LanProtocol::Udp, false));
+    assert!(conflict(1883, LanProtocol::Http, false));
+    assert!(!conflict(1883, LanProtocol::Udp, false));
+    assert!(conflict(443, LanProtocol::Tcp, true));
+    assert!(conflict(22, LanProtocol::Http, false));
+    assert!(conflict(5959, LanProtocol::Http, true));
+    assert!(conflict(5960, LanProtocol::Http, false));
+    assert!(!conflict(443, LanProtocol::Http, true));
+    assert!(conflict(53, LanProtocol::Udp, false));
+    assert!(conflict(5353, LanProtocol::Udp, false));
+    assert!(conflict(9053, LanProtocol::Udp, false));
+    assert!(conflict(51820, LanProtocol::Udp, false));
+    assert!(!conflict(53, LanProtocol::Tcp, false));
+    assert!(lan_port_conflict(&a, Port(1883), &lan(LanProtocol::Tcp, true), &port_map).is_none());
+}
@@ -0,0 +1,183 @@
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use color_eyre::eyre::eyre;
+use rpc_toolkit::command;
+use tracing::instrument;
+
+use super::PKG_ARCHIVE_DIR;
+use crate::context::RpcContext;
+use crate::db::model::InstalledPackageDataEntry;
+use crate::db::util::WithRevision;
+use crate::notifications::NotificationLevel;
+use crate::s9pk::manifest::PackageId;
+use crate::util::{display_none, Version};
+use crate::Error;
+
+/// Versions of `pkg_id` that have an archived s9pk available to reinstall.
+#[instrument(skip(ctx))]
+pub async fn archived_versions(
+    ctx: &RpcContext,
+    pkg_id: &PackageId,
+) -> Result<BTreeSet<Version>, Error> {
+    let archive_dir = ctx.datadir.join(PKG_ARCHIVE_DIR).join(pkg_id);
+    let mut versions = BTreeSet::new();
+    if tokio::fs::metadata(&archive_dir).await.is_err() {
+        return Ok(versions);
+    }
+    let mut dir = tokio::fs::read_dir(&archive_dir).await?;
+    while let Some(entry) = dir.next_entry().await? {
+        if let Some(Ok(version)) = entry.file_name().to_str().map(|v| v.parse::<Version>()) {
+            if tokio::fs::metadata(
+                entry
+                    .path()
+                    .join(AsRef::<Path>::as_ref(pkg_id).with_extension("s9pk")),
+            )
+            .await
+            .is_ok()
+            {
+                versions.insert(version);
+            }
+        }
+    }
+    Ok(versions)
+}
+
+/// Reinstalls a previously installed version of a package from its archived s9pk, running the
+/// reverse migration. The package is stopped first so its volumes are snapshotted at rest.
+#[command(display(display_none))]
+#[instrument(skip(ctx))]
+pub async fn rollback(
+    #[context] ctx: RpcContext,
+    #[arg] id: PackageId,
+    #[arg(long = "version")] version: Option<Version>,
+) -> Result<WithRevision<()>, Error> {
+    let installed: InstalledPackageDataEntry = crate::db::DatabaseModel::new()
+        .package_data()
+        .idx_model(&id)
+        .and_then(|pde| pde.installed())
+        .get(&mut ctx.db.handle(), false)
+        .await?
+        .into_owned()
+        .ok_or_else(|| Error::new(eyre!("{} is not installed", id), crate::ErrorKind::NotFound))?;
+    let current = installed.manifest.version.clone();
+    let archived = archived_versions(&ctx, &id).await?;
+    let target = match version {
+        Some(version) => {
+            if !archived.contains(&version) {
+                return Err(Error::new(
+                    eyre!("No archived s9pk for {}@{}", id, version),
+                    crate::ErrorKind::NotFound,
+                ));
+            }
+            if version >= current {
+                return Err(Error::new(
+                    eyre!(
+                        "{} is not older than the installed version {}",
+                        version,
+                        current
+                    ),
+                    crate::ErrorKind::InvalidRequest,
+                ));
+            }
+            version
+        }
+        None => archived
+            .into_iter()
+            .filter(|v| v < &current)
+            .next_back()
+            .ok_or_else(|| {
+                Error::new(
+                    eyre!("No archived version of {} older than {}", id, current),
+                    crate::ErrorKind::NotFound,
+                )
+            })?,
+    };
+
+    let was_running = installed.status.main.running();
+    let revision = if was_running {
+        crate::control::stop_impl(ctx.clone(), id.clone())
+            .await?
+            .revision
+    } else {
+        None
+    };
+
+    tokio::spawn(async move {
+        if let Err(e) = rollback_impl(&ctx, &id, &current, &target, was_running).await {
+            let err_str = format!("Rollback of {} to {} Failed: {}", id, target, e);
+            tracing::error!("{}", err_str);
+            tracing::debug!("{:?}", e);
+            if let Err(e) = ctx
+                .notification_manager
+                .notify(
+                    &mut ctx.db.handle(),
+                    Some(id),
+                    NotificationLevel::Error,
+                    String::from("Rollback Failed"),
+                    err_str,
+                    (),
+                    None,
+                )
+                .await
+            {
+                tracing::error!("Failed to issue Notification: {}", e);
+                tracing::debug!("{:?}", e);
+            }
+        }
+    });
+
+    Ok(WithRevision {
+        revision,
+        response: (),
+    })
+}
+
+#[instrument(skip(ctx))]
+async fn rollback_impl(
+    ctx: &RpcContext,
+    id: &PackageId,
+    current: &Version,
+    target: &Version,
+    was_running: bool,
+) -> Result<(), Error> {
+    if let Some(manager) = ctx.managers.get(&(id.clone(), current.clone())).await {
+        manager.synchronize().await;
+    }
+
+    // install_s9pk snapshots the data volumes before migrating and restores them if the
+    // down-migration fails
+    if let Err(e) = super::install_from_archive(ctx, id, target).await {
+        if was_running {
+            crate::control::start(ctx.clone(), id.clone()).await?;
+        }
+        return Err(e);
+    }
+
+    let configured = crate::db::DatabaseModel::new()
+        .package_data()
+        .idx_model(id)
+        .and_then(|pde| pde.installed())
+        .map(|i| i.status().configured())
+        .get(&mut ctx.db.handle(), false)
+        .await?
+        .into_owned()
+        .unwrap_or(false);
+    if was_running && configured {
+        crate::control::start(ctx.clone(), id.clone()).await?;
+    }
+
+    ctx.notification_manager
+        .notify(
+            &mut ctx.db.handle(),
+            Some(id.clone()),
+            NotificationLevel::Success,
+            String::from("Rollback Complete"),
+            format!("{} was rolled back from {} to {}", id, current, target),
+            (),
+            None,
+        )
+        .await?;
+
+    Ok(())
+}
@@ -32,9 +32,11 @@ pub mod migration;
 pub mod net;
 pub mod notifications;
 pub mod properties;
+pub mod registry;
 pub mod s9pk;
 pub mod setup;
 pub mod shutdown;
+pub mod snapshot;
 pub mod sound;
 pub mod ssh;
 pub mod static_server;
@@ -95,13 +97,19 @@ pub fn server() -> Result<(), RpcError> {
     install::delete_recovered,
     install::list,
     install::update::update,
+    install::auto_update::auto_update,
+    install::rollback::rollback,
     config::config,
+    config::bundle::export,
+    config::bundle::import,
     control::start,
     control::stop,
     logs::logs,
     properties::properties,
     dependencies::dependency,
     backup::package_backup,
+    snapshot::snapshot,
+    volume::files::volume,
 ))]
 pub fn package() -> Result<(), RpcError> {
     Ok(())
@@ -112,7 +120,8 @@ pub fn package() -> Result<(), RpcError> {
     s9pk::pack,
     developer::verify,
     developer::init,
-    inspect::inspect
+    inspect::inspect,
+    registry::registry,
 ))]
 pub fn portable_api() -> Result<(), RpcError> {
     Ok(())
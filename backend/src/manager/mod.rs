@@ -242,10 +242,43 @@ async fn run_main(
         }
     }
 
+    let domains = crate::db::DatabaseModel::new()
+        .package_data()
+        .idx_model(&state.manifest.id)
+        .and_then(|pde| pde.installed())
+        .map(|i| i.interface_addresses())
+        .get(&mut state.ctx.db.handle(), false)
+        .await?
+        .iter()
+        .flat_map(|addrs| addrs.0.iter())
+        .map(|(id, addrs)| (id.clone(), addrs.domains.clone()))
+        .collect();
+    let access = crate::db::DatabaseModel::new()
+        .package_data()
+        .idx_model(&state.manifest.id)
+        .and_then(|pde| pde.installed())
+        .map(|i| i.access_policies())
+        .get(&mut state.ctx.db.handle(), false)
+        .await?
+        .into_owned()
+        .unwrap_or_default();
+    let tor_auth = crate::net::tor::authorized_clients(
+        &mut state.ctx.secret_store.acquire().await?,
+        &state.manifest.id,
+    )
+    .await?;
     state
         .ctx
         .net_controller
-        .add(&state.manifest.id, ip, interfaces, generated_certificate)
+        .add(
+            &state.manifest.id,
+            ip,
+            interfaces,
+            domains,
+            access,
+            tor_auth,
+            generated_certificate,
+        )
         .await?;
 
     state
@@ -1,20 +1,39 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use clap::ArgMatches;
 use color_eyre::eyre::eyre;
-use reqwest::{StatusCode, Url};
+use patch_db::DbHandle;
+use reqwest::{Response, StatusCode, Url};
 use rpc_toolkit::command;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use tokio::sync::RwLock;
+use tracing::instrument;
 
+use crate::context::RpcContext;
+use crate::db::model::PackageDataEntry;
+use crate::db::util::WithRevision;
+use crate::s9pk::manifest::{Description, PackageId};
+use crate::util::serde::{display_serializable, IoFormat};
+use crate::util::{display_none, Version};
+use crate::version::{Current, VersionT};
 use crate::{Error, ResultExt};
 
-#[command(subcommands(get))]
+/// How long a fetched registry index is considered fresh.
+pub const CATALOGUE_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+const INDEX_PAGE_SIZE: usize = 100;
+/// Registries that page past this are assumed to be broken.
+const MAX_INDEX_PAGES: usize = 100;
+
+#[command(subcommands(get, list, search, refresh, registry))]
 pub fn marketplace() -> Result<(), Error> {
     Ok(())
 }
 
-#[command]
-pub async fn get(#[arg] url: Url) -> Result<Value, Error> {
-    let response = reqwest::get(url)
-        .await
-        .with_kind(crate::ErrorKind::Network)?;
+async fn handle_response<T: DeserializeOwned>(response: Response) -> Result<T, Error> {
     let status = response.status();
     if status.is_success() {
         response
@@ -33,3 +52,402 @@ pub async fn get(#[arg] url: Url) -> Result<Value, Error> {
         ))
     }
 }
+
+#[command]
+pub async fn get(#[arg] url: Url) -> Result<Value, Error> {
+    handle_response(
+        reqwest::get(url)
+            .await
+            .with_kind(crate::ErrorKind::Network)?,
+    )
+    .await
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct IndexManifest {
+    pub id: PackageId,
+    pub title: String,
+    pub version: Version,
+    pub description: Description,
+    #[serde(default)]
+    pub eos_version: Option<Version>,
+}
+
+/// A single package as returned by a registry's `/package/v0/index`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct IndexEntry {
+    pub icon: String,
+    pub manifest: IndexManifest,
+    #[serde(default)]
+    pub categories: BTreeSet<String>,
+    #[serde(default)]
+    pub versions: Vec<Version>,
+}
+impl IndexEntry {
+    /// The registry already filters by `eos-version-compat`, but registries are not obligated to
+    /// report the eos version, so this only rejects entries that are known to be incompatible.
+    fn is_compatible(&self) -> bool {
+        self.manifest
+            .eos_version
+            .as_ref()
+            .map_or(true, |v| v.satisfies(Current::new().compat()))
+    }
+}
+
+#[derive(Debug)]
+pub struct CachedRegistry {
+    pub fetched: DateTime<Utc>,
+    pub packages: BTreeMap<PackageId, IndexEntry>,
+}
+
+#[derive(Debug, Default)]
+pub struct MarketplaceCache(RwLock<BTreeMap<Url, CachedRegistry>>);
+impl MarketplaceCache {
+    #[instrument]
+    async fn fetch_index(url: &Url) -> Result<BTreeMap<PackageId, IndexEntry>, Error> {
+        let mut packages = BTreeMap::new();
+        let mut seen = BTreeSet::new();
+        for page in 1..=MAX_INDEX_PAGES {
+            let entries: Vec<IndexEntry> = handle_response(
+                reqwest::get(format!(
+                    "{}/package/v0/index?eos-version-compat={}&arch={}&page={}&per-page={}",
+                    url.as_str().trim_end_matches('/'),
+                    Current::new().compat(),
+                    platforms::TARGET_ARCH,
+                    page,
+                    INDEX_PAGE_SIZE,
+                ))
+                .await
+                .with_kind(crate::ErrorKind::Registry)?,
+            )
+            .await?;
+            let len = entries.len();
+            let mut new = false;
+            for entry in entries {
+                new |= seen.insert(entry.manifest.id.clone());
+                if entry.is_compatible() {
+                    packages.insert(entry.manifest.id.clone(), entry);
+                }
+            }
+            // a registry that ignores `page` serves the same entries forever
+            if len < INDEX_PAGE_SIZE || !new {
+                return Ok(packages);
+            }
+        }
+        Err(Error::new(
+            eyre!("Index of {} has more than {} pages", url, MAX_INDEX_PAGES),
+            crate::ErrorKind::Registry,
+        ))
+    }
+
+    /// Fetches the index of every registry in `registries` whose cached copy is older than
+    /// `max_age` (or all of them if `max_age` is `None`), and evicts registries that are no
+    /// longer configured. Registries that fail to respond keep their previous cached index.
+    #[instrument(skip(self))]
+    pub async fn refresh(
+        &self,
+        registries: &[Url],
+        max_age: Option<Duration>,
+    ) -> Result<(), Error> {
+        let stale: Vec<Url> = {
+            let cache = self.0.read().await;
+            registries
+                .iter()
+                .filter(|url| match (cache.get(url), max_age) {
+                    (Some(cached), Some(max_age)) => {
+                        Utc::now()
+                            .signed_duration_since(cached.fetched)
+                            .to_std()
+                            .unwrap_or_default()
+                            > max_age
+                    }
+                    _ => true,
+                })
+                .cloned()
+                .collect()
+        };
+        let fetched = futures::future::join_all(stale.into_iter().map(|url| async move {
+            let res = Self::fetch_index(&url).await;
+            (url, res)
+        }))
+        .await;
+        let mut cache = self.0.write().await;
+        cache.retain(|url, _| registries.contains(url));
+        for (url, res) in fetched {
+            match res {
+                Ok(packages) => {
+                    cache.insert(
+                        url,
+                        CachedRegistry {
+                            fetched: Utc::now(),
+                            packages,
+                        },
+                    );
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to fetch package index from {}: {}", url, e);
+                    tracing::debug!("{:?}", e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct MarketplacePackage {
+    pub id: PackageId,
+    pub title: String,
+    pub description: String,
+    pub categories: BTreeSet<String>,
+    /// latest compatible version offered by each registry
+    pub registries: BTreeMap<Url, Version>,
+    pub installed: Option<Version>,
+    /// registries offering a compatible version newer than the installed one
+    pub updates: BTreeMap<Url, Version>,
+}
+
+fn display_packages(packages: Vec<MarketplacePackage>, matches: &ArgMatches<'_>) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(packages, matches);
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![bc =>
+        "ID",
+        "TITLE",
+        "INSTALLED",
+        "LATEST",
+        "UPDATES",
+    ]);
+    for pkg in packages {
+        table.add_row(row![
+            &pkg.id,
+            &pkg.title,
+            &pkg.installed.as_ref().map(|v| v.as_str()).unwrap_or("N/A"),
+            &pkg.registries
+                .values()
+                .max()
+                .map(|v| v.as_str())
+                .unwrap_or("N/A"),
+            &pkg.updates
+                .iter()
+                .map(|(url, v)| format!("{} ({})", v, url))
+                .collect::<Vec<_>>()
+                .join(", "),
+        ]);
+    }
+    table.print_tty(false);
+}
+
+async fn configured_registries<Db: DbHandle>(db: &mut Db) -> Result<Vec<Url>, Error> {
+    Ok(crate::db::DatabaseModel::new()
+        .server_info()
+        .get(db, false)
+        .await?
+        .marketplace
+        .registries
+        .clone())
+}
+
+#[instrument(skip(ctx))]
+async fn catalogue(ctx: &RpcContext) -> Result<Vec<MarketplacePackage>, Error> {
+    let mut db = ctx.db.handle();
+    let registries = configured_registries(&mut db).await?;
+    ctx.marketplace_cache
+        .refresh(&registries, Some(CATALOGUE_MAX_AGE))
+        .await?;
+    let installed: BTreeMap<PackageId, Version> = crate::db::DatabaseModel::new()
+        .package_data()
+        .get(&mut db, false)
+        .await?
+        .0
+        .iter()
+        .filter_map(|(id, pde)| match pde {
+            PackageDataEntry::Installed { installed, .. } => {
+                Some((id.clone(), installed.manifest.version.clone()))
+            }
+            _ => None,
+        })
+        .collect();
+
+    let cache = ctx.marketplace_cache.0.read().await;
+    let mut res: BTreeMap<PackageId, MarketplacePackage> = BTreeMap::new();
+    for url in &registries {
+        let cached = match cache.get(url) {
+            Some(a) => a,
+            None => continue,
+        };
+        for (id, entry) in &cached.packages {
+            let pkg = res.entry(id.clone()).or_insert_with(|| MarketplacePackage {
+                id: id.clone(),
+                title: entry.manifest.title.clone(),
+                description: entry.manifest.description.short.clone(),
+                categories: BTreeSet::new(),
+                registries: BTreeMap::new(),
+                installed: installed.get(id).cloned(),
+                updates: BTreeMap::new(),
+            });
+            pkg.categories.extend(entry.categories.iter().cloned());
+            pkg.registries
+                .insert(url.clone(), entry.manifest.version.clone());
+            if matches!(&pkg.installed, Some(v) if v < &entry.manifest.version) {
+                pkg.updates
+                    .insert(url.clone(), entry.manifest.version.clone());
+            }
+        }
+    }
+    Ok(res.into_iter().map(|(_, pkg)| pkg).collect())
+}
+
+#[command(display(display_packages))]
+#[instrument(skip(ctx))]
+pub async fn list(
+    #[context] ctx: RpcContext,
+    #[arg(long = "installed")] installed: bool,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<MarketplacePackage>, Error> {
+    let mut packages = catalogue(&ctx).await?;
+    if installed {
+        packages.retain(|pkg| pkg.installed.is_some());
+    }
+    Ok(packages)
+}
+
+#[command(display(display_packages))]
+#[instrument(skip(ctx))]
+pub async fn search(
+    #[context] ctx: RpcContext,
+    #[arg] query: String,
+    #[arg(long = "category")] category: Option<String>,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<MarketplacePackage>, Error> {
+    let query = query.to_lowercase();
+    let mut packages = catalogue(&ctx).await?;
+    packages.retain(|pkg| {
+        category
+            .as_ref()
+            .map_or(true, |c| pkg.categories.contains(c))
+            && (pkg.id.contains(query.as_str())
+                || pkg.title.to_lowercase().contains(query.as_str())
+                || pkg.description.to_lowercase().contains(query.as_str()))
+    });
+    Ok(packages)
+}
+
+#[command(display(display_none))]
+#[instrument(skip(ctx))]
+pub async fn refresh(#[context] ctx: RpcContext) -> Result<(), Error> {
+    let registries = configured_registries(&mut ctx.db.handle()).await?;
+    ctx.marketplace_cache.refresh(&registries, None).await
+}
+
+#[command(subcommands(list_registries, add_registry, remove_registry))]
+pub fn registry() -> Result<(), Error> {
+    Ok(())
+}
+
+#[command(rename = "list", display(display_serializable))]
+pub async fn list_registries(
+    #[context] ctx: RpcContext,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<Url>, Error> {
+    configured_registries(&mut ctx.db.handle()).await
+}
+
+#[command(rename = "add", display(display_none))]
+#[instrument(skip(ctx))]
+pub async fn add_registry(
+    #[context] ctx: RpcContext,
+    #[arg] url: Url,
+) -> Result<WithRevision<()>, Error> {
+    let mut db = ctx.db.handle();
+    let mut tx = db.begin().await?;
+    let mut info = crate::db::DatabaseModel::new()
+        .server_info()
+        .get_mut(&mut tx)
+        .await?;
+    if info.marketplace.registries.contains(&url) {
+        return Err(Error::new(
+            eyre!("Registry {} is already configured", url),
+            crate::ErrorKind::Duplicate,
+        ));
+    }
+    info.marketplace.registries.push(url.clone());
+    let registries = info.marketplace.registries.clone();
+    info.save(&mut tx).await?;
+    let revision = tx.commit(None).await?;
+    drop(db);
+
+    tokio::spawn(async move {
+        if let Err(e) = ctx
+            .marketplace_cache
+            .refresh(&registries, Some(CATALOGUE_MAX_AGE))
+            .await
+        {
+            tracing::error!("Failed to refresh marketplace catalogue: {}", e);
+            tracing::debug!("{:?}", e);
+        }
+    });
+
+    Ok(WithRevision {
+        response: (),
+        revision,
+    })
+}
+
+#[command(rename = "remove", display(display_none))]
+#[instrument(skip(ctx))]
+pub async fn remove_registry(
+    #[context] ctx: RpcContext,
+    #[arg] url: Url,
+) -> Result<WithRevision<()>, Error> {
+    let mut db = ctx.db.handle();
+    let mut tx = db.begin().await?;
+    let mut info = crate::db::DatabaseModel::new()
+        .server_info()
+        .get_mut(&mut tx)
+        .await?;
+    let len = info.marketplace.registries.len();
+    info.marketplace.registries.retain(|r| r != &url);
+    if info.marketplace.registries.len() == len {
+        return Err(Error::new(
+            eyre!("Registry {} is not configured", url),
+            crate::ErrorKind::NotFound,
+        ));
+    }
+    info.save(&mut tx).await?;
+    let revision = tx.commit(None).await?;
+    ctx.marketplace_cache.0.write().await.remove(&url);
+
+    Ok(WithRevision {
+        response: (),
+        revision,
+    })
+}
+
+/// Periodically refreshes the cached index of every configured registry.
+pub async fn marketplace_refresh(ctx: &RpcContext) {
+    if let Err(e) = async {
+        let registries = configured_registries(&mut ctx.db.handle()).await?;
+        ctx.marketplace_cache
+            .refresh(&registries, Some(CATALOGUE_MAX_AGE))
+            .await
+    }
+    .await
+    {
+        tracing::error!("Failed to refresh marketplace catalogue: {}", e);
+        tracing::debug!("{:?}", e);
+    }
+}
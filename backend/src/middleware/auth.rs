@@ -73,7 +73,11 @@ impl HasValidSession {
     }
 
     pub async fn from_session(session: &HashSessionToken, ctx: &RpcContext) -> Result<Self, Error> {
-        let session_hash = session.hashed();
+        Self::from_session_hash(session.hashed(), ctx).await
+    }
+
+    /// Checks a session known only by its hash, e.g. one an interface session was granted for.
+    pub async fn from_session_hash(session_hash: &str, ctx: &RpcContext) -> Result<Self, Error> {
         let session = sqlx::query!("UPDATE session SET last_active = CURRENT_TIMESTAMP WHERE id = ? AND logged_out IS NULL OR logged_out > CURRENT_TIMESTAMP", session_hash)
             .execute(&mut ctx.secret_store.acquire().await?)
             .await?;
@@ -105,7 +109,10 @@ impl HashSessionToken {
         Self { hashed, token }
     }
     pub fn from_cookie(cookie: &Cookie) -> Self {
-        let token = cookie.get_value().to_owned();
+        Self::from_token(cookie.get_value())
+    }
+    pub fn from_token(token: &str) -> Self {
+        let token = token.to_owned();
         let hashed = Self::hash(&token);
         Self { hashed, token }
     }
@@ -136,6 +143,10 @@ impl HashSessionToken {
         .with_kind(crate::ErrorKind::Unknown)
     }
 
+    pub fn token(&self) -> &str {
+        self.token.as_str()
+    }
+
     pub fn hashed(&self) -> &str {
         self.hashed.as_str()
     }
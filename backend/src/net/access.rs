@@ -0,0 +1,789 @@
+//! Optional access control for the lan interfaces proxied by nginx.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use basic_cookies::Cookie;
+use color_eyre::eyre::eyre;
+use http::header::{AUTHORIZATION, COOKIE, LOCATION, SET_COOKIE, WWW_AUTHENTICATE};
+use http::request::Parts;
+use hyper::{Body, Request, Response, StatusCode};
+use ipnet::IpNet;
+use patch_db::{DbHandle, LockType};
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{Executor, Sqlite};
+use tracing::instrument;
+
+use super::interface::{ensure_lan_interface, InterfaceId};
+use crate::context::RpcContext;
+use crate::db::util::WithRevision;
+use crate::middleware::auth::{HasValidSession, HashSessionToken};
+use crate::s9pk::manifest::PackageId;
+use crate::util::display_none;
+use crate::util::serde::{display_serializable, IoFormat};
+use crate::{Error, ErrorKind};
+
+/// Prefix of the path on the websocket server nginx sends auth subrequests to, followed by
+/// `<package>/<interface>`.
+pub const AUTH_CHECK_PATH: &str = "/auth/interface/";
+/// Prefix of the path on the websocket server the main UI sends browsers to for access to an
+/// interface with session auth, followed by `<package>/<interface>`.
+pub const INTERFACE_LOGIN_PATH: &str = "/ws/interface-login/";
+/// Prefix of the path on the websocket server interface hosts redeem login grants at, followed
+/// by `<package>/<interface>`.
+pub const INTERFACE_GRANT_PATH: &str = "/auth/interface-grant/";
+/// The session cookie is scoped to the main UI host, so interfaces get their own cookie, scoped
+/// to the interface host.
+const INTERFACE_COOKIE: &str = "embassy-interface";
+const GRANT_TTL: Duration = Duration::from_secs(60);
+/// After this the browser goes through the login redirect again, which is invisible as long as
+/// the embassy session is still valid.
+const INTERFACE_SESSION_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// How long a successful basic auth check is remembered, so a page load does not hash the
+/// password once per asset.
+const AUTH_CACHE_TTL: Duration = Duration::from_secs(60);
+
+lazy_static::lazy_static! {
+    /// Expiry of the recently accepted `Authorization` headers, keyed by a hash of the package,
+    /// interface and header.
+    static ref AUTH_CACHE: Mutex<BTreeMap<[u8; 32], Instant>> = Mutex::new(BTreeMap::new());
+    static ref INTERFACE_SESSIONS: Mutex<InterfaceSessions> =
+        Mutex::new(InterfaceSessions::default());
+}
+
+fn auth_cache_key(package: &PackageId, interface: &InterfaceId, header: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(package.as_str().as_bytes());
+    hasher.update(b"/");
+    hasher.update(interface.as_str().as_bytes());
+    hasher.update(b"\0");
+    hasher.update(header.as_bytes());
+    let mut key = [0; 32];
+    key.copy_from_slice(&hasher.finalize());
+    key
+}
+
+struct InterfaceGrant {
+    package: PackageId,
+    interface: InterfaceId,
+    /// Hash of the embassy session the grant was made for.
+    session: String,
+    expiry: Instant,
+}
+
+/// Grants handed out on the main UI host and the interface sessions they were redeemed for,
+/// keyed by the hash of their token.
+#[derive(Default)]
+struct InterfaceSessions {
+    grants: BTreeMap<String, InterfaceGrant>,
+    sessions: BTreeMap<String, InterfaceGrant>,
+}
+impl InterfaceSessions {
+    fn insert(
+        map: &mut BTreeMap<String, InterfaceGrant>,
+        package: &PackageId,
+        interface: &InterfaceId,
+        session: &str,
+        ttl: Duration,
+    ) -> String {
+        let now = Instant::now();
+        map.retain(|_, grant| grant.expiry > now);
+        let token = HashSessionToken::new();
+        map.insert(
+            token.hashed().to_owned(),
+            InterfaceGrant {
+                package: package.clone(),
+                interface: interface.clone(),
+                session: session.to_owned(),
+                expiry: now + ttl,
+            },
+        );
+        token.token().to_owned()
+    }
+    /// Returns a single use token for the session with hash `session`.
+    fn grant(&mut self, package: &PackageId, interface: &InterfaceId, session: &str) -> String {
+        Self::insert(&mut self.grants, package, interface, session, GRANT_TTL)
+    }
+    /// Trades a grant for the token of an interface session.
+    fn redeem(
+        &mut self,
+        grant: &str,
+        package: &PackageId,
+        interface: &InterfaceId,
+    ) -> Option<String> {
+        let grant = self
+            .grants
+            .remove(HashSessionToken::from_token(grant).hashed())
+            .filter(|g| {
+                &g.package == package && &g.interface == interface && g.expiry > Instant::now()
+            })?;
+        Some(Self::insert(
+            &mut self.sessions,
+            package,
+            interface,
+            &grant.session,
+            INTERFACE_SESSION_TTL,
+        ))
+    }
+    /// The hash of the embassy session behind the interface cookie of `parts`.
+    fn session_of(
+        &self,
+        parts: &Parts,
+        package: &PackageId,
+        interface: &InterfaceId,
+    ) -> Option<String> {
+        let header = parts.headers.get(COOKIE)?.to_str().ok()?;
+        let cookies = Cookie::parse(header).ok()?;
+        let cookie = cookies.iter().find(|c| c.get_name() == INTERFACE_COOKIE)?;
+        self.sessions
+            .get(HashSessionToken::from_cookie(cookie).hashed())
+            .filter(|s| {
+                &s.package == package && &s.interface == interface && s.expiry > Instant::now()
+            })
+            .map(|s| s.session.clone())
+    }
+}
+
+/// Forgets every cached credential check. Called whenever a user is added, changed or removed.
+fn clear_auth_cache() {
+    AUTH_CACHE.lock().unwrap().clear();
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AccessAuth {
+    None,
+    /// HTTP basic auth against the users added with `net access add-user`.
+    Basic,
+    /// A valid embassyd session. The session cookie is not sent to interface hosts, so browsers
+    /// are sent through the main UI once to get an interface cookie, see [interface_login].
+    Session,
+}
+impl Default for AccessAuth {
+    fn default() -> Self {
+        AccessAuth::None
+    }
+}
+impl fmt::Display for AccessAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccessAuth::None => write!(f, "none"),
+            AccessAuth::Basic => write!(f, "basic"),
+            AccessAuth::Session => write!(f, "session"),
+        }
+    }
+}
+impl FromStr for AccessAuth {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(AccessAuth::None),
+            "basic" => Ok(AccessAuth::Basic),
+            "session" => Ok(AccessAuth::Session),
+            _ => Err(Error::new(
+                eyre!("unknown auth {}: expected none, basic or session", s),
+                ErrorKind::InvalidRequest,
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AccessPolicy {
+    /// Networks allowed to connect. Anyone who can reach the embassy if empty.
+    pub allow: Vec<IpNet>,
+    pub auth: AccessAuth,
+}
+impl AccessPolicy {
+    /// Directives for the server block of `package`'s `interface`. Browsers without an interface
+    /// session are sent to `main_host` to log in.
+    pub fn nginx_directives(
+        &self,
+        package: &PackageId,
+        interface: &InterfaceId,
+        main_host: &str,
+    ) -> String {
+        let mut res = self.stream_directives();
+        if self.auth != AccessAuth::None {
+            res += &format!(
+                "auth_request /.embassy-auth;
+    location = /.embassy-auth {{
+        internal;
+        auth_request off;
+        proxy_pass http://127.0.0.1:5960{}{}/{};
+        proxy_pass_request_body off;
+        proxy_set_header Content-Length \"\";
+    }}\n    ",
+                AUTH_CHECK_PATH, package, interface
+            );
+        }
+        if self.auth == AccessAuth::Session {
+            res += &format!(
+                "error_page 401 = @embassy-login;
+    location @embassy-login {{
+        return 302 https://{}{}{}/{}?origin=$scheme://$http_host&path=$request_uri;
+    }}
+    location = /.embassy-auth/grant {{
+        auth_request off;
+        proxy_pass http://127.0.0.1:5960{}{}/{}$is_args$args;
+    }}\n    ",
+                main_host,
+                INTERFACE_LOGIN_PATH,
+                package,
+                interface,
+                INTERFACE_GRANT_PATH,
+                package,
+                interface
+            );
+        }
+        res
+    }
+    /// Directives for a raw tcp/udp server block. Only the network allowlist applies there,
+    /// since streams carry no credentials nginx could check.
+    pub fn stream_directives(&self) -> String {
+        let mut res = String::new();
+        if !self.allow.is_empty() {
+            for net in &self.allow {
+                res += &format!("allow {};\n    ", net);
+            }
+            res += "deny all;\n    ";
+        }
+        res
+    }
+}
+
+#[command(subcommands(get, set, add_user, remove_user))]
+pub fn access() -> Result<(), Error> {
+    Ok(())
+}
+
+async fn load_policies<Db: DbHandle>(
+    db: &mut Db,
+    package: &PackageId,
+) -> Result<BTreeMap<InterfaceId, AccessPolicy>, Error> {
+    Ok(crate::db::DatabaseModel::new()
+        .package_data()
+        .idx_model(package)
+        .and_then(|pde| pde.installed())
+        .map(|i| i.access_policies())
+        .get(db, false)
+        .await?
+        .into_owned()
+        .unwrap_or_default())
+}
+
+async fn users<Ex>(
+    secrets: &mut Ex,
+    package: &PackageId,
+    interface: &InterfaceId,
+) -> Result<BTreeMap<String, String>, Error>
+where
+    for<'a> &'a mut Ex: Executor<'a, Database = Sqlite>,
+{
+    use sqlx::Row;
+
+    let mut res = BTreeMap::new();
+    for row in sqlx::query(
+        "SELECT username, password_hash FROM interface_users WHERE package = ? AND interface = ?",
+    )
+    .bind(package.as_str())
+    .bind(interface.as_str())
+    .fetch_all(secrets)
+    .await?
+    {
+        res.insert(row.try_get("username")?, row.try_get("password_hash")?);
+    }
+    Ok(res)
+}
+
+/// Removes the basic auth users of every interface of `package`.
+pub async fn remove_users<Ex>(secrets: &mut Ex, package: &PackageId) -> Result<(), Error>
+where
+    for<'a> &'a mut Ex: Executor<'a, Database = Sqlite>,
+{
+    sqlx::query("DELETE FROM interface_users WHERE package = ?")
+        .bind(package.as_str())
+        .execute(secrets)
+        .await?;
+    clear_auth_cache();
+    Ok(())
+}
+
+/// Reads `<package>/<interface>` from a path starting with `prefix`.
+fn parse_interface_path(
+    parts: &Parts,
+    prefix: &str,
+) -> Result<Option<(PackageId, InterfaceId)>, Error> {
+    Ok(
+        match parts
+            .uri
+            .path()
+            .strip_prefix(prefix)
+            .and_then(|p| p.split_once("/"))
+        {
+            Some((package, interface)) => Some((
+                package.parse::<PackageId>()?,
+                InterfaceId::from_str(interface)?,
+            )),
+            None => None,
+        },
+    )
+}
+
+fn empty_response(status: StatusCode) -> Result<Response<Body>, Error> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .map_err(|e| Error::new(e, ErrorKind::Network))
+}
+
+fn redirect(location: &str) -> Result<Response<Body>, Error> {
+    Response::builder()
+        .status(StatusCode::FOUND)
+        .header(LOCATION, location)
+        .body(Body::empty())
+        .map_err(|e| Error::new(e, ErrorKind::Network))
+}
+
+/// Only paths on the host being redirected to, so a grant can't send the browser elsewhere.
+fn local_path(path: &str) -> &str {
+    if path.starts_with('/') && !path.starts_with("//") && !path.starts_with("/\\") {
+        path
+    } else {
+        "/"
+    }
+}
+
+/// Splits the `origin=<scheme://host>&path=<request uri>` query nginx builds for a login. The
+/// request uri is passed through unescaped, so it is everything after `&path=`.
+fn split_login_query(query: &str) -> Option<(&str, &str)> {
+    query.strip_prefix("origin=")?.split_once("&path=")
+}
+
+/// Where a browser lands when an interface with session auth turns it away. This is served on the
+/// main UI host, where the session cookie is sent: it hands out a single use grant and sends the
+/// browser back to the interface host to redeem it.
+#[instrument(skip(ctx, req))]
+pub async fn interface_login(ctx: RpcContext, req: Request<Body>) -> Result<Response<Body>, Error> {
+    let (parts, _) = req.into_parts();
+    let (package, interface) = match parse_interface_path(&parts, INTERFACE_LOGIN_PATH)? {
+        Some(a) => a,
+        None => return empty_response(StatusCode::NOT_FOUND),
+    };
+    let (origin, path) = match parts.uri.query().and_then(split_login_query) {
+        Some(a) => a,
+        None => return empty_response(StatusCode::BAD_REQUEST),
+    };
+    if HasValidSession::from_request_parts(&parts, &ctx)
+        .await
+        .is_err()
+    {
+        // the UI shows its login page
+        return redirect("/");
+    }
+    let session = HashSessionToken::from_request_parts(&parts)?;
+    let mut target = match url::Url::parse(origin) {
+        Ok(a) if a.scheme() == "http" || a.scheme() == "https" => a,
+        _ => return empty_response(StatusCode::BAD_REQUEST),
+    };
+    match target.host_str() {
+        Some(host)
+            if ctx
+                .net_controller
+                .nginx
+                .serves(&package, &interface, host)
+                .await => {}
+        _ => return empty_response(StatusCode::FORBIDDEN),
+    }
+    let grant = INTERFACE_SESSIONS
+        .lock()
+        .unwrap()
+        .grant(&package, &interface, session.hashed());
+    target.set_path("/.embassy-auth/grant");
+    target
+        .query_pairs_mut()
+        .clear()
+        .append_pair("token", &grant)
+        .append_pair("path", local_path(path));
+    redirect(target.as_str())
+}
+
+/// Redeems a grant from [interface_login] on the interface host, setting the interface cookie.
+#[instrument(skip(req))]
+pub async fn interface_grant(req: Request<Body>) -> Result<Response<Body>, Error> {
+    let (parts, _) = req.into_parts();
+    let (package, interface) = match parse_interface_path(&parts, INTERFACE_GRANT_PATH)? {
+        Some(a) => a,
+        None => return empty_response(StatusCode::NOT_FOUND),
+    };
+    let query = url::form_urlencoded::parse(parts.uri.query().unwrap_or("").as_bytes())
+        .collect::<BTreeMap<_, _>>();
+    let path = query.get("path").map(|p| local_path(p)).unwrap_or("/");
+    let token = match query.get("token").and_then(|grant| {
+        INTERFACE_SESSIONS
+            .lock()
+            .unwrap()
+            .redeem(grant, &package, &interface)
+    }) {
+        Some(a) => a,
+        None => return empty_response(StatusCode::FORBIDDEN),
+    };
+    Response::builder()
+        .status(StatusCode::FOUND)
+        .header(LOCATION, path)
+        .header(
+            SET_COOKIE,
+            format!(
+                "{}={}; Path=/; HttpOnly; SameSite=Lax",
+                INTERFACE_COOKIE, token
+            ),
+        )
+        .body(Body::empty())
+        .map_err(|e| Error::new(e, ErrorKind::Network))
+}
+
+/// Answers the auth subrequests nginx makes for interfaces with an auth policy.
+#[instrument(skip(ctx, req))]
+pub async fn auth_check(ctx: RpcContext, req: Request<Body>) -> Result<Response<Body>, Error> {
+    let (parts, _) = req.into_parts();
+    let (package, interface) = match parse_interface_path(&parts, AUTH_CHECK_PATH)? {
+        Some(a) => a,
+        None => return empty_response(StatusCode::NOT_FOUND),
+    };
+    let policy = load_policies(&mut ctx.db.handle(), &package)
+        .await?
+        .remove(&interface)
+        .unwrap_or_default();
+    let (authorized, challenge) = match policy.auth {
+        AccessAuth::None => (true, None),
+        AccessAuth::Session => {
+            let session = INTERFACE_SESSIONS
+                .lock()
+                .unwrap()
+                .session_of(&parts, &package, &interface);
+            let authorized = match session {
+                Some(session) => HasValidSession::from_session_hash(&session, &ctx)
+                    .await
+                    .is_ok(),
+                None => false,
+            };
+            (authorized, None)
+        }
+        AccessAuth::Basic => {
+            let header = parts
+                .headers
+                .get(AUTHORIZATION)
+                .and_then(|h| h.to_str().ok());
+            let cache_key = header.map(|h| auth_cache_key(&package, &interface, h));
+            let cached = cache_key.map_or(false, |key| {
+                let mut cache = AUTH_CACHE.lock().unwrap();
+                match cache.get(&key) {
+                    Some(expiry) if *expiry > Instant::now() => true,
+                    Some(_) => {
+                        cache.remove(&key);
+                        false
+                    }
+                    None => false,
+                }
+            });
+            let credentials = header
+                .filter(|_| !cached)
+                .and_then(|h| h.strip_prefix("Basic "))
+                .and_then(|h| base64::decode(h.trim()).ok())
+                .and_then(|h| String::from_utf8(h).ok());
+            let authorized = cached
+                || match credentials.as_deref().and_then(|c| c.split_once(":")) {
+                    Some((username, password)) => {
+                        let users =
+                            users(&mut ctx.secret_store.acquire().await?, &package, &interface)
+                                .await?;
+                        users
+                            .get(username)
+                            .map(|hash| {
+                                argon2::verify_encoded(hash, password.as_bytes()).unwrap_or(false)
+                            })
+                            .unwrap_or(false)
+                    }
+                    None => false,
+                };
+            if authorized && !cached {
+                if let Some(key) = cache_key {
+                    let now = Instant::now();
+                    let mut cache = AUTH_CACHE.lock().unwrap();
+                    cache.retain(|_, expiry| *expiry > now);
+                    cache.insert(key, now + AUTH_CACHE_TTL);
+                }
+            }
+            (
+                authorized,
+                Some(format!("Basic realm=\"{}\", charset=\"UTF-8\"", package)),
+            )
+        }
+    };
+    let mut res = Response::builder();
+    if authorized {
+        res = res.status(StatusCode::NO_CONTENT);
+    } else {
+        res = res.status(StatusCode::UNAUTHORIZED);
+        if let Some(challenge) = challenge {
+            res = res.header(WWW_AUTHENTICATE, challenge);
+        }
+    }
+    res.body(Body::empty())
+        .map_err(|e| Error::new(e, ErrorKind::Network))
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AccessInfo {
+    pub policy: AccessPolicy,
+    pub users: Vec<String>,
+}
+
+#[command(display(display_serializable))]
+#[instrument(skip(ctx))]
+pub async fn get(
+    #[context] ctx: RpcContext,
+    #[arg] package: PackageId,
+    #[arg] interface: InterfaceId,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<AccessInfo, Error> {
+    Ok(AccessInfo {
+        policy: load_policies(&mut ctx.db.handle(), &package)
+            .await?
+            .remove(&interface)
+            .unwrap_or_default(),
+        users: users(&mut ctx.secret_store.acquire().await?, &package, &interface)
+            .await?
+            .keys()
+            .cloned()
+            .collect(),
+    })
+}
+
+fn parse_networks(arg: &str, _: &clap::ArgMatches<'_>) -> Result<Vec<IpNet>, Error> {
+    arg.split(",")
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            s.parse::<IpNet>()
+                .or_else(|_| s.parse::<std::net::IpAddr>().map(IpNet::from))
+                .map_err(|_| {
+                    Error::new(
+                        eyre!("{} is not an ip address or network", s),
+                        ErrorKind::ParseNetAddress,
+                    )
+                })
+        })
+        .collect()
+}
+
+/// Changes the access policy of a lan interface. Unset arguments are left as they are, an empty
+/// `--allow` lets anyone connect.
+#[command(display(display_none))]
+#[instrument(skip(ctx))]
+pub async fn set(
+    #[context] ctx: RpcContext,
+    #[arg] package: PackageId,
+    #[arg] interface: InterfaceId,
+    #[arg(long = "allow", parse(parse_networks))] allow: Option<Vec<IpNet>>,
+    #[arg(long = "auth")] auth: Option<AccessAuth>,
+) -> Result<WithRevision<()>, Error> {
+    let mut db = ctx.db.handle();
+    let mut tx = db.begin().await?;
+    crate::db::DatabaseModel::new()
+        .package_data()
+        .lock(&mut tx, LockType::Write)
+        .await?;
+    ensure_lan_interface(&mut tx, &package, &interface).await?;
+    let mut policies = crate::db::DatabaseModel::new()
+        .package_data()
+        .idx_model(&package)
+        .and_then(|pde| pde.installed())
+        .map(|i| i.access_policies())
+        .expect(&mut tx)
+        .await?
+        .get_mut(&mut tx)
+        .await?;
+    let policy = policies.entry(interface.clone()).or_default();
+    if let Some(allow) = allow {
+        policy.allow = allow;
+    }
+    if let Some(auth) = auth {
+        policy.auth = auth;
+    }
+    let policy = policy.clone();
+    policies.save(&mut tx).await?;
+    let revision = tx.commit(None).await?;
+    ctx.net_controller
+        .nginx
+        .set_access(&ctx.net_controller.ssl, &package, &interface, policy)
+        .await?;
+
+    Ok(WithRevision {
+        response: (),
+        revision,
+    })
+}
+
+/// Adds a basic auth user to a lan interface, or changes their password.
+#[command(rename = "add-user", display(display_none))]
+#[instrument(skip(ctx, password))]
+pub async fn add_user(
+    #[context] ctx: RpcContext,
+    #[arg] package: PackageId,
+    #[arg] interface: InterfaceId,
+    #[arg] username: String,
+    #[arg] password: String,
+) -> Result<(), Error> {
+    if username.is_empty() || username.contains(':') {
+        return Err(Error::new(
+            eyre!("username must be non-empty and must not contain ':'"),
+            ErrorKind::InvalidRequest,
+        ));
+    }
+    ensure_lan_interface(&mut ctx.db.handle(), &package, &interface).await?;
+    let hash = argon2::hash_encoded(
+        password.as_bytes(),
+        &rand::random::<[u8; 16]>()[..],
+        &argon2::Config::default(),
+    )
+    .map_err(|e| Error::new(e, ErrorKind::PasswordHashGeneration))?;
+    sqlx::query("INSERT INTO interface_users (package, interface, username, password_hash) VALUES (?, ?, ?, ?) ON CONFLICT (package, interface, username) DO UPDATE SET password_hash = excluded.password_hash")
+        .bind(package.as_str())
+        .bind(interface.as_str())
+        .bind(&username)
+        .bind(hash)
+        .execute(&ctx.secret_store)
+        .await?;
+    clear_auth_cache();
+    Ok(())
+}
+
+#[command(rename = "remove-user", display(display_none))]
+#[instrument(skip(ctx))]
+pub async fn remove_user(
+    #[context] ctx: RpcContext,
+    #[arg] package: PackageId,
+    #[arg] interface: InterfaceId,
+    #[arg] username: String,
+) -> Result<(), Error> {
+    let res = sqlx::query(
+        "DELETE FROM interface_users WHERE package = ? AND interface = ? AND username = ?",
+    )
+    .bind(package.as_str())
+    .bind(interface.as_str())
+    .bind(&username)
+    .execute(&ctx.secret_store)
+    .await?;
+    clear_auth_cache();
+    if res.rows_affected() == 0 {
+        return Err(Error::new(
+            eyre!(
+                "{} is not a user of {} interface {}",
+                username,
+                package,
+                interface
+            ),
+            ErrorKind::NotFound,
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_nginx_directives() {
+    let package: PackageId = "nextcloud".parse().unwrap();
+    let interface = InterfaceId::from_str("main").unwrap();
+    assert_eq!(
+        AccessPolicy::default().nginx_directives(&package, &interface, "embassy-test.local"),
+        ""
+    );
+    let policy = AccessPolicy {
+        allow: parse_networks("192.168.1.0/24, 10.0.0.1", &clap::ArgMatches::default()).unwrap(),
+        auth: AccessAuth::Session,
+    };
+    let directives = policy.nginx_directives(&package, &interface, "embassy-test.local");
+    assert!(directives.starts_with("allow 192.168.1.0/24;\n    allow 10.0.0.1/32;\n    deny all;"));
+    assert!(directives.contains("proxy_pass http://127.0.0.1:5960/auth/interface/nextcloud/main;"));
+    assert!(directives.contains(
+        "return 302 https://embassy-test.local/ws/interface-login/nextcloud/main?origin="
+    ));
+    assert!(directives.contains(
+        "proxy_pass http://127.0.0.1:5960/auth/interface-grant/nextcloud/main$is_args$args;"
+    ));
+    assert!(!policy.stream_directives().contains("auth_request"));
+    let basic = AccessPolicy {
+        allow: Vec::new(),
+        auth: AccessAuth::Basic,
+    };
+    assert!(!basic
+        .nginx_directives(&package, &interface, "embassy-test.local")
+        .contains("error_page"));
+}
+
+#[test]
+fn test_interface_session() {
+    fn request_parts(cookie: &str) -> Parts {
+        Request::builder()
+            .uri("/auth/interface/nextcloud/main")
+            .header(http::header::HOST, "abcdef.local")
+            .header(COOKIE, cookie)
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0
+    }
+    let package: PackageId = "nextcloud".parse().unwrap();
+    let interface = InterfaceId::from_str("main").unwrap();
+    let other = InterfaceId::from_str("admin").unwrap();
+    let embassy_session = HashSessionToken::new();
+    let mut sessions = InterfaceSessions::default();
+
+    // the embassy session cookie alone does not open an interface
+    let parts = request_parts(&format!("session={}", embassy_session.token()));
+    assert_eq!(sessions.session_of(&parts, &package, &interface), None);
+
+    let grant = sessions.grant(&package, &interface, embassy_session.hashed());
+    assert_eq!(sessions.redeem(&grant, &package, &other), None);
+    let grant = sessions.grant(&package, &interface, embassy_session.hashed());
+    let token = sessions.redeem(&grant, &package, &interface).unwrap();
+    // grants are single use
+    assert_eq!(sessions.redeem(&grant, &package, &interface), None);
+
+    let parts = request_parts(&format!(
+        "session={}; {}={}",
+        embassy_session.token(),
+        INTERFACE_COOKIE,
+        token
+    ));
+    assert_eq!(
+        sessions.session_of(&parts, &package, &interface).as_deref(),
+        Some(embassy_session.hashed())
+    );
+    assert_eq!(sessions.session_of(&parts, &package, &other), None);
+}
+
+#[test]
+fn test_login_query() {
+    assert_eq!(
+        split_login_query("origin=https://abcdef.local:8443&path=/apps?a=1&path=2"),
+        Some(("https://abcdef.local:8443", "/apps?a=1&path=2"))
+    );
+    assert_eq!(
+        split_login_query("path=/&origin=https://abcdef.local"),
+        None
+    );
+    assert_eq!(local_path("/apps?a=1"), "/apps?a=1");
+    assert_eq!(local_path("//evil.com/"), "/");
+    assert_eq!(local_path("/\\evil.com/"), "/");
+    assert_eq!(local_path("https://evil.com/"), "/");
+}
@@ -0,0 +1,591 @@
+//! A small authoritative dns server, so clients that cannot use mDNS (vpn peers, package
+//! containers, lan clients pointed at the embassy) resolve the same names:
+//! - `embassy` and the `.local` hostname of the embassy
+//! - the `.local` lan address and custom domains of each package interface, all served by nginx
+//!   on the embassy itself
+//! - `<package>.embassy`, the address of the package's container on the `start9` network
+//!
+//! Everything else is forwarded to the configured upstream, for package containers only as far as
+//! their egress policy allows: tor-only packages resolve through tor and isolated ones not at all.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use color_eyre::eyre::eyre;
+use ipnet::Ipv4Net;
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+use tokio::sync::{Mutex, RwLock};
+use torut::onion::TorSecretKeyV3;
+use tracing::instrument;
+
+use super::firewall::{Egress, EgressMap};
+use super::interface::InterfaceId;
+use super::vpn::PACKAGE_NETWORK;
+use crate::context::RpcContext;
+use crate::db::util::WithRevision;
+use crate::s9pk::manifest::PackageId;
+use crate::util::{display_none, NonDetachingJoinHandle};
+use crate::{Error, ErrorKind};
+
+pub const DNS_PORT: u16 = 53;
+/// The `DNSPort` of tor, set in its torrc.
+pub const TOR_DNS_PORT: u16 = 9053;
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(5);
+const TTL: u32 = 60;
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+const RCODE_SERVFAIL: u8 = 2;
+const RCODE_NXDOMAIN: u8 = 3;
+const RCODE_NOTIMP: u8 = 4;
+const RCODE_REFUSED: u8 = 5;
+
+/// Where queries for names the embassy is not authoritative for go.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum DnsUpstream {
+    /// The nameservers of the embassy itself, from `/etc/resolv.conf`.
+    System,
+    /// Tor's `DNSPort`, so lookups leave the embassy through tor. Only A and AAAA records
+    /// resolve this way.
+    Tor,
+    Servers {
+        servers: Vec<SocketAddr>,
+    },
+    /// Nothing is forwarded.
+    None,
+}
+impl Default for DnsUpstream {
+    fn default() -> Self {
+        DnsUpstream::System
+    }
+}
+impl fmt::Display for DnsUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DnsUpstream::System => write!(f, "system"),
+            DnsUpstream::Tor => write!(f, "tor"),
+            DnsUpstream::Servers { servers } => write!(
+                f,
+                "{}",
+                servers
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+            DnsUpstream::None => write!(f, "none"),
+        }
+    }
+}
+impl FromStr for DnsUpstream {
+    type Err = Error;
+    /// `system`, `tor`, `none`, or a comma separated list of nameservers, with optional ports.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "system" => Ok(DnsUpstream::System),
+            "tor" => Ok(DnsUpstream::Tor),
+            "none" => Ok(DnsUpstream::None),
+            _ => Ok(DnsUpstream::Servers {
+                servers: s
+                    .split(',')
+                    .map(|s| s.trim())
+                    .map(|s| {
+                        s.parse()
+                            .or_else(|_| s.parse().map(|ip| SocketAddr::new(ip, DNS_PORT)))
+                            .map_err(|_| {
+                                Error::new(
+                                    eyre!("invalid dns upstream: {}", s),
+                                    ErrorKind::ParseNetAddress,
+                                )
+                            })
+                    })
+                    .collect::<Result<_, _>>()?,
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DnsSettings {
+    pub upstream: DnsUpstream,
+}
+
+#[command(subcommands(config))]
+pub fn dns() -> Result<(), Error> {
+    Ok(())
+}
+
+#[command(display(display_none))]
+#[instrument(skip(ctx))]
+pub async fn config(
+    #[context] ctx: RpcContext,
+    #[arg(long = "upstream")] upstream: Option<DnsUpstream>,
+) -> Result<WithRevision<()>, Error> {
+    let mut db = ctx.db.handle();
+    let mut tx = db.begin().await?;
+    let mut settings = crate::db::DatabaseModel::new()
+        .server_info()
+        .dns()
+        .get_mut(&mut tx)
+        .await?;
+    if let Some(upstream) = upstream {
+        settings.upstream = upstream;
+    }
+    ctx.net_controller.dns.configure(&settings).await;
+    settings.save(&mut tx).await?;
+    Ok(WithRevision {
+        response: (),
+        revision: tx.commit(None).await?,
+    })
+}
+
+#[derive(Default)]
+struct DnsRecords {
+    hostname: String,
+    packages: BTreeMap<PackageId, Ipv4Addr>,
+    /// The lan address and custom domains of each interface.
+    services: BTreeMap<(PackageId, InterfaceId), BTreeSet<String>>,
+}
+impl DnsRecords {
+    /// `host` is the address of the embassy the query arrived on.
+    fn lookup(&self, name: &str, host: Ipv4Addr) -> Option<Ipv4Addr> {
+        if name == "embassy" || name == self.hostname {
+            Some(host)
+        } else if let Some(package) = name.strip_suffix(".embassy") {
+            self.packages.get(package).copied()
+        } else if self.services.values().any(|names| names.contains(name)) {
+            Some(host)
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether the name belongs to a zone nothing else may answer for.
+fn is_authoritative(name: &str) -> bool {
+    ["local", "embassy"]
+        .iter()
+        .any(|zone| name == *zone || name.ends_with(&format!(".{}", zone)))
+}
+
+pub struct DnsController {
+    records: Arc<RwLock<DnsRecords>>,
+    upstream: Arc<RwLock<Vec<SocketAddr>>>,
+    listen: Vec<Ipv4Addr>,
+    egress: EgressMap,
+    servers: Mutex<BTreeMap<Ipv4Addr, NonDetachingJoinHandle<()>>>,
+}
+impl DnsController {
+    /// Serves on each of `listen`, resolving the names nginx answers for to the address the query
+    /// arrived on.
+    #[instrument(skip(egress))]
+    pub async fn init(listen: Vec<Ipv4Addr>, hostname: String, egress: EgressMap) -> Self {
+        let records = Arc::new(RwLock::new(DnsRecords {
+            hostname: format!("{}.local", hostname.to_ascii_lowercase()),
+            ..DnsRecords::default()
+        }));
+        let upstream = Arc::new(RwLock::new(Vec::new()));
+        let res = DnsController {
+            records,
+            upstream,
+            listen: listen.clone(),
+            egress,
+            servers: Mutex::new(BTreeMap::new()),
+        };
+        for address in listen {
+            res.listen(address).await;
+        }
+        res.configure(&DnsSettings::default()).await;
+        res
+    }
+    /// Starts serving on `address` if it is not served already. Addresses that do not exist yet,
+    /// like the vpn address before the first peer, can be served once they do.
+    #[instrument(skip(self))]
+    pub async fn listen(&self, address: Ipv4Addr) {
+        let mut servers = self.servers.lock().await;
+        if servers.contains_key(&address) {
+            return;
+        }
+        // one missing address should not take down name resolution on the others
+        let socket = match UdpSocket::bind((address, DNS_PORT)).await {
+            Ok(a) => a,
+            Err(e) => {
+                tracing::error!("Could not serve dns on {}: {}", address, e);
+                tracing::debug!("{:?}", e);
+                return;
+            }
+        };
+        servers.insert(
+            address,
+            tokio::spawn(serve(
+                Arc::new(socket),
+                address,
+                self.records.clone(),
+                self.upstream.clone(),
+                self.egress.clone(),
+            ))
+            .into(),
+        );
+    }
+    #[instrument(skip(self))]
+    pub async fn configure(&self, settings: &DnsSettings) {
+        let upstream = match &settings.upstream {
+            DnsUpstream::System => tokio::fs::read_to_string(RESOLV_CONF)
+                .await
+                .map(|resolv_conf| upstream_nameservers(&resolv_conf))
+                .unwrap_or_else(|e| {
+                    tracing::error!("Could not read {}: {}", RESOLV_CONF, e);
+                    Vec::new()
+                }),
+            DnsUpstream::Tor => vec![SocketAddr::from(([127, 0, 0, 1], TOR_DNS_PORT))],
+            DnsUpstream::Servers { servers } => servers.clone(),
+            DnsUpstream::None => Vec::new(),
+        };
+        // forwarding to ourselves would loop
+        *self.upstream.write().await = upstream
+            .into_iter()
+            .filter(|addr| match addr {
+                SocketAddr::V4(addr) => !self.listen.contains(addr.ip()),
+                SocketAddr::V6(_) => true,
+            })
+            .collect();
+    }
+    pub async fn add<I: IntoIterator<Item = (InterfaceId, TorSecretKeyV3)>>(
+        &self,
+        pkg_id: &PackageId,
+        ip: Ipv4Addr,
+        interfaces: I,
+        mut domains: BTreeMap<InterfaceId, BTreeSet<String>>,
+    ) {
+        let mut records = self.records.write().await;
+        records.packages.insert(pkg_id.clone(), ip);
+        for (interface_id, key) in interfaces {
+            let mut names = domains.remove(&interface_id).unwrap_or_default();
+            names.insert(
+                key.public()
+                    .get_onion_address()
+                    .get_address_without_dot_onion()
+                    + ".local",
+            );
+            records
+                .services
+                .insert((pkg_id.clone(), interface_id), names);
+        }
+    }
+    /// Replaces the custom domains of an interface, keeping its lan address.
+    pub async fn set_domains(
+        &self,
+        pkg_id: &PackageId,
+        interface_id: &InterfaceId,
+        domains: BTreeSet<String>,
+    ) {
+        let mut records = self.records.write().await;
+        if let Some(names) = records
+            .services
+            .get_mut(&(pkg_id.clone(), interface_id.clone()))
+        {
+            names.retain(|name| name.ends_with(".local"));
+            names.extend(domains);
+        }
+    }
+    pub async fn remove<I: IntoIterator<Item = InterfaceId>>(
+        &self,
+        pkg_id: &PackageId,
+        interfaces: I,
+    ) {
+        let mut records = self.records.write().await;
+        records.packages.remove(pkg_id);
+        for interface_id in interfaces {
+            records.services.remove(&(pkg_id.clone(), interface_id));
+        }
+    }
+}
+
+/// The nameservers in the contents of a `resolv.conf`.
+fn upstream_nameservers(resolv_conf: &str) -> Vec<SocketAddr> {
+    resolv_conf
+        .lines()
+        .filter_map(|l| l.trim().strip_prefix("nameserver"))
+        .filter_map(|ns| ns.trim().parse().ok())
+        .map(|ip| SocketAddr::new(ip, DNS_PORT))
+        .collect()
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Question {
+    /// Lowercase, without the trailing dot.
+    name: String,
+    qtype: u16,
+    qclass: u16,
+    /// Offset of the end of the question in the query.
+    end: usize,
+}
+
+/// Parses the single question of a standard query.
+fn parse_question(query: &[u8]) -> Option<Question> {
+    let header = query.get(0..12)?;
+    let is_response = header[2] & 0x80 != 0;
+    if is_response || u16::from_be_bytes([header[4], header[5]]) != 1 {
+        return None;
+    }
+    let mut pos = 12;
+    let mut labels = Vec::new();
+    loop {
+        let len = *query.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        if len & 0xc0 != 0 {
+            // compression pointers have nothing to point back to in a question
+            return None;
+        }
+        labels.push(std::str::from_utf8(query.get(pos..pos + len)?).ok()?);
+        pos += len;
+    }
+    let fixed = query.get(pos..pos + 4)?;
+    Some(Question {
+        name: labels.join(".").to_ascii_lowercase(),
+        qtype: u16::from_be_bytes([fixed[0], fixed[1]]),
+        qclass: u16::from_be_bytes([fixed[2], fixed[3]]),
+        end: pos + 4,
+    })
+}
+
+/// An authoritative response to `query`, with `answer` as its only A record.
+fn response(query: &[u8], question: &Question, rcode: u8, answer: Option<Ipv4Addr>) -> Vec<u8> {
+    let mut res = Vec::with_capacity(question.end + 16);
+    res.extend_from_slice(&query[0..2]);
+    // QR and AA, echoing the opcode and RD
+    res.push(0x84 | (query[2] & 0x79));
+    res.push(rcode);
+    res.extend_from_slice(&1u16.to_be_bytes());
+    res.extend_from_slice(&(answer.is_some() as u16).to_be_bytes());
+    res.extend_from_slice(&[0, 0, 0, 0]);
+    res.extend_from_slice(&query[12..question.end]);
+    if let Some(ip) = answer {
+        // pointer to the name in the question
+        res.extend_from_slice(&[0xc0, 12]);
+        res.extend_from_slice(&TYPE_A.to_be_bytes());
+        res.extend_from_slice(&CLASS_IN.to_be_bytes());
+        res.extend_from_slice(&TTL.to_be_bytes());
+        res.extend_from_slice(&4u16.to_be_bytes());
+        res.extend_from_slice(&ip.octets());
+    }
+    res
+}
+
+async fn serve(
+    socket: Arc<UdpSocket>,
+    host: Ipv4Addr,
+    records: Arc<RwLock<DnsRecords>>,
+    upstream: Arc<RwLock<Vec<SocketAddr>>>,
+    egress: EgressMap,
+) {
+    let package_network: Ipv4Net = PACKAGE_NETWORK.parse().unwrap();
+    let mut buf = [0; 4096];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf).await {
+            Ok(a) => a,
+            Err(e) => {
+                tracing::error!("Error receiving dns query: {}", e);
+                tracing::debug!("{:?}", e);
+                continue;
+            }
+        };
+        let query = buf[..len].to_vec();
+        let question = match parse_question(&query) {
+            Some(a) => a,
+            None => continue,
+        };
+        let opcode = (query[2] >> 3) & 0x0f;
+        let found = records.read().await.lookup(&question.name, host);
+        let res = if opcode != 0 {
+            response(&query, &question, RCODE_NOTIMP, None)
+        } else if let Some(ip) = found {
+            let answer =
+                Some(ip).filter(|_| question.qtype == TYPE_A && question.qclass == CLASS_IN);
+            response(&query, &question, 0, answer)
+        } else if is_authoritative(&question.name) {
+            response(&query, &question, RCODE_NXDOMAIN, None)
+        } else {
+            let upstream = match from.ip() {
+                IpAddr::V4(ip)
+                    if package_network.contains(&ip) && ip != Ipv4Addr::from(crate::HOST_IP) =>
+                {
+                    upstream_for(egress.read().await.get(&ip).copied(), &upstream).await
+                }
+                _ => upstream.read().await.clone(),
+            };
+            if upstream.is_empty() {
+                response(&query, &question, RCODE_REFUSED, None)
+            } else {
+                let socket = socket.clone();
+                tokio::spawn(async move {
+                    let res = match forward(&query, &upstream).await {
+                        Ok(a) => a,
+                        Err(e) => {
+                            tracing::debug!("Error forwarding dns query: {:?}", e);
+                            response(&query, &question, RCODE_SERVFAIL, None)
+                        }
+                    };
+                    if let Err(e) = socket.send_to(&res, from).await {
+                        tracing::debug!("Error sending dns response: {:?}", e);
+                    }
+                });
+                continue;
+            }
+        };
+        if let Err(e) = socket.send_to(&res, from).await {
+            tracing::debug!("Error sending dns response: {:?}", e);
+        }
+    }
+}
+
+/// Where the queries of a package container go. Containers the firewall does not know yet have no
+/// egress at all.
+async fn upstream_for(
+    egress: Option<Egress>,
+    upstream: &RwLock<Vec<SocketAddr>>,
+) -> Vec<SocketAddr> {
+    match egress {
+        Some(Egress::Clearnet) => upstream.read().await.clone(),
+        Some(Egress::TorOnly) => vec![SocketAddr::from(([127, 0, 0, 1], TOR_DNS_PORT))],
+        Some(Egress::Isolated) | None => Vec::new(),
+    }
+}
+
+/// Forwards `query` to each of `upstream` in turn, until one of them answers.
+async fn forward(query: &[u8], upstream: &[SocketAddr]) -> Result<Vec<u8>, Error> {
+    let mut err = None;
+    for upstream in upstream {
+        match forward_to(query, *upstream).await {
+            Ok(a) => return Ok(a),
+            Err(e) => err = Some(e),
+        }
+    }
+    Err(err.unwrap_or_else(|| Error::new(eyre!("No DNS Upstream"), ErrorKind::Network)))
+}
+
+async fn forward_to(query: &[u8], upstream: SocketAddr) -> Result<Vec<u8>, Error> {
+    let socket = UdpSocket::bind(match upstream {
+        SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+        SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+    })
+    .await?;
+    socket.connect(upstream).await?;
+    socket.send(query).await?;
+    let mut buf = [0; 4096];
+    let len = tokio::time::timeout(FORWARD_TIMEOUT, socket.recv(&mut buf))
+        .await
+        .map_err(|_| {
+            Error::new(
+                eyre!("Timed out waiting for {}", upstream),
+                ErrorKind::Network,
+            )
+        })??;
+    Ok(buf[..len].to_vec())
+}
+
+#[test]
+fn test_dns_response() {
+    // A query for Embassy-1234.local with RD set
+    let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+    query.push(11);
+    query.extend_from_slice(b"Embassy-1234");
+    query.push(5);
+    query.extend_from_slice(b"local");
+    query.extend_from_slice(&[0, 0, 1, 0, 1]);
+    let question = parse_question(&query).unwrap();
+    assert_eq!(
+        question,
+        Question {
+            name: "embassy-1234.local".to_owned(),
+            qtype: TYPE_A,
+            qclass: CLASS_IN,
+            end: query.len(),
+        }
+    );
+    let res = response(&query, &question, 0, Some(Ipv4Addr::new(10, 59, 0, 1)));
+    assert_eq!(&res[0..4], &[0x12, 0x34, 0x85, 0x00]);
+    assert_eq!(&res[4..12], &[0, 1, 0, 1, 0, 0, 0, 0]);
+    assert_eq!(&res[12..query.len()], &query[12..]);
+    assert_eq!(
+        &res[query.len()..],
+        &[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 10, 59, 0, 1]
+    );
+}
+
+#[test]
+fn test_upstream_nameservers() {
+    assert_eq!(
+        upstream_nameservers("# Generated by NetworkManager\nsearch lan\nnameserver 192.168.1.1\nnameserver 1.1.1.1\n"),
+        vec![
+            SocketAddr::from(([192, 168, 1, 1], DNS_PORT)),
+            SocketAddr::from(([1, 1, 1, 1], DNS_PORT))
+        ]
+    );
+    assert_eq!(
+        "9.9.9.9, 192.168.1.1:5353".parse::<DnsUpstream>().unwrap(),
+        DnsUpstream::Servers {
+            servers: vec![
+                SocketAddr::from(([9, 9, 9, 9], DNS_PORT)),
+                SocketAddr::from(([192, 168, 1, 1], 5353))
+            ]
+        }
+    );
+}
+
+#[test]
+fn test_dns_lookup() {
+    let host = Ipv4Addr::new(10, 59, 0, 1);
+    let mut records = DnsRecords {
+        hostname: "embassy-1234.local".to_owned(),
+        ..DnsRecords::default()
+    };
+    let package: PackageId = "bitcoind".parse().unwrap();
+    records
+        .packages
+        .insert(package.clone(), Ipv4Addr::new(172, 18, 0, 2));
+    records.services.insert(
+        (package, "rpc".parse().unwrap()),
+        vec!["abcdef.local".to_owned(), "btc.example.com".to_owned()]
+            .into_iter()
+            .collect(),
+    );
+    assert_eq!(records.lookup("embassy-1234.local", host), Some(host));
+    assert_eq!(records.lookup("embassy", host), Some(host));
+    assert_eq!(
+        records.lookup("bitcoind.embassy", host),
+        Some(Ipv4Addr::new(172, 18, 0, 2))
+    );
+    assert_eq!(records.lookup("btc.example.com", host), Some(host));
+    assert_eq!(records.lookup("lnd.embassy", host), None);
+    assert!(is_authoritative("lnd.embassy"));
+    assert!(!is_authoritative("example.com"));
+}
+
+#[tokio::test]
+async fn test_upstream_for() {
+    let upstream = RwLock::new(vec![SocketAddr::from(([1, 1, 1, 1], 53))]);
+    assert_eq!(
+        upstream_for(Some(Egress::Clearnet), &upstream).await,
+        vec![SocketAddr::from(([1, 1, 1, 1], 53))]
+    );
+    assert_eq!(
+        upstream_for(Some(Egress::TorOnly), &upstream).await,
+        vec![SocketAddr::from(([127, 0, 0, 1], TOR_DNS_PORT))]
+    );
+    assert!(upstream_for(Some(Egress::Isolated), &upstream)
+        .await
+        .is_empty());
+    assert!(upstream_for(None, &upstream).await.is_empty());
+}
@@ -0,0 +1,251 @@
+use std::collections::BTreeMap;
+
+use clap::ArgMatches;
+use color_eyre::eyre::eyre;
+use patch_db::{DbHandle, LockType};
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use super::interface::{ensure_lan_interface, InterfaceId};
+use crate::context::RpcContext;
+use crate::db::util::WithRevision;
+use crate::s9pk::manifest::PackageId;
+use crate::util::display_none;
+use crate::util::serde::{display_serializable, IoFormat};
+use crate::{Error, ErrorKind};
+
+#[command(subcommands(add, remove, list))]
+pub fn domain() -> Result<(), Error> {
+    Ok(())
+}
+
+/// Lowercases `domain` and checks that it is a fully qualified name we could get a certificate for.
+pub fn parse_domain(domain: &str) -> Result<String, Error> {
+    let domain = domain.trim().trim_end_matches('.').to_ascii_lowercase();
+    let labels = domain.split('.').collect::<Vec<_>>();
+    let valid_label = |l: &&str| {
+        !l.is_empty()
+            && l.len() <= 63
+            && !l.starts_with('-')
+            && !l.ends_with('-')
+            && l.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+    if labels.len() < 2 || domain.len() > 253 || !labels.iter().all(valid_label) {
+        return Err(Error::new(
+            eyre!("{} is not a valid domain name", domain),
+            ErrorKind::ParseNetAddress,
+        ));
+    }
+    if domain.ends_with(".local") || domain.ends_with(".onion") {
+        return Err(Error::new(
+            eyre!("{} is not a public domain name", domain),
+            ErrorKind::ParseNetAddress,
+        ));
+    }
+    Ok(domain)
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DomainOwner {
+    pub package: PackageId,
+    pub interface: InterfaceId,
+}
+
+async fn domain_owners<Db: DbHandle>(db: &mut Db) -> Result<BTreeMap<String, DomainOwner>, Error> {
+    let mut owners = BTreeMap::new();
+    for (package, pde) in crate::db::DatabaseModel::new()
+        .package_data()
+        .get(db, true)
+        .await?
+        .0
+        .iter()
+    {
+        if let Some(installed) = pde.installed() {
+            for (interface, addrs) in &installed.interface_addresses.0 {
+                for domain in &addrs.domains {
+                    owners.insert(
+                        domain.clone(),
+                        DomainOwner {
+                            package: package.clone(),
+                            interface: interface.clone(),
+                        },
+                    );
+                }
+            }
+        }
+    }
+    Ok(owners)
+}
+
+/// The package interface serving `domain`, if any.
+pub async fn domain_owner<Db: DbHandle>(
+    db: &mut Db,
+    domain: &str,
+) -> Result<Option<DomainOwner>, Error> {
+    Ok(domain_owners(db).await?.remove(domain))
+}
+
+/// Serves the lan ports of a package interface at `domain`, in addition to its `.local` address.
+#[command(display(display_none))]
+#[instrument(skip(ctx))]
+pub async fn add(
+    #[context] ctx: RpcContext,
+    #[arg] package: PackageId,
+    #[arg] interface: InterfaceId,
+    #[arg] domain: String,
+) -> Result<WithRevision<()>, Error> {
+    let domain = parse_domain(&domain)?;
+    let mut db = ctx.db.handle();
+    let mut tx = db.begin().await?;
+    crate::db::DatabaseModel::new()
+        .package_data()
+        .lock(&mut tx, LockType::Write)
+        .await?;
+    if let Some(owner) = domain_owner(&mut tx, &domain).await? {
+        return Err(Error::new(
+            eyre!(
+                "{} is already in use by {} interface {}",
+                domain,
+                owner.package,
+                owner.interface
+            ),
+            ErrorKind::Duplicate,
+        ));
+    }
+    if crate::db::DatabaseModel::new()
+        .server_info()
+        .acme()
+        .get(&mut tx, true)
+        .await?
+        .domains
+        .contains_key(&domain)
+    {
+        return Err(Error::new(
+            eyre!("{} is already in use by the embassy UI", domain),
+            ErrorKind::Duplicate,
+        ));
+    }
+    ensure_lan_interface(&mut tx, &package, &interface).await?;
+    let mut addrs = crate::db::DatabaseModel::new()
+        .package_data()
+        .idx_model(&package)
+        .and_then(|pde| pde.installed())
+        .and_then(|i| i.interface_addresses().idx_model(&interface))
+        .expect(&mut tx)
+        .await?
+        .get_mut(&mut tx)
+        .await?;
+    addrs.domains.insert(domain);
+    let domains = addrs.domains.clone();
+    addrs.save(&mut tx).await?;
+    let revision = tx.commit(None).await?;
+    ctx.net_controller
+        .dns
+        .set_domains(&package, &interface, domains.clone())
+        .await;
+    ctx.net_controller
+        .nginx
+        .set_domains(&ctx.net_controller.ssl, &package, &interface, domains)
+        .await?;
+
+    Ok(WithRevision {
+        response: (),
+        revision,
+    })
+}
+
+#[command(display(display_none))]
+#[instrument(skip(ctx))]
+pub async fn remove(
+    #[context] ctx: RpcContext,
+    #[arg] domain: String,
+) -> Result<WithRevision<()>, Error> {
+    let domain = parse_domain(&domain)?;
+    let mut db = ctx.db.handle();
+    let mut tx = db.begin().await?;
+    crate::db::DatabaseModel::new()
+        .package_data()
+        .lock(&mut tx, LockType::Write)
+        .await?;
+    let owner = domain_owner(&mut tx, &domain).await?.ok_or_else(|| {
+        Error::new(
+            eyre!("{} is not in use by any package", domain),
+            ErrorKind::NotFound,
+        )
+    })?;
+    let mut addrs = crate::db::DatabaseModel::new()
+        .package_data()
+        .idx_model(&owner.package)
+        .and_then(|pde| pde.installed())
+        .and_then(|i| i.interface_addresses().idx_model(&owner.interface))
+        .expect(&mut tx)
+        .await?
+        .get_mut(&mut tx)
+        .await?;
+    addrs.domains.remove(&domain);
+    let domains = addrs.domains.clone();
+    addrs.save(&mut tx).await?;
+    let revision = tx.commit(None).await?;
+    ctx.net_controller
+        .dns
+        .set_domains(&owner.package, &owner.interface, domains.clone())
+        .await;
+    ctx.net_controller
+        .nginx
+        .set_domains(
+            &ctx.net_controller.ssl,
+            &owner.package,
+            &owner.interface,
+            domains,
+        )
+        .await?;
+
+    Ok(WithRevision {
+        response: (),
+        revision,
+    })
+}
+
+fn display_domains(domains: BTreeMap<String, DomainOwner>, matches: &ArgMatches<'_>) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(domains, matches);
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![bc => "DOMAIN", "PACKAGE", "INTERFACE"]);
+    for (domain, owner) in domains {
+        table.add_row(row![
+            &domain,
+            &owner.package.to_string(),
+            &owner.interface.to_string()
+        ]);
+    }
+    table.print_tty(false);
+}
+
+#[command(display(display_domains))]
+#[instrument(skip(ctx))]
+pub async fn list(
+    #[context] ctx: RpcContext,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<BTreeMap<String, DomainOwner>, Error> {
+    domain_owners(&mut ctx.db.handle()).await
+}
+
+#[test]
+fn test_parse_domain() {
+    assert_eq!(
+        parse_domain("Cloud.Example.com.").unwrap(),
+        "cloud.example.com"
+    );
+    assert!(parse_domain("localhost").is_err());
+    assert!(parse_domain("foo.local").is_err());
+    assert!(parse_domain("-bad.example.com").is_err());
+    assert!(parse_domain("bad_label.example.com").is_err());
+}
@@ -0,0 +1,423 @@
+//! Restricts what package containers may connect to, as declared by the `network` section of
+//! their manifest. Every container on the `start9` network gets a chain in an nftables table of
+//! our own once its address is known, and anything from the network that is not registered yet is
+//! dropped, so a container is never unrestricted. The table is replaced as a whole whenever a
+//! container starts or stops, since the rules of a package refer to the addresses of the packages
+//! it may reach. Dropped connections are logged to the kernel log with the prefix
+//! `embassy egress <package>: `.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::Ipv4Addr;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bollard::Docker;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::{Mutex, RwLock};
+use tracing::instrument;
+
+use super::vpn::PACKAGE_NETWORK;
+use crate::s9pk::manifest::PackageId;
+use crate::util::Invoke;
+use crate::{Error, ErrorKind, HOST_IP};
+
+pub const FIREWALL_TABLE: &str = "embassy-firewall";
+const TOR_SOCKS_PORT: u16 = 9050;
+const CONTAINER_IP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Egress {
+    /// Unrestricted access to the internet.
+    Clearnet,
+    /// Only through the tor socks proxy on the host.
+    TorOnly,
+    /// Nothing beyond the embassy itself.
+    #[serde(rename = "none")]
+    Isolated,
+}
+impl Default for Egress {
+    fn default() -> Self {
+        Egress::Clearnet
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct NetworkPolicy {
+    #[serde(default)]
+    pub egress: Egress,
+    /// The packages this one may connect to besides its dependencies. Any package if not declared.
+    #[serde(default)]
+    pub packages: Option<BTreeSet<PackageId>>,
+}
+
+/// The egress of each registered container by address, for the services on the embassy that
+/// make connections on behalf of containers, like the dns forwarder. Unregistered addresses on the
+/// `start9` network get none.
+pub type EgressMap = Arc<RwLock<BTreeMap<Ipv4Addr, Egress>>>;
+
+#[derive(Debug)]
+struct ContainerFirewall {
+    /// Tells runs of containers with the same name apart, see [`FirewallController::registration`].
+    registration: u64,
+    pkg_id: PackageId,
+    ip: Ipv4Addr,
+    egress: Egress,
+    reach: Option<BTreeSet<PackageId>>,
+}
+
+/// The whole table, replacing the previous one in the same transaction.
+fn ruleset(containers: &BTreeMap<String, ContainerFirewall>) -> String {
+    let mut forward = String::new();
+    let mut socks = Vec::new();
+    let mut chains = String::new();
+    for (name, ctr) in containers {
+        let log = format!(
+            "limit rate 6/minute log prefix \"embassy egress {}: \" level warn",
+            ctr.pkg_id
+        );
+        let mut rules = Vec::new();
+        match &ctr.reach {
+            Some(reach) => {
+                let allowed = containers
+                    .values()
+                    .filter(|other| reach.contains(&other.pkg_id))
+                    .map(|other| other.ip.to_string())
+                    .collect::<Vec<_>>();
+                if !allowed.is_empty() {
+                    rules.push(format!("ip daddr {{ {} }} accept", allowed.join(", ")));
+                }
+                rules.push(format!("ip daddr {} {}", PACKAGE_NETWORK, log));
+                rules.push(format!("ip daddr {} drop", PACKAGE_NETWORK));
+            }
+            None if ctr.egress != Egress::Clearnet => {
+                rules.push(format!("ip daddr {} accept", PACKAGE_NETWORK));
+            }
+            None => (),
+        }
+        if ctr.egress == Egress::Clearnet {
+            rules.push("accept".to_owned());
+        } else {
+            rules.push(log.clone());
+            rules.push("drop".to_owned());
+        }
+        if ctr.egress != Egress::Isolated {
+            socks.push(ctr.ip.to_string());
+        }
+        forward += &format!("        ip saddr {} jump \"pkg-{}\"\n", ctr.ip, name);
+        chains += &format!("    chain \"pkg-{}\" {{\n", name);
+        for rule in rules {
+            chains += &format!("        {}\n", rule);
+        }
+        chains += "    }\n";
+    }
+    let socks = if socks.is_empty() {
+        String::new()
+    } else {
+        format!(
+            "        ip saddr {{ {} }} tcp dport {} accept\n",
+            socks.join(", "),
+            TOR_SOCKS_PORT
+        )
+    };
+    format!(
+        concat!(
+            "table inet {table}\n",
+            "delete table inet {table}\n",
+            "table inet {table} {{\n",
+            "    chain forward {{\n",
+            "        type filter hook forward priority -10; policy accept;\n",
+            "        ct state established,related accept\n",
+            "{forward}",
+            "        ip saddr {network} {unregistered}\n",
+            "        ip saddr {network} drop\n",
+            "    }}\n",
+            "    chain input {{\n",
+            "        type filter hook input priority -10; policy accept;\n",
+            "{socks}",
+            "        ip saddr {network} ip saddr != {host} tcp dport {socks_port} {unregistered}\n",
+            "        ip saddr {network} ip saddr != {host} tcp dport {socks_port} drop\n",
+            "    }}\n",
+            "{chains}",
+            "}}\n",
+        ),
+        table = FIREWALL_TABLE,
+        network = PACKAGE_NETWORK,
+        host = Ipv4Addr::from(HOST_IP),
+        socks_port = TOR_SOCKS_PORT,
+        unregistered = "limit rate 6/minute log prefix \"embassy egress: \" level warn",
+        forward = forward,
+        socks = socks,
+        chains = chains,
+    )
+}
+
+#[instrument(skip(containers))]
+async fn apply(containers: &BTreeMap<String, ContainerFirewall>) -> Result<(), Error> {
+    let mut nft = Command::new("nft")
+        .arg("-f")
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = nft.stdin.take() {
+        stdin.write_all(ruleset(containers).as_bytes()).await?;
+    }
+    let res = nft.wait_with_output().await?;
+    crate::ensure_code!(
+        res.status.success(),
+        ErrorKind::Firewall,
+        "{}",
+        std::str::from_utf8(&res.stderr).unwrap_or("Unknown Error")
+    );
+    Ok(())
+}
+
+/// Lets everything from `ip` through the current table, for a container with nothing to enforce
+/// when the table could not be replaced. Fails if there is no table.
+#[instrument]
+async fn allow_unregistered(ip: Ipv4Addr) -> Result<(), Error> {
+    for chain in &["forward", "input"] {
+        Command::new("nft")
+            .arg("insert")
+            .arg("rule")
+            .arg("inet")
+            .arg(FIREWALL_TABLE)
+            .arg(chain)
+            .arg("ip")
+            .arg("saddr")
+            .arg(ip.to_string())
+            .arg("accept")
+            .invoke(ErrorKind::Firewall)
+            .await?;
+    }
+    Ok(())
+}
+
+async fn table_exists() -> bool {
+    Command::new("nft")
+        .arg("list")
+        .arg("table")
+        .arg("inet")
+        .arg(FIREWALL_TABLE)
+        .invoke(ErrorKind::Firewall)
+        .await
+        .is_ok()
+}
+
+/// Waits for `container` to get an address on the `start9` network.
+pub async fn container_ip(docker: &Docker, container: &str) -> Result<Ipv4Addr, Error> {
+    loop {
+        match docker.inspect_container(container, None).await {
+            Ok(res) => {
+                if let Some(ip) = res
+                    .network_settings
+                    .and_then(|ns| ns.networks)
+                    .and_then(|mut n| n.remove("start9"))
+                    .and_then(|es| es.ip_address)
+                    .filter(|ip| !ip.is_empty())
+                {
+                    return Ok(ip.parse()?);
+                }
+            }
+            Err(bollard::errors::Error::DockerResponseNotFoundError { .. }) => (),
+            Err(e) => return Err(e.into()),
+        }
+        tokio::time::sleep(CONTAINER_IP_POLL_INTERVAL).await;
+    }
+}
+
+pub struct FirewallController {
+    containers: Mutex<BTreeMap<String, ContainerFirewall>>,
+    egress: EgressMap,
+    next_registration: AtomicU64,
+}
+impl FirewallController {
+    /// Puts the default policy in place. The firewall is not required for the embassy to run: if
+    /// it cannot be set up, only the containers of packages that restrict their network are kept
+    /// from running.
+    #[instrument]
+    pub async fn init() -> Self {
+        let containers = BTreeMap::new();
+        if let Err(e) = apply(&containers).await {
+            tracing::error!("Could not set up the package firewall: {}", e);
+            tracing::debug!("{:?}", e);
+        }
+        FirewallController {
+            containers: Mutex::new(containers),
+            egress: Default::default(),
+            next_registration: AtomicU64::new(0),
+        }
+    }
+    pub fn egress(&self) -> EgressMap {
+        self.egress.clone()
+    }
+    /// A new id for a run of a container, to [`add`](Self::add) and [`remove`](Self::remove) it
+    /// with. A container restarted under the same name gets a different one, so the cleanup of
+    /// the previous run leaves it alone.
+    pub fn registration(&self) -> u64 {
+        self.next_registration.fetch_add(1, Ordering::Relaxed)
+    }
+    /// Applies the policy of a package to a container of it that has just started at `ip`. Its
+    /// dependencies are always reachable.
+    #[instrument(skip(self, policy, dependencies))]
+    pub async fn add<I: IntoIterator<Item = PackageId>>(
+        &self,
+        container: &str,
+        registration: u64,
+        pkg_id: &PackageId,
+        ip: Ipv4Addr,
+        policy: &NetworkPolicy,
+        dependencies: I,
+    ) -> Result<(), Error> {
+        let mut containers = self.containers.lock().await;
+        // a container that stopped without cleaning up may have held the address before
+        containers.retain(|_, ctr| ctr.ip != ip);
+        containers.insert(
+            container.to_owned(),
+            ContainerFirewall {
+                registration,
+                pkg_id: pkg_id.clone(),
+                ip,
+                egress: policy.egress,
+                reach: policy
+                    .packages
+                    .clone()
+                    .map(|reach| reach.into_iter().chain(dependencies).collect()),
+            },
+        );
+        match self.update(&containers).await {
+            Err(e) if policy.egress == Egress::Clearnet && policy.packages.is_none() => {
+                // nothing to enforce, but the table in place drops what it does not know
+                tracing::error!("Could not update the package firewall: {}", e);
+                tracing::debug!("{:?}", e);
+                if allow_unregistered(ip).await.is_ok() || !table_exists().await {
+                    Ok(())
+                } else {
+                    Err(e)
+                }
+            }
+            res => res,
+        }
+    }
+    /// Removes the run of `container` with id `registration`, if it is still registered.
+    #[instrument(skip(self))]
+    pub async fn remove(&self, container: &str, registration: u64) -> Result<(), Error> {
+        let mut containers = self.containers.lock().await;
+        if containers
+            .get(container)
+            .map_or(false, |ctr| ctr.registration == registration)
+        {
+            containers.remove(container);
+            if let Err(e) = self.update(&containers).await {
+                tracing::error!("Could not update the package firewall: {}", e);
+                tracing::debug!("{:?}", e);
+            }
+        }
+        Ok(())
+    }
+    async fn update(&self, containers: &BTreeMap<String, ContainerFirewall>) -> Result<(), Error> {
+        *self.egress.write().await = containers
+            .values()
+            .map(|ctr| (ctr.ip, ctr.egress))
+            .collect();
+        apply(containers).await
+    }
+}
+
+#[test]
+fn test_ruleset() {
+    let mut containers = BTreeMap::new();
+    containers.insert(
+        "bitcoind.embassy".to_owned(),
+        ContainerFirewall {
+            registration: 0,
+            pkg_id: "bitcoind".parse().unwrap(),
+            ip: Ipv4Addr::new(172, 18, 0, 2),
+            egress: Egress::Clearnet,
+            reach: None,
+        },
+    );
+    containers.insert(
+        "lnd.embassy".to_owned(),
+        ContainerFirewall {
+            registration: 0,
+            pkg_id: "lnd".parse().unwrap(),
+            ip: Ipv4Addr::new(172, 18, 0, 3),
+            egress: Egress::TorOnly,
+            reach: Some(
+                vec!["bitcoind".parse().unwrap(), "electrs".parse().unwrap()]
+                    .into_iter()
+                    .collect(),
+            ),
+        },
+    );
+    containers.insert(
+        "lnd_health.embassy".to_owned(),
+        ContainerFirewall {
+            registration: 0,
+            pkg_id: "lnd".parse().unwrap(),
+            ip: Ipv4Addr::new(172, 18, 0, 5),
+            egress: Egress::TorOnly,
+            reach: Some(vec!["bitcoind".parse().unwrap()].into_iter().collect()),
+        },
+    );
+    containers.insert(
+        "notes.embassy".to_owned(),
+        ContainerFirewall {
+            registration: 0,
+            pkg_id: "notes".parse().unwrap(),
+            ip: Ipv4Addr::new(172, 18, 0, 4),
+            egress: Egress::Isolated,
+            reach: None,
+        },
+    );
+    let rules = ruleset(&containers);
+    assert!(rules.contains("ip saddr 172.18.0.2 jump \"pkg-bitcoind.embassy\""));
+    assert!(rules.contains("ip saddr 172.18.0.3 jump \"pkg-lnd.embassy\""));
+    assert!(rules.contains("ip saddr 172.18.0.5 jump \"pkg-lnd_health.embassy\""));
+    // electrs is not running, so there is nothing to allow for it
+    assert!(rules.contains("ip daddr { 172.18.0.2 } accept"));
+    assert!(rules.contains("ip daddr 172.18.0.0/16 drop"));
+    // everything that is not registered is dropped
+    assert!(rules.contains("        ip saddr 172.18.0.0/16 drop\n"));
+    assert!(rules.contains("ip saddr { 172.18.0.2, 172.18.0.3, 172.18.0.5 } tcp dport 9050 accept"));
+    assert!(rules.contains("ip saddr 172.18.0.0/16 ip saddr != 172.18.0.1 tcp dport 9050 drop"));
+    assert!(rules.starts_with("table inet embassy-firewall\ndelete table inet embassy-firewall\n"));
+    assert!(ruleset(&BTreeMap::new()).contains("        ip saddr 172.18.0.0/16 drop\n"));
+}
+
+#[tokio::test]
+async fn test_remove_other_registration() {
+    let controller = FirewallController {
+        containers: Mutex::new(BTreeMap::new()),
+        egress: Default::default(),
+        next_registration: AtomicU64::new(0),
+    };
+    let old = controller.registration();
+    let new = controller.registration();
+    assert_ne!(old, new);
+    controller.containers.lock().await.insert(
+        "bitcoind.embassy".to_owned(),
+        ContainerFirewall {
+            registration: new,
+            pkg_id: "bitcoind".parse().unwrap(),
+            ip: Ipv4Addr::new(172, 18, 0, 2),
+            egress: Egress::Clearnet,
+            reach: None,
+        },
+    );
+    // the cleanup of the previous run comes in after the restarted container registered
+    controller.remove("bitcoind.embassy", old).await.unwrap();
+    assert!(controller
+        .containers
+        .lock()
+        .await
+        .contains_key("bitcoind.embassy"));
+}
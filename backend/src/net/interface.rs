@@ -1,17 +1,19 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::path::Path;
+use std::str::FromStr;
 
 use color_eyre::eyre::eyre;
 use futures::TryStreamExt;
 use indexmap::IndexSet;
 use itertools::Either;
+use patch_db::DbHandle;
 use serde::{Deserialize, Deserializer, Serialize};
 use sqlx::{Executor, Sqlite};
 use torut::onion::TorSecretKeyV3;
 use tracing::instrument;
 
 use crate::db::model::{InterfaceAddressMap, InterfaceAddresses};
-use crate::id::Id;
+use crate::id::{Id, InvalidId};
 use crate::s9pk::manifest::PackageId;
 use crate::util::serde::Port;
 use crate::{Error, ResultExt};
@@ -46,6 +48,7 @@ impl Interfaces {
             let mut addrs = InterfaceAddresses {
                 tor_address: None,
                 lan_address: None,
+                domains: BTreeSet::new(),
             };
             if iface.tor_config.is_some() || iface.lan_config.is_some() {
                 let key = TorSecretKeyV3::generate();
@@ -113,8 +116,40 @@ impl Interfaces {
     }
 }
 
+/// Fails unless `package` is installed and `interface` is one of its lan interfaces.
+pub async fn ensure_lan_interface<Db: DbHandle>(
+    db: &mut Db,
+    package: &PackageId,
+    interface: &InterfaceId,
+) -> Result<(), Error> {
+    let has_lan_config = crate::db::DatabaseModel::new()
+        .package_data()
+        .idx_model(package)
+        .and_then(|pde| pde.installed())
+        .map(|i| i.manifest().interfaces())
+        .get(db, true)
+        .await?
+        .as_ref()
+        .and_then(|interfaces| interfaces.0.get(interface))
+        .map(|i| i.lan_config.is_some())
+        .unwrap_or(false);
+    if !has_lan_config {
+        return Err(Error::new(
+            eyre!("{} has no lan interface {}", package, interface),
+            crate::ErrorKind::NotFound,
+        ));
+    }
+    Ok(())
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
 pub struct InterfaceId<S: AsRef<str> = String>(Id<S>);
+impl FromStr for InterfaceId {
+    type Err = InvalidId;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(InterfaceId(Id::try_from(s.to_owned())?))
+    }
+}
 impl<S: AsRef<str>> From<Id<S>> for InterfaceId<S> {
     fn from(id: Id<S>) -> Self {
         Self(id)
@@ -170,8 +205,22 @@ impl Interface {
         if self.tor_config.is_some() && !self.protocols.contains("tcp") {
             color_eyre::eyre::bail!("must support tcp to set up a tor hidden service");
         }
-        if self.lan_config.is_some() && !self.protocols.contains("http") {
-            color_eyre::eyre::bail!("must support http to set up a lan service");
+        for (port, lan) in self.lan_config.iter().flatten() {
+            match lan.protocol {
+                LanProtocol::Http if !self.protocols.contains("http") => {
+                    color_eyre::eyre::bail!("must support http to set up a lan service")
+                }
+                LanProtocol::Tcp if !self.protocols.contains("tcp") => {
+                    color_eyre::eyre::bail!("must support tcp to forward lan port {}", port.0)
+                }
+                LanProtocol::Udp if !self.protocols.contains("udp") => {
+                    color_eyre::eyre::bail!("must support udp to forward lan port {}", port.0)
+                }
+                LanProtocol::Udp if lan.ssl => {
+                    color_eyre::eyre::bail!("cannot terminate ssl on udp lan port {}", port.0)
+                }
+                _ => (),
+            }
         }
         if self.ui && !(self.protocols.contains("http") || self.protocols.contains("https")) {
             color_eyre::eyre::bail!("must support http or https to serve a ui");
@@ -186,11 +235,34 @@ pub struct TorConfig {
     pub port_mapping: BTreeMap<Port, Port>,
 }
 
+/// How nginx exposes a lan port.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LanProtocol {
+    /// Reverse proxied per `server_name`, so several packages may share the port.
+    Http,
+    /// Forwarded as a raw stream, optionally terminating ssl. The port belongs to one package.
+    Tcp,
+    /// Forwarded as datagrams. The port belongs to one package.
+    Udp,
+}
+impl Default for LanProtocol {
+    fn default() -> Self {
+        LanProtocol::Http
+    }
+}
+impl LanProtocol {
+    pub fn is_stream(&self) -> bool {
+        *self != LanProtocol::Http
+    }
+}
+
 #[derive(Clone, Debug, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct LanPortConfig {
     pub ssl: bool,
     pub internal: u16,
+    pub protocol: LanProtocol,
 }
 impl<'de> Deserialize<'de> for LanPortConfig {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
@@ -203,6 +275,8 @@ impl<'de> Deserialize<'de> for LanPortConfig {
             ssl: bool,
             internal: Option<u16>,
             mapping: Option<u16>,
+            #[serde(default)]
+            protocol: LanProtocol,
         }
 
         let config = PermissiveLanPortConfig::deserialize(deserializer)?;
@@ -212,6 +286,7 @@ impl<'de> Deserialize<'de> for LanPortConfig {
                 .internal
                 .or(config.mapping)
                 .ok_or_else(|| serde::de::Error::missing_field("internal"))?,
+            protocol: config.protocol,
         })
     }
 }
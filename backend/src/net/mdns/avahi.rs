@@ -12,7 +12,7 @@ use tokio::process::Command;
 use tokio::sync::Mutex;
 use torut::onion::TorSecretKeyV3;
 
-use super::interface::InterfaceId;
+use crate::net::interface::InterfaceId;
 use crate::s9pk::manifest::PackageId;
 use crate::util::Invoke;
 use crate::Error;
@@ -0,0 +1,16 @@
+//! Publishes the embassy's hostname and the lan addresses of package interfaces as `.local`
+//! names. With the `avahi` feature this goes through avahi-daemon, otherwise through a responder
+//! of our own, for deployments without one.
+
+#[cfg(feature = "avahi")]
+mod avahi;
+#[cfg(not(feature = "avahi"))]
+mod responder;
+
+#[cfg(feature = "avahi")]
+pub use self::avahi::{resolve_mdns, MdnsController};
+#[cfg(not(feature = "avahi"))]
+pub use self::responder::{resolve_mdns, MdnsController};
+
+/// Bound by avahi-daemon or our own responder.
+pub const MDNS_PORT: u16 = 5353;
@@ -0,0 +1,570 @@
+//! A minimal mDNS responder (RFC 6762): answers A queries for our names on every multicast
+//! capable link with the addresses of that link, announces names as they are added and says
+//! goodbye as they are removed. It does not probe for conflicts, since the names of package
+//! interfaces are derived from their onion addresses.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::Arc;
+use std::time::Duration;
+
+use color_eyre::eyre::eyre;
+use nix::ifaddrs::getifaddrs;
+use nix::net::if_::{if_nametoindex, InterfaceFlags};
+use nix::sys::socket::{
+    bind, recvmsg, setsockopt, socket, sockopt, AddressFamily, ControlMessageOwned, InetAddr,
+    MsgFlags, SockAddr, SockFlag, SockType,
+};
+use nix::sys::uio::IoVec;
+use tokio::io::Interest;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, RwLock};
+use torut::onion::TorSecretKeyV3;
+
+use super::MDNS_PORT;
+use crate::net::interface::InterfaceId;
+use crate::net::vpn::VPN_INTERFACE;
+use crate::s9pk::manifest::PackageId;
+use crate::util::NonDetachingJoinHandle;
+use crate::{Error, ErrorKind};
+
+const MDNS_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const TTL: u32 = 120;
+/// RFC 6762 §10.1: a record with a TTL of zero says it is no longer valid.
+const GOODBYE_TTL: u32 = 0;
+/// RFC 6762 §6.7: answers to legacy unicast queries must not be cached for long.
+const LEGACY_TTL: u32 = 10;
+const LINK_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+const RESOLVE_ATTEMPTS: usize = 3;
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(1);
+const TYPE_A: u16 = 1;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+const CLASS_ANY: u16 = 255;
+const CACHE_FLUSH: u16 = 0x8000;
+/// Interfaces that are not links to the lan.
+const EXCLUDED_INTERFACES: &[&str] = &["docker", "br-", "veth", VPN_INTERFACE];
+
+/// Resolves an `.local` hostname with a one-shot query.
+pub async fn resolve_mdns(hostname: &str) -> Result<IpAddr, Error> {
+    let socket = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0))).await?;
+    let id = rand::random();
+    let mut query = header(id, 0, 1, 0);
+    write_name(&mut query, hostname);
+    query.extend_from_slice(&TYPE_A.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    let mut buf = [0; 9000];
+    for _ in 0..RESOLVE_ATTEMPTS {
+        socket.send_to(&query, (MDNS_ADDR, MDNS_PORT)).await?;
+        let deadline = tokio::time::Instant::now() + RESOLVE_TIMEOUT;
+        while let Ok(res) = tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+            if let Some(ip) = parse_answers(&buf[..res?])
+                .into_iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(hostname.trim_end_matches('.')))
+                .map(|(_, ip)| ip)
+            {
+                return Ok(ip.into());
+            }
+        }
+    }
+    Err(Error::new(
+        eyre!("Failed to resolve hostname: {}", hostname),
+        ErrorKind::Network,
+    ))
+}
+
+pub struct MdnsController {
+    services: Arc<RwLock<BTreeMap<(PackageId, InterfaceId), String>>>,
+    /// Names to announce, with the TTL to announce them with.
+    announce: mpsc::UnboundedSender<(Vec<String>, u32)>,
+    _responder: NonDetachingJoinHandle<()>,
+}
+impl MdnsController {
+    pub fn init() -> Self {
+        let services = Arc::new(RwLock::new(BTreeMap::new()));
+        let (announce, announcements) = mpsc::unbounded_channel();
+        MdnsController {
+            services: services.clone(),
+            announce,
+            _responder: tokio::spawn(async move {
+                if let Err(e) = respond(services, announcements).await {
+                    tracing::error!("mDNS responder failed: {}", e);
+                    tracing::debug!("{:?}", e);
+                }
+            })
+            .into(),
+        }
+    }
+    pub async fn add<'a, I: IntoIterator<Item = (InterfaceId, TorSecretKeyV3)>>(
+        &self,
+        pkg_id: &PackageId,
+        interfaces: I,
+    ) {
+        let mut services = self.services.write().await;
+        let mut added = Vec::new();
+        for (interface_id, key) in interfaces {
+            let lan_address = key
+                .public()
+                .get_onion_address()
+                .get_address_without_dot_onion()
+                + ".local";
+            services.insert((pkg_id.clone(), interface_id), lan_address.clone());
+            added.push(lan_address);
+        }
+        // nothing to announce to if the responder failed to start
+        self.announce.send((added, TTL)).ok();
+    }
+    pub async fn remove<I: IntoIterator<Item = InterfaceId>>(
+        &self,
+        pkg_id: &PackageId,
+        interfaces: I,
+    ) {
+        let mut services = self.services.write().await;
+        let mut removed = Vec::new();
+        for interface_id in interfaces {
+            if let Some(lan_address) = services.remove(&(pkg_id.clone(), interface_id)) {
+                removed.push(lan_address);
+            }
+        }
+        self.announce.send((removed, GOODBYE_TTL)).ok();
+    }
+}
+
+/// A socket bound to `0.0.0.0:5353` or `<address>:5353`, sharing the port with any other
+/// responder on the system.
+fn mdns_socket(address: Ipv4Addr) -> Result<UdpSocket, Error> {
+    let fd = socket(
+        AddressFamily::Inet,
+        SockType::Datagram,
+        SockFlag::SOCK_CLOEXEC | SockFlag::SOCK_NONBLOCK,
+        None,
+    )
+    .map_err(|e| Error::new(e, ErrorKind::Network))?;
+    // owns the fd from here on, closing it on error
+    let socket = unsafe { std::net::UdpSocket::from_raw_fd(fd) };
+    setsockopt(fd, sockopt::ReuseAddr, &true).map_err(|e| Error::new(e, ErrorKind::Network))?;
+    setsockopt(fd, sockopt::ReusePort, &true).map_err(|e| Error::new(e, ErrorKind::Network))?;
+    bind(
+        fd,
+        &SockAddr::new_inet(InetAddr::from_std(&SocketAddr::from((address, MDNS_PORT)))),
+    )
+    .map_err(|e| Error::new(e, ErrorKind::Network))?;
+    socket.set_multicast_ttl_v4(255)?;
+    Ok(UdpSocket::from_std(socket)?)
+}
+
+/// The ipv4 addresses of the links we respond on, with the index of their interface.
+fn link_addresses() -> Result<Vec<(Ipv4Addr, u32)>, Error> {
+    let mut res = Vec::new();
+    for ifaddr in getifaddrs().map_err(|e| Error::new(e, ErrorKind::Network))? {
+        if ifaddr.flags.contains(InterfaceFlags::IFF_LOOPBACK)
+            || !ifaddr.flags.contains(InterfaceFlags::IFF_MULTICAST)
+            || EXCLUDED_INTERFACES
+                .iter()
+                .any(|prefix| ifaddr.interface_name.starts_with(prefix))
+        {
+            continue;
+        }
+        if let Some(SockAddr::Inet(addr)) = ifaddr.address {
+            if let IpAddr::V4(ip) = addr.ip().to_std() {
+                match if_nametoindex(ifaddr.interface_name.as_str()) {
+                    Ok(index) => res.push((ip, index)),
+                    Err(e) => {
+                        tracing::debug!("Could not index {}: {}", ifaddr.interface_name, e)
+                    }
+                }
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// Links we answer on. Multicast leaves through the link owning the address a socket is bound
+/// to, so answering on each of these reaches every lan we are on.
+struct Links {
+    receiver: UdpSocket,
+    /// The socket bound to each link address, with the index of the interface it is on.
+    senders: BTreeMap<Ipv4Addr, (u32, UdpSocket)>,
+}
+impl Links {
+    fn init() -> Result<Self, Error> {
+        let receiver = mdns_socket(Ipv4Addr::UNSPECIFIED)?;
+        // tells us the interface each query arrived on
+        setsockopt(receiver.as_raw_fd(), sockopt::Ipv4PacketInfo, &true)
+            .map_err(|e| Error::new(e, ErrorKind::Network))?;
+        let mut res = Links {
+            receiver,
+            senders: BTreeMap::new(),
+        };
+        res.refresh()?;
+        Ok(res)
+    }
+    /// Follows addresses coming and going with dhcp leases and cables.
+    fn refresh(&mut self) -> Result<(), Error> {
+        let addresses = link_addresses()?;
+        self.senders
+            .retain(|address, (index, _)| addresses.contains(&(*address, *index)));
+        for (address, index) in addresses {
+            if self.senders.contains_key(&address) {
+                continue;
+            }
+            if let Err(e) = self.receiver.join_multicast_v4(MDNS_ADDR, address) {
+                // already a member from before the address was lost
+                tracing::debug!("Could not join mDNS group on {}: {:?}", address, e);
+            }
+            match mdns_socket(address) {
+                Ok(socket) => {
+                    self.senders.insert(address, (index, socket));
+                }
+                Err(e) => {
+                    tracing::error!("Could not respond to mDNS on {}: {}", address, e);
+                    tracing::debug!("{:?}", e);
+                }
+            }
+        }
+        Ok(())
+    }
+    /// The addresses of the link of interface `index`, or of every link.
+    fn addresses(&self, index: Option<u32>) -> Vec<Ipv4Addr> {
+        self.senders
+            .iter()
+            .filter(|(_, (i, _))| index.map_or(true, |index| *i == index))
+            .map(|(address, _)| *address)
+            .collect()
+    }
+    /// Receives a packet, along with the index of the interface it arrived on.
+    async fn recv(&self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr, Option<u32>)> {
+        loop {
+            self.receiver.readable().await?;
+            let res = self.receiver.try_io(Interest::READABLE, || {
+                let mut cmsg = nix::cmsg_space!(libc::in_pktinfo);
+                let msg = recvmsg(
+                    self.receiver.as_raw_fd(),
+                    &[IoVec::from_mut_slice(&mut *buf)],
+                    Some(&mut cmsg),
+                    MsgFlags::empty(),
+                )
+                .map_err(|e| std::io::Error::from_raw_os_error(e as i32))?;
+                let from = match msg.address {
+                    Some(SockAddr::Inet(addr)) => addr.to_std(),
+                    _ => {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            "mDNS packet without an ipv4 source",
+                        ))
+                    }
+                };
+                let index = msg.cmsgs().find_map(|cmsg| match cmsg {
+                    ControlMessageOwned::Ipv4PacketInfo(info) => Some(info.ipi_ifindex as u32),
+                    _ => None,
+                });
+                Ok((msg.bytes, from, index))
+            });
+            match res {
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                res => return res,
+            }
+        }
+    }
+    /// Multicasts `packet` on the link of interface `index`.
+    async fn multicast(&self, index: u32, packet: &[u8]) {
+        if let Some((address, (_, socket))) = self.senders.iter().find(|(_, (i, _))| *i == index) {
+            if let Err(e) = socket.send_to(packet, (MDNS_ADDR, MDNS_PORT)).await {
+                tracing::debug!("Error sending mDNS response on {}: {:?}", address, e);
+            }
+        }
+    }
+    /// Multicasts records for `names` on every link, each with the addresses of that link.
+    async fn announce(&self, names: &[String], ttl: u32) {
+        let indices = self
+            .senders
+            .values()
+            .map(|(index, _)| *index)
+            .collect::<BTreeSet<_>>();
+        for index in indices {
+            let packet = answers(0, None, names, &self.addresses(Some(index)), ttl);
+            self.multicast(index, &packet).await;
+        }
+    }
+}
+
+async fn respond(
+    services: Arc<RwLock<BTreeMap<(PackageId, InterfaceId), String>>>,
+    mut announcements: mpsc::UnboundedReceiver<(Vec<String>, u32)>,
+) -> Result<(), Error> {
+    let hostname = format!(
+        "{}.local",
+        crate::hostname::get_current_hostname()
+            .await?
+            .trim()
+            .to_ascii_lowercase()
+    );
+    let mut links = Links::init()?;
+    links.announce(&[hostname.clone()], TTL).await;
+    let mut refresh = tokio::time::interval(LINK_REFRESH_INTERVAL);
+    let mut buf = [0; 9000];
+    loop {
+        tokio::select! {
+            _ = refresh.tick() => {
+                if let Err(e) = links.refresh() {
+                    tracing::error!("Could not list mDNS links: {}", e);
+                    tracing::debug!("{:?}", e);
+                }
+            }
+            announcement = announcements.recv() => {
+                if let Some((names, ttl)) = announcement.filter(|(n, _)| !n.is_empty()) {
+                    links.announce(&names, ttl).await;
+                }
+            }
+            res = links.recv(&mut buf) => {
+                let (len, from, index) = match res {
+                    Ok(a) => a,
+                    Err(e) => {
+                        tracing::error!("Error receiving mDNS query: {}", e);
+                        tracing::debug!("{:?}", e);
+                        continue;
+                    }
+                };
+                let query = &buf[..len];
+                let services = services.read().await;
+                let is_ours = |name: &str| {
+                    name == hostname || services.values().any(|s| s == name)
+                };
+                let questions = parse_questions(query)
+                    .into_iter()
+                    .filter(|q| {
+                        (q.qtype == TYPE_A || q.qtype == TYPE_ANY)
+                            && (q.qclass == CLASS_IN || q.qclass == CLASS_ANY)
+                            && is_ours(&q.name)
+                    })
+                    .collect::<Vec<_>>();
+                drop(services);
+                if questions.is_empty() {
+                    continue;
+                }
+                // RFC 6762 §15: answer with the addresses of the link the query arrived on
+                if from.port() == MDNS_PORT {
+                    let names = questions.into_iter().map(|q| q.name).collect::<Vec<_>>();
+                    match index {
+                        Some(index) => {
+                            let packet = answers(0, None, &names, &links.addresses(Some(index)), TTL);
+                            links.multicast(index, &packet).await;
+                        }
+                        None => links.announce(&names, TTL).await,
+                    }
+                } else {
+                    // legacy unicast: a plain dns resolver sending to the group, expecting a
+                    // plain dns response
+                    let id = u16::from_be_bytes([query[0], query[1]]);
+                    for q in questions {
+                        let res = answers(
+                            id,
+                            Some(&q.name),
+                            &[q.name.clone()],
+                            &links.addresses(index),
+                            LEGACY_TTL,
+                        );
+                        if let Err(e) = links.receiver.send_to(&res, from).await {
+                            tracing::debug!("Error sending mDNS response: {:?}", e);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn header(id: u16, flags: u16, questions: u16, answers: u16) -> Vec<u8> {
+    let mut res = Vec::with_capacity(512);
+    res.extend_from_slice(&id.to_be_bytes());
+    res.extend_from_slice(&flags.to_be_bytes());
+    res.extend_from_slice(&questions.to_be_bytes());
+    res.extend_from_slice(&answers.to_be_bytes());
+    res.extend_from_slice(&[0, 0, 0, 0]);
+    res
+}
+
+fn write_name(buf: &mut Vec<u8>, name: &str) {
+    for label in name.trim_end_matches('.').split('.') {
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+}
+
+/// Reads the possibly compressed name at `pos`, returning it lowercase along with the offset
+/// after it.
+fn read_name(packet: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // every pointer must go backwards, so this bounds the loop
+    let mut limit = pos;
+    loop {
+        let len = *packet.get(pos)? as usize;
+        if len & 0xc0 == 0xc0 {
+            let target = ((len & 0x3f) << 8) | *packet.get(pos + 1)? as usize;
+            if target >= limit {
+                return None;
+            }
+            end.get_or_insert(pos + 2);
+            limit = target;
+            pos = target;
+            continue;
+        }
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        labels.push(std::str::from_utf8(packet.get(pos..pos + len)?).ok()?);
+        pos += len;
+    }
+    Some((labels.join(".").to_ascii_lowercase(), end.unwrap_or(pos)))
+}
+
+struct Question {
+    name: String,
+    qtype: u16,
+    /// Without the unicast-response bit.
+    qclass: u16,
+}
+
+fn parse_questions(packet: &[u8]) -> Vec<Question> {
+    let mut res = Vec::new();
+    let header = match packet.get(0..12) {
+        Some(a) => a,
+        None => return res,
+    };
+    if header[2] & 0x80 != 0 {
+        return res;
+    }
+    let mut pos = 12;
+    for _ in 0..u16::from_be_bytes([header[4], header[5]]) {
+        let (name, end) = match read_name(packet, pos) {
+            Some(a) => a,
+            None => break,
+        };
+        let fixed = match packet.get(end..end + 4) {
+            Some(a) => a,
+            None => break,
+        };
+        res.push(Question {
+            name,
+            qtype: u16::from_be_bytes([fixed[0], fixed[1]]),
+            qclass: u16::from_be_bytes([fixed[2], fixed[3]]) & !CACHE_FLUSH,
+        });
+        pos = end + 4;
+    }
+    res
+}
+
+/// The A records of a response, from any section.
+fn parse_answers(packet: &[u8]) -> Vec<(String, Ipv4Addr)> {
+    let mut res = Vec::new();
+    let header = match packet.get(0..12) {
+        Some(a) => a,
+        None => return res,
+    };
+    if header[2] & 0x80 == 0 {
+        return res;
+    }
+    let count = |i: usize| u16::from_be_bytes([header[i], header[i + 1]]) as usize;
+    let mut pos = 12;
+    for _ in 0..count(4) {
+        match read_name(packet, pos) {
+            Some((_, end)) => pos = end + 4,
+            None => return res,
+        }
+    }
+    for _ in 0..(count(6) + count(8) + count(10)) {
+        let (name, end) = match read_name(packet, pos) {
+            Some(a) => a,
+            None => break,
+        };
+        let fixed = match packet.get(end..end + 10) {
+            Some(a) => a,
+            None => break,
+        };
+        let rtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let rdlength = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        let rdata = match packet.get(end + 10..end + 10 + rdlength) {
+            Some(a) => a,
+            None => break,
+        };
+        if rtype == TYPE_A && rdlength == 4 {
+            res.push((name, Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3])));
+        }
+        pos = end + 10 + rdlength;
+    }
+    res
+}
+
+/// A response with an A record for each of `names` and `addresses`. Legacy unicast responses
+/// echo their `question`, and are not to flush caches.
+fn answers(
+    id: u16,
+    question: Option<&str>,
+    names: &[String],
+    addresses: &[Ipv4Addr],
+    ttl: u32,
+) -> Vec<u8> {
+    let mut res = header(
+        id,
+        0x8400,
+        question.is_some() as u16,
+        (names.len() * addresses.len()) as u16,
+    );
+    if let Some(question) = question {
+        write_name(&mut res, question);
+        res.extend_from_slice(&TYPE_A.to_be_bytes());
+        res.extend_from_slice(&CLASS_IN.to_be_bytes());
+    }
+    let class = if question.is_some() {
+        CLASS_IN
+    } else {
+        CLASS_IN | CACHE_FLUSH
+    };
+    for name in names {
+        for address in addresses {
+            write_name(&mut res, name);
+            res.extend_from_slice(&TYPE_A.to_be_bytes());
+            res.extend_from_slice(&class.to_be_bytes());
+            res.extend_from_slice(&ttl.to_be_bytes());
+            res.extend_from_slice(&4u16.to_be_bytes());
+            res.extend_from_slice(&address.octets());
+        }
+    }
+    res
+}
+
+#[test]
+fn test_mdns_answers() {
+    let addresses = [Ipv4Addr::new(192, 168, 1, 10), Ipv4Addr::new(10, 0, 0, 5)];
+    let res = answers(0, None, &["embassy-1234.local".to_owned()], &addresses, TTL);
+    assert_eq!(&res[0..12], &[0, 0, 0x84, 0, 0, 0, 0, 2, 0, 0, 0, 0]);
+    assert_eq!(
+        parse_answers(&res),
+        vec![
+            ("embassy-1234.local".to_owned(), addresses[0]),
+            ("embassy-1234.local".to_owned(), addresses[1])
+        ]
+    );
+
+    // two questions, the second compressed against the first
+    let mut query = header(0, 0, 2, 0);
+    write_name(&mut query, "Embassy-1234.local");
+    query.extend_from_slice(&[0, 1, 0x80, 1]);
+    query.extend_from_slice(&[3, b'a', b'b', b'c', 0xc0, 25]);
+    query.extend_from_slice(&[0, 255, 0, 1]);
+    let questions = parse_questions(&query);
+    assert_eq!(questions.len(), 2);
+    assert_eq!(questions[0].name, "embassy-1234.local");
+    assert_eq!(questions[0].qclass, CLASS_IN);
+    assert_eq!(questions[1].name, "abc.local");
+    assert_eq!(questions[1].qtype, TYPE_ANY);
+
+    // pointers that do not go backwards are rejected
+    let mut looping = header(0, 0, 1, 0);
+    looping.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1]);
+    assert!(parse_questions(&looping).is_empty());
+}
@@ -1,6 +1,8 @@
+use std::collections::{BTreeMap, BTreeSet};
 use std::net::{Ipv4Addr, SocketAddr};
 use std::path::PathBuf;
 
+use ipnet::Ipv4Net;
 use openssl::pkey::{PKey, Private};
 use openssl::x509::X509;
 use rpc_toolkit::command;
@@ -8,28 +10,35 @@ use sqlx::SqlitePool;
 use torut::onion::{OnionAddressV3, TorSecretKeyV3};
 use tracing::instrument;
 
+use self::access::AccessPolicy;
+use self::dns::DnsController;
+use self::firewall::FirewallController;
 use self::interface::{Interface, InterfaceId};
-#[cfg(feature = "avahi")]
 use self::mdns::MdnsController;
 use self::nginx::NginxController;
 use self::ssl::SslManager;
-use self::tor::TorController;
+use self::tor::{ClientAuthKey, TorController};
+use self::vpn::VpnController;
 use crate::net::interface::TorConfig;
 use crate::net::nginx::InterfaceMetadata;
 use crate::s9pk::manifest::PackageId;
-use crate::Error;
+use crate::{Error, ErrorKind, ResultExt};
 
+pub mod access;
+pub mod dns;
+pub mod domain;
+pub mod firewall;
 pub mod interface;
-#[cfg(feature = "avahi")]
 pub mod mdns;
 pub mod nginx;
 pub mod ssl;
 pub mod tor;
+pub mod vpn;
 pub mod wifi;
 
 const PACKAGE_CERT_PATH: &str = "/var/lib/embassy/ssl";
 
-#[command(subcommands(tor::tor))]
+#[command(subcommands(tor::tor, ssl::ssl, domain::domain, access::access, vpn::vpn, dns::dns))]
 pub fn net() -> Result<(), Error> {
     Ok(())
 }
@@ -41,10 +50,12 @@ pub struct GeneratedCertificateMountPoint(());
 
 pub struct NetController {
     pub tor: TorController,
-    #[cfg(feature = "avahi")]
     pub mdns: MdnsController,
     pub nginx: NginxController,
     pub ssl: SslManager,
+    pub vpn: VpnController,
+    pub dns: DnsController,
+    pub firewall: FirewallController,
 }
 impl NetController {
     #[instrument(skip(db))]
@@ -52,19 +63,41 @@ impl NetController {
         embassyd_addr: SocketAddr,
         embassyd_tor_key: TorSecretKeyV3,
         tor_control: SocketAddr,
+        vpn_network: Ipv4Net,
+        vpn_port: u16,
+        dns_listen: Vec<Ipv4Addr>,
         db: SqlitePool,
         import_root_ca: Option<(PKey<Private>, X509)>,
     ) -> Result<Self, Error> {
+        let vpn = VpnController::init(db.clone(), vpn_network, vpn_port).await?;
+        let firewall = FirewallController::init().await;
+        let vpn_address = if vpn.is_up().await {
+            Some(vpn.address().await)
+        } else {
+            None
+        };
+        let dns = DnsController::init(
+            vpn_address
+                .into_iter()
+                .chain(std::iter::once(Ipv4Addr::from(crate::HOST_IP)))
+                .chain(dns_listen)
+                .collect(),
+            crate::hostname::get_hostname().await?,
+            firewall.egress(),
+        )
+        .await;
         let ssl = match import_root_ca {
             None => SslManager::init(db).await,
             Some(a) => SslManager::import_root_ca(db, a.0, a.1).await,
         }?;
         Ok(Self {
             tor: TorController::init(embassyd_addr, embassyd_tor_key, tor_control).await?,
-            #[cfg(feature = "avahi")]
             mdns: MdnsController::init(),
             nginx: NginxController::init(PathBuf::from("/etc/nginx"), &ssl).await?,
             ssl,
+            vpn,
+            dns,
+            firewall,
         })
     }
 
@@ -72,12 +105,15 @@ impl NetController {
         PathBuf::from(format!("{}/{}", PACKAGE_CERT_PATH, pkg_id))
     }
 
-    #[instrument(skip(self, interfaces, _generated_certificate))]
+    #[instrument(skip(self, interfaces, domains, access, tor_auth, _generated_certificate))]
     pub async fn add<'a, I>(
         &self,
         pkg_id: &PackageId,
         ip: Ipv4Addr,
         interfaces: I,
+        mut domains: BTreeMap<InterfaceId, BTreeSet<String>>,
+        mut access: BTreeMap<InterfaceId, AccessPolicy>,
+        mut tor_auth: BTreeMap<InterfaceId, Vec<ClientAuthKey>>,
         _generated_certificate: GeneratedCertificateMountPoint,
     ) -> Result<(), Error>
     where
@@ -89,38 +125,55 @@ impl NetController {
             .into_iter()
             .filter_map(|i| match i.1.tor_config.clone() {
                 None => None,
-                Some(cfg) => Some((i.0, cfg, i.2)),
+                Some(cfg) => {
+                    let clients = tor_auth.remove(&i.0);
+                    Some((i.0, cfg, i.2, clients))
+                }
             })
-            .collect::<Vec<(InterfaceId, TorConfig, TorSecretKeyV3)>>();
-        let (tor_res, _, nginx_res) = tokio::join!(
+            .collect::<Vec<(
+                InterfaceId,
+                TorConfig,
+                TorSecretKeyV3,
+                Option<Vec<ClientAuthKey>>,
+            )>>();
+        let (tor_res, _, _, nginx_res) = tokio::join!(
             self.tor.add(pkg_id, ip, interfaces_tor),
-            {
-                #[cfg(feature = "avahi")]
-                let mdns_fut = self.mdns.add(
-                    pkg_id,
-                    interfaces
-                        .clone()
-                        .into_iter()
-                        .map(|(interface_id, _, key)| (interface_id, key)),
-                );
-                #[cfg(not(feature = "avahi"))]
-                let mdns_fut = futures::future::ready(());
-                mdns_fut
-            },
+            self.dns.add(
+                pkg_id,
+                ip,
+                interfaces
+                    .clone()
+                    .into_iter()
+                    .map(|(interface_id, _, key)| (interface_id, key)),
+                domains.clone(),
+            ),
+            self.mdns.add(
+                pkg_id,
+                interfaces
+                    .clone()
+                    .into_iter()
+                    .map(|(interface_id, _, key)| (interface_id, key)),
+            ),
             {
                 let interfaces = interfaces
                     .into_iter()
                     .filter_map(|(id, interface, tor_key)| match &interface.lan_config {
                         None => None,
-                        Some(cfg) => Some((
-                            id,
-                            InterfaceMetadata {
-                                dns_base: OnionAddressV3::from(&tor_key.public())
-                                    .get_address_without_dot_onion(),
-                                lan_config: cfg.clone(),
-                                protocols: interface.protocols.clone(),
-                            },
-                        )),
+                        Some(cfg) => {
+                            let domains = domains.remove(&id).unwrap_or_default();
+                            let access = access.remove(&id).unwrap_or_default();
+                            Some((
+                                id,
+                                InterfaceMetadata {
+                                    dns_base: OnionAddressV3::from(&tor_key.public())
+                                        .get_address_without_dot_onion(),
+                                    domains,
+                                    access,
+                                    lan_config: cfg.clone(),
+                                    protocols: interface.protocols.clone(),
+                                },
+                            ))
+                        }
                     });
                 self.nginx.add(&self.ssl, pkg_id.clone(), ip, interfaces)
             }
@@ -137,15 +190,10 @@ impl NetController {
         pkg_id: &PackageId,
         interfaces: I,
     ) -> Result<(), Error> {
-        let (tor_res, _, nginx_res) = tokio::join!(
+        let (tor_res, _, _, nginx_res) = tokio::join!(
             self.tor.remove(pkg_id, interfaces.clone()),
-            {
-                #[cfg(feature = "avahi")]
-                let mdns_fut = self.mdns.remove(pkg_id, interfaces);
-                #[cfg(not(feature = "avahi"))]
-                let mdns_fut = futures::future::ready(());
-                mdns_fut
-            },
+            self.dns.remove(pkg_id, interfaces.clone()),
+            self.mdns.remove(pkg_id, interfaces),
             self.nginx.remove(pkg_id)
         );
         tor_res?;
@@ -178,6 +226,52 @@ impl NetController {
         Ok(GeneratedCertificateMountPoint(()))
     }
 
+    /// Puts the current certificates in place after the CA has been rotated: nginx serves them
+    /// right away, packages pick up their certificate mount on their next start.
+    #[instrument(skip(self))]
+    pub async fn refresh_certificates(&self) -> Result<(), Error> {
+        self.nginx.refresh_certificates(&self.ssl).await?;
+        let mut packages = tokio::fs::read_dir(PACKAGE_CERT_PATH)
+            .await
+            .with_ctx(|_| (ErrorKind::Filesystem, PACKAGE_CERT_PATH))?;
+        while let Some(package) = packages.next_entry().await? {
+            if !package.file_type().await?.is_dir() {
+                continue;
+            }
+            let pkg_id: PackageId = match package.file_name().to_str().map(|s| s.parse()) {
+                Some(Ok(a)) => a,
+                _ => continue,
+            };
+            let mut files = tokio::fs::read_dir(package.path()).await?;
+            while let Some(file) = files.next_entry().await? {
+                let ssl_path_cert = file.path();
+                let id = match ssl_path_cert
+                    .file_name()
+                    .and_then(|f| f.to_str())
+                    .and_then(|f| f.strip_suffix(".cert.pem"))
+                {
+                    Some(a) => a.to_owned(),
+                    None => continue,
+                };
+                // the leaf is named after the dns base of the interface
+                let cert = X509::from_pem(&tokio::fs::read(&ssl_path_cert).await?)?;
+                let dns_base = match ssl::name_entry(cert.subject_name())
+                    .and_then(|cn| cn.strip_suffix(".local").map(|s| s.to_owned()))
+                {
+                    Some(a) => a,
+                    None => continue,
+                };
+                let ssl_path_key = package.path().join(format!("{}.key.pem", id));
+                let (key, chain) = self.ssl.certificate_for(&dns_base, &pkg_id).await?;
+                tokio::try_join!(
+                    crate::net::ssl::export_key(&key, &ssl_path_key),
+                    crate::net::ssl::export_cert(&chain, &ssl_path_cert)
+                )?;
+            }
+        }
+        Ok(())
+    }
+
     pub async fn export_root_ca(&self) -> Result<(PKey<Private>, X509), Error> {
         self.ssl.export_root_ca().await
     }
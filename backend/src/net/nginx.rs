@@ -1,4 +1,4 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::net::Ipv4Addr;
 use std::path::{Path, PathBuf};
 
@@ -7,7 +7,8 @@ use indexmap::IndexSet;
 use tokio::sync::Mutex;
 use tracing::instrument;
 
-use super::interface::{InterfaceId, LanPortConfig};
+use super::access::AccessPolicy;
+use super::interface::{InterfaceId, LanPortConfig, LanProtocol};
 use super::ssl::SslManager;
 use crate::hostname::get_hostname;
 use crate::s9pk::manifest::PackageId;
@@ -46,6 +47,75 @@ impl NginxController {
             .remove(&self.nginx_root, package)
             .await
     }
+    /// Replaces the access policy of a running interface.
+    pub async fn set_access(
+        &self,
+        ssl_manager: &SslManager,
+        package: &PackageId,
+        interface: &InterfaceId,
+        access: AccessPolicy,
+    ) -> Result<(), Error> {
+        self.inner
+            .lock()
+            .await
+            .update_interface(&self.nginx_root, ssl_manager, package, interface, |meta| {
+                meta.access = access
+            })
+            .await
+    }
+    /// Replaces the custom domains of a running interface.
+    pub async fn set_domains(
+        &self,
+        ssl_manager: &SslManager,
+        package: &PackageId,
+        interface: &InterfaceId,
+        domains: BTreeSet<String>,
+    ) -> Result<(), Error> {
+        self.inner
+            .lock()
+            .await
+            .update_interface(&self.nginx_root, ssl_manager, package, interface, |meta| {
+                meta.domains = domains
+            })
+            .await
+    }
+    /// Whether `host` is one of the names nginx serves a running interface at.
+    pub async fn serves(&self, package: &PackageId, interface: &InterfaceId, host: &str) -> bool {
+        let inner = self.inner.lock().await;
+        let meta = match inner
+            .interfaces
+            .get(package)
+            .and_then(|info| info.interfaces.get(interface))
+        {
+            Some(a) => a,
+            None => return false,
+        };
+        let local = format!("{}.local", meta.dns_base);
+        host == local || host.ends_with(&format!(".{}", local)) || meta.domains.contains(host)
+    }
+    /// Serves the main UI at `domain` with its ACME certificate, if one has been issued.
+    pub async fn add_clearnet(&self, ssl_manager: &SslManager, domain: &str) -> Result<(), Error> {
+        self.inner
+            .lock()
+            .await
+            .add_clearnet(&self.nginx_root, ssl_manager, domain)
+            .await
+    }
+    /// Re-exports every local certificate nginx serves, after the CA has been rotated.
+    pub async fn refresh_certificates(&self, ssl_manager: &SslManager) -> Result<(), Error> {
+        self.inner
+            .lock()
+            .await
+            .refresh_certificates(&self.nginx_root, ssl_manager)
+            .await
+    }
+    pub async fn remove_clearnet(&self, domain: &str) -> Result<(), Error> {
+        self.inner
+            .lock()
+            .await
+            .remove_clearnet(&self.nginx_root, domain)
+            .await
+    }
 }
 
 pub struct NginxControllerInner {
@@ -57,16 +127,13 @@ impl NginxControllerInner {
         let inner = NginxControllerInner {
             interfaces: BTreeMap::new(),
         };
-        // write main ssl key/cert to fs location
-        let (key, cert) = ssl_manager
-            .certificate_for(&get_hostname().await?, &"embassy".parse().unwrap())
-            .await?;
-        let ssl_path_key = nginx_root.join(format!("ssl/embassy_main.key.pem"));
-        let ssl_path_cert = nginx_root.join(format!("ssl/embassy_main.cert.pem"));
-        tokio::try_join!(
-            crate::net::ssl::export_key(&key, &ssl_path_key),
-            crate::net::ssl::export_cert(&cert, &ssl_path_cert),
-        )?;
+        for dir in &["streams-available", "streams-enabled"] {
+            let path = nginx_root.join(dir);
+            tokio::fs::create_dir_all(&path)
+                .await
+                .with_ctx(|_| (ErrorKind::Filesystem, path.display().to_string()))?;
+        }
+        write_main_certificate(nginx_root, ssl_manager).await?;
         Ok(inner)
     }
     #[instrument(skip(self, interfaces))]
@@ -80,83 +147,23 @@ impl NginxControllerInner {
     ) -> Result<(), Error> {
         let interface_map = interfaces
             .into_iter()
-            .filter(|(_, meta)| {
-                // don't add nginx stuff for anything we can't connect to over some flavor of http
-                (meta.protocols.contains("http") || meta.protocols.contains("https"))
-                // also don't add nginx unless it has at least one exposed port
-                        && meta.lan_config.len() > 0
-            })
+            // don't add nginx stuff unless it has at least one exposed port
+            .filter(|(_, meta)| meta.lan_config.len() > 0)
             .collect::<BTreeMap<InterfaceId, InterfaceMetadata>>();
 
         for (id, meta) in interface_map.iter() {
-            for (port, lan_port_config) in meta.lan_config.iter() {
-                // get ssl certificate chain
-                let (listen_args, ssl_certificate_line, ssl_certificate_key_line) =
-                    if lan_port_config.ssl {
-                        // these have already been written by the net controller
-                        let package_path = nginx_root.join(format!("ssl/{}", package));
-                        if tokio::fs::metadata(&package_path).await.is_err() {
-                            tokio::fs::create_dir_all(&package_path)
-                                .await
-                                .with_ctx(|_| {
-                                    (ErrorKind::Filesystem, package_path.display().to_string())
-                                })?;
-                        }
-                        let ssl_path_key = package_path.join(format!("{}.key.pem", id));
-                        let ssl_path_cert = package_path.join(format!("{}.cert.pem", id));
-                        let (key, chain) = ssl_manager
-                            .certificate_for(&meta.dns_base, &package)
-                            .await?;
-                        tokio::try_join!(
-                            crate::net::ssl::export_key(&key, &ssl_path_key),
-                            crate::net::ssl::export_cert(&chain, &ssl_path_cert)
-                        )?;
-                        (
-                            format!("{} ssl", port.0),
-                            format!("ssl_certificate {};", ssl_path_cert.to_str().unwrap()),
-                            format!("ssl_certificate_key {};", ssl_path_key.to_str().unwrap()),
-                        )
-                    } else {
-                        (format!("{}", port.0), String::from(""), String::from(""))
-                    };
-                // write nginx configs
-                let nginx_conf_path = nginx_root.join(format!(
-                    "sites-available/{}_{}_{}.conf",
-                    package, id, port.0
-                ));
-                tokio::fs::write(
-                    &nginx_conf_path,
-                    format!(
-                        include_str!("nginx.conf.template"),
-                        listen_args = listen_args,
-                        listen_args_ipv6 = listen_args,
-                        hostname = meta.dns_base,
-                        ssl_certificate_line = ssl_certificate_line,
-                        ssl_certificate_key_line = ssl_certificate_key_line,
-                        app_ip = ipv4,
-                        internal_port = lan_port_config.internal,
-                    ),
-                )
-                .await
-                .with_ctx(|_| (ErrorKind::Filesystem, nginx_conf_path.display().to_string()))?;
-                let sites_enabled_link_path =
-                    nginx_root.join(format!("sites-enabled/{}_{}_{}.conf", package, id, port.0));
-                if tokio::fs::metadata(&sites_enabled_link_path).await.is_ok() {
-                    tokio::fs::remove_file(&sites_enabled_link_path).await?;
-                }
-                tokio::fs::symlink(&nginx_conf_path, &sites_enabled_link_path)
-                    .await
-                    .with_ctx(|_| (ErrorKind::Filesystem, nginx_conf_path.display().to_string()))?;
-            }
+            write_sites(nginx_root, ssl_manager, &package, ipv4, id, meta).await?;
         }
         match self.interfaces.get_mut(&package) {
             None => {
                 let info = PackageNetInfo {
+                    ip: ipv4,
                     interfaces: interface_map,
                 };
                 self.interfaces.insert(package, info);
             }
             Some(p) => {
+                p.ip = ipv4;
                 p.interfaces.extend(interface_map);
             }
         };
@@ -165,19 +172,64 @@ impl NginxControllerInner {
         Ok(())
     }
 
+    #[instrument(skip(self, ssl_manager, update))]
+    async fn update_interface<F: FnOnce(&mut InterfaceMetadata)>(
+        &mut self,
+        nginx_root: &Path,
+        ssl_manager: &SslManager,
+        package: &PackageId,
+        interface: &InterfaceId,
+        update: F,
+    ) -> Result<(), Error> {
+        // not running: the settings are picked up from the db on the next start
+        let info = match self.interfaces.get_mut(package) {
+            Some(a) => a,
+            None => return Ok(()),
+        };
+        let meta = match info.interfaces.get_mut(interface) {
+            Some(a) => a,
+            None => return Ok(()),
+        };
+        let old_domains = meta.domains.clone();
+        update(meta);
+        write_sites(nginx_root, ssl_manager, package, info.ip, interface, meta).await?;
+        let removed = old_domains
+            .difference(&meta.domains)
+            .cloned()
+            .collect::<Vec<_>>();
+        self.hup().await?;
+        // only once nginx no longer serves them
+        let package_path = nginx_root.join(format!("ssl/{}", package));
+        for domain in removed {
+            for ext in &["key", "cert"] {
+                let path = package_path.join(format!("{}_{}.{}.pem", interface, domain, ext));
+                if let Err(e) = tokio::fs::remove_file(&path).await {
+                    if e.kind() != std::io::ErrorKind::NotFound {
+                        return Err(e)
+                            .with_ctx(|_| (ErrorKind::Filesystem, path.display().to_string()));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
     #[instrument(skip(self))]
     async fn remove(&mut self, nginx_root: &Path, package: &PackageId) -> Result<(), Error> {
         let removed = self.interfaces.remove(package);
         if let Some(net_info) = removed {
             for (id, meta) in net_info.interfaces {
-                for (port, _lan_port_config) in meta.lan_config.iter() {
+                for (port, lan_port_config) in meta.lan_config.iter() {
                     // remove ssl certificates and nginx configs
                     let package_path = nginx_root.join(format!("ssl/{}", package));
-                    let enabled_path = nginx_root
-                        .join(format!("sites-enabled/{}_{}_{}.conf", package, id, port.0));
+                    let (available_dir, enabled_dir) = conf_dirs(lan_port_config.protocol);
+                    let enabled_path = nginx_root.join(format!(
+                        "{}/{}_{}_{}.conf",
+                        enabled_dir, package, id, port.0
+                    ));
                     let available_path = nginx_root.join(format!(
-                        "sites-available/{}_{}_{}.conf",
-                        package, id, port.0
+                        "{}/{}_{}_{}.conf",
+                        available_dir, package, id, port.0
                     ));
                     let _ = tokio::try_join!(
                         async {
@@ -213,6 +265,85 @@ impl NginxControllerInner {
         Ok(())
     }
 
+    #[instrument(skip(self, ssl_manager))]
+    async fn refresh_certificates(
+        &mut self,
+        nginx_root: &Path,
+        ssl_manager: &SslManager,
+    ) -> Result<(), Error> {
+        write_main_certificate(nginx_root, ssl_manager).await?;
+        for (package, info) in &self.interfaces {
+            for (id, meta) in &info.interfaces {
+                write_sites(nginx_root, ssl_manager, package, info.ip, id, meta).await?;
+            }
+        }
+        self.hup().await
+    }
+
+    #[instrument(skip(self, ssl_manager))]
+    async fn add_clearnet(
+        &mut self,
+        nginx_root: &Path,
+        ssl_manager: &SslManager,
+        domain: &str,
+    ) -> Result<(), Error> {
+        let (key, chain) = match ssl_manager.acme_certificate_for(domain).await? {
+            Some(a) => a,
+            None => return Ok(()),
+        };
+        let acme_path = nginx_root.join("ssl/acme");
+        tokio::fs::create_dir_all(&acme_path)
+            .await
+            .with_ctx(|_| (ErrorKind::Filesystem, acme_path.display().to_string()))?;
+        let ssl_path_key = acme_path.join(format!("{}.key.pem", domain));
+        let ssl_path_cert = acme_path.join(format!("{}.cert.pem", domain));
+        tokio::try_join!(
+            crate::net::ssl::export_key(&key, &ssl_path_key),
+            crate::net::ssl::export_cert(&chain, &ssl_path_cert)
+        )?;
+        let nginx_conf_path = nginx_root.join(format!("sites-available/clearnet_{}.conf", domain));
+        tokio::fs::write(
+            &nginx_conf_path,
+            format!(
+                include_str!("../nginx/clearnet-ui.conf.template"),
+                domain = domain,
+                ssl_certificate = ssl_path_cert.display(),
+                ssl_certificate_key = ssl_path_key.display(),
+                acme_webroot = super::ssl::acme::ACME_WEBROOT,
+            ),
+        )
+        .await
+        .with_ctx(|_| (ErrorKind::Filesystem, nginx_conf_path.display().to_string()))?;
+        let sites_enabled_link_path =
+            nginx_root.join(format!("sites-enabled/clearnet_{}.conf", domain));
+        if tokio::fs::symlink_metadata(&sites_enabled_link_path)
+            .await
+            .is_err()
+        {
+            tokio::fs::symlink(&nginx_conf_path, &sites_enabled_link_path)
+                .await
+                .with_ctx(|_| (ErrorKind::Filesystem, nginx_conf_path.display().to_string()))?;
+        }
+        self.hup().await
+    }
+
+    #[instrument(skip(self))]
+    async fn remove_clearnet(&mut self, nginx_root: &Path, domain: &str) -> Result<(), Error> {
+        for path in &[
+            nginx_root.join(format!("sites-enabled/clearnet_{}.conf", domain)),
+            nginx_root.join(format!("sites-available/clearnet_{}.conf", domain)),
+            nginx_root.join(format!("ssl/acme/{}.key.pem", domain)),
+            nginx_root.join(format!("ssl/acme/{}.cert.pem", domain)),
+        ] {
+            if tokio::fs::symlink_metadata(&path).await.is_ok() {
+                tokio::fs::remove_file(&path)
+                    .await
+                    .with_ctx(|_| (ErrorKind::Filesystem, path.display().to_string()))?;
+            }
+        }
+        self.hup().await
+    }
+
     #[instrument(skip(self))]
     async fn hup(&self) -> Result<(), Error> {
         let _ = tokio::process::Command::new("systemctl")
@@ -223,11 +354,162 @@ impl NginxControllerInner {
         Ok(())
     }
 }
+/// Writes the certificate of the main UI.
+#[instrument(skip(ssl_manager))]
+async fn write_main_certificate(nginx_root: &Path, ssl_manager: &SslManager) -> Result<(), Error> {
+    let (key, cert) = ssl_manager
+        .certificate_for(&get_hostname().await?, &"embassy".parse().unwrap())
+        .await?;
+    let ssl_path_key = nginx_root.join(format!("ssl/embassy_main.key.pem"));
+    let ssl_path_cert = nginx_root.join(format!("ssl/embassy_main.cert.pem"));
+    tokio::try_join!(
+        crate::net::ssl::export_key(&key, &ssl_path_key),
+        crate::net::ssl::export_cert(&cert, &ssl_path_cert),
+    )?;
+    Ok(())
+}
+
+/// Where the configs of lan ports using `protocol` live: http sites are included in the `http`
+/// block of nginx.conf, raw tcp/udp streams in its `stream` block.
+fn conf_dirs(protocol: LanProtocol) -> (&'static str, &'static str) {
+    if protocol.is_stream() {
+        ("streams-available", "streams-enabled")
+    } else {
+        ("sites-available", "sites-enabled")
+    }
+}
+
+/// Writes the site config of every lan port of an interface. Http ports get one server block for
+/// its `.local` name and one for each custom domain; tcp and udp ports get a single stream server
+/// that forwards everything arriving on the port.
+#[instrument(skip(ssl_manager, meta))]
+async fn write_sites(
+    nginx_root: &Path,
+    ssl_manager: &SslManager,
+    package: &PackageId,
+    ipv4: Ipv4Addr,
+    id: &InterfaceId,
+    meta: &InterfaceMetadata,
+) -> Result<(), Error> {
+    let package_path = nginx_root.join(format!("ssl/{}", package));
+    let main_host = format!("{}.local", get_hostname().await?);
+    if meta.lan_config.values().any(|c| c.ssl) && tokio::fs::metadata(&package_path).await.is_err()
+    {
+        tokio::fs::create_dir_all(&package_path)
+            .await
+            .with_ctx(|_| (ErrorKind::Filesystem, package_path.display().to_string()))?;
+    }
+    for (port, lan_port_config) in meta.lan_config.iter() {
+        let mut conf = String::new();
+        if lan_port_config.protocol.is_stream() {
+            let (listen_args, ssl_certificate_line, ssl_certificate_key_line) =
+                match lan_port_config.protocol {
+                    LanProtocol::Udp => (format!("{} udp", port.0), String::new(), String::new()),
+                    _ if lan_port_config.ssl => {
+                        let ssl_path_key = package_path.join(format!("{}.key.pem", id));
+                        let ssl_path_cert = package_path.join(format!("{}.cert.pem", id));
+                        let (key, chain) = ssl_manager
+                            .certificate_for(&meta.dns_base, &package)
+                            .await?;
+                        tokio::try_join!(
+                            crate::net::ssl::export_key(&key, &ssl_path_key),
+                            crate::net::ssl::export_cert(&chain, &ssl_path_cert)
+                        )?;
+                        (
+                            format!("{} ssl", port.0),
+                            format!("ssl_certificate {};", ssl_path_cert.to_str().unwrap()),
+                            format!("ssl_certificate_key {};", ssl_path_key.to_str().unwrap()),
+                        )
+                    }
+                    _ => (format!("{}", port.0), String::new(), String::new()),
+                };
+            conf += &format!(
+                include_str!("nginx-stream.conf.template"),
+                listen_args = listen_args,
+                ssl_certificate_line = ssl_certificate_line,
+                ssl_certificate_key_line = ssl_certificate_key_line,
+                access_control = meta.access.stream_directives(),
+                app_ip = ipv4,
+                internal_port = lan_port_config.internal,
+            );
+        }
+        for (server_name, file_name) in
+            std::iter::once((format!(".{}.local", meta.dns_base), id.to_string()))
+                .chain(
+                    meta.domains
+                        .iter()
+                        .map(|domain| (domain.clone(), format!("{}_{}", id, domain))),
+                )
+                .filter(|_| !lan_port_config.protocol.is_stream())
+        {
+            // get ssl certificate chain
+            let (listen_args, ssl_certificate_line, ssl_certificate_key_line) =
+                if lan_port_config.ssl {
+                    let ssl_path_key = package_path.join(format!("{}.key.pem", file_name));
+                    let ssl_path_cert = package_path.join(format!("{}.cert.pem", file_name));
+                    let (key, chain) = if server_name.starts_with('.') {
+                        ssl_manager
+                            .certificate_for(&meta.dns_base, &package)
+                            .await?
+                    } else {
+                        ssl_manager.certificate_for_domain(&server_name).await?
+                    };
+                    tokio::try_join!(
+                        crate::net::ssl::export_key(&key, &ssl_path_key),
+                        crate::net::ssl::export_cert(&chain, &ssl_path_cert)
+                    )?;
+                    (
+                        format!("{} ssl", port.0),
+                        format!("ssl_certificate {};", ssl_path_cert.to_str().unwrap()),
+                        format!("ssl_certificate_key {};", ssl_path_key.to_str().unwrap()),
+                    )
+                } else {
+                    (format!("{}", port.0), String::from(""), String::from(""))
+                };
+            conf += &format!(
+                include_str!("nginx.conf.template"),
+                listen_args = listen_args,
+                listen_args_ipv6 = listen_args,
+                server_name = server_name,
+                ssl_certificate_line = ssl_certificate_line,
+                ssl_certificate_key_line = ssl_certificate_key_line,
+                access_control = meta.access.nginx_directives(package, id, &main_host),
+                app_ip = ipv4,
+                internal_port = lan_port_config.internal,
+            );
+        }
+        // write nginx configs
+        let (available_dir, enabled_dir) = conf_dirs(lan_port_config.protocol);
+        let nginx_conf_path = nginx_root.join(format!(
+            "{}/{}_{}_{}.conf",
+            available_dir, package, id, port.0
+        ));
+        tokio::fs::write(&nginx_conf_path, conf)
+            .await
+            .with_ctx(|_| (ErrorKind::Filesystem, nginx_conf_path.display().to_string()))?;
+        let sites_enabled_link_path = nginx_root.join(format!(
+            "{}/{}_{}_{}.conf",
+            enabled_dir, package, id, port.0
+        ));
+        if tokio::fs::metadata(&sites_enabled_link_path).await.is_ok() {
+            tokio::fs::remove_file(&sites_enabled_link_path).await?;
+        }
+        tokio::fs::symlink(&nginx_conf_path, &sites_enabled_link_path)
+            .await
+            .with_ctx(|_| (ErrorKind::Filesystem, nginx_conf_path.display().to_string()))?;
+    }
+    Ok(())
+}
+
 struct PackageNetInfo {
+    ip: Ipv4Addr,
     interfaces: BTreeMap<InterfaceId, InterfaceMetadata>,
 }
 pub struct InterfaceMetadata {
     pub dns_base: String,
+    /// Custom hostnames served alongside `<dns_base>.local`.
+    pub domains: BTreeSet<String>,
+    pub access: AccessPolicy,
     pub lan_config: BTreeMap<Port, LanPortConfig>,
     pub protocols: IndexSet<String>,
 }
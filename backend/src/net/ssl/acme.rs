@@ -0,0 +1,1044 @@
+//! An RFC 8555 (ACME) client, used to obtain publicly trusted certificates for domains the user
+//! has pointed at this embassy.
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use clap::ArgMatches;
+use color_eyre::eyre::eyre;
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
+use openssl::stack::Stack;
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509NameBuilder, X509Req, X509ReqBuilder, X509};
+use patch_db::DbHandle;
+use reqwest::header::{CONTENT_TYPE, LOCATION};
+use reqwest::{Response, Url};
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio::process::Command;
+use tokio::sync::Mutex;
+use tracing::instrument;
+
+use super::generate_key;
+use crate::context::RpcContext;
+use crate::db::util::WithRevision;
+use crate::net::domain::{domain_owner, parse_domain};
+use crate::notifications::NotificationLevel;
+use crate::util::serde::{display_serializable, IoFormat};
+use crate::util::{display_none, Invoke};
+use crate::{Error, ErrorKind, ResultExt};
+
+pub const LETS_ENCRYPT_DIRECTORY: &str = "https://acme-v02.api.letsencrypt.org/directory";
+/// Served by nginx at `/.well-known/acme-challenge/` on port 80.
+pub const ACME_WEBROOT: &str = "/var/lib/embassy/acme";
+pub const ACME_RENEWAL_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+/// Certificates are renewed once they are this close to expiring.
+const RENEWAL_WINDOW_DAYS: u32 = 30;
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: usize = 90;
+/// How long to wait after publishing a dns-01 record before asking the server to check it.
+const DNS_PROPAGATION_DELAY: Duration = Duration::from_secs(60);
+
+lazy_static::lazy_static! {
+    static ref ACME_MUTEX: Mutex<()> = Mutex::new(());
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ChallengeType {
+    #[serde(rename = "http-01")]
+    Http01,
+    #[serde(rename = "dns-01")]
+    Dns01,
+}
+impl ChallengeType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChallengeType::Http01 => "http-01",
+            ChallengeType::Dns01 => "dns-01",
+        }
+    }
+}
+impl Default for ChallengeType {
+    fn default() -> Self {
+        ChallengeType::Http01
+    }
+}
+impl fmt::Display for ChallengeType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+impl FromStr for ChallengeType {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "http-01" => Ok(ChallengeType::Http01),
+            "dns-01" => Ok(ChallengeType::Dns01),
+            _ => Err(Error::new(
+                eyre!("unknown challenge type {}: expected http-01 or dns-01", s),
+                ErrorKind::ParseNetAddress,
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AcmeSettings {
+    /// Directory url of the ACME server. Let's Encrypt if unset.
+    pub directory: Option<Url>,
+    /// Email addresses the ACME server may use to contact us about our certificates.
+    pub contact: Vec<String>,
+    /// Executable publishing dns-01 records, called as `<hook> set|remove <name> <value>`.
+    pub dns_hook: Option<PathBuf>,
+    /// Skip verifying the TLS certificate of the ACME server itself. Only meant for testing
+    /// against a local server such as pebble.
+    pub accept_invalid_certs: bool,
+    pub domains: BTreeMap<String, AcmeDomain>,
+}
+impl AcmeSettings {
+    pub fn directory(&self) -> Url {
+        self.directory
+            .clone()
+            .unwrap_or_else(|| LETS_ENCRYPT_DIRECTORY.parse().unwrap())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AcmeDomain {
+    pub challenge: ChallengeType,
+    pub expires: Option<DateTime<Utc>>,
+    /// Why the last attempt to issue a certificate failed, if it did.
+    pub error: Option<String>,
+}
+
+fn b64(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+/// Account key and its JWK (RFC 7517) coordinates, used to sign every request as ES256.
+struct AccountKey {
+    key: PKey<Private>,
+    x: String,
+    y: String,
+}
+impl AccountKey {
+    fn new(key: PKey<Private>) -> Result<Self, Error> {
+        let ec_key = key.ec_key()?;
+        let mut x = BigNum::new()?;
+        let mut y = BigNum::new()?;
+        ec_key.public_key().affine_coordinates_gfp(
+            ec_key.group(),
+            &mut x,
+            &mut y,
+            &mut BigNumContext::new()?,
+        )?;
+        Ok(AccountKey {
+            x: b64(&x.to_vec_padded(32)?),
+            y: b64(&y.to_vec_padded(32)?),
+            key,
+        })
+    }
+    fn jwk(&self) -> Value {
+        json!({ "crv": "P-256", "kty": "EC", "x": self.x, "y": self.y })
+    }
+    /// RFC 7638: the members are serialized in lexicographic order without whitespace.
+    fn thumbprint(&self) -> String {
+        b64(&Sha256::digest(
+            format!(
+                r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+                self.x, self.y
+            )
+            .as_bytes(),
+        ))
+    }
+    fn key_authorization(&self, token: &str) -> String {
+        format!("{}.{}", token, self.thumbprint())
+    }
+    /// Signs `payload` as a flattened JWS. A `None` payload is a POST-as-GET.
+    fn sign(&self, protected: &Value, payload: Option<&Value>) -> Result<Value, Error> {
+        let protected = b64(&serde_json::to_vec(protected).with_kind(ErrorKind::Serialization)?);
+        let payload = match payload {
+            Some(p) => b64(&serde_json::to_vec(p).with_kind(ErrorKind::Serialization)?),
+            None => String::new(),
+        };
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.key)?;
+        signer.update(format!("{}.{}", protected, payload).as_bytes())?;
+        // openssl produces a DER sequence, JWS wants the fixed width concatenation of r and s
+        let sig = EcdsaSig::from_der(&signer.sign_to_vec()?)?;
+        let mut signature = sig.r().to_vec_padded(32)?;
+        signature.extend(sig.s().to_vec_padded(32)?);
+        Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": b64(&signature),
+        }))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: Url,
+    new_account: Url,
+    new_order: Url,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Pending,
+    Ready,
+    Processing,
+    Valid,
+    Invalid,
+    Deactivated,
+    Expired,
+    Revoked,
+}
+
+#[derive(Debug, Deserialize)]
+struct Problem {
+    #[serde(rename = "type")]
+    ty: String,
+    #[serde(default)]
+    detail: String,
+}
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.ty, self.detail)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Order {
+    status: Status,
+    authorizations: Vec<Url>,
+    finalize: Url,
+    certificate: Option<Url>,
+    error: Option<Problem>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Authorization {
+    status: Status,
+    identifier: Identifier,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    ty: String,
+    url: Url,
+    token: String,
+    error: Option<Problem>,
+}
+
+/// Proves control of a domain to the ACME server.
+#[async_trait::async_trait]
+pub trait ChallengeSolver: Send + Sync {
+    fn challenge_type(&self) -> ChallengeType;
+    async fn present(
+        &self,
+        domain: &str,
+        token: &str,
+        key_authorization: &str,
+    ) -> Result<(), Error>;
+    async fn cleanup(
+        &self,
+        domain: &str,
+        token: &str,
+        key_authorization: &str,
+    ) -> Result<(), Error>;
+}
+
+/// Serves the key authorization from the webroot nginx exposes on port 80.
+pub struct Http01Solver {
+    pub webroot: PathBuf,
+}
+impl Http01Solver {
+    fn path(&self, token: &str) -> PathBuf {
+        self.webroot.join(".well-known/acme-challenge").join(token)
+    }
+}
+#[async_trait::async_trait]
+impl ChallengeSolver for Http01Solver {
+    fn challenge_type(&self) -> ChallengeType {
+        ChallengeType::Http01
+    }
+    async fn present(
+        &self,
+        _domain: &str,
+        token: &str,
+        key_authorization: &str,
+    ) -> Result<(), Error> {
+        let path = self.path(token);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .with_ctx(|_| (ErrorKind::Filesystem, parent.display().to_string()))?;
+        }
+        tokio::fs::write(&path, key_authorization)
+            .await
+            .with_ctx(|_| (ErrorKind::Filesystem, path.display().to_string()))?;
+        Ok(())
+    }
+    async fn cleanup(&self, _domain: &str, token: &str, _: &str) -> Result<(), Error> {
+        let path = self.path(token);
+        tokio::fs::remove_file(&path)
+            .await
+            .with_ctx(|_| (ErrorKind::Filesystem, path.display().to_string()))
+    }
+}
+
+/// Publishes TXT records with some DNS host.
+#[async_trait::async_trait]
+pub trait DnsProvider: Send + Sync {
+    async fn set_txt(&self, name: &str, value: &str) -> Result<(), Error>;
+    async fn remove_txt(&self, name: &str, value: &str) -> Result<(), Error>;
+}
+
+/// Delegates to a user supplied executable, so any DNS host with an API can be supported.
+pub struct HookDnsProvider {
+    pub hook: PathBuf,
+}
+#[async_trait::async_trait]
+impl DnsProvider for HookDnsProvider {
+    async fn set_txt(&self, name: &str, value: &str) -> Result<(), Error> {
+        Command::new(&self.hook)
+            .arg("set")
+            .arg(name)
+            .arg(value)
+            .invoke(ErrorKind::Acme)
+            .await?;
+        Ok(())
+    }
+    async fn remove_txt(&self, name: &str, value: &str) -> Result<(), Error> {
+        Command::new(&self.hook)
+            .arg("remove")
+            .arg(name)
+            .arg(value)
+            .invoke(ErrorKind::Acme)
+            .await?;
+        Ok(())
+    }
+}
+
+pub struct Dns01Solver {
+    pub provider: Box<dyn DnsProvider>,
+    pub propagation_delay: Duration,
+}
+impl Dns01Solver {
+    fn record(domain: &str, key_authorization: &str) -> (String, String) {
+        (
+            format!("_acme-challenge.{}", domain),
+            b64(&Sha256::digest(key_authorization.as_bytes())),
+        )
+    }
+}
+#[async_trait::async_trait]
+impl ChallengeSolver for Dns01Solver {
+    fn challenge_type(&self) -> ChallengeType {
+        ChallengeType::Dns01
+    }
+    async fn present(&self, domain: &str, _: &str, key_authorization: &str) -> Result<(), Error> {
+        let (name, value) = Self::record(domain, key_authorization);
+        self.provider.set_txt(&name, &value).await?;
+        tokio::time::sleep(self.propagation_delay).await;
+        Ok(())
+    }
+    async fn cleanup(&self, domain: &str, _: &str, key_authorization: &str) -> Result<(), Error> {
+        let (name, value) = Self::record(domain, key_authorization);
+        self.provider.remove_txt(&name, &value).await
+    }
+}
+
+pub struct AcmeClient {
+    http: reqwest::Client,
+    directory: Directory,
+    key: AccountKey,
+    kid: Option<Url>,
+    nonce: Option<String>,
+}
+impl AcmeClient {
+    /// Fetches the directory at `directory` and registers (or looks up) the account for `key`.
+    #[instrument(skip(key))]
+    pub async fn new(
+        directory: &Url,
+        key: PKey<Private>,
+        contact: &[String],
+        accept_invalid_certs: bool,
+    ) -> Result<Self, Error> {
+        let http = reqwest::Client::builder()
+            .danger_accept_invalid_certs(accept_invalid_certs)
+            .build()
+            .with_kind(ErrorKind::Network)?;
+        let directory = http
+            .get(directory.clone())
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .with_kind(ErrorKind::Network)?
+            .json()
+            .await
+            .with_kind(ErrorKind::Deserialization)?;
+        let mut client = AcmeClient {
+            http,
+            directory,
+            key: AccountKey::new(key)?,
+            kid: None,
+            nonce: None,
+        };
+        let new_account = client.directory.new_account.clone();
+        let res = client
+            .post(
+                &new_account,
+                Some(&json!({
+                    "termsOfServiceAgreed": true,
+                    "contact": contact
+                        .iter()
+                        .map(|c| format!("mailto:{}", c))
+                        .collect::<Vec<_>>(),
+                })),
+            )
+            .await?;
+        client.kid = Some(location(&res)?);
+        Ok(client)
+    }
+
+    async fn nonce(&mut self) -> Result<String, Error> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let res = self
+            .http
+            .head(self.directory.new_nonce.clone())
+            .send()
+            .await
+            .with_kind(ErrorKind::Network)?;
+        replay_nonce(&res)
+            .ok_or_else(|| Error::new(eyre!("ACME server did not return a nonce"), ErrorKind::Acme))
+    }
+
+    async fn post(&mut self, url: &Url, payload: Option<&Value>) -> Result<Response, Error> {
+        let mut retried = false;
+        loop {
+            let mut protected = json!({
+                "alg": "ES256",
+                "nonce": self.nonce().await?,
+                "url": url.as_str(),
+            });
+            match &self.kid {
+                Some(kid) => protected["kid"] = Value::String(kid.to_string()),
+                None => protected["jwk"] = self.key.jwk(),
+            }
+            let body = self.key.sign(&protected, payload)?;
+            let res = self
+                .http
+                .post(url.clone())
+                .header(CONTENT_TYPE, "application/jose+json")
+                .json(&body)
+                .send()
+                .await
+                .with_kind(ErrorKind::Network)?;
+            self.nonce = replay_nonce(&res);
+            if res.status().is_success() {
+                return Ok(res);
+            }
+            let problem: Problem = res.json().await.with_kind(ErrorKind::Deserialization)?;
+            if problem.ty == "urn:ietf:params:acme:error:badNonce" && !retried {
+                retried = true;
+                continue;
+            }
+            return Err(Error::new(eyre!("{}", problem), ErrorKind::Acme));
+        }
+    }
+
+    async fn get_as<T: for<'de> Deserialize<'de>>(&mut self, url: &Url) -> Result<T, Error> {
+        self.post(url, None)
+            .await?
+            .json()
+            .await
+            .with_kind(ErrorKind::Deserialization)
+    }
+
+    /// Runs a full order for `domain`, returning the new key and certificate chain.
+    #[instrument(skip(self, solver))]
+    pub async fn issue(
+        &mut self,
+        domain: &str,
+        solver: &dyn ChallengeSolver,
+    ) -> Result<(PKey<Private>, Vec<X509>), Error> {
+        let new_order = self.directory.new_order.clone();
+        let res = self
+            .post(
+                &new_order,
+                Some(&json!({ "identifiers": [{ "type": "dns", "value": domain }] })),
+            )
+            .await?;
+        let order_url = location(&res)?;
+        let order: Order = res.json().await.with_kind(ErrorKind::Deserialization)?;
+
+        for authz_url in &order.authorizations {
+            self.authorize(authz_url, solver).await?;
+        }
+
+        let order = self
+            .poll_order(&order_url, |s| s != Status::Pending)
+            .await?;
+        if order.status != Status::Ready && order.status != Status::Valid {
+            return Err(order_error(&order));
+        }
+
+        let key = generate_key()?;
+        let csr = make_csr(&key, domain)?;
+        self.post(
+            &order.finalize,
+            Some(&json!({ "csr": b64(&csr.to_der()?) })),
+        )
+        .await?;
+        let order = self
+            .poll_order(&order_url, |s| {
+                s != Status::Ready && s != Status::Processing
+            })
+            .await?;
+        let certificate = match (order.status, &order.certificate) {
+            (Status::Valid, Some(url)) => url.clone(),
+            _ => return Err(order_error(&order)),
+        };
+        let pem = self
+            .post(&certificate, None)
+            .await?
+            .text()
+            .await
+            .with_kind(ErrorKind::Network)?;
+        let chain = X509::stack_from_pem(pem.as_bytes())?;
+        if chain.is_empty() {
+            return Err(Error::new(
+                eyre!("ACME server returned an empty certificate chain"),
+                ErrorKind::Acme,
+            ));
+        }
+        Ok((key, chain))
+    }
+
+    async fn authorize(&mut self, url: &Url, solver: &dyn ChallengeSolver) -> Result<(), Error> {
+        let authz: Authorization = self.get_as(url).await?;
+        if authz.status == Status::Valid {
+            return Ok(());
+        }
+        let ty = solver.challenge_type();
+        let challenge = authz
+            .challenges
+            .iter()
+            .find(|c| c.ty == ty.as_str())
+            .ok_or_else(|| {
+                Error::new(
+                    eyre!(
+                        "ACME server does not offer a {} challenge for {}",
+                        ty,
+                        authz.identifier.value
+                    ),
+                    ErrorKind::Acme,
+                )
+            })?;
+        let domain = &authz.identifier.value;
+        let key_authorization = self.key.key_authorization(&challenge.token);
+        solver
+            .present(domain, &challenge.token, &key_authorization)
+            .await?;
+        let res = async {
+            self.post(&challenge.url, Some(&json!({}))).await?;
+            for _ in 0..POLL_ATTEMPTS {
+                tokio::time::sleep(POLL_INTERVAL).await;
+                let authz: Authorization = self.get_as(url).await?;
+                match authz.status {
+                    Status::Pending => continue,
+                    Status::Valid => return Ok(()),
+                    _ => {
+                        let problem = authz
+                            .challenges
+                            .iter()
+                            .filter_map(|c| c.error.as_ref())
+                            .next()
+                            .map(|p| p.to_string())
+                            .unwrap_or_else(|| format!("{:?}", authz.status));
+                        return Err(Error::new(
+                            eyre!("authorization for {} failed: {}", domain, problem),
+                            ErrorKind::Acme,
+                        ));
+                    }
+                }
+            }
+            Err(Error::new(
+                eyre!("timed out waiting for authorization of {}", domain),
+                ErrorKind::Acme,
+            ))
+        }
+        .await;
+        if let Err(e) = solver
+            .cleanup(domain, &challenge.token, &key_authorization)
+            .await
+        {
+            tracing::warn!("Failed to clean up {} challenge: {}", ty, e);
+            tracing::debug!("{:?}", e);
+        }
+        res
+    }
+
+    async fn poll_order<F: Fn(Status) -> bool>(
+        &mut self,
+        url: &Url,
+        done: F,
+    ) -> Result<Order, Error> {
+        for _ in 0..POLL_ATTEMPTS {
+            let order: Order = self.get_as(url).await?;
+            if done(order.status) {
+                return Ok(order);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        Err(Error::new(
+            eyre!("timed out waiting for ACME order {}", url),
+            ErrorKind::Acme,
+        ))
+    }
+}
+
+fn location(res: &Response) -> Result<Url, Error> {
+    res.headers()
+        .get(LOCATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.parse().ok())
+        .ok_or_else(|| {
+            Error::new(
+                eyre!("ACME server response is missing a Location header"),
+                ErrorKind::Acme,
+            )
+        })
+}
+
+fn replay_nonce(res: &Response) -> Option<String> {
+    res.headers()
+        .get("Replay-Nonce")
+        .and_then(|h| h.to_str().ok())
+        .map(|h| h.to_owned())
+}
+
+fn order_error(order: &Order) -> Error {
+    Error::new(
+        match &order.error {
+            Some(problem) => eyre!("ACME order failed: {}", problem),
+            None => eyre!("ACME order is {:?}", order.status),
+        },
+        ErrorKind::Acme,
+    )
+}
+
+fn make_csr(key: &PKey<Private>, domain: &str) -> Result<X509Req, Error> {
+    let mut builder = X509ReqBuilder::new()?;
+    let mut subject_name_builder = X509NameBuilder::new()?;
+    subject_name_builder.append_entry_by_text("CN", domain)?;
+    builder.set_subject_name(&subject_name_builder.build())?;
+    builder.set_pubkey(key)?;
+    let subject_alt_name = SubjectAlternativeName::new()
+        .dns(domain)
+        .build(&builder.x509v3_context(None))?;
+    let mut extensions = Stack::new()?;
+    extensions.push(subject_alt_name)?;
+    builder.add_extensions(&extensions)?;
+    builder.sign(key, MessageDigest::sha256())?;
+    Ok(builder.build())
+}
+
+fn expiration(chain: &[X509]) -> Result<DateTime<Utc>, Error> {
+    super::not_after(&chain[0])
+}
+
+async fn load_settings<Db: DbHandle>(db: &mut Db) -> Result<AcmeSettings, Error> {
+    Ok(crate::db::DatabaseModel::new()
+        .server_info()
+        .acme()
+        .get(db, false)
+        .await?
+        .into_owned())
+}
+
+/// Issues a certificate for `domain` using the configured ACME server and installs it in nginx.
+#[instrument(skip(ctx))]
+pub async fn issue_certificate(ctx: &RpcContext, domain: &str) -> Result<DateTime<Utc>, Error> {
+    let _lock = ACME_MUTEX.lock().await;
+    let settings = load_settings(&mut ctx.db.handle()).await?;
+    let info = settings.domains.get(domain).ok_or_else(|| {
+        Error::new(
+            eyre!("{} is not configured for ACME", domain),
+            ErrorKind::NotFound,
+        )
+    })?;
+    let solver: Box<dyn ChallengeSolver> = match info.challenge {
+        ChallengeType::Http01 => Box::new(Http01Solver {
+            webroot: PathBuf::from(ACME_WEBROOT),
+        }),
+        ChallengeType::Dns01 => Box::new(Dns01Solver {
+            provider: Box::new(HookDnsProvider {
+                hook: settings.dns_hook.clone().ok_or_else(|| {
+                    Error::new(
+                        eyre!("dns-01 challenges require a dns hook to be configured"),
+                        ErrorKind::Acme,
+                    )
+                })?,
+            }),
+            propagation_delay: DNS_PROPAGATION_DELAY,
+        }),
+    };
+    let directory = settings.directory();
+    let res = async {
+        let account_key = ctx
+            .net_controller
+            .ssl
+            .acme_account_key(directory.as_str())
+            .await?;
+        let mut client = AcmeClient::new(
+            &directory,
+            account_key,
+            &settings.contact,
+            settings.accept_invalid_certs,
+        )
+        .await?;
+        let (key, chain) = client.issue(domain, &*solver).await?;
+        ctx.net_controller
+            .ssl
+            .save_acme_certificate(domain, &key, &chain)
+            .await?;
+        ctx.net_controller
+            .nginx
+            .add_clearnet(&ctx.net_controller.ssl, domain)
+            .await?;
+        expiration(&chain)
+    }
+    .await;
+
+    let mut db = ctx.db.handle();
+    let mut tx = db.begin().await?;
+    let mut server_info = crate::db::DatabaseModel::new()
+        .server_info()
+        .get_mut(&mut tx)
+        .await?;
+    if let Some(info) = server_info.acme.domains.get_mut(domain) {
+        match &res {
+            Ok(expires) => {
+                info.expires = Some(*expires);
+                info.error = None;
+            }
+            Err(e) => info.error = Some(e.to_string()),
+        }
+    }
+    let address = format!("https://{}", domain);
+    if res.is_ok() && !server_info.connection_addresses.clearnet.contains(&address) {
+        server_info.connection_addresses.clearnet.push(address);
+    }
+    server_info.save(&mut tx).await?;
+    tx.commit(None).await?;
+    res
+}
+
+async fn issue_certificate_notify(ctx: &RpcContext, domain: &str) {
+    if let Err(e) = issue_certificate(ctx, domain).await {
+        let err_str = format!("Could not obtain a certificate for {}: {}", domain, e);
+        tracing::error!("{}", err_str);
+        tracing::debug!("{:?}", e);
+        if let Err(e) = ctx
+            .notification_manager
+            .notify(
+                &mut ctx.db.handle(),
+                None,
+                NotificationLevel::Error,
+                String::from("Certificate Issuance Failed"),
+                err_str,
+                (),
+                None,
+            )
+            .await
+        {
+            tracing::error!("Failed to issue Notification: {}", e);
+            tracing::debug!("{:?}", e);
+        }
+    }
+}
+
+/// Renews every ACME certificate that is close to expiring.
+pub async fn acme_renewal(ctx: &RpcContext) {
+    let settings = match load_settings(&mut ctx.db.handle()).await {
+        Ok(a) => a,
+        Err(e) => {
+            tracing::error!("Failed to load ACME settings: {}", e);
+            tracing::debug!("{:?}", e);
+            return;
+        }
+    };
+    for domain in settings.domains.keys() {
+        let due = match ctx.net_controller.ssl.acme_certificate_for(domain).await {
+            Ok(Some((_, chain))) => Asn1Time::days_from_now(RENEWAL_WINDOW_DAYS)
+                .and_then(|window_end| chain[0].not_after().compare(&window_end))
+                .map(|o| o == Ordering::Less)
+                .unwrap_or(true),
+            Ok(None) => true,
+            Err(e) => {
+                tracing::error!("Failed to load certificate for {}: {}", domain, e);
+                tracing::debug!("{:?}", e);
+                false
+            }
+        };
+        if due {
+            issue_certificate_notify(ctx, domain).await;
+        }
+    }
+}
+
+#[command(subcommands(set, add, remove, list, renew))]
+pub fn acme() -> Result<(), Error> {
+    Ok(())
+}
+
+fn parse_contact(arg: &str, _: &ArgMatches<'_>) -> Result<Vec<String>, Error> {
+    Ok(arg
+        .split(",")
+        .map(|s| s.trim().to_owned())
+        .filter(|s| !s.is_empty())
+        .collect())
+}
+
+/// Configures the ACME server. Unset arguments are left as they are.
+#[command(display(display_none))]
+#[instrument(skip(ctx))]
+pub async fn set(
+    #[context] ctx: RpcContext,
+    #[arg(long = "directory")] directory: Option<Url>,
+    #[arg(long = "contact", parse(parse_contact))] contact: Option<Vec<String>>,
+    #[arg(rename = "dns-hook", long = "dns-hook")] dns_hook: Option<PathBuf>,
+    #[arg(rename = "accept-invalid-certs", long = "accept-invalid-certs")]
+    accept_invalid_certs: Option<bool>,
+) -> Result<WithRevision<()>, Error> {
+    if let Some(hook) = &dns_hook {
+        if !hook.is_absolute() {
+            return Err(Error::new(
+                eyre!("dns hook must be an absolute path"),
+                ErrorKind::InvalidRequest,
+            ));
+        }
+    }
+    let mut db = ctx.db.handle();
+    let mut tx = db.begin().await?;
+    let mut info = crate::db::DatabaseModel::new()
+        .server_info()
+        .get_mut(&mut tx)
+        .await?;
+    if let Some(directory) = directory {
+        info.acme.directory = Some(directory);
+    }
+    if let Some(contact) = contact {
+        info.acme.contact = contact;
+    }
+    if let Some(dns_hook) = dns_hook {
+        info.acme.dns_hook = Some(dns_hook);
+    }
+    if let Some(accept_invalid_certs) = accept_invalid_certs {
+        info.acme.accept_invalid_certs = accept_invalid_certs;
+    }
+    info.save(&mut tx).await?;
+    Ok(WithRevision {
+        response: (),
+        revision: tx.commit(None).await?,
+    })
+}
+
+/// Adds a domain and starts obtaining a certificate for it in the background.
+#[command(display(display_none))]
+#[instrument(skip(ctx))]
+pub async fn add(
+    #[context] ctx: RpcContext,
+    #[arg] domain: String,
+    #[arg(long = "challenge")] challenge: Option<ChallengeType>,
+) -> Result<WithRevision<()>, Error> {
+    let domain = parse_domain(&domain)?;
+    let challenge = challenge.unwrap_or_default();
+    let mut db = ctx.db.handle();
+    let mut tx = db.begin().await?;
+    let mut info = crate::db::DatabaseModel::new()
+        .server_info()
+        .get_mut(&mut tx)
+        .await?;
+    if info.acme.domains.contains_key(&domain) {
+        return Err(Error::new(
+            eyre!("{} is already configured", domain),
+            ErrorKind::Duplicate,
+        ));
+    }
+    if let Some(owner) = domain_owner(&mut tx, &domain).await? {
+        return Err(Error::new(
+            eyre!(
+                "{} is already in use by {} interface {}",
+                domain,
+                owner.package,
+                owner.interface
+            ),
+            ErrorKind::Duplicate,
+        ));
+    }
+    if challenge == ChallengeType::Dns01 && info.acme.dns_hook.is_none() {
+        return Err(Error::new(
+            eyre!("dns-01 challenges require a dns hook to be configured"),
+            ErrorKind::InvalidRequest,
+        ));
+    }
+    info.acme.domains.insert(
+        domain.clone(),
+        AcmeDomain {
+            challenge,
+            expires: None,
+            error: None,
+        },
+    );
+    info.save(&mut tx).await?;
+    let revision = tx.commit(None).await?;
+    drop(db);
+
+    tokio::spawn(async move { issue_certificate_notify(&ctx, &domain).await });
+
+    Ok(WithRevision {
+        response: (),
+        revision,
+    })
+}
+
+/// Stops serving `domain` and deletes its certificate.
+#[command(display(display_none))]
+#[instrument(skip(ctx))]
+pub async fn remove(
+    #[context] ctx: RpcContext,
+    #[arg] domain: String,
+) -> Result<WithRevision<()>, Error> {
+    let domain = parse_domain(&domain)?;
+    let mut db = ctx.db.handle();
+    let mut tx = db.begin().await?;
+    let mut info = crate::db::DatabaseModel::new()
+        .server_info()
+        .get_mut(&mut tx)
+        .await?;
+    if info.acme.domains.remove(&domain).is_none() {
+        return Err(Error::new(
+            eyre!("{} is not configured", domain),
+            ErrorKind::NotFound,
+        ));
+    }
+    let address = format!("https://{}", domain);
+    info.connection_addresses.clearnet.retain(|a| a != &address);
+    info.save(&mut tx).await?;
+    let revision = tx.commit(None).await?;
+    ctx.net_controller.nginx.remove_clearnet(&domain).await?;
+    ctx.net_controller
+        .ssl
+        .remove_acme_certificate(&domain)
+        .await?;
+
+    Ok(WithRevision {
+        response: (),
+        revision,
+    })
+}
+
+fn display_domains(domains: BTreeMap<String, AcmeDomain>, matches: &ArgMatches<'_>) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(domains, matches);
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![bc => "DOMAIN", "CHALLENGE", "EXPIRES", "ERROR"]);
+    for (domain, info) in domains {
+        table.add_row(row![
+            &domain,
+            info.challenge.as_str(),
+            &info
+                .expires
+                .map(|e| e.to_rfc3339())
+                .unwrap_or_else(|| "N/A".to_owned()),
+            info.error.as_deref().unwrap_or(""),
+        ]);
+    }
+    table.print_tty(false);
+}
+
+#[command(display(display_domains))]
+#[instrument(skip(ctx))]
+pub async fn list(
+    #[context] ctx: RpcContext,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<BTreeMap<String, AcmeDomain>, Error> {
+    Ok(load_settings(&mut ctx.db.handle()).await?.domains)
+}
+
+/// Obtains a new certificate for `domain` now, regardless of when the current one expires.
+#[command(display(display_serializable))]
+#[instrument(skip(ctx))]
+pub async fn renew(
+    #[context] ctx: RpcContext,
+    #[arg] domain: String,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<DateTime<Utc>, Error> {
+    issue_certificate(&ctx, &parse_domain(&domain)?).await
+}
+
+#[test]
+fn test_jws_signature() {
+    let key = AccountKey::new(generate_key().unwrap()).unwrap();
+    let jws = key
+        .sign(
+            &json!({ "alg": "ES256" }),
+            Some(&json!({ "hello": "world" })),
+        )
+        .unwrap();
+    let signature =
+        base64::decode_config(jws["signature"].as_str().unwrap(), base64::URL_SAFE_NO_PAD).unwrap();
+    assert_eq!(signature.len(), 64);
+    let sig = EcdsaSig::from_private_components(
+        BigNum::from_slice(&signature[..32]).unwrap(),
+        BigNum::from_slice(&signature[32..]).unwrap(),
+    )
+    .unwrap();
+    let signing_input = format!(
+        "{}.{}",
+        jws["protected"].as_str().unwrap(),
+        jws["payload"].as_str().unwrap()
+    );
+    assert!(sig
+        .verify(
+            &Sha256::digest(signing_input.as_bytes()),
+            &key.key.ec_key().unwrap()
+        )
+        .unwrap());
+}
@@ -0,0 +1,1063 @@
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::time::Duration;
+
+use chrono::{DateTime, TimeZone, Utc};
+use clap::ArgMatches;
+use color_eyre::eyre::eyre;
+use futures::FutureExt;
+use openssl::asn1::{Asn1Integer, Asn1Time};
+use openssl::bn::{BigNum, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::x509::{X509Builder, X509Extension, X509NameBuilder, X509NameRef, X509};
+use openssl::*;
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use tokio::sync::{Mutex, RwLock};
+use tracing::instrument;
+
+use crate::context::RpcContext;
+use crate::notifications::NotificationLevel;
+use crate::s9pk::manifest::PackageId;
+use crate::util::display_none;
+use crate::util::serde::{display_serializable, IoFormat};
+use crate::{Error, ErrorKind, ResultExt};
+
+pub mod acme;
+
+static CERTIFICATE_VERSION: i32 = 2; // X509 version 3 is actually encoded as '2' in the cert because fuck you.
+pub const ROOT_CA_STATIC_PATH: &str = "/var/lib/embassy/ssl/root-ca.crt";
+pub const CERTIFICATE_EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60 * 24);
+/// How close to expiring a certificate gets before its owner is warned.
+const EXPIRY_WARNING_DAYS: i64 = 14;
+
+#[command(subcommands(acme::acme, list, rotate_intermediate, rotate_root))]
+pub fn ssl() -> Result<(), Error> {
+    Ok(())
+}
+
+fn display_certificates(certificates: Vec<CertificateInfo>, matches: &ArgMatches<'_>) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(certificates, matches);
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![bc => "KIND", "NAME", "SUBJECT ALT NAMES", "ISSUER", "EXPIRES"]);
+    for certificate in certificates {
+        table.add_row(row![
+            certificate.kind.as_str(),
+            &certificate.lookup.as_deref().unwrap_or("N/A"),
+            &certificate.subject_alt_names.join(", "),
+            certificate.issuer.as_deref().unwrap_or(""),
+            &certificate.expires.to_rfc3339(),
+        ]);
+    }
+    table.print_tty(false);
+}
+
+#[command(display(display_certificates))]
+#[instrument(skip(ctx))]
+pub async fn list(
+    #[context] ctx: RpcContext,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<CertificateInfo>, Error> {
+    ctx.net_controller.ssl.list().await
+}
+
+/// Replaces the intermediate CA and re-issues every local certificate under it. Clients that only
+/// trust the root are unaffected.
+#[command(rename = "rotate-intermediate", display(display_none))]
+#[instrument(skip(ctx))]
+pub async fn rotate_intermediate(#[context] ctx: RpcContext) -> Result<(), Error> {
+    rotate(&ctx, false).await
+}
+
+/// Replaces the root and intermediate CA and re-issues every local certificate. Clients must
+/// trust the new root afterwards.
+#[command(rename = "rotate-root", display(display_none))]
+#[instrument(skip(ctx))]
+pub async fn rotate_root(#[context] ctx: RpcContext) -> Result<(), Error> {
+    rotate(&ctx, true).await
+}
+
+async fn rotate(ctx: &RpcContext, root: bool) -> Result<(), Error> {
+    ctx.net_controller.ssl.rotate(root).await?;
+    ctx.net_controller.refresh_certificates().await
+}
+
+/// Warns the owner about every certificate expiring soon, once per certificate. Local leaves are
+/// re-issued when their interface starts, and ACME ones by the renewal daemon, so a warning means
+/// neither has happened in time.
+#[instrument(skip(ctx))]
+pub async fn certificate_expiry_check(ctx: &RpcContext) {
+    lazy_static::lazy_static! {
+        static ref NOTIFIED: Mutex<BTreeSet<(String, DateTime<Utc>)>> = Mutex::new(BTreeSet::new());
+    }
+    let certificates = match ctx.net_controller.ssl.list().await {
+        Ok(a) => a,
+        Err(e) => {
+            tracing::error!("Failed to list certificates: {}", e);
+            tracing::debug!("{:?}", e);
+            return;
+        }
+    };
+    let window_end = Utc::now() + chrono::Duration::days(EXPIRY_WARNING_DAYS);
+    let mut notified = NOTIFIED.lock().await;
+    for certificate in certificates {
+        let name = certificate.name();
+        if certificate.expires > window_end || !notified.insert((name.clone(), certificate.expires))
+        {
+            continue;
+        }
+        if let Err(e) = ctx
+            .notification_manager
+            .notify(
+                &mut ctx.db.handle(),
+                None,
+                NotificationLevel::Warning,
+                String::from("Certificate Expiring"),
+                format!(
+                    "The certificate for {} expires at {}",
+                    name,
+                    certificate.expires.to_rfc3339()
+                ),
+                (),
+                None,
+            )
+            .await
+        {
+            tracing::error!("Failed to issue Notification: {}", e);
+            tracing::debug!("{:?}", e);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SslManager {
+    store: SslStore,
+    ca: RwLock<LocalCa>,
+}
+
+#[derive(Debug)]
+struct SslStore {
+    secret_store: SqlitePool,
+}
+impl SslStore {
+    fn new(db: SqlitePool) -> Result<Self, Error> {
+        Ok(SslStore { secret_store: db })
+    }
+    #[instrument(skip(self))]
+    async fn save_root_certificate(&self, key: &PKey<Private>, cert: &X509) -> Result<(), Error> {
+        let key_str = String::from_utf8(key.private_key_to_pem_pkcs8()?)?;
+        let cert_str = String::from_utf8(cert.to_pem()?)?;
+        let _n = sqlx::query!("INSERT INTO certificates (id, priv_key_pem, certificate_pem, lookup_string, created_at, updated_at) VALUES (0, ?, ?, NULL, datetime('now'), datetime('now'))", key_str, cert_str).execute(&self.secret_store).await?;
+        Ok(())
+    }
+    #[instrument(skip(self))]
+    async fn load_root_certificate(&self) -> Result<Option<(PKey<Private>, X509)>, Error> {
+        let m_row =
+            sqlx::query!("SELECT priv_key_pem, certificate_pem FROM certificates WHERE id = 0;")
+                .fetch_optional(&self.secret_store)
+                .await?;
+        match m_row {
+            None => Ok(None),
+            Some(row) => {
+                let priv_key = PKey::private_key_from_pem(&row.priv_key_pem.into_bytes())?;
+                let certificate = X509::from_pem(&row.certificate_pem.into_bytes())?;
+                Ok(Some((priv_key, certificate)))
+            }
+        }
+    }
+    #[instrument(skip(self))]
+    async fn save_intermediate_certificate(
+        &self,
+        key: &PKey<Private>,
+        cert: &X509,
+    ) -> Result<(), Error> {
+        let key_str = String::from_utf8(key.private_key_to_pem_pkcs8()?)?;
+        let cert_str = String::from_utf8(cert.to_pem()?)?;
+        let _n = sqlx::query!("INSERT INTO certificates (id, priv_key_pem, certificate_pem, lookup_string, created_at, updated_at) VALUES (1, ?, ?, NULL, datetime('now'), datetime('now'))", key_str, cert_str).execute(&self.secret_store).await?;
+        Ok(())
+    }
+    async fn load_intermediate_certificate(&self) -> Result<Option<(PKey<Private>, X509)>, Error> {
+        let m_row =
+            sqlx::query!("SELECT priv_key_pem, certificate_pem FROM certificates WHERE id = 1;")
+                .fetch_optional(&self.secret_store)
+                .await?;
+        match m_row {
+            None => Ok(None),
+            Some(row) => {
+                let priv_key = PKey::private_key_from_pem(&row.priv_key_pem.into_bytes())?;
+                let certificate = X509::from_pem(&row.certificate_pem.into_bytes())?;
+                Ok(Some((priv_key, certificate)))
+            }
+        }
+    }
+    #[instrument(skip(self))]
+    async fn save_certificate(
+        &self,
+        key: &PKey<Private>,
+        cert: &X509,
+        lookup_string: &str,
+    ) -> Result<(), Error> {
+        let key_str = String::from_utf8(key.private_key_to_pem_pkcs8()?)?;
+        let cert_str = String::from_utf8(cert.to_pem()?)?;
+        let _n = sqlx::query!("INSERT INTO certificates (priv_key_pem, certificate_pem, lookup_string, created_at, updated_at) VALUES (?, ?, ?, datetime('now'), datetime('now'))", key_str, cert_str, lookup_string).execute(&self.secret_store).await?;
+        Ok(())
+    }
+    async fn load_certificate(
+        &self,
+        lookup_string: &str,
+    ) -> Result<Option<(PKey<Private>, X509)>, Error> {
+        let m_row = sqlx::query!(
+            "SELECT priv_key_pem, certificate_pem FROM certificates WHERE lookup_string = ?",
+            lookup_string
+        )
+        .fetch_optional(&self.secret_store)
+        .await?;
+        match m_row {
+            None => Ok(None),
+            Some(row) => {
+                let priv_key = PKey::private_key_from_pem(&row.priv_key_pem.into_bytes())?;
+                let certificate = X509::from_pem(&row.certificate_pem.into_bytes())?;
+                Ok(Some((priv_key, certificate)))
+            }
+        }
+    }
+    #[instrument(skip(self))]
+    async fn update_certificate(
+        &self,
+        key: &PKey<Private>,
+        cert: &X509,
+        lookup_string: &str,
+    ) -> Result<(), Error> {
+        let key_str = String::from_utf8(key.private_key_to_pem_pkcs8()?)?;
+        let cert_str = String::from_utf8(cert.to_pem()?)?;
+        let n = sqlx::query!("UPDATE certificates SET priv_key_pem = ?, certificate_pem = ?, updated_at = datetime('now') WHERE lookup_string = ?", key_str, cert_str, lookup_string).execute(&self.secret_store).await?;
+        if n.rows_affected() == 0 {
+            return Err(Error::new(
+                eyre!(
+                    "Attempted to update non-existent certificate: {}",
+                    lookup_string
+                ),
+                ErrorKind::OpenSsl,
+            ));
+        }
+        Ok(())
+    }
+    #[instrument(skip(self, key, chain))]
+    async fn save_chain(
+        &self,
+        key: &PKey<Private>,
+        chain: &[X509],
+        lookup_string: &str,
+    ) -> Result<(), Error> {
+        let key_str = String::from_utf8(key.private_key_to_pem_pkcs8()?)?;
+        let mut cert_str = String::new();
+        for cert in chain {
+            cert_str += &String::from_utf8(cert.to_pem()?)?;
+        }
+        sqlx::query("INSERT INTO certificates (priv_key_pem, certificate_pem, lookup_string, created_at, updated_at) VALUES (?, ?, ?, datetime('now'), datetime('now')) ON CONFLICT (lookup_string) DO UPDATE SET priv_key_pem = excluded.priv_key_pem, certificate_pem = excluded.certificate_pem, updated_at = excluded.updated_at")
+            .bind(key_str)
+            .bind(cert_str)
+            .bind(lookup_string)
+            .execute(&self.secret_store)
+            .await?;
+        Ok(())
+    }
+    #[instrument(skip(self))]
+    async fn load_chain(
+        &self,
+        lookup_string: &str,
+    ) -> Result<Option<(PKey<Private>, Vec<X509>)>, Error> {
+        use sqlx::Row;
+
+        let m_row = sqlx::query(
+            "SELECT priv_key_pem, certificate_pem FROM certificates WHERE lookup_string = ?",
+        )
+        .bind(lookup_string)
+        .fetch_optional(&self.secret_store)
+        .await?;
+        match m_row {
+            None => Ok(None),
+            Some(row) => {
+                let priv_key_pem: String = row.try_get("priv_key_pem")?;
+                let certificate_pem: String = row.try_get("certificate_pem")?;
+                let priv_key = PKey::private_key_from_pem(priv_key_pem.as_bytes())?;
+                let chain = if certificate_pem.trim().is_empty() {
+                    Vec::new()
+                } else {
+                    X509::stack_from_pem(certificate_pem.as_bytes())?
+                };
+                Ok(Some((priv_key, chain)))
+            }
+        }
+    }
+    #[instrument(skip(self))]
+    async fn remove_certificate(&self, lookup_string: &str) -> Result<(), Error> {
+        sqlx::query("DELETE FROM certificates WHERE lookup_string = ?")
+            .bind(lookup_string)
+            .execute(&self.secret_store)
+            .await?;
+        Ok(())
+    }
+    /// The id, lookup string and certificate of every row except the ACME account keys, which
+    /// have no certificate.
+    #[instrument(skip(self))]
+    async fn load_all_certificates(&self) -> Result<Vec<(i64, Option<String>, X509)>, Error> {
+        use sqlx::Row;
+
+        let rows = sqlx::query("SELECT id, lookup_string, certificate_pem FROM certificates WHERE lookup_string IS NULL OR lookup_string NOT LIKE 'acme-account:%' ORDER BY id")
+            .fetch_all(&self.secret_store)
+            .await?;
+        let mut res = Vec::with_capacity(rows.len());
+        for row in rows {
+            let certificate_pem: String = row.try_get("certificate_pem")?;
+            if certificate_pem.trim().is_empty() {
+                continue;
+            }
+            res.push((
+                row.try_get("id")?,
+                row.try_get("lookup_string")?,
+                X509::from_pem(certificate_pem.as_bytes())?,
+            ));
+        }
+        Ok(res)
+    }
+    /// Replaces the local CA and the leaves it signed in a single transaction, so an interrupted
+    /// rotation leaves the old certificates in place. The root is kept if `root` is `None`.
+    #[instrument(skip(self, root, int, leaves))]
+    async fn replace_ca(
+        &self,
+        root: Option<(&PKey<Private>, &X509)>,
+        int: (&PKey<Private>, &X509),
+        leaves: &[(String, PKey<Private>, X509)],
+    ) -> Result<(), Error> {
+        let mut tx = self.secret_store.begin().await?;
+        for (id, (key, cert)) in root
+            .map(|root| (0, root))
+            .into_iter()
+            .chain(std::iter::once((1, int)))
+        {
+            sqlx::query("INSERT INTO certificates (id, priv_key_pem, certificate_pem, lookup_string, created_at, updated_at) VALUES (?, ?, ?, NULL, datetime('now'), datetime('now')) ON CONFLICT (id) DO UPDATE SET priv_key_pem = excluded.priv_key_pem, certificate_pem = excluded.certificate_pem, updated_at = excluded.updated_at")
+                .bind(id)
+                .bind(String::from_utf8(key.private_key_to_pem_pkcs8()?)?)
+                .bind(String::from_utf8(cert.to_pem()?)?)
+                .execute(&mut tx)
+                .await?;
+        }
+        for (lookup_string, key, cert) in leaves {
+            sqlx::query("UPDATE certificates SET priv_key_pem = ?, certificate_pem = ?, updated_at = datetime('now') WHERE lookup_string = ?")
+                .bind(String::from_utf8(key.private_key_to_pem_pkcs8()?)?)
+                .bind(String::from_utf8(cert.to_pem()?)?)
+                .bind(lookup_string)
+                .execute(&mut tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CertificateKind {
+    Root,
+    Intermediate,
+    /// A leaf signed by the local intermediate.
+    Local,
+    /// A publicly trusted chain obtained over ACME.
+    Acme,
+}
+impl CertificateKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CertificateKind::Root => "root",
+            CertificateKind::Intermediate => "intermediate",
+            CertificateKind::Local => "local",
+            CertificateKind::Acme => "acme",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CertificateInfo {
+    pub kind: CertificateKind,
+    /// The dns base or domain the certificate is stored under. `None` for the CA.
+    pub lookup: Option<String>,
+    pub common_name: Option<String>,
+    pub subject_alt_names: Vec<String>,
+    pub issuer: Option<String>,
+    pub expires: DateTime<Utc>,
+}
+impl CertificateInfo {
+    fn new(id: i64, lookup: Option<String>, cert: &X509) -> Result<Self, Error> {
+        let (kind, lookup) = match (id, lookup) {
+            (0, _) => (CertificateKind::Root, None),
+            (1, _) => (CertificateKind::Intermediate, None),
+            (_, Some(lookup)) if lookup.starts_with("acme:") => (
+                CertificateKind::Acme,
+                Some(lookup.trim_start_matches("acme:").to_owned()),
+            ),
+            (_, lookup) => (CertificateKind::Local, lookup),
+        };
+        Ok(CertificateInfo {
+            kind,
+            lookup,
+            common_name: name_entry(cert.subject_name()),
+            subject_alt_names: subject_alt_names(cert),
+            issuer: name_entry(cert.issuer_name()),
+            expires: not_after(cert)?,
+        })
+    }
+    pub fn name(&self) -> String {
+        match (self.kind, &self.lookup) {
+            (CertificateKind::Root, _) => "the root CA".to_owned(),
+            (CertificateKind::Intermediate, _) => "the intermediate CA".to_owned(),
+            (_, Some(lookup)) => lookup.clone(),
+            (_, None) => self.common_name.clone().unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct LocalCa {
+    root_cert: X509,
+    int_key: PKey<Private>,
+    int_cert: X509,
+}
+impl LocalCa {
+    /// Signs a new intermediate with `root` and re-issues every local leaf under it, then
+    /// replaces the stored CA and leaves at once. The root is only written if `new_root` is set.
+    #[instrument(skip(store, root_key, root_cert))]
+    async fn issue(
+        store: &SslStore,
+        root_key: &PKey<Private>,
+        root_cert: X509,
+        new_root: bool,
+    ) -> Result<Self, Error> {
+        let int_key = generate_key()?;
+        let int_cert = make_int_cert((root_key, &root_cert), &int_key)?;
+        let mut leaves = Vec::new();
+        for (id, lookup_string, cert) in store.load_all_certificates().await? {
+            let lookup_string = match lookup_string {
+                Some(a) if id > 1 && !a.starts_with("acme:") => a,
+                _ => continue,
+            };
+            let key = generate_key()?;
+            let common_name =
+                name_entry(cert.subject_name()).unwrap_or_else(|| lookup_string.clone());
+            let subject_alt_name = subject_alt_names(&cert)
+                .into_iter()
+                .map(|name| format!("DNS:{}", name))
+                .collect::<Vec<_>>()
+                .join(",");
+            let cert =
+                make_named_leaf_cert((&int_key, &int_cert), &key, &common_name, &subject_alt_name)?;
+            leaves.push((lookup_string, key, cert));
+        }
+        store
+            .replace_ca(
+                if new_root {
+                    Some((root_key, &root_cert))
+                } else {
+                    None
+                },
+                (&int_key, &int_cert),
+                &leaves,
+            )
+            .await?;
+        Ok(LocalCa {
+            root_cert,
+            int_key,
+            int_cert,
+        })
+    }
+}
+
+const EC_CURVE_NAME: nid::Nid = nid::Nid::X9_62_PRIME256V1;
+lazy_static::lazy_static! {
+    static ref EC_GROUP: EcGroup = EcGroup::from_curve_name(EC_CURVE_NAME).unwrap();
+    static ref SSL_MUTEX: Mutex<()> = Mutex::new(()); // TODO: make thread safe
+}
+
+impl SslManager {
+    #[instrument(skip(db))]
+    pub async fn init(db: SqlitePool) -> Result<Self, Error> {
+        let store = SslStore::new(db)?;
+        let (root_key, root_cert) = match store.load_root_certificate().await? {
+            None => {
+                let root_key = generate_key()?;
+                let root_cert = make_root_cert(&root_key)?;
+                store.save_root_certificate(&root_key, &root_cert).await?;
+                Ok::<_, Error>((root_key, root_cert))
+            }
+            Some((key, cert)) => Ok((key, cert)),
+        }?;
+        write_root_ca_static(&root_cert).await?;
+        let (int_key, int_cert) = match store.load_intermediate_certificate().await? {
+            None => {
+                let int_key = generate_key()?;
+                let int_cert = make_int_cert((&root_key, &root_cert), &int_key)?;
+                store
+                    .save_intermediate_certificate(&int_key, &int_cert)
+                    .await?;
+                Ok::<_, Error>((int_key, int_cert))
+            }
+            Some((key, cert)) => Ok((key, cert)),
+        }?;
+        Ok(SslManager {
+            store,
+            ca: RwLock::new(LocalCa {
+                root_cert,
+                int_key,
+                int_cert,
+            }),
+        })
+    }
+
+    // TODO: currently the burden of proof is on the caller to ensure that all of the arguments to this function are
+    // consistent. The following properties are assumed and not verified:
+    // 1. `root_cert` is self-signed and contains the public key that matches the private key `root_key`
+    // 2. certificate is not past its expiration date
+    #[instrument(skip(db))]
+    pub async fn import_root_ca(
+        db: SqlitePool,
+        root_key: PKey<Private>,
+        root_cert: X509,
+    ) -> Result<Self, Error> {
+        let store = SslStore::new(db)?;
+        let ca = LocalCa::issue(&store, &root_key, root_cert, true).await?;
+        Ok(SslManager {
+            store,
+            ca: RwLock::new(ca),
+        })
+    }
+
+    /// Replaces the intermediate CA, and the root as well if `root` is set, re-issuing every local
+    /// leaf under the new one. Nothing changes if issuing fails, but once the new CA is stored it is
+    /// used even if the root offered for download cannot be updated, since init rewrites that file
+    /// anyway. Callers are responsible for putting the new leaves in place wherever the old ones
+    /// were exported.
+    #[instrument(skip(self))]
+    pub async fn rotate(&self, root: bool) -> Result<(), Error> {
+        let mut ca = self.ca.write().await;
+        let (root_key, root_cert) = if root {
+            let root_key = generate_key()?;
+            let root_cert = make_root_cert(&root_key)?;
+            (root_key, root_cert)
+        } else {
+            self.export_root_ca().await?
+        };
+        *ca = LocalCa::issue(&self.store, &root_key, root_cert, root).await?;
+        if root {
+            if let Err(e) = write_root_ca_static(&ca.root_cert).await {
+                tracing::error!("Failed to publish the new root certificate: {}", e);
+                tracing::debug!("{:?}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Every certificate in the store, including the CA.
+    #[instrument(skip(self))]
+    pub async fn list(&self) -> Result<Vec<CertificateInfo>, Error> {
+        let _ca = self.ca.read().await;
+        self.store
+            .load_all_certificates()
+            .await?
+            .into_iter()
+            .map(|(id, lookup, cert)| CertificateInfo::new(id, lookup, &cert))
+            .collect()
+    }
+
+    #[instrument(skip(self))]
+    pub async fn export_root_ca(&self) -> Result<(PKey<Private>, X509), Error> {
+        match self.store.load_root_certificate().await? {
+            None => Err(Error::new(
+                eyre!("Failed to export root certificate: root certificate has not been generated"),
+                ErrorKind::OpenSsl,
+            )),
+            Some(a) => Ok(a),
+        }
+    }
+
+    #[instrument(skip(self))]
+    pub async fn certificate_for(
+        &self,
+        dns_base: &str,
+        package_id: &PackageId,
+    ) -> Result<(PKey<Private>, Vec<X509>), Error> {
+        let ca = self.ca.read().await;
+        let (key, cert) = match self.store.load_certificate(dns_base).await? {
+            None => {
+                let key = generate_key()?;
+                let cert =
+                    make_leaf_cert((&ca.int_key, &ca.int_cert), (&key, dns_base, package_id))?;
+                self.store.save_certificate(&key, &cert, dns_base).await?;
+                Ok::<_, Error>((key, cert))
+            }
+            Some((key, cert)) => {
+                let window_end = Asn1Time::days_from_now(30)?;
+                let expiration = cert.not_after();
+                if expiration.compare(&window_end)? == Ordering::Less {
+                    let key = generate_key()?;
+                    let cert =
+                        make_leaf_cert((&ca.int_key, &ca.int_cert), (&key, dns_base, package_id))?;
+                    self.store.update_certificate(&key, &cert, dns_base).await?;
+                    Ok((key, cert))
+                } else {
+                    Ok((key, cert))
+                }
+            }
+        }?;
+        Ok((key, vec![cert, ca.int_cert.clone(), ca.root_cert.clone()]))
+    }
+
+    /// A certificate from the local CA for a custom domain of a package interface.
+    #[instrument(skip(self))]
+    pub async fn certificate_for_domain(
+        &self,
+        domain: &str,
+    ) -> Result<(PKey<Private>, Vec<X509>), Error> {
+        let ca = self.ca.read().await;
+        let make_cert = |key: &PKey<Private>| {
+            make_named_leaf_cert(
+                (&ca.int_key, &ca.int_cert),
+                key,
+                domain,
+                &format!("DNS:{}", domain),
+            )
+        };
+        let (key, cert) = match self.store.load_certificate(domain).await? {
+            None => {
+                let key = generate_key()?;
+                let cert = make_cert(&key)?;
+                self.store.save_certificate(&key, &cert, domain).await?;
+                (key, cert)
+            }
+            Some((key, cert)) => {
+                let window_end = Asn1Time::days_from_now(30)?;
+                if cert.not_after().compare(&window_end)? == Ordering::Less {
+                    let key = generate_key()?;
+                    let cert = make_cert(&key)?;
+                    self.store.update_certificate(&key, &cert, domain).await?;
+                    (key, cert)
+                } else {
+                    (key, cert)
+                }
+            }
+        };
+        Ok((key, vec![cert, ca.int_cert.clone(), ca.root_cert.clone()]))
+    }
+
+    /// The key identifying our account with the ACME server at `directory`, generated on first use.
+    #[instrument(skip(self))]
+    pub async fn acme_account_key(&self, directory: &str) -> Result<PKey<Private>, Error> {
+        let lookup_string = format!("acme-account:{}", directory);
+        match self.store.load_chain(&lookup_string).await? {
+            Some((key, _)) => Ok(key),
+            None => {
+                let key = generate_key()?;
+                self.store.save_chain(&key, &[], &lookup_string).await?;
+                Ok(key)
+            }
+        }
+    }
+
+    /// The publicly trusted certificate chain issued for `domain` over ACME, if there is one.
+    #[instrument(skip(self))]
+    pub async fn acme_certificate_for(
+        &self,
+        domain: &str,
+    ) -> Result<Option<(PKey<Private>, Vec<X509>)>, Error> {
+        Ok(self
+            .store
+            .load_chain(&format!("acme:{}", domain))
+            .await?
+            .filter(|(_, chain)| !chain.is_empty()))
+    }
+
+    #[instrument(skip(self, key, chain))]
+    pub async fn save_acme_certificate(
+        &self,
+        domain: &str,
+        key: &PKey<Private>,
+        chain: &[X509],
+    ) -> Result<(), Error> {
+        self.store
+            .save_chain(key, chain, &format!("acme:{}", domain))
+            .await
+    }
+
+    #[instrument(skip(self))]
+    pub async fn remove_acme_certificate(&self, domain: &str) -> Result<(), Error> {
+        self.store
+            .remove_certificate(&format!("acme:{}", domain))
+            .await
+    }
+}
+
+/// Writes the root certificate where the UI offers it for download. It is lost on restart, so
+/// this happens on every init.
+async fn write_root_ca_static(root_cert: &X509) -> Result<(), Error> {
+    tokio::fs::create_dir_all(
+        Path::new(ROOT_CA_STATIC_PATH)
+            .parent()
+            .unwrap_or(Path::new("/")),
+    )
+    .await?;
+    tokio::fs::write(ROOT_CA_STATIC_PATH, root_cert.to_pem()?)
+        .await
+        .with_ctx(|_| (ErrorKind::Filesystem, ROOT_CA_STATIC_PATH))?;
+    Ok(())
+}
+
+pub async fn export_key(key: &PKey<Private>, target: &Path) -> Result<(), Error> {
+    tokio::fs::write(target, key.private_key_to_pem_pkcs8()?)
+        .map(|res| res.with_ctx(|_| (ErrorKind::Filesystem, target.display().to_string())))
+        .await?;
+    Ok(())
+}
+pub async fn export_cert(chain: &Vec<X509>, target: &Path) -> Result<(), Error> {
+    tokio::fs::write(
+        target,
+        chain
+            .into_iter()
+            .flat_map(|c| c.to_pem().unwrap())
+            .collect::<Vec<u8>>(),
+    )
+    .await?;
+    Ok(())
+}
+/// The first common name in `name`.
+pub(crate) fn name_entry(name: &X509NameRef) -> Option<String> {
+    name.entries_by_nid(Nid::COMMONNAME)
+        .next()
+        .and_then(|entry| entry.data().as_utf8().ok())
+        .map(|s| s.to_string())
+}
+
+fn subject_alt_names(cert: &X509) -> Vec<String> {
+    cert.subject_alt_names()
+        .into_iter()
+        .flatten()
+        .filter_map(|name| name.dnsname().map(|s| s.to_owned()))
+        .collect()
+}
+
+pub(crate) fn not_after(cert: &X509) -> Result<DateTime<Utc>, Error> {
+    let diff = Asn1Time::from_unix(0)?.diff(cert.not_after())?;
+    Ok(Utc.timestamp(diff.days as i64 * 86400 + diff.secs as i64, 0))
+}
+
+#[instrument]
+fn rand_serial() -> Result<Asn1Integer, Error> {
+    let mut bn = BigNum::new()?;
+    bn.rand(64, MsbOption::MAYBE_ZERO, false)?;
+    let asn1 = Asn1Integer::from_bn(&bn)?;
+    Ok(asn1)
+}
+#[instrument]
+fn generate_key() -> Result<PKey<Private>, Error> {
+    let new_key = EcKey::generate(EC_GROUP.as_ref())?;
+    let key = PKey::from_ec_key(new_key)?;
+    Ok(key)
+}
+#[instrument]
+fn make_root_cert(root_key: &PKey<Private>) -> Result<X509, Error> {
+    let mut builder = X509Builder::new()?;
+    builder.set_version(CERTIFICATE_VERSION)?;
+
+    let embargo = Asn1Time::days_from_now(0)?;
+    builder.set_not_before(&embargo)?;
+
+    let expiration = Asn1Time::days_from_now(3650)?;
+    builder.set_not_after(&expiration)?;
+
+    builder.set_serial_number(&*rand_serial()?)?;
+
+    let mut subject_name_builder = X509NameBuilder::new()?;
+    subject_name_builder.append_entry_by_text("CN", "Embassy Local Root CA")?;
+    subject_name_builder.append_entry_by_text("O", "Start9")?;
+    subject_name_builder.append_entry_by_text("OU", "Embassy")?;
+    let subject_name = subject_name_builder.build();
+    builder.set_subject_name(&subject_name)?;
+
+    builder.set_issuer_name(&subject_name)?;
+
+    builder.set_pubkey(&root_key)?;
+
+    // Extensions
+    let cfg = conf::Conf::new(conf::ConfMethod::default())?;
+    let ctx = builder.x509v3_context(None, Some(&cfg));
+    // subjectKeyIdentifier = hash
+    let subject_key_identifier =
+        X509Extension::new_nid(Some(&cfg), Some(&ctx), Nid::SUBJECT_KEY_IDENTIFIER, "hash")?;
+    // basicConstraints = critical, CA:true, pathlen:0
+    let basic_constraints = X509Extension::new_nid(
+        Some(&cfg),
+        Some(&ctx),
+        Nid::BASIC_CONSTRAINTS,
+        "critical,CA:true",
+    )?;
+    // keyUsage = critical, digitalSignature, cRLSign, keyCertSign
+    let key_usage = X509Extension::new_nid(
+        Some(&cfg),
+        Some(&ctx),
+        Nid::KEY_USAGE,
+        "critical,digitalSignature,cRLSign,keyCertSign",
+    )?;
+    builder.append_extension(subject_key_identifier)?;
+    builder.append_extension(basic_constraints)?;
+    builder.append_extension(key_usage)?;
+    builder.sign(&root_key, MessageDigest::sha256())?;
+    let cert = builder.build();
+    Ok(cert)
+}
+#[instrument]
+fn make_int_cert(
+    signer: (&PKey<Private>, &X509),
+    applicant: &PKey<Private>,
+) -> Result<X509, Error> {
+    let mut builder = X509Builder::new()?;
+    builder.set_version(CERTIFICATE_VERSION)?;
+
+    let embargo = Asn1Time::days_from_now(0)?;
+    builder.set_not_before(&embargo)?;
+
+    let expiration = Asn1Time::days_from_now(3650)?;
+    builder.set_not_after(&expiration)?;
+
+    builder.set_serial_number(&*rand_serial()?)?;
+
+    let mut subject_name_builder = X509NameBuilder::new()?;
+    subject_name_builder.append_entry_by_text("CN", "Embassy Local Intermediate CA")?;
+    subject_name_builder.append_entry_by_text("O", "Start9")?;
+    subject_name_builder.append_entry_by_text("OU", "Embassy")?;
+    let subject_name = subject_name_builder.build();
+    builder.set_subject_name(&subject_name)?;
+
+    builder.set_issuer_name(signer.1.subject_name())?;
+
+    builder.set_pubkey(&applicant)?;
+
+    let cfg = conf::Conf::new(conf::ConfMethod::default())?;
+    let ctx = builder.x509v3_context(Some(&signer.1), Some(&cfg));
+    // subjectKeyIdentifier = hash
+    let subject_key_identifier =
+        X509Extension::new_nid(Some(&cfg), Some(&ctx), Nid::SUBJECT_KEY_IDENTIFIER, "hash")?;
+    // authorityKeyIdentifier = keyid:always,issuer
+    let authority_key_identifier = X509Extension::new_nid(
+        Some(&cfg),
+        Some(&ctx),
+        Nid::AUTHORITY_KEY_IDENTIFIER,
+        "keyid:always,issuer",
+    )?;
+    // basicConstraints = critical, CA:true, pathlen:0
+    let basic_constraints = X509Extension::new_nid(
+        Some(&cfg),
+        Some(&ctx),
+        Nid::BASIC_CONSTRAINTS,
+        "critical,CA:true,pathlen:0",
+    )?;
+    // keyUsage = critical, digitalSignature, cRLSign, keyCertSign
+    let key_usage = X509Extension::new_nid(
+        Some(&cfg),
+        Some(&ctx),
+        Nid::KEY_USAGE,
+        "critical,digitalSignature,cRLSign,keyCertSign",
+    )?;
+    builder.append_extension(subject_key_identifier)?;
+    builder.append_extension(authority_key_identifier)?;
+    builder.append_extension(basic_constraints)?;
+    builder.append_extension(key_usage)?;
+    builder.sign(&signer.0, MessageDigest::sha256())?;
+    let cert = builder.build();
+    Ok(cert)
+}
+
+#[instrument]
+fn make_leaf_cert(
+    signer: (&PKey<Private>, &X509),
+    applicant: (&PKey<Private>, &str, &PackageId),
+) -> Result<X509, Error> {
+    make_named_leaf_cert(
+        signer,
+        applicant.0,
+        &format!("{}.local", &applicant.1),
+        &format!(
+            "DNS:{}.local,DNS:*.{}.local,DNS:{}.onion,DNS:*.{}.onion,DNS:{}.embassy,DNS:*.{}.embassy",
+            &applicant.1, &applicant.1, &applicant.1, &applicant.1, &applicant.2, &applicant.2,
+        ),
+    )
+}
+
+#[instrument]
+fn make_named_leaf_cert(
+    signer: (&PKey<Private>, &X509),
+    key: &PKey<Private>,
+    common_name: &str,
+    subject_alt_name: &str,
+) -> Result<X509, Error> {
+    let mut builder = X509Builder::new()?;
+    builder.set_version(CERTIFICATE_VERSION)?;
+
+    let embargo = Asn1Time::days_from_now(0)?;
+    builder.set_not_before(&embargo)?;
+
+    // Google Apple and Mozilla reject certificate horizons longer than 397 days
+    // https://techbeacon.com/security/google-apple-mozilla-enforce-1-year-max-security-certifications
+    let expiration = Asn1Time::days_from_now(397)?;
+    builder.set_not_after(&expiration)?;
+
+    builder.set_serial_number(&*rand_serial()?)?;
+
+    let mut subject_name_builder = X509NameBuilder::new()?;
+    subject_name_builder.append_entry_by_text("CN", common_name)?;
+    subject_name_builder.append_entry_by_text("O", "Start9")?;
+    subject_name_builder.append_entry_by_text("OU", "Embassy")?;
+    let subject_name = subject_name_builder.build();
+    builder.set_subject_name(&subject_name)?;
+
+    builder.set_issuer_name(signer.1.subject_name())?;
+
+    builder.set_pubkey(key)?;
+
+    // Extensions
+    let cfg = conf::Conf::new(conf::ConfMethod::default())?;
+    let ctx = builder.x509v3_context(Some(&signer.1), Some(&cfg));
+    // subjectKeyIdentifier = hash
+    let subject_key_identifier =
+        X509Extension::new_nid(Some(&cfg), Some(&ctx), Nid::SUBJECT_KEY_IDENTIFIER, "hash")?;
+    // authorityKeyIdentifier = keyid:always,issuer
+    let authority_key_identifier = X509Extension::new_nid(
+        Some(&cfg),
+        Some(&ctx),
+        Nid::AUTHORITY_KEY_IDENTIFIER,
+        "keyid,issuer:always",
+    )?;
+    let basic_constraints =
+        X509Extension::new_nid(Some(&cfg), Some(&ctx), Nid::BASIC_CONSTRAINTS, "CA:FALSE")?;
+    let key_usage = X509Extension::new_nid(
+        Some(&cfg),
+        Some(&ctx),
+        Nid::KEY_USAGE,
+        "critical,digitalSignature,keyEncipherment",
+    )?;
+
+    let subject_alt_name = X509Extension::new_nid(
+        Some(&cfg),
+        Some(&ctx),
+        Nid::SUBJECT_ALT_NAME,
+        subject_alt_name,
+    )?;
+    builder.append_extension(subject_key_identifier)?;
+    builder.append_extension(authority_key_identifier)?;
+    builder.append_extension(subject_alt_name)?;
+    builder.append_extension(basic_constraints)?;
+    builder.append_extension(key_usage)?;
+
+    builder.sign(&signer.0, MessageDigest::sha256())?;
+
+    let cert = builder.build();
+    Ok(cert)
+}
+
+#[tokio::test]
+async fn ca_details_persist() -> Result<(), Error> {
+    let pool = sqlx::Pool::<sqlx::Sqlite>::connect("sqlite::memory:").await?;
+    sqlx::query_file!("migrations/20210629193146_Init.sql")
+        .execute(&pool)
+        .await?;
+    let mgr = SslManager::init(pool.clone()).await?.ca.into_inner();
+    let root_cert0 = mgr.root_cert;
+    let int_key0 = mgr.int_key;
+    let int_cert0 = mgr.int_cert;
+    let mgr = SslManager::init(pool).await?.ca.into_inner();
+    let root_cert1 = mgr.root_cert;
+    let int_key1 = mgr.int_key;
+    let int_cert1 = mgr.int_cert;
+
+    assert_eq!(root_cert0.to_pem()?, root_cert1.to_pem()?);
+    assert_eq!(
+        int_key0.private_key_to_pem_pkcs8()?,
+        int_key1.private_key_to_pem_pkcs8()?
+    );
+    assert_eq!(int_cert0.to_pem()?, int_cert1.to_pem()?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn certificate_details_persist() -> Result<(), Error> {
+    let pool = sqlx::Pool::<sqlx::Sqlite>::connect("sqlite::memory:").await?;
+    sqlx::query_file!("migrations/20210629193146_Init.sql")
+        .execute(&pool)
+        .await?;
+    let mgr = SslManager::init(pool.clone()).await?;
+    let package_id = "bitcoind".parse().unwrap();
+    let (key0, cert_chain0) = mgr.certificate_for("start9", &package_id).await?;
+    let (key1, cert_chain1) = mgr.certificate_for("start9", &package_id).await?;
+
+    assert_eq!(
+        key0.private_key_to_pem_pkcs8()?,
+        key1.private_key_to_pem_pkcs8()?
+    );
+    assert_eq!(
+        cert_chain0
+            .iter()
+            .map(|cert| cert.to_pem().unwrap())
+            .collect::<Vec<Vec<u8>>>(),
+        cert_chain1
+            .iter()
+            .map(|cert| cert.to_pem().unwrap())
+            .collect::<Vec<Vec<u8>>>()
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn rotation_reissues_certificates() -> Result<(), Error> {
+    let pool = sqlx::Pool::<sqlx::Sqlite>::connect("sqlite::memory:").await?;
+    sqlx::query_file!("migrations/20210629193146_Init.sql")
+        .execute(&pool)
+        .await?;
+    let mgr = SslManager::init(pool.clone()).await?;
+    let package_id = "bitcoind".parse().unwrap();
+    let (_, chain0) = mgr.certificate_for("start9", &package_id).await?;
+
+    mgr.rotate(false).await?;
+    let (key1, chain1) = mgr.certificate_for("start9", &package_id).await?;
+    assert_ne!(chain0[0].to_pem()?, chain1[0].to_pem()?);
+    assert_ne!(chain0[1].to_pem()?, chain1[1].to_pem()?);
+    assert_eq!(chain0[2].to_pem()?, chain1[2].to_pem()?);
+    assert!(chain1[0].verify(&chain1[1].public_key()?)?);
+    assert_eq!(subject_alt_names(&chain0[0]), subject_alt_names(&chain1[0]));
+
+    mgr.rotate(true).await?;
+    let (_, chain2) = mgr.certificate_for("start9", &package_id).await?;
+    assert_ne!(chain1[2].to_pem()?, chain2[2].to_pem()?);
+    assert!(chain2[1].verify(&chain2[2].public_key()?)?);
+
+    // the rotated certificates are the ones that persist
+    let (key3, chain3) = SslManager::init(pool)
+        .await?
+        .certificate_for("start9", &package_id)
+        .await?;
+    assert_ne!(
+        key1.private_key_to_pem_pkcs8()?,
+        key3.private_key_to_pem_pkcs8()?
+    );
+    assert_eq!(chain2[0].to_pem()?, chain3[0].to_pem()?);
+    Ok(())
+}
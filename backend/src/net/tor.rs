@@ -1,15 +1,23 @@
 use std::collections::BTreeMap;
+use std::fmt;
 use std::net::{Ipv4Addr, SocketAddr};
-use std::time::Duration;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
+use chrono::{DateTime, Utc};
 use clap::ArgMatches;
 use color_eyre::eyre::eyre;
+use curve25519_dalek::constants::X25519_BASEPOINT;
+use curve25519_dalek::scalar::Scalar;
 use futures::future::BoxFuture;
 use futures::FutureExt;
 use reqwest::Client;
 use rpc_toolkit::command;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_json::json;
 use sqlx::{Executor, Sqlite};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpStream;
 use tokio::sync::Mutex;
 use torut::control::{AsyncEvent, AuthenticatedConn, ConnError};
@@ -18,7 +26,11 @@ use tracing::instrument;
 
 use super::interface::{InterfaceId, TorConfig};
 use crate::context::RpcContext;
+use crate::db::util::WithRevision;
+use crate::dependencies::reconfigure_dependents_with_live_pointers;
+use crate::notifications::NotificationLevel;
 use crate::s9pk::manifest::PackageId;
+use crate::util::display_none;
 use crate::util::serde::{display_serializable, IoFormat};
 use crate::{Error, ErrorKind, ResultExt as _};
 
@@ -27,7 +39,7 @@ fn random_key() {
     println!("x'{}'", hex::encode(TorSecretKeyV3::generate().as_bytes()));
 }
 
-#[command(subcommands(list_services))]
+#[command(subcommands(list_services, auth, rotate, status, config))]
 pub fn tor() -> Result<(), Error> {
     Ok(())
 }
@@ -57,6 +69,943 @@ pub async fn list_services(
     ctx.net_controller.tor.list_services().await
 }
 
+/// How often `tor_status_check` runs.
+pub const TOR_STATUS_INTERVAL: Duration = Duration::from_secs(60);
+/// How long a package's onion service may go without a descriptor before its owner is notified.
+const DESCRIPTOR_PUBLISH_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+const B32: base32::Alphabet = base32::Alphabet::RFC4648 { padding: false };
+
+/// The x25519 public key of a client allowed to fetch the descriptor of an onion service.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ClientAuthKey(pub [u8; 32]);
+impl ClientAuthKey {
+    /// Generates a key pair, returning the private half as well.
+    pub fn generate() -> (Self, [u8; 32]) {
+        let secret = rand::random::<[u8; 32]>();
+        (ClientAuthKey(x25519_public(&secret)), secret)
+    }
+}
+impl fmt::Debug for ClientAuthKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ClientAuthKey({})", self)
+    }
+}
+impl fmt::Display for ClientAuthKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", base32::encode(B32, &self.0))
+    }
+}
+impl FromStr for ClientAuthKey {
+    type Err = Error;
+    /// Accepts the bare base32 key, or a line of an `authorized_clients` file.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let s = s.strip_prefix("descriptor:x25519:").unwrap_or(s);
+        let bytes = base32::decode(B32, &s.to_ascii_uppercase())
+            .filter(|b| b.len() == 32)
+            .ok_or_else(|| {
+                Error::new(
+                    eyre!("{} is not a base32 encoded x25519 public key", s),
+                    ErrorKind::ParseNetAddress,
+                )
+            })?;
+        let mut key = [0; 32];
+        key.copy_from_slice(&bytes);
+        Ok(ClientAuthKey(key))
+    }
+}
+impl Serialize for ClientAuthKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+impl<'de> Deserialize<'de> for ClientAuthKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+pub(super) fn x25519_public(secret: &[u8; 32]) -> [u8; 32] {
+    let mut clamped = *secret;
+    clamped[0] &= 248;
+    clamped[31] &= 127;
+    clamped[31] |= 64;
+    (X25519_BASEPOINT * Scalar::from_bits(clamped)).to_bytes()
+}
+
+/// The line tor expects in a `<name>.auth_private` file of its `ClientOnionAuthDir`.
+fn auth_private(onion: &OnionAddressV3, secret: &[u8; 32]) -> String {
+    format!(
+        "{}:descriptor:x25519:{}",
+        onion.get_address_without_dot_onion(),
+        base32::encode(B32, secret)
+    )
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AuthorizedClient {
+    pub name: String,
+    pub public_key: ClientAuthKey,
+    pub created_at: DateTime<Utc>,
+}
+
+/// The clients authorized for each restricted interface of `package`. An interface stays
+/// restricted after its last client is revoked, and is then not published at all.
+#[instrument(skip(secrets))]
+pub async fn authorized_clients<Ex>(
+    secrets: &mut Ex,
+    package: &PackageId,
+) -> Result<BTreeMap<InterfaceId, Vec<ClientAuthKey>>, Error>
+where
+    for<'a> &'a mut Ex: Executor<'a, Database = Sqlite>,
+{
+    use sqlx::Row;
+
+    let mut res = BTreeMap::<InterfaceId, Vec<ClientAuthKey>>::new();
+    for row in sqlx::query(
+        "SELECT r.interface, c.public_key FROM tor_client_auth_restricted r LEFT JOIN tor_client_auth c ON c.package = r.package AND c.interface = r.interface WHERE r.package = ?",
+    )
+    .bind(package.as_str())
+    .fetch_all(secrets)
+    .await?
+    {
+        let interface: String = row.try_get("interface")?;
+        let clients = res.entry(interface.parse()?).or_default();
+        let key = match row.try_get::<Option<Vec<u8>>, _>("public_key")? {
+            Some(a) => a,
+            None => continue,
+        };
+        let mut buf = [0; 32];
+        buf.clone_from_slice(key.get(0..32).ok_or_else(|| {
+            Error::new(
+                eyre!("Invalid Client Auth Key Length"),
+                crate::ErrorKind::Database,
+            )
+        })?);
+        clients.push(ClientAuthKey(buf));
+    }
+    Ok(res)
+}
+
+/// Onion address of `package`'s `interface`, failing unless it is installed and served over tor.
+async fn onion_address(
+    ctx: &RpcContext,
+    package: &PackageId,
+    interface: &InterfaceId,
+) -> Result<OnionAddressV3, Error> {
+    crate::db::DatabaseModel::new()
+        .package_data()
+        .idx_model(package)
+        .and_then(|pde| pde.installed())
+        .and_then(|i| i.interface_addresses().idx_model(interface))
+        .get(&mut ctx.db.handle(), false)
+        .await?
+        .as_ref()
+        .and_then(|addrs| addrs.tor_address.as_ref())
+        .ok_or_else(|| {
+            Error::new(
+                eyre!("{} has no onion interface {}", package, interface),
+                ErrorKind::NotFound,
+            )
+        })?
+        .trim_end_matches(".onion")
+        .parse()
+        .with_kind(ErrorKind::Tor)
+}
+
+/// Republishes the onion service of `interface` with its current set of authorized clients.
+async fn update_authorized_clients(
+    ctx: &RpcContext,
+    package: &PackageId,
+    interface: &InterfaceId,
+) -> Result<(), Error> {
+    let clients = authorized_clients(&mut ctx.secret_store.acquire().await?, package)
+        .await?
+        .remove(interface);
+    ctx.net_controller
+        .tor
+        .set_authorized_clients(package, interface, clients)
+        .await
+}
+
+/// Restricted discovery: once a client of an interface is authorized, only authorized clients can
+/// resolve its onion address until the restriction is disabled. Requires tor 0.4.6 or later.
+#[command(subcommands(add_client, list_clients, revoke_client, disable_client_auth))]
+pub fn auth() -> Result<(), Error> {
+    Ok(())
+}
+
+fn display_auth_private(auth_private: Option<String>, matches: &ArgMatches<'_>) {
+    if matches.is_present("format") {
+        return display_serializable(auth_private, matches);
+    }
+    if let Some(auth_private) = auth_private {
+        println!("{}", auth_private);
+    }
+}
+
+/// Authorizes a client of `interface`. Without a public key a key pair is generated, and its
+/// private half is returned once, formatted as the contents of an `.auth_private` file.
+#[command(rename = "add", display(display_auth_private))]
+#[instrument(skip(ctx))]
+pub async fn add_client(
+    #[context] ctx: RpcContext,
+    #[arg] package: PackageId,
+    #[arg] interface: InterfaceId,
+    #[arg] name: String,
+    #[arg(rename = "public-key", long = "public-key")] public_key: Option<ClientAuthKey>,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Option<String>, Error> {
+    let onion = onion_address(&ctx, &package, &interface).await?;
+    let (public_key, auth_private) = match public_key {
+        Some(a) => (a, None),
+        None => {
+            let (public_key, secret) = ClientAuthKey::generate();
+            (public_key, Some(auth_private(&onion, &secret)))
+        }
+    };
+    let mut secrets = ctx.secret_store.acquire().await?;
+    let exists = sqlx::query(
+        "SELECT 1 FROM tor_client_auth WHERE package = ? AND interface = ? AND name = ?",
+    )
+    .bind(package.as_str())
+    .bind(interface.as_str())
+    .bind(&name)
+    .fetch_optional(&mut secrets)
+    .await?
+    .is_some();
+    if exists {
+        return Err(Error::new(
+            eyre!(
+                "{} is already authorized for {} interface {}",
+                name,
+                package,
+                interface
+            ),
+            ErrorKind::Duplicate,
+        ));
+    }
+    sqlx::query(
+        "INSERT INTO tor_client_auth (package, interface, name, public_key) VALUES (?, ?, ?, ?)",
+    )
+    .bind(package.as_str())
+    .bind(interface.as_str())
+    .bind(&name)
+    .bind(&public_key.0[..])
+    .execute(&mut secrets)
+    .await?;
+    sqlx::query(
+        "INSERT OR IGNORE INTO tor_client_auth_restricted (package, interface) VALUES (?, ?)",
+    )
+    .bind(package.as_str())
+    .bind(interface.as_str())
+    .execute(&mut secrets)
+    .await?;
+    drop(secrets);
+    update_authorized_clients(&ctx, &package, &interface).await?;
+    Ok(auth_private)
+}
+
+fn display_clients(clients: Vec<AuthorizedClient>, matches: &ArgMatches<'_>) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(clients, matches);
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![bc => "NAME", "PUBLIC KEY", "CREATED"]);
+    for client in clients {
+        table.add_row(row![
+            &client.name,
+            &client.public_key.to_string(),
+            &client.created_at.to_rfc3339()
+        ]);
+    }
+    table.print_tty(false);
+}
+
+#[command(rename = "list", display(display_clients))]
+#[instrument(skip(ctx))]
+pub async fn list_clients(
+    #[context] ctx: RpcContext,
+    #[arg] package: PackageId,
+    #[arg] interface: InterfaceId,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<AuthorizedClient>, Error> {
+    use sqlx::Row;
+
+    onion_address(&ctx, &package, &interface).await?;
+    let mut res = Vec::new();
+    for row in sqlx::query(
+        "SELECT name, public_key, created_at FROM tor_client_auth WHERE package = ? AND interface = ? ORDER BY created_at",
+    )
+    .bind(package.as_str())
+    .bind(interface.as_str())
+    .fetch_all(&mut ctx.secret_store.acquire().await?)
+    .await?
+    {
+        let key: Vec<u8> = row.try_get("public_key")?;
+        let mut public_key = [0; 32];
+        public_key.clone_from_slice(key.get(0..32).ok_or_else(|| {
+            Error::new(
+                eyre!("Invalid Client Auth Key Length"),
+                crate::ErrorKind::Database,
+            )
+        })?);
+        res.push(AuthorizedClient {
+            name: row.try_get("name")?,
+            public_key: ClientAuthKey(public_key),
+            created_at: DateTime::from_utc(row.try_get("created_at")?, Utc),
+        });
+    }
+    Ok(res)
+}
+
+#[command(rename = "revoke", display(display_none))]
+#[instrument(skip(ctx))]
+pub async fn revoke_client(
+    #[context] ctx: RpcContext,
+    #[arg] package: PackageId,
+    #[arg] interface: InterfaceId,
+    #[arg] name: String,
+) -> Result<(), Error> {
+    let removed =
+        sqlx::query("DELETE FROM tor_client_auth WHERE package = ? AND interface = ? AND name = ?")
+            .bind(package.as_str())
+            .bind(interface.as_str())
+            .bind(&name)
+            .execute(&mut ctx.secret_store.acquire().await?)
+            .await?
+            .rows_affected();
+    if removed == 0 {
+        return Err(Error::new(
+            eyre!(
+                "{} is not authorized for {} interface {}",
+                name,
+                package,
+                interface
+            ),
+            ErrorKind::NotFound,
+        ));
+    }
+    update_authorized_clients(&ctx, &package, &interface).await
+}
+
+/// Revokes every client of `interface` and publishes it to everyone again.
+#[command(rename = "disable", display(display_none))]
+#[instrument(skip(ctx))]
+pub async fn disable_client_auth(
+    #[context] ctx: RpcContext,
+    #[arg] package: PackageId,
+    #[arg] interface: InterfaceId,
+) -> Result<(), Error> {
+    let mut secrets = ctx.secret_store.begin().await?;
+    sqlx::query("DELETE FROM tor_client_auth WHERE package = ? AND interface = ?")
+        .bind(package.as_str())
+        .bind(interface.as_str())
+        .execute(&mut secrets)
+        .await?;
+    sqlx::query("DELETE FROM tor_client_auth_restricted WHERE package = ? AND interface = ?")
+        .bind(package.as_str())
+        .bind(interface.as_str())
+        .execute(&mut secrets)
+        .await?;
+    secrets.commit().await?;
+    update_authorized_clients(&ctx, &package, &interface).await
+}
+
+/// Parses a tor secret key, given as base64 of the 64 byte expanded key, of an
+/// `hs_ed25519_secret_key` file as written by tor and vanity address generators, or in the
+/// `ED25519-V3:` form of the control port.
+pub fn parse_secret_key(key: &str) -> Result<TorSecretKeyV3, Error> {
+    const HS_SECRET_KEY_HEADER: &[u8] = b"== ed25519v1-secret: type0 ==\0\0\0";
+    let key = key.trim();
+    let bytes = base64::decode(key.strip_prefix("ED25519-V3:").unwrap_or(key))
+        .with_kind(ErrorKind::Deserialization)?;
+    let bytes = bytes.strip_prefix(HS_SECRET_KEY_HEADER).unwrap_or(&bytes);
+    // an expanded ed25519 key starts with a clamped scalar
+    if bytes.len() != 64 || bytes[0] & 7 != 0 || bytes[31] & 128 != 0 || bytes[31] & 64 == 0 {
+        return Err(Error::new(
+            eyre!("not an expanded ed25519 secret key"),
+            ErrorKind::InvalidRequest,
+        ));
+    }
+    let mut buf = [0; 64];
+    buf.copy_from_slice(bytes);
+    Ok(buf.into())
+}
+
+fn display_rotate(res: WithRevision<String>, _: &ArgMatches<'_>) {
+    println!("{}", res.response);
+}
+
+/// Replaces the onion key of `package`'s `interface` with a new one, or with `key` to import an
+/// existing address. The lan address changes along with it, and the package is restarted. With
+/// `grace`, the old address keeps serving the interface for that many seconds, or until embassyd
+/// restarts.
+#[command(display(display_rotate))]
+#[instrument(skip(ctx, key))]
+pub async fn rotate(
+    #[context] ctx: RpcContext,
+    #[arg] package: PackageId,
+    #[arg] interface: InterfaceId,
+    #[arg(long = "key")] key: Option<String>,
+    #[arg(long = "grace")] grace: Option<u64>,
+) -> Result<WithRevision<String>, Error> {
+    let new_key = match key {
+        Some(key) => parse_secret_key(&key)?,
+        None => TorSecretKeyV3::generate(),
+    };
+    let onion = new_key.public().get_onion_address();
+    let mut db = ctx.db.handle();
+    let mut tx = db.begin().await?;
+    let mut sql_tx = ctx.secret_store.begin().await?;
+    let installed_model = || {
+        crate::db::DatabaseModel::new()
+            .package_data()
+            .idx_model(&package)
+            .and_then(|pde| pde.installed())
+    };
+    let manifest = installed_model()
+        .expect(&mut tx)
+        .await?
+        .manifest()
+        .get(&mut tx, true)
+        .await?
+        .into_owned();
+    let iface = manifest.interfaces.0.get(&interface).ok_or_else(|| {
+        Error::new(
+            eyre!("{} has no interface {}", package, interface),
+            ErrorKind::NotFound,
+        )
+    })?;
+    let old_key = manifest
+        .interfaces
+        .tor_keys(&mut sql_tx, &package)
+        .await?
+        .remove(&interface)
+        .ok_or_else(|| {
+            Error::new(
+                eyre!("{} interface {} has no onion address", package, interface),
+                ErrorKind::NotFound,
+            )
+        })?;
+    let key_vec = new_key.as_bytes().to_vec();
+    if sqlx::query("SELECT 1 FROM tor WHERE key = ?")
+        .bind(&key_vec)
+        .fetch_optional(&mut sql_tx)
+        .await?
+        .is_some()
+    {
+        return Err(Error::new(
+            eyre!("{} is already in use", onion),
+            ErrorKind::Duplicate,
+        ));
+    }
+    sqlx::query("UPDATE tor SET key = ? WHERE package = ? AND interface = ?")
+        .bind(&key_vec)
+        .bind(package.as_str())
+        .bind(interface.as_str())
+        .execute(&mut sql_tx)
+        .await?;
+
+    let mut addrs = installed_model()
+        .and_then(|i| i.interface_addresses().idx_model(&interface))
+        .expect(&mut tx)
+        .await?
+        .get_mut(&mut tx)
+        .await?;
+    if iface.tor_config.is_some() {
+        addrs.tor_address = Some(onion.to_string());
+    }
+    if iface.lan_config.is_some() {
+        addrs.lan_address = Some(format!("{}.local", onion.get_address_without_dot_onion()));
+    }
+    addrs.save(&mut tx).await?;
+    let installed = installed_model()
+        .expect(&mut tx)
+        .await?
+        .get(&mut tx, true)
+        .await?;
+    reconfigure_dependents_with_live_pointers(&ctx, &mut tx, &mut sql_tx, &installed).await?;
+    let revision = tx.commit(None).await?;
+    sql_tx.commit().await?;
+
+    if let Some(grace) = grace.filter(|grace| *grace > 0) {
+        ctx.net_controller
+            .tor
+            .retire(&package, &interface, old_key.clone())
+            .await;
+        let ctx = ctx.clone();
+        let (package, interface) = (package.clone(), interface.clone());
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(grace)).await;
+            if let Err(e) = ctx
+                .net_controller
+                .tor
+                .expire(&package, &interface, &old_key)
+                .await
+            {
+                tracing::error!("Failed to remove retired onion address: {}", e);
+                tracing::debug!("{:?}", e);
+            }
+        });
+    }
+
+    // restart the package so its services pick up the new key
+    let tor_keys = manifest
+        .interfaces
+        .tor_keys(&mut ctx.secret_store.acquire().await?, &package)
+        .await?;
+    ctx.managers.add(ctx.clone(), manifest, tor_keys).await?;
+
+    Ok(WithRevision {
+        response: onion.to_string(),
+        revision,
+    })
+}
+
+/// Whether a service is restricted to no clients at all, and therefore not published.
+fn is_withheld(clients: &Option<Vec<ClientAuthKey>>) -> bool {
+    clients.as_ref().map_or(false, |c| c.is_empty())
+}
+
+/// Adds an onion service that only `clients` can discover. Torut cannot pass client keys to
+/// ADD_ONION, so this speaks the control protocol over a connection of its own, and detaches
+/// the service so it outlives that connection.
+#[instrument(skip(key, clients))]
+async fn add_restricted_onion(
+    control_addr: SocketAddr,
+    key: &TorSecretKeyV3,
+    ports: &[(u16, SocketAddr)],
+    clients: &[ClientAuthKey],
+) -> Result<(), Error> {
+    let (mut lines, mut write) = raw_control_connection(control_addr).await?;
+    let mut add_onion = format!(
+        "ADD_ONION ED25519-V3:{} Flags=Detach,V3Auth",
+        base64::encode(&key.as_bytes()[..])
+    );
+    for (external, internal) in ports {
+        add_onion += &format!(" Port={},{}", external, internal);
+    }
+    for client in clients {
+        add_onion += &format!(" ClientAuthV3={}", client);
+    }
+    control_command(&mut lines, &mut write, &add_onion).await?;
+    Ok(())
+}
+
+/// Opens a control connection of our own, for the commands torut has no support for.
+async fn raw_control_connection(
+    control_addr: SocketAddr,
+) -> Result<(Lines<BufReader<OwnedReadHalf>>, OwnedWriteHalf), Error> {
+    let (read, mut write) = TcpStream::connect(control_addr).await?.into_split();
+    let mut lines = BufReader::new(read).lines();
+    let info = control_command(&mut lines, &mut write, "PROTOCOLINFO 1").await?;
+    let cookie_file = info
+        .iter()
+        .filter_map(|l| l.split("COOKIEFILE=\"").nth(1))
+        .filter_map(|l| l.split('"').next())
+        .next()
+        .ok_or_else(|| Error::new(eyre!("Cookie Auth Not Available"), ErrorKind::Tor))?
+        .to_owned();
+    let cookie = tokio::fs::read(&cookie_file)
+        .await
+        .with_ctx(|_| (ErrorKind::Tor, cookie_file.clone()))?;
+    control_command(
+        &mut lines,
+        &mut write,
+        &format!("AUTHENTICATE {}", hex::encode(cookie)),
+    )
+    .await?;
+    Ok((lines, write))
+}
+
+/// Sends `cmd` and reads its reply, failing unless it succeeded.
+async fn control_command(
+    lines: &mut Lines<BufReader<OwnedReadHalf>>,
+    write: &mut OwnedWriteHalf,
+    cmd: &str,
+) -> Result<Vec<String>, Error> {
+    write.write_all(cmd.as_bytes()).await?;
+    write.write_all(b"\r\n").await?;
+    let mut reply = Vec::new();
+    loop {
+        let line = lines
+            .next_line()
+            .await?
+            .ok_or_else(|| Error::new(eyre!("Tor Control Connection Closed"), ErrorKind::Tor))?;
+        // the last line of a reply has a space after its status code
+        let end = line.get(3..4) == Some(" ");
+        reply.push(line);
+        if end {
+            break;
+        }
+    }
+    match reply.last() {
+        Some(status) if status.starts_with("250") => Ok(reply),
+        status => Err(Error::new(
+            eyre!(
+                "Tor Control Error: {}",
+                status.map(|s| s.as_str()).unwrap_or("")
+            ),
+            ErrorKind::Tor,
+        )),
+    }
+}
+
+/// Pluggable transports we can configure bridges for, and the binaries providing them.
+const TRANSPORT_PLUGINS: &[(&str, &str)] = &[
+    ("obfs4", "/usr/bin/obfs4proxy"),
+    ("meek_lite", "/usr/bin/obfs4proxy"),
+    ("snowflake", "/usr/bin/snowflake-client"),
+];
+
+/// How tor connects to the network, for networks where it cannot bootstrap directly.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TorSettings {
+    /// Bridge lines as handed out by bridges.torproject.org, e.g.
+    /// `obfs4 <ip>:<port> <fingerprint> cert=<cert> iat-mode=0`. Tor only uses bridges if any are
+    /// set.
+    pub bridges: Vec<String>,
+    /// Relays allowed as the first hop, by fingerprint or `{country code}`.
+    pub entry_nodes: Vec<String>,
+    /// `<host>:<port>` of a SOCKS5 proxy tor makes all of its connections through.
+    pub socks_proxy: Option<String>,
+}
+impl TorSettings {
+    fn validate(&self) -> Result<(), Error> {
+        let invalid = |what: &str, value: &str| {
+            Err(Error::new(
+                eyre!("invalid {}: {}", what, value),
+                ErrorKind::InvalidRequest,
+            ))
+        };
+        for bridge in &self.bridges {
+            let first = bridge.split_whitespace().next().unwrap_or("");
+            if bridge
+                .chars()
+                .any(|c| c == '"' || c == '\\' || c.is_control())
+                || !(first.contains(':') || TRANSPORT_PLUGINS.iter().any(|(t, _)| *t == first))
+            {
+                return invalid("bridge", bridge);
+            }
+        }
+        for node in &self.entry_nodes {
+            if node.is_empty()
+                || !node
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '$' || c == '{' || c == '}')
+            {
+                return invalid("entry node", node);
+            }
+        }
+        if let Some(proxy) = &self.socks_proxy {
+            match proxy.rsplit_once(':') {
+                Some((host, port))
+                    if !host.is_empty()
+                        && !host.chars().any(|c| c.is_whitespace() || c == '"')
+                        && port.parse::<u16>().is_ok() => {}
+                _ => return invalid("socks proxy", proxy),
+            }
+        }
+        Ok(())
+    }
+
+    /// The SETCONF command applying these settings, resetting whatever is unset to tor's default.
+    fn setconf_command(&self) -> String {
+        let mut cmd = String::from("SETCONF");
+        if self.bridges.is_empty() {
+            cmd += " UseBridges=0 Bridge ClientTransportPlugin";
+        } else {
+            cmd += " UseBridges=1";
+            for bridge in &self.bridges {
+                cmd += &format!(" Bridge=\"{}\"", bridge.trim());
+            }
+            let mut plugins = BTreeMap::<&str, Vec<&str>>::new();
+            for (transport, binary) in TRANSPORT_PLUGINS {
+                if self
+                    .bridges
+                    .iter()
+                    .any(|b| b.split_whitespace().next() == Some(transport))
+                {
+                    plugins.entry(binary).or_default().push(transport);
+                }
+            }
+            if plugins.is_empty() {
+                cmd += " ClientTransportPlugin";
+            }
+            for (binary, transports) in plugins {
+                cmd += &format!(
+                    " ClientTransportPlugin=\"{} exec {}\"",
+                    transports.join(","),
+                    binary
+                );
+            }
+        }
+        if self.entry_nodes.is_empty() {
+            cmd += " EntryNodes";
+        } else {
+            cmd += &format!(" EntryNodes=\"{}\"", self.entry_nodes.join(","));
+        }
+        match &self.socks_proxy {
+            Some(proxy) => cmd += &format!(" Socks5Proxy={}", proxy),
+            None => cmd += " Socks5Proxy",
+        }
+        cmd
+    }
+}
+
+fn parse_bridges(arg: &str, _: &ArgMatches<'_>) -> Result<Vec<String>, Error> {
+    Ok(arg
+        .split(';')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_owned())
+        .collect())
+}
+
+fn parse_entry_nodes(arg: &str, _: &ArgMatches<'_>) -> Result<Vec<String>, Error> {
+    Ok(arg
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_owned())
+        .collect())
+}
+
+/// Configures how tor connects to the network. Unset arguments are left as they are, and empty
+/// ones clear the setting.
+#[command(display(display_none))]
+#[instrument(skip(ctx))]
+pub async fn config(
+    #[context] ctx: RpcContext,
+    /// Bridge lines, separated by `;`.
+    #[arg(long = "bridges", parse(parse_bridges))]
+    bridges: Option<Vec<String>>,
+    #[arg(rename = "entry-nodes", long = "entry-nodes", parse(parse_entry_nodes))]
+    entry_nodes: Option<Vec<String>>,
+    #[arg(rename = "socks-proxy", long = "socks-proxy")] socks_proxy: Option<String>,
+) -> Result<WithRevision<()>, Error> {
+    let mut db = ctx.db.handle();
+    let mut tx = db.begin().await?;
+    let mut settings = crate::db::DatabaseModel::new()
+        .server_info()
+        .tor()
+        .get_mut(&mut tx)
+        .await?;
+    if let Some(bridges) = bridges {
+        settings.bridges = bridges;
+    }
+    if let Some(entry_nodes) = entry_nodes {
+        settings.entry_nodes = entry_nodes;
+    }
+    if let Some(socks_proxy) = socks_proxy {
+        settings.socks_proxy = Some(socks_proxy).filter(|p| !p.is_empty());
+    }
+    settings.validate()?;
+    // tor checks the settings as a whole before we persist them
+    ctx.net_controller.tor.configure(settings.clone()).await?;
+    settings.save(&mut tx).await?;
+    Ok(WithRevision {
+        response: (),
+        revision: tx.commit(None).await?,
+    })
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TorBootstrap {
+    /// Percentage of the bootstrap process completed.
+    pub progress: u8,
+    pub summary: String,
+}
+
+/// Parses the reply to `GETINFO status/bootstrap-phase`.
+fn parse_bootstrap_phase(phase: &str) -> TorBootstrap {
+    TorBootstrap {
+        progress: phase
+            .split_whitespace()
+            .find_map(|f| f.strip_prefix("PROGRESS="))
+            .and_then(|p| p.parse().ok())
+            .unwrap_or(0),
+        summary: phase
+            .split("SUMMARY=\"")
+            .nth(1)
+            .and_then(|s| s.split('"').next())
+            .unwrap_or("")
+            .to_owned(),
+    }
+}
+
+async fn record_bootstrap(ctx: &RpcContext, bootstrap: &TorBootstrap) -> Result<(), Error> {
+    let mut db = ctx.db.handle();
+    let mut current = crate::db::DatabaseModel::new()
+        .server_info()
+        .tor_bootstrap()
+        .get_mut(&mut db)
+        .await?;
+    if &*current != bootstrap {
+        *current = bootstrap.clone();
+        current.save(&mut db).await?;
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct OnionServiceStatus {
+    /// Unset for the onion service of embassyd itself.
+    pub package: Option<PackageId>,
+    pub interface: Option<InterfaceId>,
+    pub address: String,
+    pub descriptor_published: bool,
+    pub circuits: usize,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TorStatus {
+    pub bootstrap: TorBootstrap,
+    /// Totals of the tor daemon since it started. Tor does not account traffic per service.
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub circuits: usize,
+    pub services: Vec<OnionServiceStatus>,
+}
+
+/// Counts the built circuits in the reply to `GETINFO circuit-status`, in total and by the onion
+/// service they were built for.
+fn count_circuits(circuit_status: &str) -> (usize, BTreeMap<String, usize>) {
+    let mut total = 0;
+    let mut by_service = BTreeMap::<String, usize>::new();
+    for line in circuit_status.lines() {
+        if line.split_whitespace().nth(1) != Some("BUILT") {
+            continue;
+        }
+        total += 1;
+        if let Some(address) = line
+            .split_whitespace()
+            .find_map(|field| field.strip_prefix("REND_QUERY="))
+        {
+            *by_service.entry(address.to_owned()).or_default() += 1;
+        }
+    }
+    (total, by_service)
+}
+
+fn display_status(status: TorStatus, matches: &ArgMatches<'_>) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(status, matches);
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![bc => "PACKAGE", "INTERFACE", "ADDRESS", "DESCRIPTOR", "CIRCUITS"]);
+    for service in &status.services {
+        table.add_row(row![
+            &service
+                .package
+                .as_ref()
+                .map(|p| p.to_string())
+                .unwrap_or_else(|| "embassy".to_owned()),
+            &service
+                .interface
+                .as_ref()
+                .map(|i| i.to_string())
+                .unwrap_or_default(),
+            &service.address,
+            if service.descriptor_published {
+                "published"
+            } else {
+                "not published"
+            },
+            &service.circuits.to_string()
+        ]);
+    }
+    table.print_tty(false);
+    println!(
+        "Bootstrapped {}%: {}",
+        status.bootstrap.progress, status.bootstrap.summary
+    );
+    println!(
+        "{} circuits, {:.2} MiB read, {:.2} MiB written",
+        status.circuits,
+        status.bytes_read as f64 / 1024_f64.powi(2),
+        status.bytes_written as f64 / 1024_f64.powi(2)
+    );
+}
+
+#[command(display(display_status))]
+#[instrument(skip(ctx))]
+pub async fn status(
+    #[context] ctx: RpcContext,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<TorStatus, Error> {
+    ctx.net_controller.tor.status().await
+}
+
+/// Records tor statistics in the metrics, and notifies the owners of onion services that tor
+/// has not been able to publish.
+pub async fn tor_status_check(ctx: &RpcContext) {
+    let status = match ctx.net_controller.tor.status().await {
+        Ok(a) => a,
+        Err(e) => {
+            tracing::error!("Could not get tor status: {}", e);
+            tracing::debug!("{:?}", e);
+            return;
+        }
+    };
+    crate::system::record_tor_metrics(&ctx.metrics_cache, &status).await;
+    if let Err(e) = record_bootstrap(ctx, &status.bootstrap).await {
+        tracing::error!("Could not record tor bootstrap progress: {}", e);
+        tracing::debug!("{:?}", e);
+    }
+    for (package, interface, address) in ctx
+        .net_controller
+        .tor
+        .publish_failures(DESCRIPTOR_PUBLISH_TIMEOUT)
+        .await
+    {
+        let message = format!(
+            "Tor has not published the onion address {} of interface {} for over {} minutes. It may be unreachable over tor.",
+            address,
+            interface,
+            DESCRIPTOR_PUBLISH_TIMEOUT.as_secs() / 60
+        );
+        tracing::warn!("{}: {}", package, message);
+        if let Err(e) = ctx
+            .notification_manager
+            .notify(
+                &mut ctx.db.handle(),
+                Some(package),
+                NotificationLevel::Warning,
+                String::from("Onion Service Not Published"),
+                message,
+                (),
+                None,
+            )
+            .await
+        {
+            tracing::error!("Failed to send notification: {}", e);
+            tracing::debug!("{:?}", e);
+        }
+    }
+}
+
 #[instrument(skip(secrets))]
 pub async fn os_key<Ex>(secrets: &mut Ex) -> Result<TorSecretKeyV3, Error>
 where
@@ -92,7 +1041,16 @@ impl TorController {
         )))
     }
 
-    pub async fn add<I: IntoIterator<Item = (InterfaceId, TorConfig, TorSecretKeyV3)> + Clone>(
+    pub async fn add<
+        I: IntoIterator<
+                Item = (
+                    InterfaceId,
+                    TorConfig,
+                    TorSecretKeyV3,
+                    Option<Vec<ClientAuthKey>>,
+                ),
+            > + Clone,
+    >(
         &self,
         pkg_id: &PackageId,
         ip: Ipv4Addr,
@@ -109,6 +1067,53 @@ impl TorController {
         self.0.lock().await.remove(pkg_id, interfaces).await
     }
 
+    /// Republishes a running onion service, restricted to `clients` if set. A restricted service
+    /// without clients is not published.
+    pub async fn set_authorized_clients(
+        &self,
+        pkg_id: &PackageId,
+        interface: &InterfaceId,
+        clients: Option<Vec<ClientAuthKey>>,
+    ) -> Result<(), Error> {
+        self.0
+            .lock()
+            .await
+            .set_authorized_clients(pkg_id, interface, clients)
+            .await
+    }
+
+    pub async fn retire(&self, pkg_id: &PackageId, interface: &InterfaceId, key: TorSecretKeyV3) {
+        self.0.lock().await.retire(pkg_id, interface, key)
+    }
+
+    /// Stops serving an address retired with `retire`.
+    pub async fn expire(
+        &self,
+        pkg_id: &PackageId,
+        interface: &InterfaceId,
+        key: &TorSecretKeyV3,
+    ) -> Result<(), Error> {
+        self.0.lock().await.expire(pkg_id, interface, key).await
+    }
+
+    /// Applies `settings` to the running tor daemon, and again whenever it is restarted.
+    pub async fn configure(&self, settings: TorSettings) -> Result<(), Error> {
+        self.0.lock().await.configure(settings).await
+    }
+
+    pub async fn status(&self) -> Result<TorStatus, Error> {
+        self.0.lock().await.status().await
+    }
+
+    /// Package onion services that have gone without a descriptor for `timeout`. Each is
+    /// returned once, until it is published again.
+    pub async fn publish_failures(
+        &self,
+        timeout: Duration,
+    ) -> Vec<(PackageId, InterfaceId, OnionAddressV3)> {
+        self.0.lock().await.publish_failures(timeout)
+    }
+
     pub async fn replace(&self) -> Result<bool, Error> {
         self.0.lock().await.replace().await
     }
@@ -142,71 +1147,175 @@ pub struct TorControllerInner {
     embassyd_tor_key: TorSecretKeyV3,
     control_addr: SocketAddr,
     connection: Option<AuthenticatedConnection>,
-    services: BTreeMap<(PackageId, InterfaceId), (TorSecretKeyV3, TorConfig, Ipv4Addr)>,
+    services: BTreeMap<
+        (PackageId, InterfaceId),
+        (
+            TorSecretKeyV3,
+            TorConfig,
+            Ipv4Addr,
+            Option<Vec<ClientAuthKey>>,
+        ),
+    >,
+    /// Rotated out keys whose addresses still serve the interface until their grace period ends.
+    retired: BTreeMap<(PackageId, InterfaceId), Vec<TorSecretKeyV3>>,
+    /// When each package service was first seen without a descriptor, and whether its owner has
+    /// been notified.
+    unpublished: BTreeMap<(PackageId, InterfaceId), (Instant, bool)>,
+    settings: TorSettings,
 }
 impl TorControllerInner {
     #[instrument(skip(self, interfaces))]
-    async fn add<'a, I: IntoIterator<Item = (InterfaceId, TorConfig, TorSecretKeyV3)>>(
+    async fn add<
+        'a,
+        I: IntoIterator<
+            Item = (
+                InterfaceId,
+                TorConfig,
+                TorSecretKeyV3,
+                Option<Vec<ClientAuthKey>>,
+            ),
+        >,
+    >(
         &mut self,
         pkg_id: &PackageId,
         ip: Ipv4Addr,
         interfaces: I,
     ) -> Result<(), Error> {
-        for (interface_id, tor_cfg, key) in interfaces {
+        for (interface_id, tor_cfg, key, clients) in interfaces {
             let id = (pkg_id.clone(), interface_id);
             match self.services.get(&id) {
-                Some(k) if k.0 != key => {
+                Some(k) if k.0 != key || k.3 != clients => {
                     self.remove(pkg_id, std::iter::once(id.1.clone())).await?;
                 }
                 Some(_) => continue,
                 None => (),
             }
-            self.connection
-                .as_mut()
-                .ok_or_else(|| {
-                    Error::new(eyre!("Missing Tor Control Connection"), ErrorKind::Unknown)
-                })?
-                .add_onion_v3(
-                    &key,
-                    false,
-                    false,
-                    false,
-                    None,
-                    &mut tor_cfg
-                        .port_mapping
-                        .iter()
-                        .map(|(external, internal)| {
-                            (external.0, SocketAddr::from((ip, internal.0)))
-                        })
-                        .collect::<Vec<_>>()
-                        .iter(),
-                )
-                .await?;
-            self.services.insert(id, (key, tor_cfg, ip));
+            let ports = tor_cfg
+                .port_mapping
+                .iter()
+                .map(|(external, internal)| (external.0, SocketAddr::from((ip, internal.0))))
+                .collect::<Vec<_>>();
+            self.publish(&key, &ports, clients.as_deref()).await?;
+            for old_key in self.retired.get(&id).cloned().unwrap_or_default() {
+                if let Err(e) = self.publish(&old_key, &ports, clients.as_deref()).await {
+                    tracing::warn!(
+                        "Failed to publish retired address {}: {}",
+                        old_key.public().get_onion_address(),
+                        e
+                    );
+                }
+            }
+            self.services.insert(id, (key, tor_cfg, ip, clients));
         }
         Ok(())
     }
 
-    #[instrument(skip(self, interfaces))]
-    async fn remove<I: IntoIterator<Item = InterfaceId>>(
+    async fn publish(
         &mut self,
-        pkg_id: &PackageId,
-        interfaces: I,
+        key: &TorSecretKeyV3,
+        ports: &[(u16, SocketAddr)],
+        clients: Option<&[ClientAuthKey]>,
     ) -> Result<(), Error> {
-        for interface_id in interfaces {
-            if let Some((key, _cfg, _ip)) = self.services.remove(&(pkg_id.clone(), interface_id)) {
+        match clients {
+            None => {
                 self.connection
                     .as_mut()
                     .ok_or_else(|| {
-                        Error::new(eyre!("Missing Tor Control Connection"), ErrorKind::Tor)
+                        Error::new(eyre!("Missing Tor Control Connection"), ErrorKind::Unknown)
                     })?
-                    .del_onion(
-                        &key.public()
-                            .get_onion_address()
-                            .get_address_without_dot_onion(),
-                    )
+                    .add_onion_v3(key, false, false, false, None, &mut ports.iter())
                     .await?;
             }
+            // restricted to nobody: withheld until client auth is disabled
+            Some([]) => (),
+            Some(clients) => {
+                add_restricted_onion(self.control_addr, key, ports, clients).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn unpublish(&mut self, key: &TorSecretKeyV3) -> Result<(), Error> {
+        self.connection
+            .as_mut()
+            .ok_or_else(|| Error::new(eyre!("Missing Tor Control Connection"), ErrorKind::Tor))?
+            .del_onion(
+                &key.public()
+                    .get_onion_address()
+                    .get_address_without_dot_onion(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Keeps serving `interface` at the address of `key` after it has been rotated out. The
+    /// address is published alongside the new one when the interface is next added.
+    #[instrument(skip(self, key))]
+    fn retire(&mut self, pkg_id: &PackageId, interface: &InterfaceId, key: TorSecretKeyV3) {
+        self.retired
+            .entry((pkg_id.clone(), interface.clone()))
+            .or_default()
+            .push(key);
+    }
+
+    #[instrument(skip(self, key))]
+    async fn expire(
+        &mut self,
+        pkg_id: &PackageId,
+        interface: &InterfaceId,
+        key: &TorSecretKeyV3,
+    ) -> Result<(), Error> {
+        let id = (pkg_id.clone(), interface.clone());
+        if let Some(keys) = self.retired.get_mut(&id) {
+            keys.retain(|k| k != key);
+            if keys.is_empty() {
+                self.retired.remove(&id);
+            }
+        }
+        if matches!(self.services.get(&id), Some(s) if !is_withheld(&s.3)) {
+            self.unpublish(key).await?;
+        }
+        Ok(())
+    }
+
+    #[instrument(skip(self, clients))]
+    async fn set_authorized_clients(
+        &mut self,
+        pkg_id: &PackageId,
+        interface: &InterfaceId,
+        clients: Option<Vec<ClientAuthKey>>,
+    ) -> Result<(), Error> {
+        // not running: the clients are picked up from the secret store on the next start
+        let (key, tor_cfg, ip, _) = match self.services.get(&(pkg_id.clone(), interface.clone())) {
+            Some(a) => a.clone(),
+            None => return Ok(()),
+        };
+        self.add(
+            pkg_id,
+            ip,
+            std::iter::once((interface.clone(), tor_cfg, key, clients)),
+        )
+        .await
+    }
+
+    #[instrument(skip(self, interfaces))]
+    async fn remove<I: IntoIterator<Item = InterfaceId>>(
+        &mut self,
+        pkg_id: &PackageId,
+        interfaces: I,
+    ) -> Result<(), Error> {
+        for interface_id in interfaces {
+            let id = (pkg_id.clone(), interface_id);
+            if let Some((key, _cfg, _ip, clients)) = self.services.remove(&id) {
+                if !is_withheld(&clients) {
+                    self.unpublish(&key).await?;
+                }
+                for old_key in self.retired.get(&id).cloned().unwrap_or_default() {
+                    if let Err(e) = self.unpublish(&old_key).await {
+                        tracing::debug!("Retired address was not published: {}", e);
+                    }
+                }
+            }
         }
         Ok(())
     }
@@ -236,6 +1345,9 @@ impl TorControllerInner {
             control_addr: tor_control,
             connection: Some(connection),
             services: BTreeMap::new(),
+            retired: BTreeMap::new(),
+            unpublished: BTreeMap::new(),
+            settings: TorSettings::default(),
         };
         controller.add_embassyd_onion().await?;
         Ok(controller)
@@ -324,11 +1436,11 @@ impl TorControllerInner {
         let old_services = std::mem::replace(&mut self.services, BTreeMap::new());
 
         // re add all of the services on the new control socket
-        for ((package_id, interface_id), (tor_key, tor_cfg, ipv4)) in old_services {
+        for ((package_id, interface_id), (tor_key, tor_cfg, ipv4, clients)) in old_services {
             self.add(
                 &package_id,
                 ipv4,
-                std::iter::once((interface_id, tor_cfg, tor_key)),
+                std::iter::once((interface_id, tor_cfg, tor_key, clients)),
             )
             .await?;
         }
@@ -336,9 +1448,104 @@ impl TorControllerInner {
         // add embassyd hidden service again
         self.add_embassyd_onion().await?;
 
+        // the new tor daemon starts from torrc again
+        if self.settings != TorSettings::default() {
+            self.configure(self.settings.clone()).await?;
+        }
+
         Ok(true)
     }
 
+    #[instrument(skip(self))]
+    async fn configure(&mut self, settings: TorSettings) -> Result<(), Error> {
+        let (mut lines, mut write) = raw_control_connection(self.control_addr).await?;
+        control_command(&mut lines, &mut write, &settings.setconf_command()).await?;
+        self.settings = settings;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn status(&mut self) -> Result<TorStatus, Error> {
+        let services = std::iter::once((None, self.embassyd_onion(), false))
+            .chain(self.services.iter().map(|(id, (key, _, _, clients))| {
+                (
+                    Some(id.clone()),
+                    key.public().get_onion_address(),
+                    is_withheld(clients),
+                )
+            }))
+            .collect::<Vec<_>>();
+        let connection = self
+            .connection
+            .as_mut()
+            .ok_or_else(|| Error::new(eyre!("Missing Tor Control Connection"), ErrorKind::Tor))?;
+        let bootstrap =
+            parse_bootstrap_phase(&connection.get_info("status/bootstrap-phase").await?);
+        let bytes_read = connection.get_info("traffic/read").await?.parse::<u64>()?;
+        let bytes_written = connection
+            .get_info("traffic/written")
+            .await?
+            .parse::<u64>()?;
+        let (circuits, mut service_circuits) =
+            count_circuits(&connection.get_info("circuit-status").await?);
+        let mut res = Vec::with_capacity(services.len());
+        for (id, onion, withheld) in services {
+            let address = onion.get_address_without_dot_onion();
+            let descriptor_published = connection
+                .get_info(&format!("hs/service/desc/id/{}", address))
+                .await
+                .is_ok();
+            // withheld services are not expected to have a descriptor
+            if let Some(id) = id.as_ref().filter(|_| !withheld) {
+                if descriptor_published {
+                    self.unpublished.remove(id);
+                } else {
+                    self.unpublished
+                        .entry(id.clone())
+                        .or_insert_with(|| (Instant::now(), false));
+                }
+            }
+            let (package, interface) = match id {
+                Some((package, interface)) => (Some(package), Some(interface)),
+                None => (None, None),
+            };
+            res.push(OnionServiceStatus {
+                package,
+                interface,
+                address: onion.to_string(),
+                descriptor_published,
+                circuits: service_circuits.remove(&address).unwrap_or(0),
+            });
+        }
+        Ok(TorStatus {
+            bootstrap,
+            bytes_read,
+            bytes_written,
+            circuits,
+            services: res,
+        })
+    }
+
+    fn publish_failures(
+        &mut self,
+        timeout: Duration,
+    ) -> Vec<(PackageId, InterfaceId, OnionAddressV3)> {
+        let services = &self.services;
+        self.unpublished.retain(|id, _| services.contains_key(id));
+        let mut res = Vec::new();
+        for (id, (since, notified)) in self.unpublished.iter_mut() {
+            if !*notified && since.elapsed() >= timeout {
+                *notified = true;
+                res.push((
+                    id.0.clone(),
+                    id.1.clone(),
+                    services[id].0.public().get_onion_address(),
+                ));
+            }
+        }
+        res
+    }
+
     fn embassyd_onion(&self) -> OnionAddressV3 {
         self.embassyd_tor_key.public().get_onion_address()
     }
@@ -401,6 +1608,95 @@ pub async fn tor_health_check(client: &Client, tor_controller: &TorController) {
     }
 }
 
+#[test]
+fn test_client_auth_key() {
+    // RFC 7748, section 6.1
+    let secret =
+        hex::decode("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a").unwrap();
+    let mut buf = [0; 32];
+    buf.copy_from_slice(&secret);
+    let public = ClientAuthKey(x25519_public(&buf));
+    assert_eq!(
+        hex::encode(public.0),
+        "8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a"
+    );
+    assert_eq!(
+        format!("descriptor:x25519:{}", public)
+            .parse::<ClientAuthKey>()
+            .unwrap(),
+        public
+    );
+    assert!("not a key".parse::<ClientAuthKey>().is_err());
+}
+
+#[test]
+fn test_setconf_command() {
+    assert_eq!(
+        TorSettings::default().setconf_command(),
+        "SETCONF UseBridges=0 Bridge ClientTransportPlugin EntryNodes Socks5Proxy"
+    );
+    let settings = TorSettings {
+        bridges: vec![
+            "obfs4 192.0.2.1:443 0123456789ABCDEF0123456789ABCDEF01234567 cert=abc iat-mode=0"
+                .to_owned(),
+            "snowflake 192.0.2.3:80 2B280B23E1107BB62ABFC40DDCC8824814F80A72".to_owned(),
+        ],
+        entry_nodes: vec!["{de}".to_owned()],
+        socks_proxy: Some("192.168.1.1:1080".to_owned()),
+    };
+    settings.validate().unwrap();
+    assert_eq!(
+        settings.setconf_command(),
+        "SETCONF UseBridges=1 \
+        Bridge=\"obfs4 192.0.2.1:443 0123456789ABCDEF0123456789ABCDEF01234567 cert=abc iat-mode=0\" \
+        Bridge=\"snowflake 192.0.2.3:80 2B280B23E1107BB62ABFC40DDCC8824814F80A72\" \
+        ClientTransportPlugin=\"obfs4 exec /usr/bin/obfs4proxy\" \
+        ClientTransportPlugin=\"snowflake exec /usr/bin/snowflake-client\" \
+        EntryNodes=\"{de}\" Socks5Proxy=192.168.1.1:1080"
+    );
+    assert!(TorSettings {
+        bridges: vec!["webtunnel 192.0.2.4:443".to_owned()],
+        ..TorSettings::default()
+    }
+    .validate()
+    .is_err());
+}
+
+#[test]
+fn test_parse_bootstrap_phase() {
+    assert_eq!(
+        parse_bootstrap_phase(
+            "NOTICE BOOTSTRAP PROGRESS=75 TAG=enough_dirinfo SUMMARY=\"Loaded enough directory info to build circuits\""
+        ),
+        TorBootstrap {
+            progress: 75,
+            summary: "Loaded enough directory info to build circuits".to_owned(),
+        }
+    );
+}
+
+#[test]
+fn test_count_circuits() {
+    let (total, by_service) = count_circuits(
+        "1 BUILT $A~a,$B~b BUILD_FLAGS=NEED_CAPACITY PURPOSE=GENERAL TIME_CREATED=2021-11-04T00:00:00.000000
+2 BUILT $A~a,$C~c,$D~d PURPOSE=HS_SERVICE_INTRO HS_STATE=HSSI_ESTABLISHED REND_QUERY=abc TIME_CREATED=2021-11-04T00:00:00.000000
+3 EXTENDED $A~a PURPOSE=HS_SERVICE_REND HS_STATE=HSSR_CONNECTING REND_QUERY=abc
+4 BUILT $A~a,$C~c,$D~d PURPOSE=HS_SERVICE_REND HS_STATE=HSSR_JOINED REND_QUERY=abc",
+    );
+    assert_eq!(total, 3);
+    assert_eq!(by_service.get("abc"), Some(&2));
+}
+
+#[test]
+fn test_parse_secret_key() {
+    let key = TorSecretKeyV3::generate();
+    let mut file = b"== ed25519v1-secret: type0 ==\0\0\0".to_vec();
+    file.extend_from_slice(&key.as_bytes()[..]);
+    assert!(parse_secret_key(&base64::encode(&file)).unwrap() == key);
+    assert!(parse_secret_key(&base64::encode(&key.as_bytes()[..])).unwrap() == key);
+    assert!(parse_secret_key(&base64::encode(&[0xff; 64][..])).is_err());
+}
+
 #[tokio::test]
 async fn test() {
     let mut conn = torut::control::UnauthenticatedConn::new(
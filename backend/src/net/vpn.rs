@@ -0,0 +1,736 @@
+//! WireGuard remote access. Peers get an address in the vpn network, and reach the embassy and
+//! the package containers on the `start9` network through it. The lan addresses of package
+//! interfaces resolve for them through the dns server on the embassy's vpn address.
+//!
+//! Everything is set up with `ip` and `wg` in the network namespace embassyd runs in, once the
+//! first peer is added.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::process::Stdio;
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use clap::ArgMatches;
+use color_eyre::eyre::eyre;
+use ipnet::Ipv4Net;
+use rpc_toolkit::command;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sqlx::SqlitePool;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::Mutex;
+use tracing::instrument;
+
+use super::tor::x25519_public;
+use crate::context::RpcContext;
+use crate::util::serde::{display_serializable, IoFormat};
+use crate::util::{display_none, Invoke};
+use crate::{Error, ErrorKind};
+
+pub const VPN_INTERFACE: &str = "wg-embassy";
+pub const DEFAULT_VPN_PORT: u16 = 51820;
+/// The `start9` docker network the package containers are on.
+pub(super) const PACKAGE_NETWORK: &str = "172.18.0.0/16";
+const PERSISTENT_KEEPALIVE: u16 = 25;
+
+pub fn default_vpn_network() -> Ipv4Net {
+    Ipv4Net::new(Ipv4Addr::new(10, 59, 0, 0), 24).unwrap()
+}
+
+/// A WireGuard key, public or private.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct WgKey(pub [u8; 32]);
+impl WgKey {
+    /// Generates a private key.
+    pub fn generate() -> Self {
+        WgKey(rand::random())
+    }
+    pub fn public(&self) -> Self {
+        WgKey(x25519_public(&self.0))
+    }
+}
+impl fmt::Debug for WgKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "WgKey({})", self)
+    }
+}
+impl fmt::Display for WgKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", base64::encode(&self.0))
+    }
+}
+impl FromStr for WgKey {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = base64::decode(s.trim())
+            .ok()
+            .filter(|b| b.len() == 32)
+            .ok_or_else(|| {
+                Error::new(
+                    eyre!("{} is not a base64 encoded WireGuard key", s),
+                    ErrorKind::ParseNetAddress,
+                )
+            })?;
+        let mut key = [0; 32];
+        key.copy_from_slice(&bytes);
+        Ok(WgKey(key))
+    }
+}
+impl Serialize for WgKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+impl<'de> Deserialize<'de> for WgKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+fn key_from_row(key: Vec<u8>) -> Result<WgKey, Error> {
+    let mut buf = [0; 32];
+    buf.clone_from_slice(
+        key.get(0..32).ok_or_else(|| {
+            Error::new(eyre!("Invalid WireGuard Key Length"), ErrorKind::Database)
+        })?,
+    );
+    Ok(WgKey(buf))
+}
+
+/// The lowest address of `network` not taken by the server or a peer.
+fn next_peer_address(network: &Ipv4Net, used: &BTreeSet<Ipv4Addr>) -> Option<Ipv4Addr> {
+    network.hosts().skip(1).find(|addr| !used.contains(addr))
+}
+
+/// The `wg-quick` config of a peer. Without the peer's private key it is left for the user to
+/// fill in.
+fn client_config(
+    private_key: Option<&WgKey>,
+    address: Ipv4Addr,
+    network: &Ipv4Net,
+    server_key: &WgKey,
+    endpoint: &str,
+) -> String {
+    let server_address = network.hosts().next().unwrap_or_else(|| network.addr());
+    let private_key = match private_key {
+        Some(key) => format!("PrivateKey = {}", key),
+        None => "# PrivateKey = <private key of this peer>".to_owned(),
+    };
+    format!(
+        "[Interface]\n\
+        {}\n\
+        Address = {}/32\n\
+        DNS = {}\n\
+        \n\
+        [Peer]\n\
+        PublicKey = {}\n\
+        AllowedIPs = {}, {}\n\
+        Endpoint = {}\n\
+        PersistentKeepalive = {}\n",
+        private_key,
+        address,
+        server_address,
+        server_key.public(),
+        network.trunc(),
+        PACKAGE_NETWORK,
+        endpoint,
+        PERSISTENT_KEEPALIVE
+    )
+}
+
+#[command(subcommands(peer))]
+pub fn vpn() -> Result<(), Error> {
+    Ok(())
+}
+
+#[command(subcommands(add_peer, remove_peer, list_peers))]
+pub fn peer() -> Result<(), Error> {
+    Ok(())
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct VpnPeer {
+    pub name: String,
+    pub public_key: WgKey,
+    pub address: Ipv4Addr,
+    pub created_at: DateTime<Utc>,
+}
+
+fn display_client_config(config: String, matches: &ArgMatches<'_>) {
+    if matches.is_present("format") {
+        return display_serializable(config, matches);
+    }
+    print!("{}", config);
+}
+
+/// Adds a peer, returning its client config. Without a public key a key pair is generated, and
+/// the config is the only place its private half is kept: pipe it into `qrencode -t ansiutf8`
+/// to scan it with a phone. The endpoint defaults to the lan address of the embassy, so peers
+/// that connect from elsewhere need one that is forwarded to the vpn port.
+#[command(rename = "add", display(display_client_config))]
+#[instrument(skip(ctx))]
+pub async fn add_peer(
+    #[context] ctx: RpcContext,
+    #[arg] name: String,
+    #[arg(rename = "public-key", long = "public-key")] public_key: Option<WgKey>,
+    #[arg(long = "endpoint")] endpoint: Option<String>,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<String, Error> {
+    use sqlx::Row;
+
+    let (public_key, private_key) = match public_key {
+        Some(a) => (a, None),
+        None => {
+            let private_key = WgKey::generate();
+            (private_key.public(), Some(private_key))
+        }
+    };
+    let vpn = &ctx.net_controller.vpn;
+    let endpoint = match endpoint {
+        Some(a) => a,
+        None => crate::db::DatabaseModel::new()
+            .server_info()
+            .lan_address()
+            .get(&mut ctx.db.handle(), false)
+            .await?
+            .host_str()
+            .unwrap_or_default()
+            .to_owned(),
+    };
+    let endpoint = if endpoint
+        .rsplit_once(':')
+        .map_or(false, |(_, port)| port.parse::<u16>().is_ok())
+    {
+        endpoint
+    } else {
+        format!("{}:{}", endpoint, vpn.port().await)
+    };
+
+    let mut tx = ctx.secret_store.begin().await?;
+    let mut used = BTreeSet::new();
+    for row in sqlx::query("SELECT name, public_key, address FROM vpn_peers")
+        .fetch_all(&mut tx)
+        .await?
+    {
+        if row.try_get::<String, _>("name")? == name {
+            return Err(Error::new(
+                eyre!("vpn peer {} already exists", name),
+                ErrorKind::Duplicate,
+            ));
+        }
+        if key_from_row(row.try_get("public_key")?)? == public_key {
+            return Err(Error::new(
+                eyre!("{} is already the key of another vpn peer", public_key),
+                ErrorKind::Duplicate,
+            ));
+        }
+        used.insert(row.try_get::<String, _>("address")?.parse()?);
+    }
+    let network = vpn.network().await;
+    let address = next_peer_address(&network, &used).ok_or_else(|| {
+        Error::new(
+            eyre!("no addresses left in vpn network {}", network),
+            ErrorKind::Vpn,
+        )
+    })?;
+    sqlx::query("INSERT INTO vpn_peers (name, public_key, address) VALUES (?, ?, ?)")
+        .bind(&name)
+        .bind(&public_key.0[..])
+        .bind(address.to_string())
+        .execute(&mut tx)
+        .await?;
+    vpn.add_peer(&public_key, address).await?;
+    tx.commit().await?;
+    // the address only exists once the interface is up
+    ctx.net_controller.dns.listen(vpn.address().await).await;
+    Ok(client_config(
+        private_key.as_ref(),
+        address,
+        &network,
+        &vpn.server_key().await,
+        &endpoint,
+    ))
+}
+
+#[command(rename = "remove", display(display_none))]
+#[instrument(skip(ctx))]
+pub async fn remove_peer(#[context] ctx: RpcContext, #[arg] name: String) -> Result<(), Error> {
+    use sqlx::Row;
+
+    let mut tx = ctx.secret_store.begin().await?;
+    let public_key = key_from_row(
+        sqlx::query("SELECT public_key FROM vpn_peers WHERE name = ?")
+            .bind(&name)
+            .fetch_optional(&mut tx)
+            .await?
+            .ok_or_else(|| {
+                Error::new(
+                    eyre!("vpn peer {} does not exist", name),
+                    ErrorKind::NotFound,
+                )
+            })?
+            .try_get("public_key")?,
+    )?;
+    sqlx::query("DELETE FROM vpn_peers WHERE name = ?")
+        .bind(&name)
+        .execute(&mut tx)
+        .await?;
+    ctx.net_controller.vpn.remove_peer(&public_key).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+fn display_peers(peers: Vec<VpnPeer>, matches: &ArgMatches<'_>) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(peers, matches);
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![bc => "NAME", "ADDRESS", "PUBLIC KEY", "CREATED"]);
+    for peer in peers {
+        table.add_row(row![
+            &peer.name,
+            &peer.address.to_string(),
+            &peer.public_key.to_string(),
+            &peer.created_at.to_rfc3339()
+        ]);
+    }
+    table.print_tty(false);
+}
+
+async fn load_peers<Ex>(secrets: &mut Ex) -> Result<Vec<VpnPeer>, Error>
+where
+    for<'a> &'a mut Ex: sqlx::Executor<'a, Database = sqlx::Sqlite>,
+{
+    use sqlx::Row;
+
+    let mut res = Vec::new();
+    for row in sqlx::query(
+        "SELECT name, public_key, address, created_at FROM vpn_peers ORDER BY created_at",
+    )
+    .fetch_all(&mut *secrets)
+    .await?
+    {
+        res.push(VpnPeer {
+            name: row.try_get("name")?,
+            public_key: key_from_row(row.try_get("public_key")?)?,
+            address: row.try_get::<String, _>("address")?.parse()?,
+            created_at: DateTime::from_utc(row.try_get("created_at")?, Utc),
+        });
+    }
+    Ok(res)
+}
+
+#[command(rename = "list", display(display_peers))]
+#[instrument(skip(ctx))]
+pub async fn list_peers(
+    #[context] ctx: RpcContext,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<VpnPeer>, Error> {
+    load_peers(&mut ctx.secret_store.acquire().await?).await
+}
+
+/// The WireGuard interface only exists while there are peers, so an embassy that does not use the
+/// vpn does not listen on its port.
+pub struct VpnController(Mutex<VpnControllerInner>);
+impl VpnController {
+    pub async fn init(db: SqlitePool, network: Ipv4Net, port: u16) -> Result<Self, Error> {
+        Ok(VpnController(Mutex::new(
+            VpnControllerInner::init(db, network, port).await?,
+        )))
+    }
+    /// The embassy's own address on the vpn.
+    pub async fn address(&self) -> Ipv4Addr {
+        self.0.lock().await.address()
+    }
+    pub async fn network(&self) -> Ipv4Net {
+        self.0.lock().await.network
+    }
+    pub async fn port(&self) -> u16 {
+        self.0.lock().await.port
+    }
+    pub async fn server_key(&self) -> WgKey {
+        self.0.lock().await.key
+    }
+    pub async fn is_up(&self) -> bool {
+        self.0.lock().await.up
+    }
+    pub async fn add_peer(&self, public_key: &WgKey, address: Ipv4Addr) -> Result<(), Error> {
+        self.0.lock().await.add_peer(public_key, address).await
+    }
+    pub async fn remove_peer(&self, public_key: &WgKey) -> Result<(), Error> {
+        self.0.lock().await.remove_peer(public_key).await
+    }
+}
+
+struct VpnControllerInner {
+    network: Ipv4Net,
+    port: u16,
+    key: WgKey,
+    up: bool,
+    peers: BTreeMap<WgKey, Ipv4Addr>,
+}
+impl VpnControllerInner {
+    #[instrument(skip(db))]
+    async fn init(db: SqlitePool, network: Ipv4Net, port: u16) -> Result<Self, Error> {
+        use sqlx::Row;
+
+        let mut secrets = db.acquire().await?;
+        let key = match sqlx::query("SELECT private_key FROM vpn_key WHERE id = 0")
+            .fetch_optional(&mut secrets)
+            .await?
+        {
+            Some(row) => key_from_row(row.try_get("private_key")?)?,
+            None => {
+                let key = WgKey::generate();
+                sqlx::query("INSERT INTO vpn_key (id, private_key) VALUES (0, ?)")
+                    .bind(&key.0[..])
+                    .execute(&mut secrets)
+                    .await?;
+                key
+            }
+        };
+        let mut res = VpnControllerInner {
+            network,
+            port,
+            key,
+            up: false,
+            peers: BTreeMap::new(),
+        };
+        for peer in load_peers(&mut secrets).await? {
+            if !network.contains(&peer.address) {
+                tracing::warn!(
+                    "vpn peer {} has address {} outside of {}, skipping",
+                    peer.name,
+                    peer.address,
+                    network
+                );
+                continue;
+            }
+            // the vpn is optional, so the embassy still starts if it cannot be brought up
+            if let Err(e) = res.add_peer(&peer.public_key, peer.address).await {
+                tracing::error!("Could not add vpn peer {}: {}", peer.name, e);
+                tracing::debug!("{:?}", e);
+            }
+        }
+        Ok(res)
+    }
+
+    fn address(&self) -> Ipv4Addr {
+        self.network
+            .hosts()
+            .next()
+            .unwrap_or_else(|| self.network.addr())
+    }
+
+    /// (Re)creates the interface, and lets peers through to the package containers.
+    #[instrument(skip(self))]
+    async fn up(&mut self) -> Result<(), Error> {
+        // left over from before a crash
+        self.down().await.ok();
+        Command::new("ip")
+            .arg("link")
+            .arg("add")
+            .arg(VPN_INTERFACE)
+            .arg("type")
+            .arg("wireguard")
+            .invoke(ErrorKind::Vpn)
+            .await?;
+        Command::new("ip")
+            .arg("address")
+            .arg("add")
+            .arg(format!("{}/{}", self.address(), self.network.prefix_len()))
+            .arg("dev")
+            .arg(VPN_INTERFACE)
+            .invoke(ErrorKind::Vpn)
+            .await?;
+        // keep the private key off of the command line
+        let mut wg = Command::new("wg")
+            .arg("set")
+            .arg(VPN_INTERFACE)
+            .arg("listen-port")
+            .arg(self.port.to_string())
+            .arg("private-key")
+            .arg("/dev/stdin")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        if let Some(mut stdin) = wg.stdin.take() {
+            stdin.write_all(self.key.to_string().as_bytes()).await?;
+        }
+        let res = wg.wait_with_output().await?;
+        crate::ensure_code!(
+            res.status.success(),
+            ErrorKind::Vpn,
+            "{}",
+            std::str::from_utf8(&res.stderr).unwrap_or("Unknown Error")
+        );
+        Command::new("ip")
+            .arg("link")
+            .arg("set")
+            .arg(VPN_INTERFACE)
+            .arg("up")
+            .invoke(ErrorKind::Vpn)
+            .await?;
+        if let Err(e) = allow_forwarding().await {
+            tracing::error!("Could not route vpn peers to packages: {}", e);
+            tracing::debug!("{:?}", e);
+        }
+        self.up = true;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn down(&mut self) -> Result<(), Error> {
+        self.up = false;
+        if let Err(e) = disallow_forwarding().await {
+            tracing::error!("Could not remove vpn forwarding rule: {}", e);
+            tracing::debug!("{:?}", e);
+        }
+        Command::new("ip")
+            .arg("link")
+            .arg("del")
+            .arg(VPN_INTERFACE)
+            .invoke(ErrorKind::Vpn)
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn add_peer(&mut self, public_key: &WgKey, address: Ipv4Addr) -> Result<(), Error> {
+        if !self.up {
+            if let Err(e) = self.up().await {
+                self.down().await.ok();
+                return Err(e);
+            }
+        }
+        Command::new("wg")
+            .arg("set")
+            .arg(VPN_INTERFACE)
+            .arg("peer")
+            .arg(public_key.to_string())
+            .arg("allowed-ips")
+            .arg(format!("{}/32", address))
+            .invoke(ErrorKind::Vpn)
+            .await?;
+        self.peers.insert(*public_key, address);
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn remove_peer(&mut self, public_key: &WgKey) -> Result<(), Error> {
+        if self.peers.remove(public_key).is_none() || !self.up {
+            return Ok(());
+        }
+        if self.peers.is_empty() {
+            return self.down().await;
+        }
+        Command::new("wg")
+            .arg("set")
+            .arg(VPN_INTERFACE)
+            .arg("peer")
+            .arg(public_key.to_string())
+            .arg("remove")
+            .invoke(ErrorKind::Vpn)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Docker drops forwarded traffic to its networks unless `DOCKER-USER` lets it through.
+const FORWARDING_RULE: [&str; 7] = [
+    "DOCKER-USER",
+    "-i",
+    VPN_INTERFACE,
+    "-d",
+    PACKAGE_NETWORK,
+    "-j",
+    "ACCEPT",
+];
+
+async fn forwarding_allowed() -> bool {
+    Command::new("iptables")
+        .arg("-C")
+        .args(&FORWARDING_RULE)
+        .invoke(ErrorKind::Vpn)
+        .await
+        .is_ok()
+}
+
+async fn allow_forwarding() -> Result<(), Error> {
+    Command::new("sysctl")
+        .arg("-w")
+        .arg("net.ipv4.ip_forward=1")
+        .invoke(ErrorKind::Vpn)
+        .await?;
+    if !forwarding_allowed().await {
+        Command::new("iptables")
+            .arg("-I")
+            .args(&FORWARDING_RULE)
+            .invoke(ErrorKind::Vpn)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Removes every copy of the rule inserted by `allow_forwarding`.
+async fn disallow_forwarding() -> Result<(), Error> {
+    while forwarding_allowed().await {
+        Command::new("iptables")
+            .arg("-D")
+            .args(&FORWARDING_RULE)
+            .invoke(ErrorKind::Vpn)
+            .await?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_next_peer_address() {
+    let network = default_vpn_network();
+    let mut used = BTreeSet::new();
+    assert_eq!(
+        next_peer_address(&network, &used),
+        Some(Ipv4Addr::new(10, 59, 0, 2))
+    );
+    used.insert(Ipv4Addr::new(10, 59, 0, 2));
+    used.insert(Ipv4Addr::new(10, 59, 0, 4));
+    assert_eq!(
+        next_peer_address(&network, &used),
+        Some(Ipv4Addr::new(10, 59, 0, 3))
+    );
+    let network: Ipv4Net = "10.59.0.0/30".parse().unwrap();
+    assert_eq!(next_peer_address(&network, &used), None);
+}
+
+#[test]
+fn test_client_config() {
+    let server_key = WgKey([1; 32]);
+    let peer_key = WgKey([2; 32]);
+    assert_eq!(
+        client_config(
+            Some(&peer_key),
+            Ipv4Addr::new(10, 59, 0, 2),
+            &default_vpn_network(),
+            &server_key,
+            "embassy.example.com:51820",
+        ),
+        format!(
+            "[Interface]\n\
+            PrivateKey = AgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgI=\n\
+            Address = 10.59.0.2/32\n\
+            DNS = 10.59.0.1\n\
+            \n\
+            [Peer]\n\
+            PublicKey = {}\n\
+            AllowedIPs = 10.59.0.0/24, 172.18.0.0/16\n\
+            Endpoint = embassy.example.com:51820\n\
+            PersistentKeepalive = 25\n",
+            server_key.public()
+        )
+    );
+    assert_eq!(peer_key.to_string().parse::<WgKey>().unwrap(), peer_key);
+}
+
+/// Brings the vpn up in a network namespace of its own, and connects to it from a peer in a second
+/// one. Needs root, the wireguard module, and `ip`, `wg` and `ping`.
+#[test]
+#[ignore]
+fn test_vpn_netns() {
+    use nix::sched::{unshare, CloneFlags};
+
+    const PEER_NS: &str = "embassy-vpn-test";
+
+    async fn ip_netns(ns: Option<&str>, args: &[&str]) -> Result<Vec<u8>, Error> {
+        let mut cmd = match ns {
+            Some(ns) => {
+                let mut cmd = Command::new("ip");
+                cmd.arg("netns").arg("exec").arg(ns).arg(args[0]);
+                cmd
+            }
+            None => Command::new(args[0]),
+        };
+        cmd.args(&args[1..]).invoke(ErrorKind::Vpn).await
+    }
+
+    // only this thread, and the processes it spawns, move to the new namespace
+    unshare(CloneFlags::CLONE_NEWNET).unwrap();
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    rt.block_on(async {
+        ip_netns(None, &["ip", "link", "set", "lo", "up"])
+            .await
+            .unwrap();
+        ip_netns(None, &["ip", "netns", "add", PEER_NS]).await.unwrap();
+        let key_file = std::env::temp_dir().join("embassy-vpn-test.key");
+        let res = async {
+            let peer = Some(PEER_NS);
+            #[rustfmt::skip]
+            let setup: &[(Option<&str>, &[&str])] = &[
+                (None, &["ip", "link", "add", "veth-embassy", "type", "veth", "peer", "name", "veth-peer", "netns", PEER_NS]),
+                (None, &["ip", "address", "add", "192.0.2.1/24", "dev", "veth-embassy"]),
+                (None, &["ip", "link", "set", "veth-embassy", "up"]),
+                (peer, &["ip", "address", "add", "192.0.2.2/24", "dev", "veth-peer"]),
+                (peer, &["ip", "link", "set", "veth-peer", "up"]),
+            ];
+            for (ns, args) in setup {
+                ip_netns(*ns, args).await?;
+            }
+
+            let mut vpn = VpnControllerInner {
+                network: default_vpn_network(),
+                port: DEFAULT_VPN_PORT,
+                key: WgKey::generate(),
+                up: false,
+                peers: BTreeMap::new(),
+            };
+            let peer_key = WgKey::generate();
+            let peer_address = Ipv4Addr::new(10, 59, 0, 2);
+            vpn.add_peer(&peer_key.public(), peer_address).await?;
+            assert!(vpn.up);
+
+            tokio::fs::write(&key_file, peer_key.to_string()).await?;
+            let key_path = key_file.display().to_string();
+            let server_key = vpn.key.public().to_string();
+            let endpoint = format!("192.0.2.1:{}", DEFAULT_VPN_PORT);
+            let network = default_vpn_network().to_string();
+            let address = format!("{}/24", peer_address);
+            #[rustfmt::skip]
+            let connect: &[&[&str]] = &[
+                &["ip", "link", "add", "wg-peer", "type", "wireguard"],
+                &["wg", "set", "wg-peer", "private-key", &key_path, "peer", &server_key, "endpoint", &endpoint, "allowed-ips", &network],
+                &["ip", "address", "add", &address, "dev", "wg-peer"],
+                &["ip", "link", "set", "wg-peer", "up"],
+            ];
+            for args in connect {
+                ip_netns(peer, args).await?;
+            }
+            let ping = ["ping", "-c", "1", "-W", "2", "10.59.0.1"];
+            ip_netns(peer, &ping).await?;
+
+            // removing the last peer takes the interface down
+            vpn.remove_peer(&peer_key.public()).await?;
+            assert!(!vpn.up);
+            assert!(ip_netns(peer, &ping).await.is_err());
+            Ok::<_, Error>(())
+        }
+        .await;
+        ip_netns(None, &["ip", "netns", "del", PEER_NS]).await.ok();
+        tokio::fs::remove_file(&key_file).await.ok();
+        res.unwrap();
+    });
+}
@@ -0,0 +1,650 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use color_eyre::eyre::eyre;
+use emver::VersionRange;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use http::header::{CONTENT_LENGTH, CONTENT_TYPE, HOST};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Error as HyperError, Method, Request, Response, Server, StatusCode};
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+use tokio_util::codec::{BytesCodec, FramedRead};
+use tracing::instrument;
+
+use crate::s9pk::manifest::{Manifest, PackageId};
+use crate::s9pk::reader::S9pkReader;
+use crate::util::{display_none, Version};
+use crate::{Error, ErrorKind, ResultExt};
+
+/// Directory names that are interpreted as an architecture when they directly contain s9pk files.
+/// Packages outside of such a directory are served for every architecture.
+const ARCH_DIRS: &[&str] = &["aarch64", "x86_64", "armv7", "riscv64"];
+const REGISTRY_CONFIG: &str = "registry.yaml";
+const EOS_DIR: &str = "eos";
+const HEADER_KEY: &str = "x-eos-hash";
+
+#[command(subcommands(serve))]
+pub fn registry() -> Result<(), Error> {
+    Ok(())
+}
+
+#[command(cli_only, display(display_none))]
+#[instrument]
+pub async fn serve(
+    #[arg] path: PathBuf,
+    #[arg(short = "l", long = "listen")] listen: Option<SocketAddr>,
+) -> Result<(), Error> {
+    let index = Arc::new(RegistryIndex::load(&path).await?);
+    let listen = listen.unwrap_or(([0, 0, 0, 0], 8080).into());
+    tracing::info!(
+        "Serving {} package(s) from {} on {}",
+        index.packages.len(),
+        path.display(),
+        listen
+    );
+    init(index, listen, async {
+        tokio::signal::ctrl_c().await.unwrap_or_default();
+    })
+    .await
+    .with_kind(ErrorKind::Network)
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RegistryConfig {
+    pub name: Option<String>,
+    #[serde(default)]
+    pub categories: BTreeMap<PackageId, BTreeSet<String>>,
+}
+
+#[derive(Debug)]
+pub struct PackageEntry {
+    pub path: PathBuf,
+    pub arch: Option<String>,
+    pub manifest: Manifest,
+}
+
+#[derive(Debug)]
+pub struct EosEntry {
+    pub path: PathBuf,
+    pub arch: Option<String>,
+    pub hash: String,
+    pub release_notes: String,
+}
+
+#[derive(Debug, Default)]
+pub struct RegistryIndex {
+    pub config: RegistryConfig,
+    pub packages: BTreeMap<PackageId, BTreeMap<Version, Vec<PackageEntry>>>,
+    pub eos: BTreeMap<Version, Vec<EosEntry>>,
+}
+impl RegistryIndex {
+    #[instrument]
+    pub async fn load(root: &Path) -> Result<Self, Error> {
+        let mut index = RegistryIndex::default();
+        let cfg_path = root.join(REGISTRY_CONFIG);
+        if tokio::fs::metadata(&cfg_path).await.is_ok() {
+            index.config = serde_yaml::from_slice(&tokio::fs::read(&cfg_path).await?)
+                .with_ctx(|_| (ErrorKind::Deserialization, cfg_path.display().to_string()))?;
+        }
+        let mut s9pks = Vec::new();
+        find_s9pks(root.to_owned(), &mut s9pks).await?;
+        for path in s9pks {
+            let manifest =
+                match async { S9pkReader::open(&path, true).await?.manifest().await }.await {
+                    Ok(a) => a,
+                    Err(e) => {
+                        tracing::warn!("Skipping {}: {}", path.display(), e);
+                        tracing::debug!("{:?}", e);
+                        continue;
+                    }
+                };
+            let arch = arch_of(&path);
+            tracing::info!(
+                "Indexed {}@{} ({})",
+                manifest.id,
+                manifest.version,
+                arch.as_deref().unwrap_or("any arch")
+            );
+            index
+                .packages
+                .entry(manifest.id.clone())
+                .or_default()
+                .entry(manifest.version.clone())
+                .or_default()
+                .push(PackageEntry {
+                    path,
+                    arch,
+                    manifest,
+                });
+        }
+        index.load_eos(&root.join(EOS_DIR)).await?;
+        Ok(index)
+    }
+
+    /// Expects `eos/<version>/eos.img` (or `eos/<version>/<arch>.img`) with an optional
+    /// `release-notes.md` next to it.
+    async fn load_eos(&mut self, eos_dir: &Path) -> Result<(), Error> {
+        if tokio::fs::metadata(eos_dir).await.is_err() {
+            return Ok(());
+        }
+        let mut versions = tokio::fs::read_dir(eos_dir).await?;
+        while let Some(version_dir) = versions.next_entry().await? {
+            let version: Version = match version_dir.file_name().to_str().map(|v| v.parse()) {
+                Some(Ok(v)) => v,
+                _ => continue,
+            };
+            let release_notes =
+                tokio::fs::read_to_string(version_dir.path().join("release-notes.md"))
+                    .await
+                    .unwrap_or_default();
+            let mut images = tokio::fs::read_dir(version_dir.path()).await?;
+            while let Some(image) = images.next_entry().await? {
+                let path = image.path();
+                if path.extension().and_then(|e| e.to_str()) != Some("img") {
+                    continue;
+                }
+                let arch = match path.file_stem().and_then(|s| s.to_str()) {
+                    Some("eos") => None,
+                    Some(arch) => Some(arch.to_owned()),
+                    None => continue,
+                };
+                let hash = hash_file(&path).await?;
+                tracing::info!("Indexed EmbassyOS {} ({:?})", version, arch);
+                self.eos.entry(version.clone()).or_default().push(EosEntry {
+                    path,
+                    arch,
+                    hash,
+                    release_notes: release_notes.clone(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Finds the greatest version of `id` satisfying `spec` that was built for a compatible
+    /// EmbassyOS version and is available for `arch`.
+    pub fn resolve(
+        &self,
+        id: &PackageId,
+        spec: &VersionRange,
+        eos_compat: Option<&VersionRange>,
+        arch: Option<&str>,
+    ) -> Option<&PackageEntry> {
+        self.packages
+            .get(id)?
+            .iter()
+            .rev()
+            .filter(|(version, _)| version.satisfies(spec))
+            .flat_map(|(_, entries)| entries.iter())
+            .find(|entry| {
+                eos_compat.map_or(true, |c| entry.manifest.eos_version.satisfies(c))
+                    && arch_matches(entry.arch.as_deref(), arch)
+            })
+    }
+
+    pub fn resolve_eos(
+        &self,
+        spec: &VersionRange,
+        arch: Option<&str>,
+    ) -> Option<(&Version, &EosEntry)> {
+        self.eos
+            .iter()
+            .rev()
+            .filter(|(version, _)| version.satisfies(spec))
+            .flat_map(|(version, entries)| entries.iter().map(move |e| (version, e)))
+            .find(|(_, entry)| arch_matches(entry.arch.as_deref(), arch))
+    }
+}
+
+fn arch_matches(entry: Option<&str>, requested: Option<&str>) -> bool {
+    match (entry, requested) {
+        (Some(entry), Some(requested)) => entry == requested,
+        _ => true,
+    }
+}
+
+fn arch_of(path: &Path) -> Option<String> {
+    path.parent()
+        .and_then(|p| p.file_name())
+        .and_then(|n| n.to_str())
+        .filter(|n| ARCH_DIRS.contains(n))
+        .map(|n| n.to_owned())
+}
+
+fn find_s9pks<'a>(dir: PathBuf, found: &'a mut Vec<PathBuf>) -> BoxFuture<'a, Result<(), Error>> {
+    async move {
+        let mut entries = tokio::fs::read_dir(&dir)
+            .await
+            .with_ctx(|_| (ErrorKind::Filesystem, dir.display().to_string()))?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let m = entry.metadata().await?;
+            if m.is_dir() {
+                if path.file_name().and_then(|n| n.to_str()) != Some(EOS_DIR) {
+                    find_s9pks(path, found).await?;
+                }
+            } else if m.is_file() && path.extension().and_then(|e| e.to_str()) == Some("s9pk") {
+                found.push(path);
+            }
+        }
+        Ok(())
+    }
+    .boxed()
+}
+
+async fn hash_file(path: &Path) -> Result<String, Error> {
+    let mut file = File::open(path)
+        .await
+        .with_ctx(|_| (ErrorKind::Filesystem, path.display().to_string()))?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; crate::BUFFER_SIZE * 64];
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+pub fn init(
+    index: Arc<RegistryIndex>,
+    addr: SocketAddr,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> impl Future<Output = Result<(), HyperError>> {
+    let make_service = make_service_fn(move |_| {
+        let index = index.clone();
+        async move {
+            Ok::<_, HyperError>(service_fn(move |req| {
+                let index = index.clone();
+                async move {
+                    match registry_router(req, &index).await {
+                        Ok(x) => Ok::<_, HyperError>(x),
+                        Err(e) => {
+                            tracing::debug!("{:?}", e);
+                            Ok(error_response(e))
+                        }
+                    }
+                }
+            }))
+        }
+    });
+
+    Server::bind(&addr)
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown)
+}
+
+#[derive(Debug, Default)]
+struct Query(BTreeMap<String, String>);
+impl Query {
+    fn parse(req: &Request<Body>) -> Self {
+        Query(
+            url::form_urlencoded::parse(req.uri().query().unwrap_or("").as_bytes())
+                .into_owned()
+                .collect(),
+        )
+    }
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(|s| s.as_str())
+    }
+    fn range(&self, key: &str) -> Result<Option<VersionRange>, Error> {
+        self.get(key)
+            .map(|s| s.parse::<VersionRange>())
+            .transpose()
+            .with_kind(ErrorKind::InvalidRequest)
+    }
+    fn spec(&self) -> Result<VersionRange, Error> {
+        Ok(self.range("spec")?.unwrap_or(VersionRange::Any))
+    }
+    fn arch(&self) -> Option<&str> {
+        self.get("arch")
+    }
+}
+
+async fn registry_router(
+    req: Request<Body>,
+    index: &RegistryIndex,
+) -> Result<Response<Body>, Error> {
+    if req.method() != Method::GET {
+        return Ok(status_response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "Method Not Allowed",
+        ));
+    }
+    let query = Query::parse(&req);
+    let base = format!(
+        "http://{}",
+        req.headers()
+            .get(HOST)
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or("localhost")
+    );
+    let path = req.uri().path().trim_start_matches('/');
+    let segments: Vec<&str> = path.split('/').collect();
+    match segments.as_slice() {
+        ["package", "v0", "info"] => json_response(&json!({
+            "name": index.config.name.as_deref().unwrap_or("Local Registry"),
+            "categories": index
+                .config
+                .categories
+                .values()
+                .flatten()
+                .collect::<BTreeSet<_>>(),
+        })),
+        ["package", "v0", "index"] => json_response(&package_index(index, &query, &base)?),
+        ["package", "v0", "release-notes", id] => {
+            let id: PackageId = id.parse().with_kind(ErrorKind::InvalidPackageId)?;
+            let versions = index.packages.get(&id).ok_or_else(|| not_found(&id))?;
+            json_response(
+                &versions
+                    .iter()
+                    .filter_map(|(version, entries)| {
+                        entries
+                            .first()
+                            .map(|e| (version.as_str(), e.manifest.release_notes.as_str()))
+                    })
+                    .collect::<BTreeMap<_, _>>(),
+            )
+        }
+        ["package", "v0", "manifest", id] => {
+            json_response(&resolve_package(index, id, &query)?.manifest)
+        }
+        ["package", "v0", "license", id] => {
+            let entry = resolve_package(index, id, &query)?;
+            let mut rdr = S9pkReader::open(&entry.path, false).await?;
+            let license = rdr.license().await?.to_vec().await?;
+            bytes_response(license, "text/markdown")
+        }
+        ["package", "v0", "instructions", id] => {
+            let entry = resolve_package(index, id, &query)?;
+            let mut rdr = S9pkReader::open(&entry.path, false).await?;
+            let instructions = rdr.instructions().await?.to_vec().await?;
+            bytes_response(instructions, "text/markdown")
+        }
+        ["package", "v0", "icon", id] => {
+            let entry = resolve_package(index, id, &query)?;
+            let icon_type = entry.manifest.assets.icon_type().to_owned();
+            let mut rdr = S9pkReader::open(&entry.path, false).await?;
+            let icon = rdr.icon().await?.to_vec().await?;
+            bytes_response(
+                icon,
+                match icon_type.as_str() {
+                    "svg" => "image/svg+xml".to_owned(),
+                    "jpg" => "image/jpeg".to_owned(),
+                    t => format!("image/{}", t),
+                },
+            )
+        }
+        ["package", "v0", s9pk] if s9pk.ends_with(".s9pk") => {
+            let entry = resolve_package(index, s9pk.trim_end_matches(".s9pk"), &query)?;
+            file_response(&entry.path, "application/octet-stream", None).await
+        }
+        ["eos", "v0", "latest"] => {
+            let (version, entry) = index
+                .resolve_eos(&VersionRange::Any, query.arch())
+                .ok_or_else(|| {
+                    Error::new(eyre!("No EmbassyOS images available"), ErrorKind::NotFound)
+                })?;
+            json_response(&json!({
+                "version": version,
+                "headline": entry
+                    .release_notes
+                    .lines()
+                    .map(|l| l.trim_start_matches('#').trim())
+                    .find(|l| !l.is_empty())
+                    .unwrap_or_default(),
+                "release-notes": index
+                    .eos
+                    .iter()
+                    .filter_map(|(v, e)| e.first().map(|e| (v.as_str(), e.release_notes.as_str())))
+                    .collect::<BTreeMap<_, _>>(),
+            }))
+        }
+        ["eos", "v0", "eos.img"] => {
+            let spec = query.spec()?;
+            let (_, entry) = index.resolve_eos(&spec, query.arch()).ok_or_else(|| {
+                Error::new(
+                    eyre!("No EmbassyOS image satisfies {}", spec),
+                    ErrorKind::InvalidRequest,
+                )
+            })?;
+            file_response(&entry.path, "application/octet-stream", Some(&entry.hash)).await
+        }
+        _ => Ok(status_response(StatusCode::NOT_FOUND, "Not Found")),
+    }
+}
+
+#[derive(Deserialize)]
+struct IdSpec {
+    id: PackageId,
+    version: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct MarketplacePkg<'a> {
+    icon: String,
+    license: String,
+    instructions: String,
+    manifest: &'a Manifest,
+    categories: BTreeSet<&'a str>,
+    versions: Vec<&'a Version>,
+    dependency_metadata: BTreeMap<&'a PackageId, Value>,
+}
+
+fn package_index<'a>(
+    index: &'a RegistryIndex,
+    query: &Query,
+    base: &str,
+) -> Result<Vec<MarketplacePkg<'a>>, Error> {
+    let compat = query.range("eos-version-compat")?;
+    let arch = query.arch();
+    let entries: Vec<&PackageEntry> = if let Some(ids) = query.get("ids") {
+        let ids: Vec<IdSpec> = serde_json::from_str(ids).with_kind(ErrorKind::InvalidRequest)?;
+        ids.into_iter()
+            .map(|i| {
+                let spec: VersionRange = i.version.parse()?;
+                Ok(index.resolve(&i.id, &spec, compat.as_ref(), arch))
+            })
+            .filter_map(|r| r.transpose())
+            .collect::<Result<_, Error>>()?
+    } else {
+        let category = query.get("category");
+        let search = query.get("query").map(|q| q.to_lowercase());
+        index
+            .packages
+            .keys()
+            .filter_map(|id| index.resolve(id, &VersionRange::Any, compat.as_ref(), arch))
+            .filter(|e| {
+                category.map_or(true, |c| {
+                    index
+                        .config
+                        .categories
+                        .get(&e.manifest.id)
+                        .map_or(false, |cats| cats.contains(c))
+                })
+            })
+            .filter(|e| {
+                search.as_ref().map_or(true, |q| {
+                    e.manifest.id.contains(q.as_str())
+                        || e.manifest.title.to_lowercase().contains(q.as_str())
+                        || e.manifest
+                            .description
+                            .short
+                            .to_lowercase()
+                            .contains(q.as_str())
+                })
+            })
+            .collect()
+    };
+    let per_page: usize = query
+        .get("per-page")
+        .map(|p| p.parse())
+        .transpose()?
+        .unwrap_or(20);
+    let page: usize = query
+        .get("page")
+        .map(|p| p.parse())
+        .transpose()?
+        .unwrap_or(1);
+    Ok(entries
+        .into_iter()
+        .skip(page.saturating_sub(1) * per_page)
+        .take(per_page)
+        .map(|e| {
+            let man = &e.manifest;
+            let asset_url = |kind: &str, id: &PackageId, version: &Version| {
+                format!("{}/package/v0/{}/{}?spec=={}", base, kind, id, version)
+            };
+            MarketplacePkg {
+                icon: asset_url("icon", &man.id, &man.version),
+                license: asset_url("license", &man.id, &man.version),
+                instructions: asset_url("instructions", &man.id, &man.version),
+                manifest: man,
+                categories: index
+                    .config
+                    .categories
+                    .get(&man.id)
+                    .map(|c| c.iter().map(|c| c.as_str()).collect())
+                    .unwrap_or_default(),
+                versions: index
+                    .packages
+                    .get(&man.id)
+                    .map(|v| v.keys().collect())
+                    .unwrap_or_default(),
+                dependency_metadata: man
+                    .dependencies
+                    .0
+                    .iter()
+                    .filter_map(|(dep, info)| {
+                        let dep_entry = index.resolve(dep, &info.version, compat.as_ref(), arch)?;
+                        Some((
+                            dep,
+                            json!({
+                                "title": dep_entry.manifest.title,
+                                "icon": asset_url("icon", dep, &dep_entry.manifest.version),
+                            }),
+                        ))
+                    })
+                    .collect(),
+            }
+        })
+        .collect())
+}
+
+fn resolve_package<'a>(
+    index: &'a RegistryIndex,
+    id: &str,
+    query: &Query,
+) -> Result<&'a PackageEntry, Error> {
+    let id: PackageId = id.parse().with_kind(ErrorKind::InvalidPackageId)?;
+    let spec = query.spec()?;
+    index
+        .resolve(
+            &id,
+            &spec,
+            query.range("eos-version-compat")?.as_ref(),
+            query.arch(),
+        )
+        .ok_or_else(|| {
+            // `install` treats BAD_REQUEST as "no satisfying version" for optional dependencies
+            Error::new(
+                eyre!("No version of {} satisfies {}", id, spec),
+                ErrorKind::InvalidRequest,
+            )
+        })
+}
+
+fn not_found(id: &PackageId) -> Error {
+    Error::new(eyre!("Unknown package {}", id), ErrorKind::NotFound)
+}
+
+fn status_response(status: StatusCode, message: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(message.into())
+        .unwrap()
+}
+
+fn error_response(e: Error) -> Response<Body> {
+    let status = match e.kind {
+        ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        ErrorKind::InvalidRequest
+        | ErrorKind::InvalidPackageId
+        | ErrorKind::ParseVersion
+        | ErrorKind::ParseNumber => StatusCode::BAD_REQUEST,
+        _ => {
+            tracing::error!("{}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+    Response::builder()
+        .status(status)
+        .body(format!("{}", e.source).into())
+        .unwrap()
+}
+
+fn json_response<T: Serialize>(value: &T) -> Result<Response<Body>, Error> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(
+            serde_json::to_vec(value)
+                .with_kind(ErrorKind::Serialization)?
+                .into(),
+        )
+        .with_kind(ErrorKind::Network)
+}
+
+fn bytes_response(bytes: Vec<u8>, content_type: impl AsRef<str>) -> Result<Response<Body>, Error> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, content_type.as_ref())
+        .header(CONTENT_LENGTH, bytes.len())
+        .body(bytes.into())
+        .with_kind(ErrorKind::Network)
+}
+
+async fn file_response(
+    path: &Path,
+    content_type: &str,
+    hash: Option<&str>,
+) -> Result<Response<Body>, Error> {
+    let file = File::open(path)
+        .await
+        .with_ctx(|_| (ErrorKind::Filesystem, path.display().to_string()))?;
+    let len = file.metadata().await?.len();
+    let mut builder = Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, content_type)
+        .header(CONTENT_LENGTH, len);
+    if let Some(hash) = hash {
+        builder = builder.header(HEADER_KEY, hash);
+    }
+    builder
+        .body(Body::wrap_stream(FramedRead::new(file, BytesCodec::new())))
+        .with_kind(ErrorKind::Network)
+}
+
+#[test]
+fn arch_dir_detection() {
+    assert_eq!(
+        arch_of(Path::new("/srv/registry/aarch64/bitcoind.s9pk")).as_deref(),
+        Some("aarch64")
+    );
+    assert_eq!(arch_of(Path::new("/srv/registry/bitcoind.s9pk")), None);
+    assert!(arch_matches(None, Some("x86_64")));
+    assert!(!arch_matches(Some("aarch64"), Some("x86_64")));
+}
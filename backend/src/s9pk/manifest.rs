@@ -12,6 +12,7 @@ use crate::config::action::ConfigActions;
 use crate::dependencies::Dependencies;
 use crate::id::{Id, InvalidId, SYSTEM_ID};
 use crate::migration::Migrations;
+use crate::net::firewall::NetworkPolicy;
 use crate::net::interface::Interfaces;
 use crate::status::health_check::HealthChecks;
 use crate::util::Version;
@@ -149,6 +150,8 @@ pub struct Manifest {
     #[serde(default)]
     #[model]
     pub dependencies: Dependencies,
+    #[serde(default)]
+    pub network: NetworkPolicy,
 }
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
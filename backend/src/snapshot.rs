@@ -0,0 +1,365 @@
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use clap::ArgMatches;
+use color_eyre::eyre::eyre;
+use nix::sys::statvfs::statvfs;
+use rpc_toolkit::command;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use tracing::instrument;
+
+use crate::context::RpcContext;
+use crate::db::model::InstalledPackageDataEntry;
+use crate::s9pk::manifest::PackageId;
+use crate::util::serde::{display_serializable, IoFormat};
+use crate::util::{display_none, Invoke, Version};
+use crate::volume::PKG_VOLUME_DIR;
+use crate::{Error, ResultExt};
+
+pub const PKG_SNAPSHOT_DIR: &'static str = "package-data/snapshots";
+/// Number of snapshots kept per package; older ones are removed when a new snapshot is taken.
+pub const PKG_SNAPSHOT_RETENTION: usize = 5;
+/// Fraction of the data partition that must remain free once a snapshot is taken. Older snapshots
+/// of the package are removed to make room, and if that is not enough no snapshot is taken.
+pub const PKG_SNAPSHOT_FREE_RESERVE: f64 = 0.2;
+const SNAPSHOT_METADATA: &'static str = "snapshot.json";
+
+/// Directory containing all data volumes of a package.
+pub fn volume_data_dir<P: AsRef<Path>>(datadir: P, pkg_id: &PackageId) -> PathBuf {
+    datadir
+        .as_ref()
+        .join(PKG_VOLUME_DIR)
+        .join(pkg_id)
+        .join("data")
+}
+
+pub fn snapshot_dir<P: AsRef<Path>>(datadir: P, pkg_id: &PackageId, snapshot_id: &str) -> PathBuf {
+    datadir
+        .as_ref()
+        .join(PKG_SNAPSHOT_DIR)
+        .join(pkg_id)
+        .join(snapshot_id)
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Snapshot {
+    pub id: String,
+    pub version: Version,
+    pub created: DateTime<Utc>,
+    pub reason: String,
+}
+
+/// Space available to new snapshots on the data partition, once the reserve is set aside.
+fn snapshot_budget(datadir: &Path) -> Result<u64, Error> {
+    let stat = statvfs(datadir)
+        .with_ctx(|_| (crate::ErrorKind::Filesystem, datadir.display().to_string()))?;
+    let total = stat.blocks() as u64 * stat.fragment_size() as u64;
+    let available = stat.blocks_available() as u64 * stat.fragment_size() as u64;
+    Ok(available.saturating_sub((total as f64 * PKG_SNAPSHOT_FREE_RESERVE) as u64))
+}
+
+/// Copies the data volumes of `pkg_id` into a new snapshot. The package must not be running. If the
+/// copy would not fit within [`PKG_SNAPSHOT_FREE_RESERVE`], even after removing the older snapshots
+/// of the package, no snapshot is taken and `None` is returned.
+#[instrument(skip(ctx))]
+pub async fn create(
+    ctx: &RpcContext,
+    pkg_id: &PackageId,
+    version: &Version,
+    reason: &str,
+) -> Result<Option<Snapshot>, Error> {
+    let src = volume_data_dir(&ctx.datadir, pkg_id);
+    let size = if tokio::fs::metadata(&src).await.is_ok() {
+        crate::volume::disk_usage(src.clone()).await?
+    } else {
+        0
+    };
+    let mut snapshots = list(ctx, pkg_id).await?;
+    let budget = snapshot_budget(&ctx.datadir)?;
+    if budget < size {
+        let mut reclaimable = 0;
+        for snapshot in &snapshots {
+            reclaimable +=
+                crate::volume::disk_usage(snapshot_dir(&ctx.datadir, pkg_id, &snapshot.id)).await?;
+        }
+        if budget + reclaimable < size {
+            tracing::warn!(
+                "Not snapshotting {}: its volumes take {} bytes but only {} are available",
+                pkg_id,
+                size,
+                budget + reclaimable
+            );
+            return Ok(None);
+        }
+        // make room by dropping the oldest snapshots first
+        while snapshot_budget(&ctx.datadir)? < size {
+            match snapshots.pop() {
+                Some(oldest) => {
+                    tracing::info!("Pruning snapshot {} of {} for space", oldest.id, pkg_id);
+                    delete(ctx, pkg_id, &oldest.id).await?;
+                }
+                None => break,
+            }
+        }
+    }
+
+    let created = Utc::now();
+    let mut snapshot = Snapshot {
+        id: created.format("%Y%m%dT%H%M%S%3fZ").to_string(),
+        version: version.clone(),
+        created,
+        reason: reason.to_owned(),
+    };
+    let pkg_dir = ctx.datadir.join(PKG_SNAPSHOT_DIR).join(pkg_id);
+    tokio::fs::create_dir_all(&pkg_dir)
+        .await
+        .with_ctx(|_| (crate::ErrorKind::Filesystem, pkg_dir.display().to_string()))?;
+    let base_id = snapshot.id.clone();
+    let mut dir = pkg_dir.join(&snapshot.id);
+    let mut n = 0;
+    // never reuse the directory of an existing snapshot, `cp` would copy into it
+    while let Err(e) = tokio::fs::create_dir(&dir).await {
+        if e.kind() != std::io::ErrorKind::AlreadyExists {
+            return Err(e).with_ctx(|_| (crate::ErrorKind::Filesystem, dir.display().to_string()));
+        }
+        n += 1;
+        snapshot.id = format!("{}-{}", base_id, n);
+        dir = pkg_dir.join(&snapshot.id);
+    }
+    if let Err(e) = async {
+        if tokio::fs::metadata(&src).await.is_ok() {
+            copy_dir(&src, &dir.join("data")).await?;
+        }
+        tokio::fs::write(
+            dir.join(SNAPSHOT_METADATA),
+            serde_json::to_vec(&snapshot).with_kind(crate::ErrorKind::Serialization)?,
+        )
+        .await?;
+        Ok::<_, Error>(())
+    }
+    .await
+    {
+        if let Err(e) = tokio::fs::remove_dir_all(&dir).await {
+            tracing::error!(
+                "Failed to remove incomplete snapshot {}: {}",
+                dir.display(),
+                e
+            );
+        }
+        return Err(e);
+    }
+    tracing::info!("Created snapshot {} of {}@{}", snapshot.id, pkg_id, version);
+    for old in snapshots.into_iter().skip(PKG_SNAPSHOT_RETENTION - 1) {
+        tracing::info!("Pruning snapshot {} of {}", old.id, pkg_id);
+        if let Err(e) = delete(ctx, pkg_id, &old.id).await {
+            tracing::error!("Failed to prune snapshot {} of {}: {}", old.id, pkg_id, e);
+            tracing::debug!("{:?}", e);
+        }
+    }
+    Ok(Some(snapshot))
+}
+
+/// Like [`create`], but a snapshot that cannot be taken is logged instead of failing the
+/// operation it was meant to protect.
+pub async fn try_create(
+    ctx: &RpcContext,
+    pkg_id: &PackageId,
+    version: &Version,
+    reason: &str,
+) -> Option<Snapshot> {
+    match create(ctx, pkg_id, version, reason).await {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            tracing::error!("Failed to snapshot {}: {}", pkg_id, e);
+            tracing::debug!("{:?}", e);
+            None
+        }
+    }
+}
+
+/// All snapshots of `pkg_id`, newest first.
+#[instrument(skip(ctx))]
+pub async fn list(ctx: &RpcContext, pkg_id: &PackageId) -> Result<Vec<Snapshot>, Error> {
+    let pkg_dir = ctx.datadir.join(PKG_SNAPSHOT_DIR).join(pkg_id);
+    let mut snapshots = Vec::new();
+    if tokio::fs::metadata(&pkg_dir).await.is_err() {
+        return Ok(snapshots);
+    }
+    let mut dir = tokio::fs::read_dir(&pkg_dir).await?;
+    while let Some(entry) = dir.next_entry().await? {
+        let metadata_path = entry.path().join(SNAPSHOT_METADATA);
+        if tokio::fs::metadata(&metadata_path).await.is_err() {
+            continue; // incomplete
+        }
+        snapshots.push(
+            serde_json::from_slice(&tokio::fs::read(&metadata_path).await?).with_ctx(|_| {
+                (
+                    crate::ErrorKind::Deserialization,
+                    metadata_path.display().to_string(),
+                )
+            })?,
+        );
+    }
+    snapshots.sort_by(|a: &Snapshot, b: &Snapshot| b.created.cmp(&a.created));
+    Ok(snapshots)
+}
+
+async fn find(ctx: &RpcContext, pkg_id: &PackageId, snapshot_id: &str) -> Result<Snapshot, Error> {
+    list(ctx, pkg_id)
+        .await?
+        .into_iter()
+        .find(|s| s.id == snapshot_id)
+        .ok_or_else(|| {
+            Error::new(
+                eyre!("Snapshot {} of {} does not exist", snapshot_id, pkg_id),
+                crate::ErrorKind::NotFound,
+            )
+        })
+}
+
+/// Replaces the data volumes of `pkg_id` with the contents of a snapshot. The package should not
+/// be running.
+#[instrument(skip(ctx))]
+pub async fn restore(ctx: &RpcContext, pkg_id: &PackageId, snapshot_id: &str) -> Result<(), Error> {
+    let dir = snapshot_dir(&ctx.datadir, pkg_id, snapshot_id);
+    if tokio::fs::metadata(dir.join(SNAPSHOT_METADATA))
+        .await
+        .is_err()
+    {
+        return Err(Error::new(
+            eyre!("Snapshot {} of {} does not exist", snapshot_id, pkg_id),
+            crate::ErrorKind::NotFound,
+        ));
+    }
+    let dst = volume_data_dir(&ctx.datadir, pkg_id);
+    let tmp = dst.with_file_name("data.restore");
+    if tokio::fs::metadata(&tmp).await.is_ok() {
+        tokio::fs::remove_dir_all(&tmp).await?;
+    }
+    if tokio::fs::metadata(dir.join("data")).await.is_ok() {
+        copy_dir(&dir.join("data"), &tmp).await?;
+    } else {
+        tokio::fs::create_dir_all(&tmp).await?;
+    }
+    if tokio::fs::metadata(&dst).await.is_ok() {
+        tokio::fs::remove_dir_all(&dst)
+            .await
+            .with_ctx(|_| (crate::ErrorKind::Filesystem, dst.display().to_string()))?;
+    }
+    tokio::fs::rename(&tmp, &dst)
+        .await
+        .with_ctx(|_| (crate::ErrorKind::Filesystem, dst.display().to_string()))?;
+    tracing::info!("Restored snapshot {} of {}", snapshot_id, pkg_id);
+    Ok(())
+}
+
+#[instrument(skip(ctx))]
+pub async fn delete(ctx: &RpcContext, pkg_id: &PackageId, snapshot_id: &str) -> Result<(), Error> {
+    let dir = snapshot_dir(&ctx.datadir, pkg_id, snapshot_id);
+    if tokio::fs::metadata(&dir).await.is_ok() {
+        tokio::fs::remove_dir_all(&dir)
+            .await
+            .with_ctx(|_| (crate::ErrorKind::Filesystem, dir.display().to_string()))?;
+    }
+    Ok(())
+}
+
+/// Copies `src` to `dst` preserving ownership and permissions, sharing extents where the
+/// filesystem supports it.
+async fn copy_dir(src: &Path, dst: &Path) -> Result<(), Error> {
+    Command::new("cp")
+        .arg("-a")
+        .arg("--reflink=auto")
+        .arg(src)
+        .arg(dst)
+        .invoke(crate::ErrorKind::Filesystem)
+        .await?;
+    Ok(())
+}
+
+#[command(subcommands(snapshot_list, snapshot_restore, snapshot_delete))]
+pub fn snapshot(#[arg] id: PackageId) -> Result<PackageId, Error> {
+    Ok(id)
+}
+
+fn display_snapshots(snapshots: Vec<Snapshot>, matches: &ArgMatches<'_>) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(snapshots, matches);
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![bc => "ID", "VERSION", "CREATED", "REASON"]);
+    for snapshot in snapshots {
+        table.add_row(row![
+            &snapshot.id,
+            &snapshot.version.as_str(),
+            &format!("{}", snapshot.created),
+            &snapshot.reason,
+        ]);
+    }
+    table.print_tty(false);
+}
+
+#[command(rename = "list", display(display_snapshots))]
+#[instrument(skip(ctx))]
+pub async fn snapshot_list(
+    #[context] ctx: RpcContext,
+    #[parent_data] id: PackageId,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<Snapshot>, Error> {
+    list(&ctx, &id).await
+}
+
+#[command(rename = "restore", display(display_none))]
+#[instrument(skip(ctx))]
+pub async fn snapshot_restore(
+    #[context] ctx: RpcContext,
+    #[parent_data] id: PackageId,
+    #[arg(rename = "snapshot-id")] snapshot_id: String,
+) -> Result<(), Error> {
+    let installed: InstalledPackageDataEntry = crate::db::DatabaseModel::new()
+        .package_data()
+        .idx_model(&id)
+        .and_then(|pde| pde.installed())
+        .get(&mut ctx.db.handle(), false)
+        .await?
+        .into_owned()
+        .ok_or_else(|| Error::new(eyre!("{} is not installed", id), crate::ErrorKind::NotFound))?;
+    if installed.status.main.running() {
+        return Err(Error::new(
+            eyre!("{} must be stopped before restoring a snapshot", id),
+            crate::ErrorKind::InvalidRequest,
+        ));
+    }
+    let snapshot = find(&ctx, &id, &snapshot_id).await?;
+    if snapshot.version != installed.manifest.version {
+        return Err(Error::new(
+            eyre!(
+                "Snapshot {} was taken of {} {}, but {} is installed",
+                snapshot.id,
+                id,
+                snapshot.version,
+                installed.manifest.version
+            ),
+            crate::ErrorKind::InvalidRequest,
+        ));
+    }
+    restore(&ctx, &id, &snapshot.id).await
+}
+
+#[command(rename = "delete", display(display_none))]
+#[instrument(skip(ctx))]
+pub async fn snapshot_delete(
+    #[context] ctx: RpcContext,
+    #[parent_data] id: PackageId,
+    #[arg(rename = "snapshot-id")] snapshot_id: String,
+) -> Result<(), Error> {
+    let snapshot = find(&ctx, &id, &snapshot_id).await?;
+    delete(&ctx, &id, &snapshot.id).await
+}
@@ -10,6 +10,7 @@ use tracing::instrument;
 use crate::context::RpcContext;
 use crate::disk::util::{get_available, get_percentage, get_used};
 use crate::logs::{display_logs, fetch_logs, LogResponse, LogSource};
+use crate::net::tor::TorStatus;
 use crate::shutdown::Shutdown;
 use crate::util::serde::{display_serializable, IoFormat};
 use crate::{Error, ErrorKind};
@@ -143,6 +144,30 @@ impl<'de> Deserialize<'de> for GigaBytes {
     }
 }
 
+#[derive(Clone, Debug)]
+pub struct Count(usize);
+impl Serialize for Count {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        MetricLeaf {
+            value: self.0.to_string(),
+            unit: None,
+        }
+        .serialize(serializer)
+    }
+}
+impl<'de> Deserialize<'de> for Count {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = MetricLeaf::<String>::deserialize(deserializer)?;
+        Ok(Count(s.value.parse().map_err(serde::de::Error::custom)?))
+    }
+}
+
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct MetricsGeneral {
     #[serde(rename = "Temperature")]
@@ -190,6 +215,19 @@ pub struct MetricsDisk {
     used_percentage: Percentage,
 }
 #[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct MetricsTor {
+    #[serde(rename = "Read")]
+    read: MebiBytes,
+    #[serde(rename = "Written")]
+    written: MebiBytes,
+    #[serde(rename = "Circuits")]
+    circuits: Count,
+    #[serde(rename = "Published Services")]
+    published_services: Count,
+    #[serde(rename = "Unpublished Services")]
+    unpublished_services: Count,
+}
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct Metrics {
     #[cfg(feature = "metal")]
     #[serde(rename = "General")]
@@ -200,6 +238,11 @@ pub struct Metrics {
     cpu: MetricsCpu,
     #[serde(rename = "Disk")]
     disk: MetricsDisk,
+    /// Recorded by the tor status daemon, once it has run.
+    #[serde(rename = "Tor")]
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tor: Option<MetricsTor>,
 }
 
 #[command(display(display_serializable))]
@@ -219,6 +262,23 @@ pub async fn metrics(
     }
 }
 
+pub async fn record_tor_metrics(cache: &RwLock<Option<Metrics>>, status: &TorStatus) {
+    let published = status
+        .services
+        .iter()
+        .filter(|s| s.descriptor_published)
+        .count();
+    if let Some(metrics) = cache.write().await.as_mut() {
+        metrics.tor = Some(MetricsTor {
+            read: MebiBytes(status.bytes_read as f64 / 1024_f64.powi(2)),
+            written: MebiBytes(status.bytes_written as f64 / 1024_f64.powi(2)),
+            circuits: Count(status.circuits),
+            published_services: Count(published),
+            unpublished_services: Count(status.services.len() - published),
+        });
+    }
+}
+
 pub async fn launch_metrics_task<F: FnMut() -> Receiver<Option<Shutdown>>>(
     cache: &RwLock<Option<Metrics>>,
     mut mk_shutdown: F,
@@ -303,6 +363,7 @@ pub async fn launch_metrics_task<F: FnMut() -> Receiver<Option<Shutdown>>>(
             memory: init_mem,
             cpu: init_cpu,
             disk: init_disk,
+            tor: None,
         })
     }
     // launch persistent temp task
@@ -1,3 +1,4 @@
+use std::convert::TryFrom;
 use std::marker::PhantomData;
 use std::ops::Deref;
 use std::process::exit;
@@ -450,37 +451,46 @@ impl std::str::FromStr for Duration {
         })?;
         let (num, units) = s.split_at(units_idx);
         use std::time::Duration;
+        // user input, so out of range values must not panic
+        let out_of_range = || {
+            Error::new(
+                eyre!("Duration {} is out of range", s),
+                crate::ErrorKind::Deserialization,
+            )
+        };
+        let secs_f64 = |secs: f64| {
+            if secs.is_finite() && secs >= 0.0 && secs < u64::MAX as f64 {
+                Ok(Duration::from_secs_f64(secs))
+            } else {
+                Err(out_of_range())
+            }
+        };
+        let secs = |num: u64, mul: u64| {
+            num.checked_mul(mul)
+                .map(Duration::from_secs)
+                .ok_or_else(out_of_range)
+        };
+        let subsec = |num: u128, per_sec: u128| {
+            Ok::<_, Error>(Duration::new(
+                u64::try_from(num / per_sec).map_err(|_| out_of_range())?,
+                ((num % per_sec) * (1_000_000_000 / per_sec)) as u32,
+            ))
+        };
         Ok(Duration(match units {
-            "d" if num.contains(".") => Duration::from_secs_f64(num.parse::<f64>()? * 86_400_f64),
-            "d" => Duration::from_secs(num.parse::<u64>()? * 86_400),
-            "h" if num.contains(".") => Duration::from_secs_f64(num.parse::<f64>()? * 3_600_f64),
-            "h" => Duration::from_secs(num.parse::<u64>()? * 3_600),
-            "m" if num.contains(".") => Duration::from_secs_f64(num.parse::<f64>()? * 60_f64),
-            "m" => Duration::from_secs(num.parse::<u64>()? * 60),
-            "s" if num.contains(".") => Duration::from_secs_f64(num.parse()?),
-            "s" => Duration::from_secs(num.parse()?),
-            "ms" if num.contains(".") => Duration::from_secs_f64(num.parse::<f64>()? / 1_000_f64),
-            "ms" => {
-                let millis: u128 = num.parse()?;
-                Duration::new((millis / 1_000) as u64, (millis % 1_000) as u32)
-            }
-            "us" | "µs" if num.contains(".") => {
-                Duration::from_secs_f64(num.parse::<f64>()? / 1_000_000_f64)
-            }
-            "us" | "µs" => {
-                let micros: u128 = num.parse()?;
-                Duration::new((micros / 1_000_000) as u64, (micros % 1_000_000) as u32)
-            }
-            "ns" if num.contains(".") => {
-                Duration::from_secs_f64(num.parse::<f64>()? / 1_000_000_000_f64)
-            }
-            "ns" => {
-                let nanos: u128 = num.parse()?;
-                Duration::new(
-                    (nanos / 1_000_000_000) as u64,
-                    (nanos % 1_000_000_000) as u32,
-                )
-            }
+            "d" if num.contains(".") => secs_f64(num.parse::<f64>()? * 86_400_f64)?,
+            "d" => secs(num.parse()?, 86_400)?,
+            "h" if num.contains(".") => secs_f64(num.parse::<f64>()? * 3_600_f64)?,
+            "h" => secs(num.parse()?, 3_600)?,
+            "m" if num.contains(".") => secs_f64(num.parse::<f64>()? * 60_f64)?,
+            "m" => secs(num.parse()?, 60)?,
+            "s" if num.contains(".") => secs_f64(num.parse()?)?,
+            "s" => secs(num.parse()?, 1)?,
+            "ms" if num.contains(".") => secs_f64(num.parse::<f64>()? / 1_000_f64)?,
+            "ms" => subsec(num.parse()?, 1_000)?,
+            "us" | "µs" if num.contains(".") => secs_f64(num.parse::<f64>()? / 1_000_000_f64)?,
+            "us" | "µs" => subsec(num.parse()?, 1_000_000)?,
+            "ns" if num.contains(".") => secs_f64(num.parse::<f64>()? / 1_000_000_000_f64)?,
+            "ns" => subsec(num.parse()?, 1_000_000_000)?,
             _ => {
                 return Err(Error::new(
                     eyre!("Invalid units for duration"),
@@ -722,3 +732,27 @@ impl<'de, K: Deserialize<'de>, V: Deserialize<'de>> Deserialize<'de> for KeyVal<
         deserializer.deserialize_map(Visitor(PhantomData))
     }
 }
+
+#[test]
+fn test_duration_from_str() {
+    use std::time::Duration as StdDuration;
+
+    assert_eq!(
+        *"1.5h".parse::<Duration>().unwrap(),
+        StdDuration::from_secs(5_400)
+    );
+    assert_eq!(
+        *"1500ms".parse::<Duration>().unwrap(),
+        StdDuration::from_millis(1_500)
+    );
+    assert_eq!(
+        *"7us".parse::<Duration>().unwrap(),
+        StdDuration::from_micros(7)
+    );
+    assert!("-1.5h".parse::<Duration>().is_err());
+    assert!("99999999999999999999999.0h".parse::<Duration>().is_err());
+    assert!("18446744073709551615h".parse::<Duration>().is_err());
+    assert!("340282366920938463463374607431768211455ns"
+        .parse::<Duration>()
+        .is_err());
+}
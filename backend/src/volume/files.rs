@@ -0,0 +1,703 @@
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::{Component, Path, PathBuf};
+use std::time::Instant;
+
+use chrono::{DateTime, TimeZone, Utc};
+use clap::ArgMatches;
+use color_eyre::eyre::eyre;
+use futures::future::FutureExt;
+use futures::StreamExt;
+use http::{Request, Response, StatusCode};
+use hyper::Body;
+use nix::dir::Dir;
+use nix::errno::Errno;
+use nix::fcntl::{open, openat, renameat, AtFlags, OFlag};
+use nix::sys::stat::{fstat, fstatat, mkdirat, Mode};
+use nix::unistd::{close, fchownat, unlinkat, FchownatFlags, Gid, Uid, UnlinkatFlags};
+use rpc_toolkit::command;
+use rpc_toolkit::command_helpers::prelude::RequestParts;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{Executor, Row, Sqlite};
+use tokio::io::AsyncWriteExt;
+use tracing::instrument;
+
+use super::{Volume, VolumeId};
+use crate::context::RpcContext;
+use crate::core::rpc_continuations::{RequestGuid, RpcContinuation};
+use crate::middleware::auth::HashSessionToken;
+use crate::s9pk::manifest::PackageId;
+use crate::util::serde::{display_serializable, IoFormat};
+use crate::{Error, ResultExt};
+
+/// Browses and transfers the files of a package volume. Only data volumes can be written to.
+#[command(subcommands(list, download, upload, audit))]
+pub fn volume(
+    #[arg] id: PackageId,
+    #[arg(rename = "volume-id")] volume_id: VolumeId,
+) -> Result<(PackageId, VolumeId), Error> {
+    Ok((id, volume_id))
+}
+
+/// The directory of `volume_id`, if it can be browsed, and written to if `write` is set.
+#[instrument(skip(ctx))]
+async fn volume_root(
+    ctx: &RpcContext,
+    id: &PackageId,
+    volume_id: &VolumeId,
+    write: bool,
+) -> Result<PathBuf, Error> {
+    let mut db = ctx.db.handle();
+    let manifest = crate::db::DatabaseModel::new()
+        .package_data()
+        .idx_model(id)
+        .and_then(|m| m.installed())
+        .map(|m| m.manifest())
+        .get(&mut db, true)
+        .await?
+        .to_owned()
+        .ok_or_else(|| Error::new(eyre!("{} is not installed", id), crate::ErrorKind::NotFound))?;
+    let volume = manifest.volumes.get(volume_id).ok_or_else(|| {
+        Error::new(
+            eyre!("{} has no volume {}", id, volume_id),
+            crate::ErrorKind::NotFound,
+        )
+    })?;
+    match volume {
+        Volume::Data { .. } => (),
+        Volume::Assets {} | Volume::Certificate { .. } if !write => (),
+        Volume::Assets {} | Volume::Certificate { .. } => {
+            return Err(Error::new(
+                eyre!("Volume {} of {} is read-only", volume_id, id),
+                crate::ErrorKind::InvalidRequest,
+            ))
+        }
+        Volume::Pointer { package_id, .. } => {
+            return Err(Error::new(
+                eyre!(
+                    "Volume {} of {} belongs to {}, browse it there",
+                    volume_id,
+                    id,
+                    package_id
+                ),
+                crate::ErrorKind::InvalidRequest,
+            ))
+        }
+        Volume::Backup { .. } => {
+            return Err(Error::new(
+                eyre!("The backup volume cannot be browsed"),
+                crate::ErrorKind::InvalidRequest,
+            ))
+        }
+    }
+    Ok(volume.path_for(ctx, id, &manifest.version, volume_id))
+}
+
+/// Joins `path` onto `root` without leaving it: `..` cannot climb above the root and absolute
+/// paths are taken relative to it.
+fn confine(root: &Path, path: &Path) -> Result<PathBuf, Error> {
+    let mut relative = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(c) => relative.push(c),
+            Component::CurDir | Component::RootDir => (),
+            Component::ParentDir if relative.pop() => (),
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(Error::new(
+                    eyre!("{} is outside of the volume", path.display()),
+                    crate::ErrorKind::InvalidRequest,
+                ))
+            }
+        }
+    }
+    Ok(root.join(relative))
+}
+
+/// A directory of a volume, opened one component at a time without following symlinks. Packages
+/// control the contents of their volumes, so writes go through these handles: a path component
+/// swapped for a symlink cannot redirect them out of the volume.
+pub(crate) struct VolumeDir {
+    fd: RawFd,
+    uid: u32,
+    gid: u32,
+}
+impl VolumeDir {
+    fn from_fd(fd: RawFd) -> Result<Self, Error> {
+        match fstat(fd) {
+            Ok(stat) => Ok(VolumeDir {
+                fd,
+                uid: stat.st_uid,
+                gid: stat.st_gid,
+            }),
+            Err(e) => {
+                let _ = close(fd);
+                Err(Error::new(e, crate::ErrorKind::Filesystem))
+            }
+        }
+    }
+    /// Opens `relative` below `root`, creating missing directories owned by the owner of their
+    /// parent.
+    pub(crate) fn open(root: &Path, relative: &Path) -> Result<Self, Error> {
+        Self::open_impl(root, relative, true)
+    }
+    /// Opens `relative` below `root`, which must exist.
+    pub(crate) fn open_existing(root: &Path, relative: &Path) -> Result<Self, Error> {
+        Self::open_impl(root, relative, false)
+    }
+    fn open_impl(root: &Path, relative: &Path, create: bool) -> Result<Self, Error> {
+        let mut dir = VolumeDir::from_fd(
+            open(
+                root,
+                OFlag::O_DIRECTORY | OFlag::O_RDONLY | OFlag::O_CLOEXEC,
+                Mode::empty(),
+            )
+            .with_ctx(|_| (crate::ErrorKind::Filesystem, root.display().to_string()))?,
+        )?;
+        for component in relative.components() {
+            let name = match component {
+                Component::Normal(name) => name,
+                _ => {
+                    return Err(Error::new(
+                        eyre!("{} is outside of the volume", relative.display()),
+                        crate::ErrorKind::InvalidRequest,
+                    ))
+                }
+            };
+            if create {
+                match mkdirat(dir.fd, name, Mode::from_bits_truncate(0o755)) {
+                    Ok(()) => fchownat(
+                        Some(dir.fd),
+                        name,
+                        Some(Uid::from_raw(dir.uid)),
+                        Some(Gid::from_raw(dir.gid)),
+                        FchownatFlags::NoFollowSymlink,
+                    )
+                    .with_ctx(|_| (crate::ErrorKind::Filesystem, relative.display().to_string()))?,
+                    Err(Errno::EEXIST) => (),
+                    Err(e) => {
+                        return Err(e).with_ctx(|_| {
+                            (crate::ErrorKind::Filesystem, relative.display().to_string())
+                        })
+                    }
+                }
+            }
+            let fd = openat(
+                dir.fd,
+                name,
+                OFlag::O_DIRECTORY | OFlag::O_RDONLY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC,
+                Mode::empty(),
+            )
+            .map_err(|e| match e {
+                Errno::ENOENT => Error::new(
+                    eyre!("{} does not exist", relative.display()),
+                    crate::ErrorKind::NotFound,
+                ),
+                Errno::ELOOP | Errno::ENOTDIR => Error::new(
+                    eyre!(
+                        "{} is not a directory in {}",
+                        name.to_string_lossy(),
+                        relative.display()
+                    ),
+                    crate::ErrorKind::InvalidRequest,
+                ),
+                e => Error::new(e, crate::ErrorKind::Filesystem),
+            })?;
+            dir = VolumeDir::from_fd(fd)?;
+        }
+        Ok(dir)
+    }
+    /// Opens `name` for reading. Fails if it is a symlink.
+    fn open_file(&self, name: &str) -> Result<std::fs::File, Error> {
+        let fd = openat(
+            self.fd,
+            name,
+            OFlag::O_RDONLY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )
+        .map_err(|e| match e {
+            Errno::ENOENT => {
+                Error::new(eyre!("{} does not exist", name), crate::ErrorKind::NotFound)
+            }
+            Errno::ELOOP => Error::new(
+                eyre!("{} is a symlink", name),
+                crate::ErrorKind::InvalidRequest,
+            ),
+            e => Error::new(e, crate::ErrorKind::Filesystem),
+        })?;
+        Ok(unsafe { std::fs::File::from_raw_fd(fd) })
+    }
+    /// The entries of the directory, without following symlinks.
+    fn entries(&self) -> Result<Vec<VolumeEntry>, Error> {
+        let mut dir = Dir::openat(
+            self.fd,
+            ".",
+            OFlag::O_DIRECTORY | OFlag::O_RDONLY | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )
+        .with_kind(crate::ErrorKind::Filesystem)?;
+        let mut res = Vec::new();
+        for entry in dir.iter() {
+            let entry = entry.with_kind(crate::ErrorKind::Filesystem)?;
+            let name = entry.file_name();
+            if name.to_bytes() == b"." || name.to_bytes() == b".." {
+                continue;
+            }
+            let stat = match fstatat(self.fd, name, AtFlags::AT_SYMLINK_NOFOLLOW) {
+                Ok(a) => a,
+                // removed since it was listed
+                Err(Errno::ENOENT) => continue,
+                Err(e) => return Err(Error::new(e, crate::ErrorKind::Filesystem)),
+            };
+            res.push(VolumeEntry {
+                name: name.to_string_lossy().into_owned(),
+                kind: match stat.st_mode & libc::S_IFMT {
+                    libc::S_IFLNK => EntryKind::Symlink,
+                    libc::S_IFDIR => EntryKind::Directory,
+                    libc::S_IFREG => EntryKind::File,
+                    _ => EntryKind::Other,
+                },
+                size: stat.st_size as u64,
+                modified: Utc
+                    .timestamp_opt(stat.st_mtime, stat.st_mtime_nsec as u32)
+                    .single(),
+            });
+        }
+        Ok(res)
+    }
+    /// Creates `name` for writing, owned by the owner of the directory. Fails if anything exists
+    /// there already.
+    pub(crate) fn create(&self, name: &str) -> Result<std::fs::File, Error> {
+        let fd = openat(
+            self.fd,
+            name,
+            OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC,
+            Mode::from_bits_truncate(0o644),
+        )
+        .with_ctx(|_| (crate::ErrorKind::Filesystem, name.to_owned()))?;
+        let file = unsafe { std::fs::File::from_raw_fd(fd) };
+        fchownat(
+            Some(self.fd),
+            name,
+            Some(Uid::from_raw(self.uid)),
+            Some(Gid::from_raw(self.gid)),
+            FchownatFlags::NoFollowSymlink,
+        )
+        .with_ctx(|_| (crate::ErrorKind::Filesystem, name.to_owned()))?;
+        Ok(file)
+    }
+    /// Moves `from` over `to`, which may only be a regular file if it exists.
+    pub(crate) fn replace(&self, from: &str, to: &str) -> Result<(), Error> {
+        match fstatat(self.fd, to, AtFlags::AT_SYMLINK_NOFOLLOW) {
+            Ok(stat) => crate::ensure_code!(
+                stat.st_mode & libc::S_IFMT == libc::S_IFREG,
+                crate::ErrorKind::InvalidRequest,
+                "{} exists and is not a regular file",
+                to
+            ),
+            Err(Errno::ENOENT) => (),
+            Err(e) => return Err(e).with_ctx(|_| (crate::ErrorKind::Filesystem, to.to_owned())),
+        }
+        renameat(Some(self.fd), from, Some(self.fd), to)
+            .with_ctx(|_| (crate::ErrorKind::Filesystem, to.to_owned()))
+    }
+    pub(crate) fn remove(&self, name: &str) {
+        let _ = unlinkat(Some(self.fd), name, UnlinkatFlags::NoRemoveDir);
+    }
+}
+impl Drop for VolumeDir {
+    fn drop(&mut self) {
+        let _ = close(self.fd);
+    }
+}
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    Deserialize,
+    Serialize,
+    Clone,
+    Copy,
+    Debug,
+    Deserialize,
+    Serialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+    Other,
+}
+impl EntryKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EntryKind::File => "file",
+            EntryKind::Directory => "directory",
+            EntryKind::Symlink => "symlink",
+            EntryKind::Other => "other",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct VolumeEntry {
+    pub name: String,
+    pub kind: EntryKind,
+    pub size: u64,
+    pub modified: Option<DateTime<Utc>>,
+}
+
+fn display_entries(entries: Vec<VolumeEntry>, matches: &ArgMatches<'_>) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(entries, matches);
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![bc => "NAME", "KIND", "SIZE", "MODIFIED"]);
+    for entry in entries {
+        table.add_row(row![
+            &entry.name,
+            entry.kind.as_str(),
+            entry.size,
+            &entry
+                .modified
+                .map(|m| m.to_rfc3339())
+                .unwrap_or_else(|| "N/A".to_owned()),
+        ]);
+    }
+    table.print_tty(false);
+}
+
+/// The contents of a directory in the volume, the root if no path is given.
+#[command(display(display_entries))]
+#[instrument(skip(ctx))]
+pub async fn list(
+    #[context] ctx: RpcContext,
+    #[parent_data] (id, volume_id): (PackageId, VolumeId),
+    #[arg] path: Option<PathBuf>,
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<VolumeEntry>, Error> {
+    let root = volume_root(&ctx, &id, &volume_id, false).await?;
+    let relative = confine(Path::new(""), path.as_deref().unwrap_or(Path::new("")))?;
+    let mut res =
+        tokio::task::spawn_blocking(move || VolumeDir::open_existing(&root, &relative)?.entries())
+            .await
+            .with_kind(crate::ErrorKind::Unknown)??;
+    res.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(res)
+}
+
+/// Streams a file of the volume from the returned guid.
+#[command(rpc_only)]
+#[instrument(skip(ctx))]
+pub async fn download(
+    #[context] ctx: RpcContext,
+    #[parent_data] (id, volume_id): (PackageId, VolumeId),
+    #[arg] path: PathBuf,
+) -> Result<RequestGuid, Error> {
+    let root = volume_root(&ctx, &id, &volume_id, false).await?;
+    let relative = confine(Path::new(""), &path)?;
+    let filename = relative
+        .file_name()
+        .ok_or_else(|| {
+            Error::new(
+                eyre!("{} is not a file path", path.display()),
+                crate::ErrorKind::InvalidRequest,
+            )
+        })?
+        .to_string_lossy()
+        .into_owned();
+    let parent = relative.parent().unwrap_or(Path::new("")).to_owned();
+    let file = tokio::fs::File::from_std(
+        tokio::task::spawn_blocking(move || {
+            VolumeDir::open_existing(&root, &parent)?.open_file(&filename)
+        })
+        .await
+        .with_kind(crate::ErrorKind::Unknown)??,
+    );
+    let metadata = file.metadata().await?;
+    crate::ensure_code!(
+        metadata.is_file(),
+        crate::ErrorKind::InvalidRequest,
+        "{} is not a file",
+        path.display()
+    );
+    let handler = Box::new(move |_: Request<Body>| {
+        async move {
+            Response::builder()
+                .status(StatusCode::OK)
+                .header(http::header::CONTENT_TYPE, "application/octet-stream")
+                .header(http::header::CONTENT_LENGTH, metadata.len())
+                .body(Body::wrap_stream(tokio_util::io::ReaderStream::new(file)))
+                .with_kind(crate::ErrorKind::Network)
+        }
+        .boxed()
+    });
+    let guid = RequestGuid::new();
+    ctx.add_continuation(
+        guid.clone(),
+        RpcContinuation {
+            created_at: Instant::now(),
+            handler,
+        },
+    )
+    .await;
+    Ok(guid)
+}
+
+/// Accepts a file for a data volume, streamed to the returned guid. An existing file at `path` is
+/// replaced, and missing directories are created. Every upload is recorded in the audit log.
+#[command(rpc_only)]
+#[instrument(skip(ctx, req))]
+pub async fn upload(
+    #[context] ctx: RpcContext,
+    #[request] req: &RequestParts,
+    #[parent_data] (id, volume_id): (PackageId, VolumeId),
+    #[arg] path: PathBuf,
+) -> Result<RequestGuid, Error> {
+    let root = volume_root(&ctx, &id, &volume_id, true).await?;
+    let relative = confine(Path::new(""), &path)?;
+    let filename = relative
+        .file_name()
+        .ok_or_else(|| {
+            Error::new(
+                eyre!("{} is not a file path", path.display()),
+                crate::ErrorKind::InvalidRequest,
+            )
+        })?
+        .to_string_lossy()
+        .into_owned();
+    let parent = relative.parent().unwrap_or(Path::new("")).to_owned();
+    let session = HashSessionToken::from_request_parts(req)
+        .ok()
+        .map(|s| s.hashed().to_owned());
+
+    let guid = RequestGuid::new();
+    let handler_ctx = ctx.clone();
+    let handler = Box::new(move |req: Request<Body>| {
+        async move {
+            let ctx = handler_ctx;
+            let tmp = format!(".{}.{:016x}.tmp", filename, rand::random::<u64>());
+            let (dir, file) = tokio::task::spawn_blocking({
+                let tmp = tmp.clone();
+                move || {
+                    let dir = VolumeDir::open(&root, &parent)?;
+                    let file = dir.create(&tmp)?;
+                    Ok::<_, Error>((dir, file))
+                }
+            })
+            .await
+            .with_kind(crate::ErrorKind::Unknown)??;
+            let res = async {
+                let mut file = tokio::fs::File::from_std(file);
+                let mut body = req.into_body();
+                let mut size = 0;
+                let mut hasher = Sha256::new();
+                while let Some(chunk) = body.next().await {
+                    let chunk = chunk.with_kind(crate::ErrorKind::Network)?;
+                    size += chunk.len() as u64;
+                    hasher.update(&chunk);
+                    file.write_all(&chunk)
+                        .await
+                        .with_ctx(|_| (crate::ErrorKind::Filesystem, tmp.clone()))?;
+                }
+                file.sync_all().await?;
+                dir.replace(&tmp, &filename)?;
+                Ok::<_, Error>((size, hasher.finalize()))
+            }
+            .await;
+            let (size, hash) = match res {
+                Ok(a) => a,
+                Err(e) => {
+                    dir.remove(&tmp);
+                    return Err(e);
+                }
+            };
+            record(
+                &mut ctx.secret_store.acquire().await?,
+                &id,
+                &volume_id,
+                &relative,
+                size,
+                &hex::encode(hash),
+                session.as_deref(),
+            )
+            .await?;
+            Response::builder()
+                .status(StatusCode::OK)
+                .body(Body::empty())
+                .with_kind(crate::ErrorKind::Network)
+        }
+        .boxed()
+    });
+    ctx.add_continuation(
+        guid.clone(),
+        RpcContinuation {
+            created_at: Instant::now(),
+            handler,
+        },
+    )
+    .await;
+    Ok(guid)
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AuditEntry {
+    pub created: DateTime<Utc>,
+    pub path: PathBuf,
+    pub size: u64,
+    pub sha256: String,
+    /// Hash of the session token the file was uploaded with.
+    pub session: Option<String>,
+}
+
+#[instrument(skip(secrets))]
+async fn record<Ex>(
+    secrets: &mut Ex,
+    pkg_id: &PackageId,
+    volume_id: &VolumeId,
+    path: &Path,
+    size: u64,
+    sha256: &str,
+    session: Option<&str>,
+) -> Result<(), Error>
+where
+    for<'a> &'a mut Ex: Executor<'a, Database = Sqlite>,
+{
+    sqlx::query(
+        "INSERT INTO volume_audit (package, volume, path, size, sha256, created_at, session) VALUES (?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(pkg_id.as_str())
+    .bind(volume_id.to_string())
+    .bind(path.display().to_string())
+    .bind(size as i64)
+    .bind(sha256)
+    .bind(Utc::now().to_rfc3339())
+    .bind(session)
+    .execute(&mut *secrets)
+    .await?;
+    Ok(())
+}
+
+#[instrument(skip(secrets))]
+pub async fn remove_audit<Ex>(secrets: &mut Ex, pkg_id: &PackageId) -> Result<(), Error>
+where
+    for<'a> &'a mut Ex: Executor<'a, Database = Sqlite>,
+{
+    sqlx::query("DELETE FROM volume_audit WHERE package = ?")
+        .bind(pkg_id.as_str())
+        .execute(&mut *secrets)
+        .await?;
+    Ok(())
+}
+
+fn display_audit(entries: Vec<AuditEntry>, matches: &ArgMatches<'_>) {
+    use prettytable::*;
+
+    if matches.is_present("format") {
+        return display_serializable(entries, matches);
+    }
+
+    let mut table = Table::new();
+    table.add_row(row![bc => "TIME", "PATH", "SIZE", "SHA256", "SESSION"]);
+    for entry in entries {
+        table.add_row(row![
+            &entry.created.to_rfc3339(),
+            &entry.path.display().to_string(),
+            entry.size,
+            &entry.sha256,
+            entry.session.as_deref().unwrap_or("N/A"),
+        ]);
+    }
+    table.print_tty(false);
+}
+
+/// The files written to the volume through [`upload`], newest first.
+#[command(display(display_audit))]
+#[instrument(skip(ctx))]
+pub async fn audit(
+    #[context] ctx: RpcContext,
+    #[parent_data] (id, volume_id): (PackageId, VolumeId),
+    #[allow(unused_variables)]
+    #[arg(long = "format")]
+    format: Option<IoFormat>,
+) -> Result<Vec<AuditEntry>, Error> {
+    sqlx::query(
+        "SELECT path, size, sha256, created_at, session FROM volume_audit WHERE package = ? AND volume = ? ORDER BY id DESC",
+    )
+    .bind(id.as_str())
+    .bind(volume_id.to_string())
+    .fetch_all(&mut ctx.secret_store.acquire().await?)
+    .await?
+    .into_iter()
+    .map(|row| -> Result<_, Error> {
+        Ok(AuditEntry {
+            created: DateTime::parse_from_rfc3339(&row.try_get::<String, _>("created_at")?)
+                .with_kind(crate::ErrorKind::Deserialization)?
+                .with_timezone(&Utc),
+            path: PathBuf::from(row.try_get::<String, _>("path")?),
+            size: row.try_get::<i64, _>("size")? as u64,
+            sha256: row.try_get("sha256")?,
+            session: row.try_get("session")?,
+        })
+    })
+    .collect()
+}
+
+#[test]
+fn test_confine() {
+    let root = Path::new("/embassy-data/package-data/volumes/bitcoind/data/main");
+    assert_eq!(
+        confine(root, Path::new("/bitcoin.conf")).unwrap(),
+        root.join("bitcoin.conf")
+    );
+    assert_eq!(
+        confine(root, Path::new("blocks/../wallets/./w.dat")).unwrap(),
+        root.join("wallets/w.dat")
+    );
+    assert_eq!(confine(root, Path::new("")).unwrap(), root);
+    assert!(confine(root, Path::new("../other")).is_err());
+    assert!(confine(root, Path::new("a/../../other")).is_err());
+}
+
+#[test]
+fn test_volume_dir_symlinks() {
+    let tmp = std::env::temp_dir().join(format!("embassy-volume-test-{}", std::process::id()));
+    let root = tmp.join("volume");
+    std::fs::create_dir_all(root.join("sub")).unwrap();
+    std::fs::write(tmp.join("secret"), "secret").unwrap();
+    std::fs::write(root.join("sub/file"), "file").unwrap();
+    std::os::unix::fs::symlink(tmp.join("secret"), root.join("link")).unwrap();
+    std::os::unix::fs::symlink(&tmp, root.join("linkdir")).unwrap();
+
+    let dir = VolumeDir::open_existing(&root, Path::new("")).unwrap();
+    assert!(dir.open_file("link").is_err());
+    assert!(VolumeDir::open_existing(&root, Path::new("linkdir")).is_err());
+    assert!(VolumeDir::open_existing(&root, Path::new("missing")).is_err());
+    assert!(!root.join("missing").exists());
+    let mut entries = dir.entries().unwrap();
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    let kinds: Vec<_> = entries.iter().map(|e| (e.name.as_str(), e.kind)).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            ("link", EntryKind::Symlink),
+            ("linkdir", EntryKind::Symlink),
+            ("sub", EntryKind::Directory),
+        ]
+    );
+    let sub = VolumeDir::open_existing(&root, Path::new("sub")).unwrap();
+    let mut contents = String::new();
+    std::io::Read::read_to_string(&mut sub.open_file("file").unwrap(), &mut contents).unwrap();
+    assert_eq!(contents, "file");
+
+    std::fs::remove_dir_all(&tmp).unwrap();
+}
@@ -0,0 +1,526 @@
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::{Deref, DerefMut};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use patch_db::{DbHandle, HasModel, Map, MapModel};
+use serde::{Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::process::Command;
+use tracing::instrument;
+
+use crate::context::RpcContext;
+use crate::db::model::PackageDataEntry;
+use crate::id::{Id, IdUnchecked, InvalidId};
+use crate::net::interface::{InterfaceId, Interfaces};
+use crate::s9pk::manifest::PackageId;
+use crate::util::{Invoke, Version};
+use crate::{Error, ResultExt};
+
+pub mod files;
+
+pub const PKG_VOLUME_DIR: &'static str = "package-data/volumes";
+/// The filesystem holding the package volumes, mounted with project quotas enforced.
+pub const PKG_DATA_FS: &'static str = "package-data";
+pub const BACKUP_DIR: &'static str = "/media/embassy-os/backups";
+pub const VOLUME_USAGE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum VolumeId<S: AsRef<str> = String> {
+    Backup,
+    Custom(Id<S>),
+}
+impl<S: AsRef<str>> std::fmt::Display for VolumeId<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VolumeId::Backup => write!(f, "BACKUP"),
+            VolumeId::Custom(id) => write!(f, "{}", id),
+        }
+    }
+}
+impl<S: AsRef<str>> AsRef<str> for VolumeId<S> {
+    fn as_ref(&self) -> &str {
+        match self {
+            VolumeId::Backup => "BACKUP",
+            VolumeId::Custom(id) => id.as_ref(),
+        }
+    }
+}
+impl<S: AsRef<str>> Borrow<str> for VolumeId<S> {
+    fn borrow(&self) -> &str {
+        self.as_ref()
+    }
+}
+impl<S: AsRef<str>> AsRef<Path> for VolumeId<S> {
+    fn as_ref(&self) -> &Path {
+        AsRef::<str>::as_ref(self).as_ref()
+    }
+}
+impl FromStr for VolumeId {
+    type Err = InvalidId;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "BACKUP" => VolumeId::Backup,
+            _ => VolumeId::Custom(Id::try_from(s.to_owned())?),
+        })
+    }
+}
+impl<'de, S> Deserialize<'de> for VolumeId<S>
+where
+    S: AsRef<str>,
+    IdUnchecked<S>: Deserialize<'de>,
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let unchecked: IdUnchecked<S> = Deserialize::deserialize(deserializer)?;
+        Ok(match unchecked.0.as_ref() {
+            "BACKUP" => VolumeId::Backup,
+            _ => VolumeId::Custom(Id::try_from(unchecked.0).map_err(serde::de::Error::custom)?),
+        })
+    }
+}
+impl<S: AsRef<str>> Serialize for VolumeId<S> {
+    fn serialize<Ser>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error>
+    where
+        Ser: serde::Serializer,
+    {
+        serializer.serialize_str(self.as_ref())
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Volumes(BTreeMap<VolumeId, Volume>);
+impl Volumes {
+    #[instrument]
+    pub fn validate(&self, interfaces: &Interfaces) -> Result<(), Error> {
+        for (id, volume) in &self.0 {
+            volume
+                .validate(interfaces)
+                .with_ctx(|_| (crate::ErrorKind::ValidateS9pk, format!("Volume {}", id)))?;
+        }
+        Ok(())
+    }
+    #[instrument(skip(ctx))]
+    pub async fn install(
+        &self,
+        ctx: &RpcContext,
+        pkg_id: &PackageId,
+        version: &Version,
+    ) -> Result<(), Error> {
+        for (volume_id, volume) in &self.0 {
+            volume.install(ctx, pkg_id, version, volume_id).await?; // TODO: concurrent?
+        }
+        Ok(())
+    }
+    pub fn get_path_for(
+        &self,
+        ctx: &RpcContext,
+        pkg_id: &PackageId,
+        version: &Version,
+        volume_id: &VolumeId,
+    ) -> Option<PathBuf> {
+        self.0
+            .get(volume_id)
+            .map(|volume| volume.path_for(ctx, pkg_id, version, volume_id))
+    }
+    pub fn to_readonly(&self) -> Self {
+        Volumes(
+            self.0
+                .iter()
+                .map(|(id, volume)| {
+                    let mut volume = volume.clone();
+                    volume.set_readonly();
+                    (id.clone(), volume)
+                })
+                .collect(),
+        )
+    }
+}
+impl Deref for Volumes {
+    type Target = BTreeMap<VolumeId, Volume>;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+impl DerefMut for Volumes {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+impl Map for Volumes {
+    type Key = VolumeId;
+    type Value = Volume;
+    fn get(&self, key: &Self::Key) -> Option<&Self::Value> {
+        self.0.get(key)
+    }
+}
+pub type VolumesModel = MapModel<Volumes>;
+impl HasModel for Volumes {
+    type Model = MapModel<Self>;
+}
+
+pub fn data_dir<P: AsRef<Path>>(datadir: P, pkg_id: &PackageId, volume_id: &VolumeId) -> PathBuf {
+    datadir
+        .as_ref()
+        .join(PKG_VOLUME_DIR)
+        .join(pkg_id)
+        .join("data")
+        .join(volume_id)
+}
+
+pub fn asset_dir<P: AsRef<Path>>(datadir: P, pkg_id: &PackageId, version: &Version) -> PathBuf {
+    datadir
+        .as_ref()
+        .join(PKG_VOLUME_DIR)
+        .join(pkg_id)
+        .join("assets")
+        .join(version.as_str())
+}
+
+pub fn backup_dir(pkg_id: &PackageId) -> PathBuf {
+    Path::new(BACKUP_DIR).join(pkg_id).join("data")
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, HasModel)]
+#[serde(tag = "type")]
+#[serde(rename_all = "kebab-case")]
+pub enum Volume {
+    #[serde(rename_all = "kebab-case")]
+    Data {
+        #[serde(skip)]
+        readonly: bool,
+        /// The most the volume may hold, in bytes. Unlimited if not set.
+        #[serde(default)]
+        quota: Option<u64>,
+    },
+    #[serde(rename_all = "kebab-case")]
+    Assets {},
+    #[serde(rename_all = "kebab-case")]
+    Pointer {
+        package_id: PackageId,
+        volume_id: VolumeId,
+        path: PathBuf,
+        readonly: bool,
+    },
+    #[serde(rename_all = "kebab-case")]
+    Certificate { interface_id: InterfaceId },
+    #[serde(rename_all = "kebab-case")]
+    #[serde(skip)]
+    Backup { readonly: bool },
+}
+impl Volume {
+    #[instrument]
+    pub fn validate(&self, interfaces: &Interfaces) -> Result<(), color_eyre::eyre::Report> {
+        match self {
+            Volume::Certificate { interface_id } => {
+                if !interfaces.0.contains_key(interface_id) {
+                    color_eyre::eyre::bail!("unknown interface: {}", interface_id);
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+    pub async fn install(
+        &self,
+        ctx: &RpcContext,
+        pkg_id: &PackageId,
+        version: &Version,
+        volume_id: &VolumeId,
+    ) -> Result<(), Error> {
+        match self {
+            Volume::Data { quota, .. } => {
+                tokio::fs::create_dir_all(self.path_for(ctx, pkg_id, version, volume_id)).await?;
+                set_quota(ctx, pkg_id, volume_id, *quota).await?;
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+    pub fn path_for(
+        &self,
+        ctx: &RpcContext,
+        pkg_id: &PackageId,
+        version: &Version,
+        volume_id: &VolumeId,
+    ) -> PathBuf {
+        match self {
+            Volume::Data { .. } => data_dir(&ctx.datadir, pkg_id, volume_id),
+            Volume::Assets {} => asset_dir(&ctx.datadir, pkg_id, version).join(volume_id),
+            Volume::Pointer {
+                package_id,
+                volume_id,
+                path,
+                ..
+            } => data_dir(&ctx.datadir, package_id, volume_id).join(if path.is_absolute() {
+                path.strip_prefix("/").unwrap()
+            } else {
+                path.as_ref()
+            }),
+            Volume::Certificate { interface_id: _ } => ctx.net_controller.ssl_directory_for(pkg_id),
+            Volume::Backup { .. } => backup_dir(pkg_id),
+        }
+    }
+    pub fn set_readonly(&mut self) {
+        match self {
+            Volume::Data { readonly, .. } => {
+                *readonly = true;
+            }
+            Volume::Pointer { readonly, .. } => {
+                *readonly = true;
+            }
+            Volume::Backup { readonly } => {
+                *readonly = true;
+            }
+            _ => (),
+        }
+    }
+    pub fn readonly(&self) -> bool {
+        match self {
+            Volume::Data { readonly, .. } => *readonly,
+            Volume::Assets {} => true,
+            Volume::Pointer { readonly, .. } => *readonly,
+            Volume::Certificate { .. } => true,
+            Volume::Backup { readonly } => *readonly,
+        }
+    }
+}
+
+/// The project quota id of a data volume. It is derived from the names of the package and volume
+/// so that it needs no bookkeeping and survives reinstalls.
+fn project_id(pkg_id: &PackageId, volume_id: &VolumeId) -> u32 {
+    let hash = Sha256::digest(format!("{}/{}", pkg_id, volume_id).as_bytes());
+    (u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]) & 0x7fff_ffff).max(1)
+}
+
+/// Limits a data volume to `quota` bytes, or lifts the limit. Files created in the volume inherit
+/// its project, existing ones are tagged here, so its usage is accounted for either way.
+#[instrument(skip(ctx))]
+async fn set_quota(
+    ctx: &RpcContext,
+    pkg_id: &PackageId,
+    volume_id: &VolumeId,
+    quota: Option<u64>,
+) -> Result<(), Error> {
+    let project = project_id(pkg_id, volume_id).to_string();
+    let path = data_dir(&ctx.datadir, pkg_id, volume_id);
+    let mut setquota = Command::new("setquota");
+    setquota
+        .arg("-P")
+        .arg(&project)
+        .arg("0")
+        // block limits are in KiB
+        .arg(quota.map(|q| (q + 1023) / 1024).unwrap_or(0).to_string())
+        .arg("0")
+        .arg("0")
+        .arg(ctx.datadir.join(PKG_DATA_FS));
+    // some files, like sockets, cannot carry a project; they just don't count
+    if let Err(e) = Command::new("chattr")
+        .arg("-R")
+        .arg("-p")
+        .arg(&project)
+        .arg("+P")
+        .arg(&path)
+        .invoke(crate::ErrorKind::Filesystem)
+        .await
+    {
+        tracing::warn!("Failed to tag {} with its project: {}", path.display(), e);
+        tracing::debug!("{:?}", e);
+    }
+    match quota {
+        Some(_) => {
+            setquota.invoke(crate::ErrorKind::Filesystem).await?;
+        }
+        None => {
+            // there is only a limit to lift if an earlier version declared one
+            if let Err(e) = setquota.invoke(crate::ErrorKind::Filesystem).await {
+                tracing::debug!("Failed to clear quota of {}: {:?}", path.display(), e);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct VolumeUsage {
+    /// Bytes on disk.
+    pub used: u64,
+    pub quota: Option<u64>,
+}
+
+/// The space taken by everything under `path`, counting each file once. Files removed while
+/// walking are skipped.
+pub async fn disk_usage(path: PathBuf) -> Result<u64, Error> {
+    fn walk(path: &Path, seen: &mut BTreeSet<(u64, u64)>) -> std::io::Result<u64> {
+        let metadata = match std::fs::symlink_metadata(path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            a => a?,
+        };
+        if !metadata.is_dir()
+            && metadata.nlink() > 1
+            && !seen.insert((metadata.dev(), metadata.ino()))
+        {
+            return Ok(0);
+        }
+        let mut used = metadata.blocks() * 512;
+        if metadata.is_dir() {
+            let entries = match std::fs::read_dir(path) {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+                a => a?,
+            };
+            for entry in entries {
+                used += walk(&entry?.path(), seen)?;
+            }
+        }
+        Ok(used)
+    }
+    tokio::task::spawn_blocking(move || {
+        walk(&path, &mut Default::default())
+            .with_ctx(|_| (crate::ErrorKind::Filesystem, path.display().to_string()))
+    })
+    .await
+    .with_kind(crate::ErrorKind::Unknown)?
+}
+
+/// The bytes used by each project on the package data filesystem, as accounted by its project
+/// quotas.
+#[instrument(skip(ctx))]
+async fn project_usage(ctx: &RpcContext) -> Result<BTreeMap<u32, u64>, Error> {
+    let out = Command::new("repquota")
+        .arg("-P")
+        .arg("-n")
+        .arg(ctx.datadir.join(PKG_DATA_FS))
+        .invoke(crate::ErrorKind::Filesystem)
+        .await?;
+    Ok(parse_repquota(&String::from_utf8(out)?))
+}
+
+/// Parses the lines of `repquota -n`, which look like `#<id> -- <used KiB> <soft> <hard> ...`.
+fn parse_repquota(out: &str) -> BTreeMap<u32, u64> {
+    out.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let id = fields.next()?.strip_prefix('#')?.parse().ok()?;
+            let used: u64 = fields.nth(1)?.parse().ok()?;
+            Some((id, used * 1024))
+        })
+        .collect()
+}
+
+#[instrument(skip(ctx, db, volumes, projects))]
+async fn volume_usage<Db: DbHandle>(
+    ctx: &RpcContext,
+    db: &mut Db,
+    pkg_id: &PackageId,
+    volumes: &Volumes,
+    projects: &BTreeMap<u32, u64>,
+) -> Result<(), Error> {
+    let mut usage = BTreeMap::new();
+    for (volume_id, volume) in volumes.iter() {
+        if let Volume::Data { quota, .. } = volume {
+            // volumes from before they were all tagged with a project are walked instead
+            let used = match projects.get(&project_id(pkg_id, volume_id)) {
+                Some(used) => *used,
+                None => disk_usage(data_dir(&ctx.datadir, pkg_id, volume_id)).await?,
+            };
+            usage.insert(
+                volume_id.clone(),
+                VolumeUsage {
+                    used,
+                    quota: *quota,
+                },
+            );
+        }
+    }
+    let installed = match crate::db::DatabaseModel::new()
+        .package_data()
+        .idx_model(pkg_id)
+        .and_then(|pde| pde.installed())
+        .check(db)
+        .await?
+    {
+        Some(a) => a,
+        None => return Ok(()),
+    };
+    // every write is a db revision sent to the ui
+    if *installed.clone().volume_usage().get(db, true).await? != usage {
+        installed.volume_usage().put(db, &usage).await?;
+    }
+    Ok(())
+}
+
+/// Records how much of the disk the data volumes of every installed package take up.
+#[instrument(skip(ctx))]
+pub async fn volume_usage_check(ctx: &RpcContext) {
+    let mut db = ctx.db.handle();
+    let packages = match crate::db::DatabaseModel::new()
+        .package_data()
+        .get(&mut db, false)
+        .await
+    {
+        Ok(a) => {
+            a.0.iter()
+                .filter_map(|(id, pde)| match pde {
+                    PackageDataEntry::Installed { installed, .. } => {
+                        Some((id.clone(), installed.manifest.volumes.clone()))
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        }
+        Err(e) => {
+            tracing::error!("Failed to list packages for volume usage: {}", e);
+            tracing::debug!("{:?}", e);
+            return;
+        }
+    };
+    let projects = match project_usage(ctx).await {
+        Ok(a) => a,
+        Err(e) => {
+            tracing::warn!(
+                "Failed to read project quotas, walking volumes instead: {}",
+                e
+            );
+            tracing::debug!("{:?}", e);
+            BTreeMap::new()
+        }
+    };
+    for (id, volumes) in packages {
+        if let Err(e) = volume_usage(ctx, &mut db, &id, &volumes, &projects).await {
+            tracing::error!("Failed to measure volume usage of {}: {}", id, e);
+            tracing::debug!("{:?}", e);
+        }
+    }
+}
+
+#[test]
+fn test_parse_repquota() {
+    let out = "*** Report for project quotas on device /dev/sda3
+Block grace time: 7days; Inode grace time: 7days
+                        Block limits                File limits
+Project         used    soft    hard  grace    used  soft  hard  grace
+----------------------------------------------------------------------
+#0        --      20       0       0              2     0     0
+#1234     +-    2048       0    1024  6days       5     0     0
+";
+    let usage = parse_repquota(out);
+    assert_eq!(usage.len(), 2);
+    assert_eq!(usage[&0], 20 * 1024);
+    assert_eq!(usage[&1234], 2048 * 1024);
+}
+
+#[test]
+fn test_project_id() {
+    let bitcoind: PackageId = "bitcoind".parse().unwrap();
+    let main: VolumeId = VolumeId::Custom(Id::try_from("main".to_owned()).unwrap());
+    let other: VolumeId = VolumeId::Custom(Id::try_from("other".to_owned()).unwrap());
+    assert_eq!(project_id(&bitcoind, &main), project_id(&bitcoind, &main));
+    assert_ne!(project_id(&bitcoind, &main), project_id(&bitcoind, &other));
+    assert!(project_id(&bitcoind, &main) <= i32::MAX as u32);
+}
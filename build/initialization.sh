@@ -12,6 +12,12 @@ apt-get update
 apt-get install -y \
 	tor \
 	nginx \
+	libnginx-mod-stream \
+	obfs4proxy \
+	snowflake-client \
+	wireguard-tools \
+	nftables \
+	quota \
 	libavahi-client3 \
 	avahi-daemon \
 	avahi-utils \
@@ -39,6 +45,8 @@ sed -i 's/#allow-interfaces=eth0/allow-interfaces=eth0,wlan0/g' /etc/avahi/avahi
 echo "auto wlan0" > /etc/network/interfaces
 echo "iface wlan0 inet dhcp" >> /etc/network/interfaces
 mkdir -p /etc/nginx/ssl
+mkdir -p /etc/nginx/streams-available /etc/nginx/streams-enabled
+echo 'stream { include /etc/nginx/streams-enabled/*.conf; }' >> /etc/nginx/nginx.conf
 
 # fix to suppress docker warning, fixed in 21.xx release of docker cli: https://github.com/docker/cli/pull/2934
 mkdir -p /root/.docker
@@ -55,6 +63,7 @@ SocksPolicy accept 127.0.0.1
 SocksPolicy accept 172.18.0.0/16
 SocksPolicy reject *
 ControlPort 9051
+DNSPort 127.0.0.1:9053
 CookieAuthentication 1
 EOF
 
@@ -1,61 +1,540 @@
-use std::{path::Path, process::Stdio};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::process::Stdio;
+use std::time::{Duration, Instant};
 
+use embassy::db::model::BackupProgress;
 use embassy::disk::main::DEFAULT_PASSWORD;
+use embassy::disk::mount::backup::{BACKUP_PROGRESS_FILE, PACKAGE_BACKUP_KEY_FILE};
+use embassy::middleware::encrypt::{decrypt_slice, encrypt_slice};
+use nix::sys::stat::{utimensat, UtimensatFlags};
+use nix::sys::time::{TimeSpec, TimeValLike};
+use nix::unistd::{fchownat, FchownatFlags, Gid, Uid};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
+const INDEX_FILE: &str = "index.enc";
+const CHUNK_DIR: &str = "chunks";
+const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+const INDEX_VERSION: u32 = 1;
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+/// Archives, manifests and signatures written by duplicity all start with this.
+const DUPLICITY_PREFIX: &str = "duplicity-";
+
+/// A `.backupignore` file. Each line is a glob relative to the data path that excludes matching
+/// paths, or includes them if prefixed with `!`. As with duplicity, the first matching line
+/// decides, and paths matching no line are included.
+struct IgnoreRules(Vec<IgnoreRule>);
+struct IgnoreRule {
+    include: bool,
+    glob: String,
+    regex: Regex,
+}
+impl IgnoreRules {
+    fn load(data_path: &Path) -> Result<Self, anyhow::Error> {
+        let ignore_path = data_path.join(".backupignore");
+        if !ignore_path.is_file() {
+            return Ok(IgnoreRules(Vec::new()));
+        }
+        let mut rules = Vec::new();
+        for line in std::fs::read_to_string(ignore_path)?
+            .lines()
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty() && !s.starts_with('#'))
+        {
+            let (include, glob) = match line.strip_prefix('!') {
+                Some(glob) => (true, glob),
+                None => (false, line),
+            };
+            let glob = glob
+                .trim_start_matches('/')
+                .trim_end_matches('/')
+                .to_owned();
+            rules.push(IgnoreRule {
+                include,
+                regex: glob_to_regex(&glob)?,
+                glob,
+            });
+        }
+        Ok(IgnoreRules(rules))
+    }
+
+    /// Whether `path` (relative to the data path) should be backed up. Directories that are
+    /// excluded but may contain included paths are returned as `Traverse`.
+    fn select(&self, path: &Path, is_dir: bool) -> Selection {
+        let path_str = path.to_string_lossy();
+        for (idx, rule) in self.0.iter().enumerate() {
+            if path
+                .ancestors()
+                .any(|a| !a.as_os_str().is_empty() && rule.regex.is_match(&a.to_string_lossy()))
+            {
+                if rule.include {
+                    return Selection::Include;
+                }
+                if is_dir
+                    && self.0[..idx].iter().any(|r| {
+                        r.include
+                            && (r.glob.starts_with(&format!("{}/", path_str))
+                                || r.glob.contains("**"))
+                    })
+                {
+                    return Selection::Traverse;
+                }
+                return Selection::Exclude;
+            }
+        }
+        Selection::Include
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Selection {
+    Include,
+    Traverse,
+    Exclude,
+}
+
+fn glob_to_regex(glob: &str) -> Result<Regex, anyhow::Error> {
+    let mut res = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                res.push_str(".*");
+            }
+            '*' => res.push_str("[^/]*"),
+            '?' => res.push_str("[^/]"),
+            '[' => {
+                res.push('[');
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        break;
+                    }
+                    if c == '\\' {
+                        res.push('\\');
+                    }
+                    res.push(c);
+                }
+                res.push(']');
+            }
+            c => res.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    res.push('$');
+    Ok(Regex::new(&res)?)
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Index {
+    version: u32,
+    entries: BTreeMap<PathBuf, Entry>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Entry {
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    mtime: i64,
+    mtime_nsec: i64,
+    #[serde(flatten)]
+    kind: EntryKind,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "kebab-case")]
+enum EntryKind {
+    Dir,
+    File { size: u64, chunks: Vec<String> },
+    Symlink { target: PathBuf },
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BackupStats {
+    pub files: u64,
+    pub bytes: u64,
+    pub bytes_unchanged: u64,
+    pub chunks_written: u64,
+    pub chunks_removed: u64,
+    pub duplicity_files_removed: u64,
+}
+
+fn read_key(mountpoint: &Path) -> Result<String, anyhow::Error> {
+    let key_path = mountpoint.join(PACKAGE_BACKUP_KEY_FILE);
+    Ok(std::fs::read_to_string(&key_path)
+        .map_err(|e| anyhow::anyhow!("Could not read backup key {}: {}", key_path.display(), e))?
+        .trim()
+        .to_owned())
+}
+
+/// Chunks are named by a hash keyed with the backup key, so names reveal nothing about the
+/// contents and double as an integrity check on restore.
+fn chunk_id(key: &str, data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(key.as_bytes());
+    hasher.update(data);
+    format!("{:x}", hasher.finalize())
+}
+
+fn chunk_path(mountpoint: &Path, id: &str) -> PathBuf {
+    mountpoint.join(CHUNK_DIR).join(&id[..2]).join(id)
+}
+
+/// Writes `data` to `path` by way of a temporary file, so an interrupted backup never leaves a
+/// truncated file behind.
+fn write_atomic(path: &Path, data: &[u8]) -> Result<(), anyhow::Error> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Reports how many bytes of the backup have been read to the backend, at most once per
+/// [`PROGRESS_INTERVAL`].
+struct ProgressReporter {
+    path: PathBuf,
+    progress: BackupProgress,
+    last_report: Option<Instant>,
+}
+impl ProgressReporter {
+    fn new(mountpoint: &Path, bytes_total: u64) -> Self {
+        ProgressReporter {
+            path: mountpoint.join(BACKUP_PROGRESS_FILE),
+            progress: BackupProgress {
+                complete: false,
+                bytes_done: Some(0),
+                bytes_total: Some(bytes_total),
+            },
+            last_report: None,
+        }
+    }
+    fn add(&mut self, bytes: u64) -> Result<(), anyhow::Error> {
+        self.progress.bytes_done = Some(self.progress.bytes_done.unwrap_or_default() + bytes);
+        if self
+            .last_report
+            .map_or(true, |last| last.elapsed() >= PROGRESS_INTERVAL)
+        {
+            self.report()?;
+        }
+        Ok(())
+    }
+    fn report(&mut self) -> Result<(), anyhow::Error> {
+        write_atomic(&self.path, &serde_json::to_vec(&self.progress)?)?;
+        self.last_report = Some(Instant::now());
+        Ok(())
+    }
+}
+
+fn read_index(mountpoint: &Path, key: &str) -> Result<Option<Index>, anyhow::Error> {
+    let index_path = mountpoint.join(INDEX_FILE);
+    if !index_path.is_file() {
+        return Ok(None);
+    }
+    let index: Index = serde_json::from_slice(&decrypt_slice(std::fs::read(&index_path)?, key))
+        .map_err(|e| anyhow::anyhow!("Could not read backup index (wrong key?): {}", e))?;
+    if index.version != INDEX_VERSION {
+        return Err(anyhow::anyhow!(
+            "Unsupported backup index version {}",
+            index.version
+        ));
+    }
+    Ok(Some(index))
+}
+
+fn scan(
+    data_path: &Path,
+    rel: &Path,
+    rules: &IgnoreRules,
+    entries: &mut BTreeMap<PathBuf, (std::fs::Metadata, Selection)>,
+) -> Result<(), anyhow::Error> {
+    for dir_entry in std::fs::read_dir(data_path.join(rel))? {
+        let dir_entry = dir_entry?;
+        let rel = rel.join(dir_entry.file_name());
+        let metadata = std::fs::symlink_metadata(data_path.join(&rel))?;
+        match rules.select(&rel, metadata.is_dir()) {
+            Selection::Exclude => continue,
+            selection => {
+                if metadata.is_dir() {
+                    scan(data_path, &rel, rules, entries)?;
+                }
+                entries.insert(rel, (metadata, selection));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Backs up `data_path` into `mountpoint`. Unchanged files reuse the chunks of the previous
+/// backup, and chunks no longer referenced are removed afterwards.
 pub fn create_backup(
     mountpoint: impl AsRef<Path>,
     data_path: impl AsRef<Path>,
-) -> Result<(), anyhow::Error> {
+) -> Result<BackupStats, anyhow::Error> {
     let mountpoint = std::fs::canonicalize(mountpoint)?;
     let data_path = std::fs::canonicalize(data_path)?;
+    let key = read_key(&mountpoint)?;
+    let rules = IgnoreRules::load(&data_path)?;
+    let prev = read_index(&mountpoint, &key)?.unwrap_or_default();
 
-    let ignore_path = data_path.join(".backupignore");
-    let exclude = if ignore_path.is_file() {
-        std::fs::read_to_string(ignore_path)?
-    } else {
-        String::new()
-    };
+    let mut scanned = BTreeMap::new();
+    scan(&data_path, Path::new(""), &rules, &mut scanned)?;
+    // directories only traversed for included children are kept if anything was kept below them
+    let kept_dirs: BTreeSet<PathBuf> = scanned
+        .iter()
+        .filter(|(_, (_, selection))| *selection == Selection::Include)
+        .flat_map(|(path, _)| path.ancestors().skip(1).map(|a| a.to_owned()))
+        .collect();
+    scanned.retain(|path, (_, selection)| {
+        *selection == Selection::Include || kept_dirs.contains(path)
+    });
 
-    let mut data_cmd = std::process::Command::new("duplicity");
-    for exclude in exclude.lines().map(|s| s.trim()).filter(|s| !s.is_empty()) {
-        if exclude.to_string().starts_with('!') {
-            data_cmd.arg(format!(
-                "--include={}",
-                data_path
-                    .join(exclude.to_string().trim_start_matches('!'))
-                    .display()
-            )).arg("--allow-source-mismatch");
+    let mut progress = ProgressReporter::new(
+        &mountpoint,
+        scanned
+            .values()
+            .filter(|(metadata, _)| metadata.is_file())
+            .map(|(metadata, _)| metadata.len())
+            .sum(),
+    );
+    progress.report()?;
+    let mut stats = BackupStats::default();
+    let mut index = Index {
+        version: INDEX_VERSION,
+        entries: BTreeMap::new(),
+    };
+    for (rel, (metadata, _)) in scanned {
+        let kind = if metadata.file_type().is_symlink() {
+            EntryKind::Symlink {
+                target: std::fs::read_link(data_path.join(&rel))?,
+            }
+        } else if metadata.is_dir() {
+            EntryKind::Dir
+        } else if metadata.is_file() {
+            stats.files += 1;
+            stats.bytes += metadata.len();
+            match prev.entries.get(&rel) {
+                Some(Entry {
+                    mtime,
+                    mtime_nsec,
+                    kind: EntryKind::File { size, chunks },
+                    ..
+                }) if *size == metadata.len()
+                    && *mtime == metadata.mtime()
+                    && *mtime_nsec == metadata.mtime_nsec()
+                    && chunks.iter().all(|c| chunk_path(&mountpoint, c).is_file()) =>
+                {
+                    stats.bytes_unchanged += size;
+                    progress.add(*size)?;
+                    EntryKind::File {
+                        size: *size,
+                        chunks: chunks.clone(),
+                    }
+                }
+                _ => {
+                    let mut file = File::open(data_path.join(&rel))?;
+                    let mut chunks = Vec::new();
+                    let mut size = 0;
+                    let mut buf = vec![0; CHUNK_SIZE];
+                    loop {
+                        let mut len = 0;
+                        while len < CHUNK_SIZE {
+                            match file.read(&mut buf[len..])? {
+                                0 => break,
+                                n => len += n,
+                            }
+                        }
+                        if len == 0 {
+                            break;
+                        }
+                        let id = chunk_id(&key, &buf[..len]);
+                        let path = chunk_path(&mountpoint, &id);
+                        if !path.is_file() {
+                            write_atomic(&path, &encrypt_slice(&buf[..len], &key))?;
+                            stats.chunks_written += 1;
+                        }
+                        chunks.push(id);
+                        size += len as u64;
+                        progress.add(len as u64)?;
+                    }
+                    EntryKind::File { size, chunks }
+                }
+            }
         } else {
-            data_cmd.arg(format!(
-                "--exclude={}",
-                data_path.join(exclude.to_string()).display()
-            )).arg("--allow-source-mismatch");
+            continue; // sockets, fifos and devices are not backed up
+        };
+        index.entries.insert(
+            rel,
+            Entry {
+                mode: metadata.mode(),
+                uid: metadata.uid(),
+                gid: metadata.gid(),
+                mtime: metadata.mtime(),
+                mtime_nsec: metadata.mtime_nsec(),
+                kind,
+            },
+        );
+    }
+    write_atomic(
+        &mountpoint.join(INDEX_FILE),
+        &encrypt_slice(serde_json::to_vec(&index)?, &key),
+    )?;
+    progress.report()?;
+
+    // remove chunks only referenced by the previous backup
+    let referenced: BTreeSet<&str> = index
+        .entries
+        .values()
+        .filter_map(|e| match &e.kind {
+            EntryKind::File { chunks, .. } => Some(chunks.iter().map(|c| c.as_str())),
+            _ => None,
+        })
+        .flatten()
+        .collect();
+    let chunk_dir = mountpoint.join(CHUNK_DIR);
+    if chunk_dir.is_dir() {
+        for prefix in std::fs::read_dir(&chunk_dir)? {
+            for chunk in std::fs::read_dir(prefix?.path())? {
+                let chunk = chunk?;
+                if !referenced.contains(&*chunk.file_name().to_string_lossy()) {
+                    std::fs::remove_file(chunk.path())?;
+                    stats.chunks_removed += 1;
+                }
+            }
         }
     }
-    let data_output = data_cmd
-        .env("PASSPHRASE", DEFAULT_PASSWORD)
-        .arg(data_path)
-        .arg(format!("file://{}", mountpoint.display().to_string()))
-        .arg("--allow-source-mismatch")
-        .stderr(Stdio::piped())
-        .output()?;
-    if !data_output.status.success() {
+
+    // the index supersedes any backup made by duplicity before it
+    for file in std::fs::read_dir(&mountpoint)? {
+        let file = file?;
+        if file.file_type()?.is_file()
+            && file
+                .file_name()
+                .to_string_lossy()
+                .starts_with(DUPLICITY_PREFIX)
+        {
+            std::fs::remove_file(file.path())?;
+            stats.duplicity_files_removed += 1;
+        }
+    }
+
+    Ok(stats)
+}
+
+/// Rejects index entries that would be restored outside of the data path: paths that are not
+/// relative, contain `..`, or lead through a restored symlink.
+fn check_entry_path(index: &Index, rel: &Path) -> Result<(), anyhow::Error> {
+    if rel.as_os_str().is_empty() || rel.components().any(|c| !matches!(c, Component::Normal(_))) {
         return Err(anyhow::anyhow!(
-            "duplicity error: {}",
-            String::from_utf8(data_output.stderr).unwrap()
+            "Backup index contains invalid path {}",
+            rel.display()
         ));
     }
+    for ancestor in rel.ancestors().skip(1) {
+        if let Some(Entry {
+            kind: EntryKind::Symlink { .. },
+            ..
+        }) = index.entries.get(ancestor)
+        {
+            return Err(anyhow::anyhow!(
+                "Backup index contains {} inside of symlink {}",
+                rel.display(),
+                ancestor.display()
+            ));
+        }
+    }
+    Ok(())
+}
 
+fn restore_metadata(path: &Path, entry: &Entry) -> Result<(), anyhow::Error> {
+    fchownat(
+        None,
+        path,
+        Some(Uid::from_raw(entry.uid)),
+        Some(Gid::from_raw(entry.gid)),
+        FchownatFlags::NoFollowSymlink,
+    )?;
+    if let EntryKind::Symlink { .. } = entry.kind {
+        return Ok(());
+    }
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(entry.mode))?;
+    let mtime = TimeSpec::nanoseconds(entry.mtime * 1_000_000_000 + entry.mtime_nsec);
+    utimensat(None, path, &mtime, &mtime, UtimensatFlags::NoFollowSymlink)?;
     Ok(())
 }
 
+/// Restores the backup in `mountpoint` into `data_path`. Backups made by duplicity are restored
+/// with duplicity.
 pub fn restore_backup(
     mountpoint: impl AsRef<Path>,
     data_path: impl AsRef<Path>,
 ) -> Result<(), anyhow::Error> {
     let mountpoint = std::fs::canonicalize(mountpoint)?;
     let data_path = std::fs::canonicalize(data_path)?;
+    if !mountpoint.join(INDEX_FILE).is_file() {
+        return restore_duplicity_backup(&mountpoint, &data_path);
+    }
+    let key = read_key(&mountpoint)?;
+    let index = read_index(&mountpoint, &key)?.unwrap_or_default();
+    for rel in index.entries.keys() {
+        check_entry_path(&index, rel)?;
+    }
 
+    // entries are sorted, so parents are created before their children
+    for (rel, entry) in &index.entries {
+        let path = data_path.join(rel);
+        match &entry.kind {
+            EntryKind::Dir => std::fs::create_dir_all(&path)?,
+            EntryKind::Symlink { target } => {
+                if std::fs::symlink_metadata(&path).is_ok() {
+                    std::fs::remove_file(&path)?;
+                }
+                std::os::unix::fs::symlink(target, &path)?;
+            }
+            EntryKind::File { chunks, .. } => {
+                let tmp = path.with_file_name(format!(
+                    ".{}.restore",
+                    path.file_name().unwrap_or_default().to_string_lossy()
+                ));
+                let mut file = File::create(&tmp)?;
+                for id in chunks {
+                    let data = decrypt_slice(std::fs::read(chunk_path(&mountpoint, id))?, &key);
+                    if &chunk_id(&key, &data) != id {
+                        return Err(anyhow::anyhow!(
+                            "Backup of {} is corrupted: chunk {} does not match its contents",
+                            rel.display(),
+                            id
+                        ));
+                    }
+                    file.write_all(&data)?;
+                }
+                file.sync_all()?;
+                std::fs::rename(&tmp, &path)?;
+            }
+        }
+    }
+    // set directory metadata last, since restoring their contents changes their mtime
+    for (rel, entry) in index.entries.iter().rev() {
+        restore_metadata(&data_path.join(rel), entry)?;
+    }
+
+    Ok(())
+}
+
+fn restore_duplicity_backup(mountpoint: &Path, data_path: &Path) -> Result<(), anyhow::Error> {
     let data_output = std::process::Command::new("duplicity")
         .arg("--allow-source-mismatch")
         .env("PASSPHRASE", DEFAULT_PASSWORD)
@@ -73,3 +552,62 @@ pub fn restore_backup(
 
     Ok(())
 }
+
+#[test]
+fn test_check_entry_path() {
+    let entry = |kind| Entry {
+        mode: 0o755,
+        uid: 0,
+        gid: 0,
+        mtime: 0,
+        mtime_nsec: 0,
+        kind,
+    };
+    let mut index = Index::default();
+    index
+        .entries
+        .insert(PathBuf::from("a"), entry(EntryKind::Dir));
+    index.entries.insert(
+        PathBuf::from("link"),
+        entry(EntryKind::Symlink {
+            target: PathBuf::from("/etc"),
+        }),
+    );
+    assert!(check_entry_path(&index, Path::new("a/b")).is_ok());
+    assert!(check_entry_path(&index, Path::new("link")).is_ok());
+    assert!(check_entry_path(&index, Path::new("link/passwd")).is_err());
+    assert!(check_entry_path(&index, Path::new("../etc/passwd")).is_err());
+    assert!(check_entry_path(&index, Path::new("a/../../b")).is_err());
+    assert!(check_entry_path(&index, Path::new("/etc/passwd")).is_err());
+}
+
+#[test]
+fn test_ignore_rules() {
+    let rules = IgnoreRules(
+        vec![(true, "cache/keep"), (false, "cache"), (false, "**/*.log")]
+            .into_iter()
+            .map(|(include, glob)| IgnoreRule {
+                include,
+                glob: glob.to_owned(),
+                regex: glob_to_regex(glob).unwrap(),
+            })
+            .collect(),
+    );
+    assert_eq!(rules.select(Path::new("cache"), true), Selection::Traverse);
+    assert_eq!(
+        rules.select(Path::new("cache/keep/a"), false),
+        Selection::Include
+    );
+    assert_eq!(
+        rules.select(Path::new("cache/a"), false),
+        Selection::Exclude
+    );
+    assert_eq!(
+        rules.select(Path::new("a/b.log"), false),
+        Selection::Exclude
+    );
+    assert_eq!(
+        rules.select(Path::new("a/b.txt"), false),
+        Selection::Include
+    );
+}
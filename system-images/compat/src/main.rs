@@ -132,6 +132,7 @@ fn inner_main() -> Result<(), anyhow::Error> {
         )
         .subcommand(
             SubCommand::with_name("duplicity")
+                .about("Incremental, encrypted backups of the data volume. Backups made by duplicity can still be restored.")
                 .subcommand(
                     SubCommand::with_name("create")
                         .arg(